@@ -1,6 +1,192 @@
 local T = {}
 _G.T = T
 
+-- The version of the protocol between the runner and test-utils (e.g. the result file), which the runner reads from
+-- this line to check that it can use this test-utils. Keep it in sync with `TEST_UTILS_PROTOCOL_VERSION` of the runner
+T.PROTOCOL_VERSION = "1.10"
+
+local function new_report()
+  return {
+    updated_snapshots = {},
+    filtered_tests = {},
+    failures = {},
+    assertions = {},
+    metadata = {},
+  }
+end
+
+-- The structured results reported back to the test runner. Written as JSON to the file at
+-- `$NVIM_TEST_RUNNER_RESULT_FILE` when nvim exits
+local report = new_report()
+
+-- The full name of the `T.it` block that is running, if any
+local current_test
+
+-- With `$NVIM_TEST_RUNNER_CAPTURE_MESSAGES`, the message history (`:messages`), one entry per line, for the runner
+-- to show with the failures and check for errors that never reached stderr, e.g. those of deferred callbacks
+local function capture_messages()
+  if os.getenv("NVIM_TEST_RUNNER_CAPTURE_MESSAGES") ~= "1" then return {} end
+  local ok, output
+  if vim.api.nvim_exec2 then
+    ok, output = pcall(function() return vim.api.nvim_exec2("messages", { output = true }).output end)
+  else
+    ok, output = pcall(vim.api.nvim_exec, "messages", true)
+  end
+  if not ok or output == "" then return {} end
+  return vim.split(output, "\n", { plain = true })
+end
+
+---@param path? string
+local function write_report(path)
+  if not path or path == "" then return end
+
+  -- Empty lists are omitted, as they would otherwise be encoded as JSON objects
+  local result = {}
+  if #report.updated_snapshots > 0 then
+    result.updatedSnapshots = report.updated_snapshots
+  end
+  if #report.filtered_tests > 0 then
+    result.filteredTests = report.filtered_tests
+  end
+  if #report.failures > 0 then
+    result.failures = report.failures
+  end
+  if #report.assertions > 0 then
+    result.assertions = report.assertions
+  end
+  if next(report.metadata) then
+    result.metadata = report.metadata
+  end
+  if report.duration_ms then
+    result.durationMs = report.duration_ms
+  end
+  local messages = capture_messages()
+  if #messages > 0 then
+    result.messages = messages
+  end
+  -- For the runner to tell which dependencies the test used, with --check-unused-deps
+  if os.getenv("NVIM_TEST_RUNNER_REPORT_REQUIRES") == "1" then
+    local modules = {}
+    for name in pairs(package.loaded) do
+      if type(name) == "string" then table.insert(modules, name) end
+    end
+    table.sort(modules)
+    result.requiredModules = modules
+  end
+
+  local file = io.open(path, "w")
+  if not file then return end
+  file:write(vim.json.encode(result))
+  file:close()
+end
+
+-- With --coverage, the number of times each line of each Lua file ran in this nvim, by the path of the file.
+-- Traced with a line hook from when test-utils is loaded, and written to `$NVIM_TEST_RUNNER_COVERAGE_FILE` (in
+-- the stats format of luacov) when nvim exits
+local coverage_file = os.getenv("NVIM_TEST_RUNNER_COVERAGE_FILE")
+local coverage = {}
+if coverage_file and coverage_file ~= "" then
+  debug.sethook(function(_, line)
+    local source = debug.getinfo(2, "S").source
+    -- Code loaded from strings (e.g. `:lua` commands) has no file to report the lines of
+    if source:sub(1, 1) ~= "@" then return end
+    local hits = coverage[source]
+    if not hits then
+      hits = {}
+      coverage[source] = hits
+    end
+    hits[line] = (hits[line] or 0) + 1
+  end, "l")
+end
+
+local function write_coverage()
+  if not coverage_file or coverage_file == "" then return end
+  debug.sethook()
+  local file = io.open(coverage_file, "w")
+  if not file then return end
+  for source, hits in pairs(coverage) do
+    local max = 0
+    for line in pairs(hits) do
+      max = math.max(max, line)
+    end
+    local counts = {}
+    for line = 1, max do
+      counts[line] = hits[line] or 0
+    end
+    file:write(("%d:%s\n"):format(max, vim.fn.fnamemodify(source:sub(2), ":p")))
+    file:write(table.concat(counts, " "), "\n")
+  end
+  file:close()
+end
+
+-- Fail the test if the main module of the plugin under test (`$NVIM_TEST_RUNNER_PLUGIN_MODULE`, from the
+-- `pluginModule` config field) cannot be required, as the test would otherwise pass without exercising the plugin
+local function check_plugin_module()
+  local module = os.getenv("NVIM_TEST_RUNNER_PLUGIN_MODULE")
+  if not module or module == "" then return end
+  local ok, err = pcall(require, module)
+  if not ok then
+    table.insert(report.failures, {
+      message = ("The plugin module %q cannot be required, check the runtimepath: %s"):format(module, err),
+    })
+  end
+end
+
+vim.api.nvim_create_autocmd("VimLeavePre", {
+  callback = function()
+    check_plugin_module()
+    write_report(os.getenv("NVIM_TEST_RUNNER_RESULT_FILE"))
+    write_coverage()
+  end,
+})
+
+-- Record a failure for the test runner, then throw it. `level` is the stack level of the function whose caller
+-- is blamed for the failure, as in `error`. `values` are the expected and the actual value of a comparison, as
+-- strings, which the runner diffs
+--
+---@param message string
+---@param level? integer
+---@param values? { expected: string, actual: string }
+local function fail(message, level, values)
+  level = level or 2
+  local info = debug.getinfo(level + 1, "Sl") or {}
+  values = values or {}
+  table.insert(report.failures, {
+    message = message,
+    file = info.short_src,
+    line = info.currentline,
+    expected = values.expected,
+    actual = values.actual,
+  })
+  table.insert(report.assertions, {
+    name = (debug.getinfo(level, "n") or {}).name or "assertion",
+    status = "failed",
+    message = message,
+    test = current_test,
+  })
+  error(message, level)
+end
+
+-- Record for the test runner that the calling assertion passed
+local function pass()
+  table.insert(report.assertions, {
+    name = (debug.getinfo(2, "n") or {}).name or "assertion",
+    status = "passed",
+    test = current_test,
+  })
+end
+
+-- Provided by `test.nvim`
+--
+-- Attach a key/value pair (e.g. a category or a coverage marker) to the result of the test file. The test runner
+-- does not interpret it, but passes it on to the events, the TAP stream and the results of the run
+--
+---@param key string
+---@param value any
+function T.metadata(key, value)
+  report.metadata[key] = value
+end
+
 -- Provided by `test.nvim`
 --
 -- Print and return value
@@ -21,7 +207,7 @@ end
 ---@param message? string
 function T.assert(expr, message)
   if not expr then
-    error(
+    fail(
       message
         or (
           "Assertion failed. Expected truthy value, but got "
@@ -29,6 +215,7 @@ function T.assert(expr, message)
         )
     )
   end
+  pass()
 end
 
 -- Provided by `test.nvim`
@@ -40,8 +227,9 @@ end
 function T.assert_error(fn, message)
   local ok, result = pcall(fn)
   if ok then
-    error(message or ("Expected an error, but got " .. vim.inspect(result)))
+    fail(message or ("Expected an error, but got " .. vim.inspect(result)))
   end
+  pass()
 end
 
 -- Provided by `test.nvim`
@@ -52,7 +240,7 @@ end
 -- @param message? string
 function T.assert_not(expr, message)
   if expr then
-    error(
+    fail(
       message
         or (
           "Assertion failed. Expected falsey value, but got "
@@ -60,6 +248,7 @@ function T.assert_not(expr, message)
         )
     )
   end
+  pass()
 end
 
 -- Provided by `test.nvim`
@@ -78,8 +267,9 @@ function T.assert_eq(lhs, rhs, message)
         .. ", but got "
         .. vim.inspect(lhs)
       )
-    error(msg)
+    fail(msg, 2, { expected = vim.inspect(rhs), actual = vim.inspect(lhs) })
   end
+  pass()
 end
 
 -- Provided by `test.nvim`
@@ -98,8 +288,9 @@ function T.assert_not_eq(lhs, rhs, message)
         .. ", but got "
         .. vim.inspect(lhs)
       )
-    error(msg)
+    fail(msg)
   end
+  pass()
 end
 
 -- Provided by `test.nvim`
@@ -118,8 +309,9 @@ function T.assert_deep_eq(lhs, rhs, message)
         .. ", but got "
         .. vim.inspect(lhs)
       )
-    error(msg)
+    fail(msg, 2, { expected = vim.inspect(rhs), actual = vim.inspect(lhs) })
   end
+  pass()
 end
 
 -- Provided by `test.nvim`
@@ -138,8 +330,9 @@ function T.assert_not_deep_eq(lhs, rhs, message)
         .. ", but got "
         .. vim.inspect(lhs)
       )
-    error(msg)
+    fail(msg)
   end
+  pass()
 end
 
 -- Provided by `test.nvim`
@@ -151,7 +344,10 @@ end
 ---@param message? string
 function T.assert_contains(list, item, message)
   for _, v in ipairs(list) do
-    if v == item then return end
+    if v == item then
+      pass()
+      return
+    end
   end
 
   local msg = message
@@ -161,7 +357,7 @@ function T.assert_contains(list, item, message)
       .. " to be in the list "
       .. vim.inspect(list)
     )
-  error(msg)
+  fail(msg)
 end
 
 -- Provided by `test.nvim`
@@ -181,7 +377,152 @@ function T.assert_not_contains(list, item, message)
           .. " to not be in the list "
           .. vim.inspect(list)
         )
-      error(msg)
+      fail(msg)
+    end
+  end
+  pass()
+end
+
+-- Provided by `test.nvim`
+--
+-- Throws error if the input value does not match the snapshot stored at the given path (relative to the
+-- current directory). Non-string values are compared by their `vim.inspect` representation. The snapshot is
+-- written instead if it does not exist yet, or if the test runner is invoked with `--update`
+--
+---@param path string
+---@param value any
+---@param message? string
+function T.assert_snapshot(path, value, message)
+  local actual = type(value) == "string" and value or vim.inspect(value)
+
+  local update = os.getenv("NVIM_TEST_RUNNER_UPDATE_SNAPSHOTS") == "1"
+  local file = io.open(path, "r")
+  if file and not update then
+    local expected = file:read("*a")
+    file:close()
+    if actual ~= expected then
+      fail(
+        message
+          or (
+            "Snapshot "
+            .. path
+            .. " does not match. Expected "
+            .. vim.inspect(expected)
+            .. ", but got "
+            .. vim.inspect(actual)
+          ),
+        2,
+        { expected = expected, actual = actual }
+      )
+    end
+    pass()
+    return
+  end
+  if file then file:close() end
+
+  vim.fn.mkdir(vim.fn.fnamemodify(path, ":h"), "p")
+  file = assert(io.open(path, "w"))
+  file:write(actual)
+  file:close()
+  table.insert(report.updated_snapshots, path)
+  pass()
+end
+
+-- The names of the enclosing `T.describe` blocks
+local describe_stack = {}
+
+-- Provided by `test.nvim`
+--
+-- Group the `T.it` blocks in the input function under a name, which prefixes their names
+--
+---@param name string
+---@param fn function
+function T.describe(name, fn)
+  table.insert(describe_stack, name)
+  local ok, err = pcall(fn)
+  table.remove(describe_stack)
+  if not ok then error(err, 0) end
+end
+
+-- Provided by `test.nvim`
+--
+-- Run the input function as a test block. If the test runner is invoked with `--grep <pattern>`, the block is
+-- skipped unless its full name (including the names of the enclosing `T.describe` blocks) contains the pattern
+--
+---@param name string
+---@param fn function
+function T.it(name, fn)
+  local full_name = table.concat(vim.list_extend(vim.list_slice(describe_stack), { name }), " ")
+
+  local grep = os.getenv("NVIM_TEST_RUNNER_GREP")
+  if grep and grep ~= "" and not full_name:find(grep, 1, true) then
+    table.insert(report.filtered_tests, full_name)
+    return
+  end
+
+  local num_failures = #report.failures
+  local enclosing_test = current_test
+  current_test = full_name
+  local ok, err = pcall(fn)
+  current_test = enclosing_test
+  if not ok then
+    -- Errors that are not raised by the assertions of test-utils are recorded here
+    if #report.failures == num_failures then
+      local file, line, message = tostring(err):match("^(.-):(%d+): (.*)$")
+      table.insert(report.failures, {
+        name = full_name,
+        message = message or tostring(err),
+        file = file,
+        line = tonumber(line),
+      })
+    else
+      report.failures[#report.failures].name = full_name
+    end
+    error(err, 0)
+  end
+end
+
+-- Provided by `test.nvim`
+--
+-- Run the test files listed in the JSON file at `$NVIM_TEST_RUNNER_BATCH_FILE` (a list of `{ path, resultFile }`)
+-- one after another, writing the report of each to its result file. Used by the test runner with `--batch`.
+-- The modules a test loads and the buffers it opens are cleared before the next test
+function T.run_batch()
+  local batch_file = io.open(os.getenv("NVIM_TEST_RUNNER_BATCH_FILE") or "", "r")
+  if not batch_file then error("Failed to read the batch file") end
+  local tests = vim.json.decode(batch_file:read("*a"))
+  batch_file:close()
+
+  local hrtime = (vim.uv or vim.loop).hrtime
+  for _, test in ipairs(tests) do
+    report = new_report()
+    describe_stack = {}
+    current_test = nil
+    local loaded = {}
+    for name in pairs(package.loaded) do
+      loaded[name] = true
+    end
+
+    local start = hrtime()
+    local ok, err = pcall(dofile, test.path)
+    report.duration_ms = math.floor((hrtime() - start) / 1e6)
+    -- Errors raised outside of `T.it` blocks (and assertions) are not recorded yet
+    if not ok and #report.failures == 0 then
+      local file, line, message = tostring(err):match("^(.-):(%d+): (.*)$")
+      table.insert(report.failures, {
+        message = message or tostring(err),
+        file = file,
+        line = tonumber(line),
+      })
+    end
+    check_plugin_module()
+    write_report(test.resultFile)
+    -- The messages of a test are not to be reported with those of the next
+    vim.cmd("silent! messages clear")
+
+    for name in pairs(package.loaded) do
+      if not loaded[name] then package.loaded[name] = nil end
     end
+    vim.cmd("silent! %bwipeout!")
   end
 end
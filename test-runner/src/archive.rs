@@ -0,0 +1,285 @@
+//! Dependencies published as archives (e.g. release tarballs) rather than git repositories: a uri ending in
+//! `.tar.gz`, `.tgz` or `.zip` is downloaded and extracted into the cache in place of a clone, and the SHA-256 of
+//! the archive is recorded in the state as its hash (and checked against the `checksum` of the dependency, if
+//! given). An archive is taken not to change at its uri, so it is only downloaded again once the uri or the
+//! checksum changes, or the extracted directory is removed
+
+use ansi_term::Colour;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::config::TestDepedency;
+use crate::deps::{dep_relative_path, DepResolution, ResolveOptions};
+use crate::error::RunnerError;
+use crate::state::{State, TestDepedencyState};
+
+/// The formats of archive dependencies, by the suffix of their uri
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    TarGz,
+    Zip,
+}
+
+impl ArchiveFormat {
+    const SUFFIXES: [(&'static str, ArchiveFormat); 3] = [
+        (".tar.gz", ArchiveFormat::TarGz),
+        (".tgz", ArchiveFormat::TarGz),
+        (".zip", ArchiveFormat::Zip),
+    ];
+}
+
+/// The path of a uri without its query and fragment, e.g. `https://example.com/v1.0.zip` for
+/// `https://example.com/v1.0.zip?download=1`
+fn uri_path(uri: &str) -> &str {
+    uri.split(['?', '#']).next().unwrap_or(uri)
+}
+
+/// The format of the archive a uri points to, or `None` if it is not an archive (e.g. a git repository)
+pub fn archive_format(uri: &str) -> Option<ArchiveFormat> {
+    let path = uri_path(uri).to_ascii_lowercase();
+    ArchiveFormat::SUFFIXES
+        .iter()
+        .find(|(suffix, _)| path.ends_with(suffix))
+        .map(|(_, format)| *format)
+}
+
+/// The path (relative to the external dependency directory) an archive is extracted into: that of
+/// [`dep_relative_path`] without the suffix of the archive, e.g. `github.com/foo/bar/archive/v1.0` for
+/// `https://github.com/foo/bar/archive/v1.0.tar.gz`
+pub fn archive_relative_path(uri: &str) -> Option<PathBuf> {
+    let path = uri_path(uri);
+    let lowercase = path.to_ascii_lowercase();
+    let stem = ArchiveFormat::SUFFIXES
+        .iter()
+        .find(|(suffix, _)| lowercase.ends_with(suffix))
+        .map_or(path, |(suffix, _)| &path[..path.len() - suffix.len()]);
+    dep_relative_path(stem)
+}
+
+/// Resolve an archive dependency as [`crate::deps::resolve_external_dep`] resolves a git one: the extracted
+/// archive in the cache is reused if it was recorded for the uri (with the same checksum, if the dependency gives
+/// one), and the archive is downloaded and extracted otherwise
+pub fn resolve_archive_dep(
+    dep: &TestDepedency,
+    uri: &str,
+    state: &State,
+    new_state: &mut State,
+    options: &ResolveOptions,
+    explanation: &mut Vec<String>,
+) -> Result<(PathBuf, DepResolution), RunnerError> {
+    let (Some(format), Some(relative_path)) = (archive_format(uri), archive_relative_path(uri))
+    else {
+        return Err(RunnerError::Config(format!("Invalid uri: {}", dep.uri)));
+    };
+    let dep_path = options.cache_dir.join("external-dep").join(relative_path);
+
+    let recorded = state
+        .test_dependencies
+        .iter()
+        .find(|dep_state| dep_state.uri == uri);
+    explanation.push(match recorded {
+        Some(dep_state) => format!("recorded checksum: {}", dep_state.hash),
+        None => "recorded checksum: <none>, the archive has not been downloaded before".to_string(),
+    });
+    let reusable = recorded.is_some_and(|dep_state| match &dep.checksum {
+        Some(checksum) => {
+            let matches = checksum.eq_ignore_ascii_case(&dep_state.hash);
+            explanation.push(format!(
+                "pinned checksum {}: {} the recorded checksum",
+                checksum,
+                if matches { "matches" } else { "differs from" }
+            ));
+            matches
+        }
+        None => true,
+    });
+    if reusable {
+        if dep_path.exists() {
+            return Ok((dep_path, DepResolution::Reused));
+        }
+        explanation.push(format!(
+            "the extracted archive at {} is missing",
+            dep_path.display()
+        ));
+    }
+
+    if options.skip_remote_check {
+        explanation.push("remote not checked (skipRemoteCheck, --offline or --frozen)".to_string());
+        return Err(RunnerError::Network(format!(
+            "Test dependency {} is not downloaded at {} and cannot be downloaded {}",
+            dep.uri,
+            dep_path.display(),
+            if options.frozen {
+                "with --frozen"
+            } else if options.offline {
+                "in offline mode"
+            } else {
+                "without checking the remote (skipRemoteCheck)"
+            }
+        )));
+    }
+
+    let overwritten = dep_path.exists();
+    if overwritten {
+        info!(
+            "Removing outdated test dependency at path {}",
+            dep_path.display()
+        );
+        std::fs::remove_dir_all(&dep_path)
+            .map_err(|e| RunnerError::io(format!("Failed to remove {}", dep_path.display()), e))?;
+    }
+    new_state
+        .test_dependencies
+        .retain(|dep_state| dep_state.uri != uri);
+
+    let archive = download_archive(dep, options)?;
+    let checksum = sha256_hex(&archive);
+    if let Some(expected) = &dep.checksum {
+        if !expected.eq_ignore_ascii_case(&checksum) {
+            return Err(RunnerError::Network(format!(
+                "The archive of test dependency {} has the checksum {}, but {} is expected",
+                dep.uri, checksum, expected
+            )));
+        }
+    }
+    extract_archive(dep, format, &archive, &dep_path)?;
+
+    new_state.test_dependencies.push(TestDepedencyState {
+        uri: uri.to_string(),
+        hash: checksum,
+        branch: None,
+        sha: None,
+        git_ref: None,
+        post_install: None,
+    });
+    if overwritten {
+        Ok((dep_path, DepResolution::Updated))
+    } else {
+        Ok((dep_path, DepResolution::Cloned))
+    }
+}
+
+/// Download the archive of a dependency, attempting it again (after a delay that doubles each time) as long as
+/// it fails for what may be a network problem, up to `clone_attempts` times as a clone is
+fn download_archive(dep: &TestDepedency, options: &ResolveOptions) -> Result<Vec<u8>, RunnerError> {
+    let client = crate::test_utils::http_client(options.proxy)?;
+    let message = format!("Downloading archive {}...", dep.uri);
+    println!("{}", Colour::Yellow.paint(&message));
+    info!("{}", message);
+
+    let mut attempt = 1;
+    loop {
+        let e = match client
+            .get(&dep.uri)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.bytes())
+        {
+            Ok(bytes) => return Ok(bytes.to_vec()),
+            Err(e) => e,
+        };
+        let transient = e.is_connect()
+            || e.is_timeout()
+            || e.status().is_some_and(|status| status.is_server_error());
+        if !transient || attempt >= options.clone_attempts || crate::interrupt::is_interrupted() {
+            return Err(RunnerError::Network(format!(
+                "Failed to download test dependency {}: {}",
+                dep.uri, e
+            )));
+        }
+
+        let delay = std::time::Duration::from_secs(1 << (attempt - 1).min(5));
+        let message = format!(
+            "Downloading {} failed (attempt {} of {}), retrying in {:?}: {}",
+            dep.uri, attempt, options.clone_attempts, delay, e
+        );
+        println!("{}", Colour::Yellow.paint(&message));
+        warn!("{}", message);
+        std::thread::sleep(delay);
+        attempt += 1;
+    }
+}
+
+/// Extract an archive into the given directory, which must not exist. An archive holding a single top-level
+/// directory (e.g. `plugin-1.0/` in a GitHub release tarball) has that directory extracted in its place, so that
+/// the plugin is at the root of the dependency
+fn extract_archive(
+    dep: &TestDepedency,
+    format: ArchiveFormat,
+    archive: &[u8],
+    dep_path: &Path,
+) -> Result<(), RunnerError> {
+    let parent = dep_path.parent().unwrap_or(Path::new("."));
+    std::fs::create_dir_all(parent)
+        .map_err(|e| RunnerError::io(format!("Failed to create {}", parent.display()), e))?;
+    // Next to the destination, so that moving the extracted directory into place is a rename
+    let with_suffix = |suffix: &str| {
+        let mut path = dep_path.as_os_str().to_os_string();
+        path.push(suffix);
+        PathBuf::from(path)
+    };
+    let archive_path = with_suffix(".download");
+    let extract_dir = with_suffix(".partial");
+    if extract_dir.exists() {
+        std::fs::remove_dir_all(&extract_dir).map_err(|e| {
+            RunnerError::io(format!("Failed to remove {}", extract_dir.display()), e)
+        })?;
+    }
+    std::fs::create_dir_all(&extract_dir)
+        .map_err(|e| RunnerError::io(format!("Failed to create {}", extract_dir.display()), e))?;
+    std::fs::write(&archive_path, archive)
+        .map_err(|e| RunnerError::io(format!("Failed to write {}", archive_path.display()), e))?;
+
+    let result = run_extractor(format, &archive_path, &extract_dir).and_then(|()| {
+        let entries: Vec<PathBuf> = std::fs::read_dir(&extract_dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<_>>()?;
+        match entries.as_slice() {
+            [root] if root.is_dir() => {
+                std::fs::rename(root, dep_path)?;
+                std::fs::remove_dir(&extract_dir)
+            }
+            _ => std::fs::rename(&extract_dir, dep_path),
+        }
+    });
+    let _ = std::fs::remove_file(&archive_path);
+    if result.is_err() {
+        let _ = std::fs::remove_dir_all(&extract_dir);
+    }
+    result.map_err(|e| RunnerError::io(format!("Failed to extract test dependency {}", dep.uri), e))
+}
+
+/// Extract an archive into a directory with `tar` or `unzip`
+fn run_extractor(format: ArchiveFormat, archive_path: &Path, dir: &Path) -> std::io::Result<()> {
+    let (program, mut cmd) = match format {
+        ArchiveFormat::TarGz => {
+            let mut cmd = Command::new("tar");
+            cmd.arg("-xzf").arg(archive_path).arg("-C").arg(dir);
+            ("tar", cmd)
+        }
+        ArchiveFormat::Zip => {
+            let mut cmd = Command::new("unzip");
+            cmd.arg("-q").arg(archive_path).arg("-d").arg(dir);
+            ("unzip", cmd)
+        }
+    };
+    let output = cmd
+        .output()
+        .map_err(|e| std::io::Error::new(e.kind(), format!("Failed to run {}: {}", program, e)))?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "{} failed with {}:\n{}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        )));
+    }
+    Ok(())
+}
+
+/// The SHA-256 of the given bytes, in lowercase hex
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
@@ -0,0 +1,186 @@
+//! Timing baselines of the suite, for a run to fail if its tests got slower. `--save-baseline <name>` records
+//! the median duration of each test over the rounds of `--repeat`, and `--baseline <name>` compares a run
+//! against it: a test regresses if its median exceeds that of the baseline by more than `baselineThreshold`
+//! percent and by more than `baselineTolerance`, which keeps the noise of very short tests from failing the run
+
+use ansi_term::Colour;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::bench::BenchStats;
+use crate::config::TestConfig;
+use crate::discovery::parse_duration;
+use crate::error::RunnerError;
+use crate::report::TimingRegression;
+use crate::state::write_json_atomically;
+
+/// By how many percent the median of a test may exceed that of the baseline
+pub const DEFAULT_THRESHOLD_PERCENT: f64 = 20.0;
+/// By how much the median of a test may exceed that of the baseline whatever the percentage, as a few
+/// milliseconds more are noise rather than a regression for a test taking a few milliseconds
+pub const DEFAULT_TOLERANCE: Duration = Duration::from_millis(50);
+/// The number of durations of each test the median is taken of, i.e. the rounds of `--repeat` needed
+pub const DEFAULT_SAMPLES: usize = 3;
+
+/// How a run is compared to a baseline, from the config
+#[derive(Debug, Clone, Copy)]
+pub struct BaselineSettings {
+    pub threshold_percent: f64,
+    pub tolerance: Duration,
+    pub samples: usize,
+}
+
+impl BaselineSettings {
+    pub fn new(config: &TestConfig) -> BaselineSettings {
+        BaselineSettings {
+            threshold_percent: config
+                .settings
+                .baseline_threshold
+                .unwrap_or(DEFAULT_THRESHOLD_PERCENT),
+            // Validated with the config
+            tolerance: config
+                .settings
+                .baseline_tolerance
+                .as_deref()
+                .and_then(|tolerance| parse_duration(tolerance).ok())
+                .unwrap_or(DEFAULT_TOLERANCE),
+            samples: config.settings.baseline_samples.unwrap_or(DEFAULT_SAMPLES),
+        }
+    }
+}
+
+/// The median duration of each test of a run, by path
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Baseline {
+    pub tests: BTreeMap<PathBuf, BaselineTiming>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct BaselineTiming {
+    pub median_ms: f64,
+    /// The number of durations the median was taken of
+    pub samples: usize,
+}
+
+/// Parse the name of a baseline as given to --baseline and --save-baseline. Names are the file names of the
+/// baselines within the cache directory, so they are kept to letters, digits, `-`, `_` and `.`
+pub fn parse_name(name: &str) -> Result<String, String> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        return Err(format!(
+            "Invalid baseline name {:?}, expected letters, digits, -, _ and .",
+            name
+        ));
+    }
+    Ok(name.to_string())
+}
+
+/// Where the baseline of the given name is stored
+pub fn baseline_path(cache_dir: &Path, name: &str) -> PathBuf {
+    cache_dir.join("baselines").join(format!("{}.json", name))
+}
+
+impl Baseline {
+    /// The baseline of the durations of the tests that passed, in each round of the run. Tests with fewer than
+    /// `samples` durations (e.g. as they failed in a round) are left out, as their median would not be stable
+    pub fn from_samples(samples: &HashMap<PathBuf, Vec<Duration>>, min_samples: usize) -> Baseline {
+        let mut tests = BTreeMap::new();
+        for (path, durations) in samples {
+            if durations.len() < min_samples {
+                info!(
+                    "Leaving {} out of the baseline, it has {} of {} samples",
+                    path.display(),
+                    durations.len(),
+                    min_samples
+                );
+                continue;
+            }
+            let stats = BenchStats {
+                samples: durations.clone(),
+            };
+            tests.insert(
+                path.clone(),
+                BaselineTiming {
+                    median_ms: stats.median_ms(),
+                    samples: durations.len(),
+                },
+            );
+        }
+        Baseline { tests }
+    }
+
+    pub fn load(cache_dir: &Path, name: &str) -> Result<Baseline, RunnerError> {
+        let path = baseline_path(cache_dir, name);
+        let contents = std::fs::read_to_string(&path).map_err(|e| {
+            RunnerError::Config(format!(
+                "Failed to read the baseline {} from {} (save it with --save-baseline {}): {}",
+                name,
+                path.display(),
+                name,
+                e
+            ))
+        })?;
+        serde_json::from_str(&contents).map_err(|e| {
+            RunnerError::Config(format!("{} is not a valid baseline: {}", path.display(), e))
+        })
+    }
+
+    pub fn save(&self, cache_dir: &Path, name: &str) -> Result<(), RunnerError> {
+        let path = baseline_path(cache_dir, name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| {
+                RunnerError::io(format!("Failed to create {}", parent.display()), e)
+            })?;
+        }
+        write_json_atomically(&path, self)?;
+        let message = format!(
+            "Saved the baseline {} ({} tests) to {}",
+            name,
+            self.tests.len(),
+            path.display()
+        );
+        println!("{}", Colour::Blue.paint(&message));
+        info!("{}", message);
+        Ok(())
+    }
+
+    /// The tests of `current` that got slower than this baseline allows, the worst first. Tests missing from
+    /// either are not compared
+    pub fn regressions(
+        &self,
+        current: &Baseline,
+        settings: &BaselineSettings,
+    ) -> Vec<TimingRegression> {
+        let tolerance_ms = settings.tolerance.as_secs_f64() * 1000.0;
+        let mut regressions: Vec<TimingRegression> = current
+            .tests
+            .iter()
+            .filter_map(|(path, timing)| {
+                let baseline = self.tests.get(path)?;
+                let allowed = (baseline.median_ms * (1.0 + settings.threshold_percent / 100.0))
+                    .max(baseline.median_ms + tolerance_ms);
+                info!(
+                    "Test {} took {:.1}ms against {:.1}ms in the baseline",
+                    path.display(),
+                    timing.median_ms,
+                    baseline.median_ms
+                );
+                (timing.median_ms > allowed).then(|| TimingRegression {
+                    path: path.clone(),
+                    baseline_ms: baseline.median_ms,
+                    current_ms: timing.median_ms,
+                })
+            })
+            .collect();
+        regressions.sort_by(|a, b| b.change_percent().total_cmp(&a.change_percent()));
+        regressions
+    }
+}
@@ -0,0 +1,171 @@
+use log::{debug, info};
+use serde_json::json;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+use crate::config::check_config;
+use crate::error::RunnerError;
+use crate::nvim::{describe_command, escape_file_name, new_command, set_rtp_command};
+use crate::{check_nvim, resolve_dependencies, RunOptions, TestConfig};
+
+/// What the `bench` subcommand starts nvim with
+#[derive(Debug, Clone)]
+pub enum BenchTarget {
+    /// A Lua (or Vim script) file that is sourced once nvim has started
+    File(PathBuf),
+    /// A Lua snippet that is run once nvim has started, e.g. `require("my-plugin").setup()`
+    Lua(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    pub target: BenchTarget,
+    /// The number of times nvim is started
+    pub runs: usize,
+}
+
+/// The time it took nvim to start, run the target and quit, for each run
+#[derive(Debug, Clone)]
+pub struct BenchStats {
+    pub samples: Vec<Duration>,
+}
+
+impl BenchStats {
+    fn sorted_ms(&self) -> Vec<f64> {
+        let mut samples: Vec<f64> = self.samples.iter().map(|d| as_ms(*d)).collect();
+        samples.sort_by(f64::total_cmp);
+        samples
+    }
+
+    pub fn min_ms(&self) -> f64 {
+        self.sorted_ms().first().copied().unwrap_or_default()
+    }
+
+    pub fn max_ms(&self) -> f64 {
+        self.sorted_ms().last().copied().unwrap_or_default()
+    }
+
+    pub fn median_ms(&self) -> f64 {
+        let samples = self.sorted_ms();
+        match samples.len() {
+            0 => 0.0,
+            len if len % 2 == 0 => (samples[len / 2 - 1] + samples[len / 2]) / 2.0,
+            len => samples[len / 2],
+        }
+    }
+
+    pub fn mean_ms(&self) -> f64 {
+        if self.samples.is_empty() {
+            return 0.0;
+        }
+        self.samples.iter().map(|d| as_ms(*d)).sum::<f64>() / self.samples.len() as f64
+    }
+
+    /// The population standard deviation, as all runs are measured rather than sampled from a larger set
+    pub fn stddev_ms(&self) -> f64 {
+        if self.samples.is_empty() {
+            return 0.0;
+        }
+        let mean = self.mean_ms();
+        let variance = self
+            .samples
+            .iter()
+            .map(|d| (as_ms(*d) - mean).powi(2))
+            .sum::<f64>()
+            / self.samples.len() as f64;
+        variance.sqrt()
+    }
+
+    /// The statistics as printed by `bench --json`
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "runs": self.samples.len(),
+            "minMs": self.min_ms(),
+            "medianMs": self.median_ms(),
+            "maxMs": self.max_ms(),
+            "meanMs": self.mean_ms(),
+            "stddevMs": self.stddev_ms(),
+            "samplesMs": self.samples.iter().map(|d| as_ms(*d)).collect::<Vec<_>>(),
+        })
+    }
+}
+
+fn as_ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Start nvim the given number of times with the plugin and its dependencies on the runtimepath, measuring
+/// the time from starting nvim until it has run the target and quit
+pub fn run_bench(
+    config: &TestConfig,
+    options: &RunOptions,
+    bench: &BenchOptions,
+) -> Result<BenchStats, RunnerError> {
+    if bench.runs == 0 {
+        return Err(RunnerError::Config(
+            "--runs has to be at least 1".to_string(),
+        ));
+    }
+    if let BenchTarget::File(path) = &bench.target {
+        if !path.is_file() {
+            return Err(RunnerError::Config(format!(
+                "Benchmark file {} does not exist",
+                path.display()
+            )));
+        }
+    }
+
+    check_config(config)?;
+    check_nvim(config, &crate::resolve_cache_dir(options, config))?;
+    let current_dir = std::env::current_dir()?;
+    let rtp = resolve_dependencies(config, options, &current_dir)?.runtimepath(config);
+    debug!("runtimepath: {:?}", rtp);
+
+    let mut samples = Vec::with_capacity(bench.runs);
+    for run in 1..=bench.runs {
+        let mut cmd = new_command(config.settings.runner_wrapper.as_deref());
+        // Skip the user's config and plugins, but load the plugin scripts on the runtimepath as in a real
+        // startup, since that is what is measured
+        cmd.arg("--clean")
+            .arg("--headless")
+            .arg("--cmd")
+            .arg("set nobackup nowritebackup noswapfile")
+            .arg("-i")
+            .arg("NONE");
+        for entry in &rtp {
+            cmd.arg("--cmd").arg(set_rtp_command(entry));
+        }
+        cmd.args(config.settings.nvim_args.as_deref().unwrap_or_default());
+        match &bench.target {
+            BenchTarget::File(path) => cmd
+                .arg("-c")
+                .arg(format!("source {}", escape_file_name(path))),
+            BenchTarget::Lua(snippet) => cmd.arg("-c").arg(format!("lua {}", snippet)),
+        };
+        cmd.arg("-c").arg("qa!");
+        cmd.stdin(Stdio::null());
+
+        let start = Instant::now();
+        let output = cmd.output().map_err(|e| {
+            RunnerError::Nvim(format!(
+                "Failed to run {}: {}",
+                describe_command(config.settings.runner_wrapper.as_deref()),
+                e
+            ))
+        })?;
+        let duration = start.elapsed();
+        if !output.status.success() {
+            return Err(RunnerError::Nvim(format!(
+                "nvim exited with {} in benchmark run {}: {}",
+                output.status,
+                run,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        info!("Benchmark run {}: {:.2?}", run, duration);
+        samples.push(duration);
+    }
+
+    Ok(BenchStats { samples })
+}
@@ -0,0 +1,83 @@
+//! Concurrency groups: the tests in a named group (given by their `group` directive) run at most N at once, e.g.
+//! so that CPU-heavy tests do not run alongside each other, while the tests outside of limited groups only
+//! share the --jobs threads
+
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+
+/// Lets at most `max` holders of a permit run at once
+struct Semaphore {
+    max: usize,
+    running: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl Semaphore {
+    fn acquire(&self) -> Permit<'_> {
+        let mut running = self.running.lock().unwrap_or_else(|e| e.into_inner());
+        while *running >= self.max {
+            running = self.freed.wait(running).unwrap_or_else(|e| e.into_inner());
+        }
+        *running += 1;
+        Permit { semaphore: self }
+    }
+}
+
+/// The permission of a test to run within its group, given back when dropped
+pub struct Permit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self
+            .semaphore
+            .running
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) -= 1;
+        self.semaphore.freed.notify_one();
+    }
+}
+
+/// The limits of the concurrency groups of a run
+pub struct GroupLimits {
+    groups: HashMap<String, Semaphore>,
+}
+
+impl GroupLimits {
+    /// The groups by name, with the number of their tests that may run at once
+    pub fn new(limits: &HashMap<String, usize>) -> GroupLimits {
+        GroupLimits {
+            groups: limits
+                .iter()
+                .map(|(name, max)| {
+                    let semaphore = Semaphore {
+                        max: (*max).max(1),
+                        running: Mutex::new(0),
+                        freed: Condvar::new(),
+                    };
+                    (name.clone(), semaphore)
+                })
+                .collect(),
+        }
+    }
+
+    /// Whether the group has a limit
+    pub fn contains(&self, group: &str) -> bool {
+        self.groups.contains_key(group)
+    }
+
+    /// Wait until the tests of the given groups (e.g. those of a batch) may run, returning a permit for each of
+    /// the groups with a limit. The groups are taken in order of their names, so that two batches waiting for
+    /// the same groups cannot each hold what the other waits for
+    pub fn acquire<'a>(&self, groups: impl Iterator<Item = &'a str>) -> Vec<Permit<'_>> {
+        let mut groups: Vec<&str> = groups.collect();
+        groups.sort_unstable();
+        groups.dedup();
+        groups
+            .into_iter()
+            .filter_map(|group| self.groups.get(group))
+            .map(|semaphore| semaphore.acquire())
+            .collect()
+    }
+}
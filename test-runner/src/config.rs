@@ -0,0 +1,1112 @@
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Read;
+use std::{env, fs::File};
+
+use crate::deps::{dep_relative_path, normalize_uri};
+use crate::error::RunnerError;
+use crate::nvim::parse_version;
+
+/// Deserializes a list of strings that may also be given as a single string, e.g. `"testPaths": "tests/**/*.lua"`
+/// for a list of one pattern. With `single_string` false, as for the arguments of a command, a single string is
+/// rejected rather than taken as a single argument, as it would not be split into arguments
+struct StringList {
+    single_string: bool,
+}
+
+impl<'de> serde::de::Visitor<'de> for StringList {
+    type Value = Option<Vec<String>>;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.single_string {
+            write!(f, "a string or a list of strings")
+        } else {
+            write!(
+                f,
+                "a list of strings, one per argument (a single string is not split into arguments)"
+            )
+        }
+    }
+
+    fn visit_unit<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_none<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: serde::Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        if self.single_string {
+            Ok(Some(vec![value.to_string()]))
+        } else {
+            Err(E::invalid_type(serde::de::Unexpected::Str(value), &self))
+        }
+    }
+
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut items = Vec::new();
+        while let Some(item) = seq.next_element::<String>()? {
+            items.push(item);
+        }
+        Ok(Some(items))
+    }
+}
+
+/// A list of strings, or a single string for a list of one (see [`StringList`])
+fn string_or_list<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error> {
+    deserializer.deserialize_any(StringList {
+        single_string: true,
+    })
+}
+
+/// The arguments of a command, which have to be given as a list (see [`StringList`])
+fn argument_list<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error> {
+    deserializer.deserialize_any(StringList {
+        single_string: false,
+    })
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TestDepedency {
+    pub uri: String,
+    pub branch: Option<String>,
+    pub sha: Option<String>,
+    /// A git revision expression (e.g. `HEAD~1` or `origin/dev^`) resolved in the clone and checked out,
+    /// taking precedence over the branch and the sha
+    #[serde(rename = "ref")]
+    pub git_ref: Option<String>,
+    /// Glob patterns (relative to the dependency) of tests of a local dependency to include in the run
+    #[serde(default, deserialize_with = "string_or_list")]
+    pub test_paths: Option<Vec<String>>,
+    /// The subdirectory (relative to the dependency) the plugin lives in, added to the runtimepath instead of
+    /// the root of the dependency
+    pub subdir: Option<String>,
+    /// Whether the submodules of the dependency are cloned and checked out along with it. Defaults to false
+    pub submodules: Option<bool>,
+    /// Whether each immediate subdirectory of a local dependency that looks like a plugin is added to the
+    /// runtimepath, rather than the dependency itself. Defaults to false
+    pub recursive: Option<bool>,
+    /// Whether the dependency is resolved and added to the runtimepath. Defaults to true
+    pub enabled: Option<bool>,
+    /// A local clone of the dependency that a new clone borrows its objects from (`git clone --reference`),
+    /// overriding --reference-repo. `~` and environment variables are expanded
+    pub reference: Option<String>,
+    /// The number of commits of history cloned (`git clone --depth`), for a large repository of which only the
+    /// tip is needed. A sha or ref further back than that is missing from the clone. Defaults to the whole history
+    pub depth: Option<u32>,
+    /// Whether only the history of the branch is cloned (`git clone --single-branch`), rather than that of every
+    /// branch. Defaults to true with `depth` (as for git), false otherwise
+    pub single_branch: Option<bool>,
+    /// Shell commands run (with `sh -c`) in the clone once it is cloned or reset, e.g. `make` to build a native
+    /// component. They are not run again until they change or the dependency is cloned again
+    #[serde(default, deserialize_with = "string_or_list")]
+    pub post_install: Option<Vec<String>>,
+    /// The SHA-256 (in hex) of an archive dependency, i.e. one whose uri ends in `.tar.gz`, `.tgz` or `.zip`,
+    /// checked once the archive is downloaded. Without it, the archive is trusted as first downloaded
+    pub checksum: Option<String>,
+}
+
+/// Settings applied to the tests whose path matches a glob, e.g. a longer timeout for the integration tests.
+/// The directives of a test file take precedence over them, and later overrides over earlier ones
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TestOverride {
+    /// The glob the paths of the tests are matched against, as in `test_paths`
+    #[serde(rename = "match")]
+    pub pattern: String,
+    /// As the `timeout` directive, e.g. `2m`
+    pub timeout: Option<String>,
+    /// Environment variables set for the tests
+    pub env: Option<HashMap<String, String>>,
+    /// As the `serial` directive
+    pub serial: Option<bool>,
+    /// As the `group` directive
+    pub group: Option<String>,
+    /// As the `serial-group` directive
+    pub serial_group: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TestConfig {
+    #[serde(flatten)]
+    pub settings: TestSettings,
+    /// Named sets of overrides, of which one can be selected with --profile
+    pub profiles: Option<HashMap<String, TestProfile>>,
+    /// Combinations of nvims and dependency pins to run the suite with, once per combination
+    pub matrix: Option<TestMatrix>,
+}
+
+/// The fields of the config that a profile can override, flattened into both so that a profile can override any
+/// field of the config but the profiles and the matrix
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct TestSettings {
+    pub test_dependencies: Option<Vec<TestDepedency>>,
+    #[serde(default, deserialize_with = "string_or_list")]
+    pub test_paths: Option<Vec<String>>,
+    /// Whether the default test paths are used if `test_paths` is not given, rather than running no tests.
+    /// Defaults to true
+    pub default_test_paths: Option<bool>,
+    /// A shell command printing the test files to run (one path per line, relative to the current directory) on
+    /// its stdout, used instead of the `test_paths` globs, e.g. for a layout globs cannot express
+    pub discovery_command: Option<String>,
+    pub cache_dir: Option<String>,
+    pub before_all: Option<String>,
+    pub after_all: Option<String>,
+    /// A shell command run once the tests have finished, with the summary of the run as JSON on its stdin
+    pub after_run: Option<String>,
+    pub min_nvim_version: Option<String>,
+    /// Whether the current directory (the plugin under test) is added to the runtimepath. Defaults to true
+    pub add_cwd_to_rtp: Option<bool>,
+    /// The init file nvim is started with (`-u`) instead of the test file, which is then sourced after startup
+    pub init_file: Option<String>,
+    /// The working directory the tests are run in, relative to the current directory, so that the relative paths
+    /// of a test (e.g. to its fixtures) resolve the same wherever the runner is run from. Overridden by the `cwd`
+    /// directive of a test. Defaults to the current directory
+    pub test_cwd: Option<String>,
+    /// The main Lua module of the plugin under test, e.g. `my-plugin`. test-utils fails every test in which it
+    /// cannot be required, so that a broken runtimepath does not pass as a passing test
+    pub plugin_module: Option<String>,
+    /// Regular expressions (in the syntax of the `regex` crate) any of which fails a test if it matches a line of
+    /// its stderr. Defaults to [`DEFAULT_ERROR_PATTERNS`]
+    #[serde(default, deserialize_with = "string_or_list")]
+    pub error_patterns: Option<Vec<String>>,
+    /// Whether a test fails if nvim writes anything to its stderr, rather than only on the `error_patterns`.
+    /// With `--batch`, output on the stderr of a batch fails all of its tests. Defaults to false
+    pub strict_stderr: Option<bool>,
+    /// With `strict_stderr`, the lines of stderr containing any of these are ignored, e.g. a known deprecation
+    /// notice
+    #[serde(default, deserialize_with = "string_or_list")]
+    pub allowed_stderr: Option<Vec<String>>,
+    /// Whether test-utils reports the message history of nvim (`:messages`) when a test finishes, which is shown
+    /// with the failures of the test and fails it if a message matches the `error_patterns`, e.g. an error raised
+    /// in a deferred callback that never reaches stderr. Defaults to true
+    pub capture_messages: Option<bool>,
+    /// The number of tests of each concurrency group (given by the `group` directive of the tests) that may run
+    /// at once, e.g. `{"heavy": 2}`. The tests of other groups are only limited by --jobs
+    pub concurrency_groups: Option<HashMap<String, usize>>,
+    /// Glob patterns of known flaky tests, which are run as usual but whose failures are reported as
+    /// quarantined rather than failing the run
+    #[serde(default, deserialize_with = "string_or_list")]
+    pub quarantine: Option<Vec<String>>,
+    /// With --baseline, by how many percent the median duration of a test may exceed that of the baseline before
+    /// it fails the run. Defaults to 20
+    pub baseline_threshold: Option<f64>,
+    /// With --baseline, by how much the median duration of a test may exceed that of the baseline whatever the
+    /// percentage, e.g. `100ms`, so that the noise of short tests does not fail the run. Defaults to 50ms
+    pub baseline_tolerance: Option<String>,
+    /// The number of durations of each test the medians of --baseline and --save-baseline are taken of, which
+    /// --repeat has to run at least. Defaults to 3
+    pub baseline_samples: Option<usize>,
+    /// Directories appended to the runtimepath after the dependencies, e.g. a system-wide plugin. `~` and
+    /// environment variables (`$VAR` or `${VAR}`) are expanded
+    #[serde(default, deserialize_with = "string_or_list")]
+    pub extra_rtp: Option<Vec<String>>,
+    /// Environment variables set for every test whose values are redacted from the log, the output of the tests
+    /// and the reproduction bundles, e.g. an API token. A value is read from a file for `@FILE`, from an
+    /// environment variable of the runner for `$VAR`, and taken as it is otherwise (see [`crate::secrets`])
+    pub secrets: Option<HashMap<String, String>>,
+    /// Settings applied to the tests matching a glob (see [`TestOverride`]), in order
+    pub overrides: Option<Vec<TestOverride>>,
+    /// With --installed, the files and directories of the plugin (relative to the current directory) that are
+    /// installed, mapped to their path in the install directory, e.g. `{"lua": "lua", "build/doc": "doc"}`.
+    /// Defaults to the runtime directories of the plugin that exist (see [`crate::install::DEFAULT_INSTALL_DIRS`])
+    pub install_files: Option<std::collections::BTreeMap<String, String>>,
+    /// The command nvim is run with instead of `nvim`, e.g. `["docker", "run", "--rm", "image", "nvim"]`. The
+    /// arguments of nvim are appended to it
+    #[serde(default, deserialize_with = "argument_list")]
+    pub runner_wrapper: Option<Vec<String>>,
+    /// Extra arguments nvim is run with for every test, after those of the runner and before the test file
+    #[serde(default, deserialize_with = "argument_list")]
+    pub nvim_args: Option<Vec<String>>,
+    /// The branch of the external dependencies that do not give one, instead of the `HEAD` of their remote
+    pub default_branch: Option<String>,
+    /// The git executable used instead of `git` on the PATH, unless overridden by --git. `~` and environment
+    /// variables are expanded
+    pub git_path: Option<String>,
+    /// How many times cloning an external dependency is attempted when it fails for what may be a network
+    /// problem (e.g. a dropped connection), with a growing delay between the attempts. Defaults to
+    /// [`DEFAULT_CLONE_ATTEMPTS`]
+    pub clone_attempts: Option<usize>,
+    /// Whether test-utils is loaded as the `test.utils` module rather than `test-utils`, which may collide with a
+    /// module of a dependency. Defaults to false
+    pub namespace_test_utils: Option<bool>,
+    /// Whether test-utils is required before each test is run. Disable for tests that require it themselves, or
+    /// do not use it at all. Defaults to true
+    pub test_utils_require: Option<bool>,
+    /// Whether test-utils is required once the init file has been loaded rather than before it (with `--cmd`),
+    /// e.g. for an init file that sets up what test-utils depends on. Defaults to false
+    pub test_utils_after_init: Option<bool>,
+    /// Whether to skip checking that the clones of the external dependencies are up-to-date, unless overridden by
+    /// --skip-remote-check. Defaults to false
+    pub skip_remote_check: Option<bool>,
+}
+
+/// Overrides of the config, merged over it when selected. Dependencies are appended to those of the config,
+/// replacing those with the same uri; any other field replaces that of the config
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TestProfile {
+    #[serde(flatten)]
+    pub settings: TestSettings,
+}
+
+impl TestConfig {
+    pub fn new() -> TestConfig {
+        TestConfig {
+            settings: TestSettings::default(),
+            profiles: None,
+            matrix: None,
+        }
+    }
+
+    /// Merge the profile with the given name over the config. Fails if the config has no such profile
+    pub fn apply_profile(&mut self, name: &str) -> Result<(), RunnerError> {
+        let mut profiles = self.profiles.take().unwrap_or_default();
+        let Some(profile) = profiles.remove(name) else {
+            let mut names: Vec<&str> = profiles.keys().map(|name| name.as_str()).collect();
+            names.sort();
+            let available = if names.is_empty() {
+                "none".to_string()
+            } else {
+                names.join(", ")
+            };
+            return Err(RunnerError::Config(format!(
+                "Profile {} not found in config (available profiles: {})",
+                name, available
+            )));
+        };
+        debug!("Applying profile {}", name);
+
+        self.settings.merge(profile.settings);
+        Ok(())
+    }
+}
+
+impl TestSettings {
+    /// Append the dependencies to these, replacing those with the same uri
+    fn merge_dependencies(&mut self, overrides: Vec<TestDepedency>) {
+        let mut deps = self.test_dependencies.take().unwrap_or_default();
+        deps.retain(|dep| {
+            !overrides
+                .iter()
+                .any(|other| normalize_uri(&other.uri) == normalize_uri(&dep.uri))
+        });
+        deps.extend(overrides);
+        self.test_dependencies = Some(deps);
+    }
+
+    /// Merge the given settings over these: the dependencies are appended as with `merge_dependencies`, and any
+    /// other field that is given replaces this one
+    fn merge(&mut self, other: TestSettings) {
+        // Destructured so that a field added to the settings cannot be left out of the merge
+        let TestSettings {
+            test_dependencies,
+            test_paths,
+            default_test_paths,
+            discovery_command,
+            cache_dir,
+            before_all,
+            after_all,
+            after_run,
+            min_nvim_version,
+            add_cwd_to_rtp,
+            init_file,
+            test_cwd,
+            plugin_module,
+            error_patterns,
+            strict_stderr,
+            allowed_stderr,
+            capture_messages,
+            concurrency_groups,
+            quarantine,
+            baseline_threshold,
+            baseline_tolerance,
+            baseline_samples,
+            extra_rtp,
+            secrets,
+            overrides,
+            install_files,
+            runner_wrapper,
+            nvim_args,
+            default_branch,
+            git_path,
+            clone_attempts,
+            namespace_test_utils,
+            test_utils_require,
+            test_utils_after_init,
+            skip_remote_check,
+        } = other;
+        if let Some(test_dependencies) = test_dependencies {
+            self.merge_dependencies(test_dependencies);
+        }
+        merge_field(&mut self.test_paths, test_paths);
+        merge_field(&mut self.default_test_paths, default_test_paths);
+        merge_field(&mut self.discovery_command, discovery_command);
+        merge_field(&mut self.cache_dir, cache_dir);
+        merge_field(&mut self.before_all, before_all);
+        merge_field(&mut self.after_all, after_all);
+        merge_field(&mut self.after_run, after_run);
+        merge_field(&mut self.min_nvim_version, min_nvim_version);
+        merge_field(&mut self.add_cwd_to_rtp, add_cwd_to_rtp);
+        merge_field(&mut self.init_file, init_file);
+        merge_field(&mut self.test_cwd, test_cwd);
+        merge_field(&mut self.plugin_module, plugin_module);
+        merge_field(&mut self.error_patterns, error_patterns);
+        merge_field(&mut self.strict_stderr, strict_stderr);
+        merge_field(&mut self.allowed_stderr, allowed_stderr);
+        merge_field(&mut self.capture_messages, capture_messages);
+        merge_field(&mut self.concurrency_groups, concurrency_groups);
+        merge_field(&mut self.quarantine, quarantine);
+        merge_field(&mut self.baseline_threshold, baseline_threshold);
+        merge_field(&mut self.baseline_tolerance, baseline_tolerance);
+        merge_field(&mut self.baseline_samples, baseline_samples);
+        merge_field(&mut self.extra_rtp, extra_rtp);
+        merge_field(&mut self.secrets, secrets);
+        merge_field(&mut self.overrides, overrides);
+        merge_field(&mut self.install_files, install_files);
+        merge_field(&mut self.runner_wrapper, runner_wrapper);
+        merge_field(&mut self.nvim_args, nvim_args);
+        merge_field(&mut self.default_branch, default_branch);
+        merge_field(&mut self.git_path, git_path);
+        merge_field(&mut self.clone_attempts, clone_attempts);
+        merge_field(&mut self.namespace_test_utils, namespace_test_utils);
+        merge_field(&mut self.test_utils_require, test_utils_require);
+        merge_field(&mut self.test_utils_after_init, test_utils_after_init);
+        merge_field(&mut self.skip_remote_check, skip_remote_check);
+    }
+}
+
+/// Replace the field with the value, if one is given
+fn merge_field<T>(field: &mut Option<T>, value: Option<T>) {
+    if value.is_some() {
+        *field = value;
+    }
+}
+
+impl Default for TestConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The axes of a test matrix. The suite is run once for every combination of the values of the axes that are
+/// given, e.g. twice for two nvims, or four times for two nvims and two dependency sets
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct TestMatrix {
+    /// The nvim executables to run the suite with, each replacing `runner_wrapper`. `~` and environment
+    /// variables are expanded
+    #[serde(default, deserialize_with = "string_or_list")]
+    pub nvim: Option<Vec<String>>,
+    /// Named sets of dependencies to run the suite with, each merged over `test_dependencies` as those of a
+    /// profile are, e.g. to pin the dependencies to other shas
+    pub dependencies: Option<std::collections::BTreeMap<String, Vec<TestDepedency>>>,
+}
+
+/// A combination of the values of the axes of a test matrix
+#[derive(Clone, Debug)]
+pub struct MatrixCell {
+    /// The values of the cell, e.g. `nvim=~/nvim-0.9/bin/nvim, dependencies=pinned`
+    pub name: String,
+    pub nvim: Option<String>,
+    pub dependencies: Option<Vec<TestDepedency>>,
+}
+
+impl TestMatrix {
+    /// The cells of the matrix, in order of the nvims, then of the names of the dependency sets
+    pub fn cells(&self) -> Vec<MatrixCell> {
+        let nvims: Vec<Option<&String>> = match &self.nvim {
+            Some(nvims) => nvims.iter().map(Some).collect(),
+            None => vec![None],
+        };
+        let dependency_sets: Vec<Option<(&String, &Vec<TestDepedency>)>> = match &self.dependencies
+        {
+            Some(sets) => sets.iter().map(Some).collect(),
+            None => vec![None],
+        };
+
+        let mut cells = Vec::new();
+        for nvim in &nvims {
+            for dependency_set in &dependency_sets {
+                let mut values = Vec::new();
+                if let Some(nvim) = nvim {
+                    values.push(format!("nvim={}", nvim));
+                }
+                if let Some((name, _)) = dependency_set {
+                    values.push(format!("dependencies={}", name));
+                }
+                cells.push(MatrixCell {
+                    name: values.join(", "),
+                    nvim: nvim.cloned(),
+                    dependencies: dependency_set.map(|(_, deps)| deps.clone()),
+                });
+            }
+        }
+        cells
+    }
+}
+
+impl MatrixCell {
+    /// The config to run the cell with: the given config with the values of the cell, and without the matrix
+    pub fn apply(&self, config: &TestConfig) -> TestConfig {
+        let mut config = config.clone();
+        config.matrix = None;
+        if let Some(nvim) = &self.nvim {
+            config.settings.runner_wrapper =
+                Some(vec![expand_path(nvim).to_string_lossy().to_string()]);
+        }
+        if let Some(deps) = &self.dependencies {
+            config.settings.merge_dependencies(deps.clone());
+        }
+        config
+    }
+}
+
+/// Parse a dependency given in the compact form `<uri>[@branch][#sha]`, e.g.
+/// `https://github.com/foo/bar@dev#1aeb758`. An `@` in the user info of the uri (e.g. `git@github.com:foo/bar`)
+/// is not taken for the branch
+pub fn parse_dep_spec(spec: &str) -> Result<TestDepedency, String> {
+    let (rest, sha) = match spec.rsplit_once('#') {
+        Some((rest, sha)) => (rest, Some(sha.to_string())),
+        None => (spec, None),
+    };
+    // The branch can only follow the start of the path, which comes after the host
+    let scheme_end = rest.find("://").map_or(0, |i| i + 3);
+    let path_start = rest[scheme_end..].find(['/', ':']).map(|i| scheme_end + i);
+    let (uri, branch) = match (rest.rfind('@'), path_start) {
+        (Some(at), Some(path_start)) if at > path_start => {
+            (&rest[..at], Some(rest[at + 1..].to_string()))
+        }
+        _ => (rest, None),
+    };
+    if uri.is_empty() {
+        return Err(format!("missing uri in dependency {:?}", spec));
+    }
+    if branch.as_deref() == Some("") || sha.as_deref() == Some("") {
+        return Err(format!("empty branch or sha in dependency {:?}", spec));
+    }
+    Ok(TestDepedency {
+        uri: uri.to_string(),
+        branch,
+        sha,
+        git_ref: None,
+        test_paths: None,
+        subdir: None,
+        submodules: None,
+        recursive: None,
+        enabled: None,
+        reference: None,
+        depth: None,
+        single_branch: None,
+        post_install: None,
+        checksum: None,
+    })
+}
+
+/// The patterns of stderr that fail a test if the `errorPatterns` config field does not give any: the error nvim
+/// reports for a script that failed, its error messages (e.g. `E5108: Error executing lua`) and the stack
+/// traceback of a Lua error
+pub const DEFAULT_ERROR_PATTERNS: &[&str] = &[
+    "Error detected while processing",
+    r"^E\d+: ",
+    "^stack traceback:",
+];
+
+/// How many times cloning a dependency is attempted if the `cloneAttempts` config field is not given
+pub const DEFAULT_CLONE_ATTEMPTS: usize = 3;
+
+/// The test paths used if the config does not give any
+pub fn default_test_paths() -> Vec<String> {
+    vec![
+        "tests/**/*.lua".to_string(),
+        "test/**/*.lua".to_string(),
+        "lua/tests/**/*.lua".to_string(),
+        "lua/test/**/*.lua".to_string(),
+    ]
+}
+
+/// The config files that are looked for, in order of precedence
+pub const CONFIG_PATHS: &[&str] = &[
+    "nvim-test-runner.json",
+    "nvim-test-runner.toml",
+    "nvim-test-runner.yaml",
+    "nvim-test-runner.yml",
+];
+
+/// Find the config file to use. If more than one of them exist, the one with the highest precedence is used
+pub fn find_config() -> Option<&'static str> {
+    let mut existing = CONFIG_PATHS
+        .iter()
+        .copied()
+        .filter(|path| std::path::Path::new(path).exists());
+    let path = existing.next()?;
+    for ignored in existing {
+        crate::warnings::warn(format!(
+            "Both {} and {} exist, ignoring {}",
+            path, ignored, ignored
+        ));
+    }
+    Some(path)
+}
+
+/// The config path that stands for reading the (JSON) config from stdin
+pub const STDIN_CONFIG_PATH: &str = "-";
+
+/// Read the JSON config from stdin. Fails if stdin is empty
+pub fn read_config_stdin() -> Result<TestConfig, RunnerError> {
+    let mut contents = String::new();
+    std::io::stdin()
+        .read_to_string(&mut contents)
+        .map_err(|e| RunnerError::io("Failed to read config from stdin", e))?;
+    if contents.trim().is_empty() {
+        return Err(RunnerError::Config(
+            "Failed to read config from stdin: stdin is empty".to_string(),
+        ));
+    }
+    serde_json::from_str(&contents)
+        .map_err(|e| RunnerError::Config(format!("Failed to parse config from stdin: {}", e)))
+}
+
+/// Load the config file, picking the parser by its extension (JSON unless it is `.toml`, `.yaml` or `.yml`).
+/// The path `-` reads the config from stdin
+pub fn load_config(path: &str) -> Result<TestConfig, RunnerError> {
+    let mut config = parse_config(path)?;
+    if let Some(deps) = &mut config.settings.test_dependencies {
+        dedup_deps(deps, "testDependencies")?;
+    }
+    for (name, profile) in config.profiles.iter_mut().flatten() {
+        if let Some(deps) = &mut profile.settings.test_dependencies {
+            dedup_deps(deps, &format!("the testDependencies of profile {}", name))?;
+        }
+    }
+    if let Some(matrix) = &mut config.matrix {
+        for (name, deps) in matrix.dependencies.iter_mut().flatten() {
+            dedup_deps(deps, &format!("the matrix dependencies {}", name))?;
+        }
+    }
+    Ok(config)
+}
+
+/// Remove the dependencies that are listed more than once, i.e. with the same uri once normalized and the same
+/// subdir. Fails if the entries of the same dependency differ otherwise (e.g. are pinned to different branches),
+/// as they would be cloned to the same directory. Entries with different subdirs (e.g. of a monorepo) are
+/// separate dependencies sharing a clone
+fn dedup_deps(deps: &mut Vec<TestDepedency>, list: &str) -> Result<(), RunnerError> {
+    let mut unique: Vec<TestDepedency> = Vec::new();
+    for dep in deps.drain(..) {
+        let uri = normalize_uri(&dep.uri);
+        let Some(existing) = unique
+            .iter()
+            .find(|other| normalize_uri(&other.uri) == uri && other.subdir == dep.subdir)
+        else {
+            unique.push(dep);
+            continue;
+        };
+        let same_uri = TestDepedency {
+            uri: existing.uri.clone(),
+            ..dep.clone()
+        };
+        if same_uri != *existing {
+            // As in the config, leaving out the fields that are not set
+            let describe = |dep: &TestDepedency| {
+                let mut value = serde_json::to_value(dep).unwrap_or_default();
+                if let Some(fields) = value.as_object_mut() {
+                    fields.retain(|_, field| !field.is_null());
+                }
+                value.to_string()
+            };
+            return Err(RunnerError::Config(format!(
+                "Test dependency {} is listed twice in {} with different settings: {} and {}",
+                existing.uri,
+                list,
+                describe(existing),
+                describe(&dep)
+            )));
+        }
+        crate::warnings::warn(format!(
+            "Test dependency {} is listed twice in {}, ignoring the duplicate",
+            existing.uri, list
+        ));
+    }
+    *deps = unique;
+    Ok(())
+}
+
+/// Parse the config file (or stdin) at the given path
+fn parse_config(path: &str) -> Result<TestConfig, RunnerError> {
+    if path == STDIN_CONFIG_PATH {
+        return read_config_stdin();
+    }
+    let mut file = File::open(path)
+        .map_err(|e| RunnerError::io(format!("Failed to open config file {}", path), e))?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let config = if path.ends_with(".toml") {
+        toml::from_str(&contents).map_err(|e| e.to_string())
+    } else if path.ends_with(".yaml") || path.ends_with(".yml") {
+        serde_yaml::from_str(&contents).map_err(|e| e.to_string())
+    } else {
+        serde_json::from_str(&contents).map_err(|e| e.to_string())
+    };
+    config.map_err(|e| RunnerError::Config(format!("Failed to parse config file {}: {}", path, e)))
+}
+
+/// A problem with a field of the config, found by [`validate_config`]
+#[derive(Debug, Clone)]
+pub struct ConfigError {
+    /// Where in the config the problem is, e.g. `testDependencies[2].sha`
+    pub field: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+/// Check the whole config before anything is resolved, reporting all of its problems at once: local
+/// dependencies that do not exist, malformed uris, over-specified dependencies and invalid globs
+pub fn validate_config(config: &TestConfig) -> Result<(), Vec<ConfigError>> {
+    let mut errors = Vec::new();
+    let mut error = |field: String, message: String| errors.push(ConfigError { field, message });
+    // The external dependencies share a clone per uri, which can only be checked out at one commit
+    let mut checkouts: HashMap<String, (usize, &TestDepedency)> = HashMap::new();
+
+    for (index, dep) in config
+        .settings
+        .test_dependencies
+        .iter()
+        .flatten()
+        .enumerate()
+    {
+        let field = format!("testDependencies[{}]", index);
+        if !dep.enabled.unwrap_or(true) {
+            continue;
+        }
+        if let Some(path) = dep.uri.strip_prefix("file:") {
+            let path = path.strip_prefix("//").unwrap_or(path);
+            if !std::path::Path::new(path).is_dir() {
+                error(
+                    format!("{}.uri", field),
+                    format!("{} is not a directory", dep.uri),
+                );
+            }
+        } else if dep.uri.trim().is_empty()
+            || dep.uri.contains(char::is_whitespace)
+            || dep_relative_path(&dep.uri).is_none()
+        {
+            error(
+                format!("{}.uri", field),
+                format!("{:?} is not a valid git uri", dep.uri),
+            );
+        }
+        if !dep.uri.starts_with("file:") {
+            match checkouts.get(&normalize_uri(&dep.uri)) {
+                Some((first, other))
+                    if other.branch != dep.branch
+                        || other.sha != dep.sha
+                        || other.git_ref != dep.git_ref
+                        || other.submodules != dep.submodules
+                        || other.depth != dep.depth
+                        || other.single_branch != dep.single_branch
+                        || other.post_install != dep.post_install
+                        || other.checksum != dep.checksum =>
+                {
+                    error(
+                        field.clone(),
+                        format!(
+                            "shares the clone of testDependencies[{}], but not its branch, sha, ref, submodules, depth, singleBranch, postInstall or checksum",
+                            first
+                        ),
+                    );
+                }
+                Some(_) => {}
+                None => {
+                    checkouts.insert(normalize_uri(&dep.uri), (index, dep));
+                }
+            }
+        }
+        // A ref is resolved relative to the branch, but replaces the sha, which in turn replaces the branch
+        if dep.sha.is_some() && dep.git_ref.is_some() {
+            error(
+                format!("{}.sha", field),
+                "sha and ref are both given, but only one commit can be checked out".to_string(),
+            );
+        }
+        if dep.sha.is_some() && dep.branch.is_some() {
+            error(
+                format!("{}.branch", field),
+                "branch is ignored as sha is given".to_string(),
+            );
+        }
+        if dep.depth == Some(0) {
+            error(format!("{}.depth", field), "must be at least 1".to_string());
+        }
+        // An archive is downloaded as it is, with none of the options of a clone
+        if crate::archive::archive_format(&dep.uri).is_some() {
+            let git_options = [
+                ("branch", dep.branch.is_some()),
+                ("sha", dep.sha.is_some()),
+                ("ref", dep.git_ref.is_some()),
+                ("submodules", dep.submodules.is_some()),
+                ("reference", dep.reference.is_some()),
+                ("depth", dep.depth.is_some()),
+                ("singleBranch", dep.single_branch.is_some()),
+            ];
+            for (name, _) in git_options.iter().filter(|(_, given)| *given) {
+                error(
+                    format!("{}.{}", field, name),
+                    "only applies to git dependencies, not archives".to_string(),
+                );
+            }
+        }
+        if let Some(checksum) = &dep.checksum {
+            if crate::archive::archive_format(&dep.uri).is_none() {
+                error(
+                    format!("{}.checksum", field),
+                    "only applies to archive dependencies (a uri ending in .tar.gz, .tgz or .zip)"
+                        .to_string(),
+                );
+            } else if checksum.len() != 64 || !checksum.chars().all(|c| c.is_ascii_hexdigit()) {
+                error(
+                    format!("{}.checksum", field),
+                    format!("{:?} is not a SHA-256 in hex", checksum),
+                );
+            }
+        }
+        if (dep.depth.is_some() || dep.single_branch.is_some()) && dep.uri.starts_with("file:") {
+            error(
+                format!("{}.depth", field),
+                "depth and singleBranch only apply to cloned dependencies, not local (file:) ones"
+                    .to_string(),
+            );
+        }
+        if dep.post_install.is_some() && dep.uri.starts_with("file:") {
+            error(
+                format!("{}.postInstall", field),
+                "only applies to cloned dependencies, not local (file:) ones".to_string(),
+            );
+        }
+        for (i, command) in dep.post_install.iter().flatten().enumerate() {
+            if command.trim().is_empty() {
+                error(
+                    format!("{}.postInstall[{}]", field, i),
+                    "is empty".to_string(),
+                );
+            }
+        }
+        if let Some(reference) = &dep.reference {
+            if !expand_path(reference).is_dir() {
+                error(
+                    format!("{}.reference", field),
+                    format!("{} is not a directory", reference),
+                );
+            }
+        }
+        for (i, pattern) in dep.test_paths.iter().flatten().enumerate() {
+            if let Err(e) = glob::Pattern::new(pattern) {
+                error(
+                    format!("{}.testPaths[{}]", field, i),
+                    format!("{:?} is not a valid glob: {}", pattern, e),
+                );
+            }
+        }
+    }
+
+    for (index, pattern) in config.settings.test_paths.iter().flatten().enumerate() {
+        if let Err(e) = glob::Pattern::new(pattern) {
+            error(
+                format!("testPaths[{}]", index),
+                format!("{:?} is not a valid glob: {}", pattern, e),
+            );
+        }
+    }
+
+    if let Some(test_cwd) = &config.settings.test_cwd {
+        if !expand_path(test_cwd).is_dir() {
+            error(
+                "testCwd".to_string(),
+                format!("{} is not a directory", test_cwd),
+            );
+        }
+    }
+
+    for (index, pattern) in config.settings.quarantine.iter().flatten().enumerate() {
+        if let Err(e) = glob::Pattern::new(pattern) {
+            error(
+                format!("quarantine[{}]", index),
+                format!("{:?} is not a valid glob: {}", pattern, e),
+            );
+        }
+    }
+
+    for (index, pattern) in config.settings.error_patterns.iter().flatten().enumerate() {
+        if let Err(e) = regex::Regex::new(pattern) {
+            error(
+                format!("errorPatterns[{}]", index),
+                format!("invalid regular expression /{}/: {}", pattern, e),
+            );
+        }
+    }
+
+    if config.settings.clone_attempts == Some(0) {
+        error(
+            "cloneAttempts".to_string(),
+            "has to be at least 1, or no dependency could be cloned".to_string(),
+        );
+    }
+
+    for (name, max) in config.settings.concurrency_groups.iter().flatten() {
+        if *max == 0 {
+            error(
+                format!("concurrencyGroups.{}", name),
+                "has to be at least 1, or no test of the group could run".to_string(),
+            );
+        }
+    }
+
+    for (index, test_override) in config.settings.overrides.iter().flatten().enumerate() {
+        let field = format!("overrides[{}]", index);
+        if let Err(e) = glob::Pattern::new(&test_override.pattern) {
+            error(
+                format!("{}.match", field),
+                format!("{:?} is not a valid glob: {}", test_override.pattern, e),
+            );
+        }
+        if let Some(timeout) = &test_override.timeout {
+            match crate::discovery::parse_duration(timeout) {
+                Ok(timeout) if timeout.is_zero() => error(
+                    format!("{}.timeout", field),
+                    "has to be longer than 0".to_string(),
+                ),
+                Ok(_) => {}
+                Err(e) => error(format!("{}.timeout", field), e),
+            }
+        }
+        for name in test_override.env.iter().flat_map(|env| env.keys()) {
+            if !crate::secrets::is_valid_name(name) {
+                error(
+                    format!("{}.env.{}", field, name),
+                    "has to be the name of an environment variable".to_string(),
+                );
+            }
+        }
+        for (name, value) in [
+            ("group", &test_override.group),
+            ("serialGroup", &test_override.serial_group),
+        ] {
+            if value
+                .as_deref()
+                .is_some_and(|value| value.trim().is_empty())
+            {
+                error(format!("{}.{}", field, name), "is empty".to_string());
+            }
+        }
+    }
+
+    if let Some(threshold) = config.settings.baseline_threshold {
+        if !threshold.is_finite() || threshold < 0.0 {
+            error(
+                "baselineThreshold".to_string(),
+                "has to be a percentage of at least 0".to_string(),
+            );
+        }
+    }
+    if let Some(tolerance) = &config.settings.baseline_tolerance {
+        if let Err(e) = crate::discovery::parse_duration(tolerance) {
+            error("baselineTolerance".to_string(), e);
+        }
+    }
+    if config.settings.baseline_samples == Some(0) {
+        error(
+            "baselineSamples".to_string(),
+            "has to be at least 1".to_string(),
+        );
+    }
+
+    for name in config
+        .settings
+        .secrets
+        .iter()
+        .flat_map(|secrets| secrets.keys())
+    {
+        if !crate::secrets::is_valid_name(name) {
+            error(
+                format!("secrets.{}", name),
+                "has to be the name of an environment variable".to_string(),
+            );
+        }
+    }
+
+    for (from, to) in config.settings.install_files.iter().flatten() {
+        for path in [from, to] {
+            if !crate::install::is_relative_within(path) {
+                error(
+                    format!("installFiles.{}", from),
+                    format!(
+                        "{:?} has to be a relative path within the directory, without ..",
+                        path
+                    ),
+                );
+            }
+        }
+    }
+
+    if let Some(matrix) = &config.matrix {
+        if matrix.nvim.as_ref().is_some_and(|nvims| nvims.is_empty()) {
+            error(
+                "matrix.nvim".to_string(),
+                "is empty, the matrix would have no cells".to_string(),
+            );
+        }
+        if matrix
+            .dependencies
+            .as_ref()
+            .is_some_and(|sets| sets.is_empty())
+        {
+            error(
+                "matrix.dependencies".to_string(),
+                "is empty, the matrix would have no cells".to_string(),
+            );
+        }
+    }
+
+    if let Some(version) = &config.settings.min_nvim_version {
+        if parse_version(version).is_none() {
+            error(
+                "minNvimVersion".to_string(),
+                format!("{:?} is not a version", version),
+            );
+        }
+    }
+
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors),
+    }
+}
+
+/// [`validate_config`], with all of the problems in a single error
+pub fn check_config(config: &TestConfig) -> Result<(), RunnerError> {
+    validate_config(config).map_err(|errors| {
+        let problems: Vec<String> = errors
+            .iter()
+            .map(|error| format!("  - {}", error))
+            .collect();
+        RunnerError::Config(format!(
+            "The config has {} problem(s):\n{}",
+            errors.len(),
+            problems.join("\n")
+        ))
+    })
+}
+
+/// Expand a leading `~` to the home directory, and the environment variables (`$VAR` or `${VAR}`) in a path.
+/// Variables that are not set are left as they are
+pub fn expand_path(path: &str) -> std::path::PathBuf {
+    let mut expanded = String::new();
+    let mut rest = path;
+    if rest == "~" || rest.starts_with("~/") {
+        if let Ok(home) = env::var("HOME") {
+            expanded.push_str(&home);
+            rest = &rest[1..];
+        }
+    }
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let (name, len) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => ("", 0),
+            },
+            None => {
+                let end = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                (&after[..end], end)
+            }
+        };
+        match env::var(name) {
+            Ok(value) if !name.is_empty() => expanded.push_str(&value),
+            _ => expanded.push_str(&rest[start..start + 1 + len]),
+        }
+        rest = &after[len..];
+    }
+    expanded.push_str(rest);
+    std::path::PathBuf::from(expanded)
+}
+
+/// The prefix of the environment variables that override config values
+pub const ENV_PREFIX: &str = "NVIM_TEST_RUNNER_";
+
+/// Override config values with those given as environment variables (e.g. `NVIM_TEST_RUNNER_TEST_PATHS`).
+/// List values are comma-separated
+pub fn apply_env_overrides(config: &mut TestConfig) {
+    let var = |name: &str| {
+        let key = format!("{}{}", ENV_PREFIX, name);
+        let value = env::var(&key).ok().filter(|value| !value.is_empty())?;
+        debug!("Overriding config with {}={}", key, value);
+        Some(value)
+    };
+
+    if let Some(test_paths) = var("TEST_PATHS") {
+        config.settings.test_paths = Some(
+            test_paths
+                .split(',')
+                .map(|p| p.trim().to_string())
+                .collect(),
+        );
+    }
+    if let Some(before_all) = var("BEFORE_ALL") {
+        config.settings.before_all = Some(before_all);
+    }
+    if let Some(after_all) = var("AFTER_ALL") {
+        config.settings.after_all = Some(after_all);
+    }
+    if let Some(after_run) = var("AFTER_RUN") {
+        config.settings.after_run = Some(after_run);
+    }
+    if let Some(min_nvim_version) = var("MIN_NVIM_VERSION") {
+        config.settings.min_nvim_version = Some(min_nvim_version);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TestConfig;
+
+    #[test]
+    fn profiles_override_the_settings_of_the_config() {
+        let mut config: TestConfig = serde_json::from_str(
+            r#"{
+                "testPaths": "tests/unit",
+                "skipRemoteCheck": false,
+                "namespaceTestUtils": true,
+                "profiles": { "ci": { "testPaths": ["tests"], "skipRemoteCheck": true } }
+            }"#,
+        )
+        .unwrap();
+        config.apply_profile("ci").unwrap();
+        assert_eq!(config.settings.test_paths, Some(vec!["tests".to_string()]));
+        assert_eq!(config.settings.skip_remote_check, Some(true));
+        assert_eq!(config.settings.namespace_test_utils, Some(true));
+        assert!(config.apply_profile("ci").is_err());
+    }
+}
@@ -0,0 +1,118 @@
+//! Line coverage of the Lua code run by the tests, with --coverage. test-utils traces the lines that run (with a
+//! debug hook) and writes the hits of each nvim process to the file at `$NVIM_TEST_RUNNER_COVERAGE_FILE`, which the
+//! runner merges into a single report. Both use the stats format of luacov, so that its reporter can be run on
+//! the report:
+//!
+//! ```text
+//! <number of lines>:<path>
+//! <hits of line 1> <hits of line 2> ...
+//! ```
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// The number of times each line of each file ran
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    /// The hits of each line by path, the line `n` at index `n - 1`
+    files: BTreeMap<PathBuf, Vec<u64>>,
+}
+
+impl Coverage {
+    /// Parse coverage in the stats format of luacov
+    pub fn parse(contents: &str) -> Result<Coverage, String> {
+        let mut coverage = Coverage::default();
+        let mut lines = contents.lines().filter(|line| !line.trim().is_empty());
+        while let Some(header) = lines.next() {
+            let (num_lines, path) = header
+                .split_once(':')
+                .ok_or_else(|| format!("Invalid header {:?}, expected <lines>:<path>", header))?;
+            let num_lines: usize = num_lines
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid number of lines in {:?}", header))?;
+            let hits = lines
+                .next()
+                .ok_or_else(|| format!("Missing the hits of {}", path))?
+                .split_whitespace()
+                .map(|hits| hits.parse::<u64>())
+                .collect::<Result<Vec<u64>, _>>()
+                .map_err(|_| format!("Invalid hits of {}", path))?;
+            if hits.len() != num_lines {
+                return Err(format!(
+                    "Expected the hits of {} lines of {}, got {}",
+                    num_lines,
+                    path,
+                    hits.len()
+                ));
+            }
+            coverage.add(PathBuf::from(path), &hits);
+        }
+        Ok(coverage)
+    }
+
+    /// Read the coverage file written by test-utils
+    pub fn read(path: &Path) -> Result<Coverage, String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Coverage::parse(&contents)
+    }
+
+    /// Add the hits of the lines of a file to those recorded
+    fn add(&mut self, path: PathBuf, hits: &[u64]) {
+        let recorded = self.files.entry(path).or_default();
+        if recorded.len() < hits.len() {
+            recorded.resize(hits.len(), 0);
+        }
+        for (recorded, hits) in recorded.iter_mut().zip(hits) {
+            *recorded += hits;
+        }
+    }
+
+    /// Add the hits of another coverage, e.g. of another nvim process
+    pub fn merge(&mut self, other: Coverage) {
+        for (path, hits) in other.files {
+            self.add(path, &hits);
+        }
+    }
+
+    /// Keep the files for which the predicate holds, giving them the path it returns
+    pub fn filter_map_paths(self, f: impl Fn(&Path) -> Option<PathBuf>) -> Coverage {
+        let mut coverage = Coverage::default();
+        for (path, hits) in self.files {
+            if let Some(path) = f(&path) {
+                coverage.add(path, &hits);
+            }
+        }
+        coverage
+    }
+
+    pub fn num_files(&self) -> usize {
+        self.files.len()
+    }
+
+    /// The number of lines that ran at least once
+    pub fn num_lines_hit(&self) -> usize {
+        self.files
+            .values()
+            .map(|hits| hits.iter().filter(|hits| **hits > 0).count())
+            .sum()
+    }
+
+    /// Write the coverage in the stats format of luacov
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        let mut contents = String::new();
+        for (file, hits) in &self.files {
+            contents.push_str(&format!("{}:{}\n", hits.len(), file.display()));
+            let hits: Vec<String> = hits.iter().map(|hits| hits.to_string()).collect();
+            contents.push_str(&hits.join(" "));
+            contents.push('\n');
+        }
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, contents)
+    }
+}
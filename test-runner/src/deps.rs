@@ -0,0 +1,1398 @@
+use ansi_term::Colour;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Read;
+use std::process::{Command, Stdio};
+
+use crate::archive::{archive_format, resolve_archive_dep};
+use crate::config::{expand_path, TestDepedency};
+use crate::error::RunnerError;
+use crate::state::{CachedRemoteRefs, State, TestDepedencyState};
+
+/// How an external dependency was resolved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepResolution {
+    /// The cached clone was up-to-date
+    Reused,
+    /// The cached clone was outdated, and was replaced
+    Updated,
+    /// There was no cached clone
+    Cloned,
+}
+
+/// The refs of a remote repository, as listed by `git ls-remote --symref`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RemoteRefs {
+    /// Ref name to commit hash
+    pub hashes: HashMap<String, String>,
+    /// Symbolic ref name to the ref it points to, e.g. `HEAD` to `refs/heads/main`
+    pub symrefs: HashMap<String, String>,
+}
+
+impl RemoteRefs {
+    pub fn parse(ls_remote_output: &str) -> RemoteRefs {
+        let mut refs = RemoteRefs::default();
+        for line in ls_remote_output.lines() {
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next(), parts.next()) {
+                // e.g. "ref: refs/heads/main	HEAD"
+                (Some("ref:"), Some(target), Some(ref_name)) => {
+                    refs.symrefs
+                        .insert(ref_name.to_string(), target.to_string());
+                }
+                (Some(hash), Some(ref_name), None) => {
+                    refs.hashes.insert(ref_name.to_string(), hash.to_string());
+                }
+                _ => {}
+            }
+        }
+        refs
+    }
+
+    /// Resolve the commit hash the head of the given branch points to. Without a branch, the default branch
+    /// is resolved: `HEAD`, or the ref that `HEAD` symbolically points to, falling back to `main` and then
+    /// `master` for remotes that do not advertise `HEAD`
+    pub fn resolve(&self, branch: Option<&str>) -> Option<&String> {
+        if let Some(branch) = branch {
+            return self.hashes.get(&format!("refs/heads/{}", branch));
+        }
+
+        self.hashes
+            .get("HEAD")
+            .or_else(|| {
+                self.symrefs
+                    .get("HEAD")
+                    .and_then(|target| self.hashes.get(target))
+            })
+            .or_else(|| self.hashes.get("refs/heads/main"))
+            .or_else(|| self.hashes.get("refs/heads/master"))
+    }
+}
+
+/// Options controlling how external dependencies are resolved
+pub struct ResolveOptions<'a> {
+    pub cache_dir: &'a std::path::Path,
+    pub skip_remote_check: bool,
+    pub offline: bool,
+    /// Fail if the dependency is not cloned as configured, rather than (re-)cloning it
+    pub frozen: bool,
+    /// How long the listed refs of a remote are reused before it is listed again
+    pub remote_ttl: Option<std::time::Duration>,
+    /// Print why each dependency is reused, updated or cloned
+    pub explain: bool,
+    /// The directory clones are shared from between projects, which link to them from their own cache dir
+    pub global_cache: Option<&'a std::path::Path>,
+    /// The git executable, see [`crate::resolve_git_path`]
+    pub git: &'a std::path::Path,
+    /// Check that the reused clones have no local modifications and are at the recorded commit
+    pub verify_clones: bool,
+    /// Reset the reused clones that fail the check, rather than only warning about them
+    pub repair_clones: bool,
+    /// A local repository that new clones borrow their objects from, unless a dependency gives its own
+    pub reference_repo: Option<&'a std::path::Path>,
+    /// Copy the borrowed objects into the new clones, so that they do not depend on the reference repository
+    pub dissociate: bool,
+    /// Show the progress of git while cloning, e.g. when attached to a terminal
+    pub progress: bool,
+    /// How many times a clone (or the download of an archive) that fails for what may be a network problem is
+    /// attempted
+    pub clone_attempts: usize,
+    /// The proxy archives are downloaded through, see [`crate::test_utils::http_client`]
+    pub proxy: Option<&'a str>,
+}
+
+/// The progress lines of the git commands that are running, one per command, so that clones running at once
+/// do not draw over each other
+static GIT_PROGRESS: std::sync::LazyLock<MultiProgress> =
+    std::sync::LazyLock::new(MultiProgress::new);
+
+/// Run a git command that reports its progress on stderr (e.g. `git clone --progress`), showing its latest
+/// progress line as a spinner labelled with the given text while it runs. Returns the output as
+/// [`Command::output`] does, with the whole stderr
+fn output_with_progress(
+    cmd: &mut Command,
+    label: &str,
+    progress: bool,
+) -> std::io::Result<std::process::Output> {
+    if !progress {
+        return cmd.output();
+    }
+    let mut child = cmd
+        .arg("--progress")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdout_pipe = child.stdout.take().expect("stdout is piped");
+    let stdout = std::thread::spawn(move || {
+        let mut stdout = Vec::new();
+        let _ = stdout_pipe.read_to_end(&mut stdout);
+        stdout
+    });
+
+    let spinner = GIT_PROGRESS.add(ProgressBar::new_spinner());
+    spinner.set_style(ProgressStyle::with_template("{spinner} {msg}").unwrap());
+    spinner.enable_steady_tick(std::time::Duration::from_millis(100));
+    spinner.set_message(label.to_string());
+
+    // git redraws its progress with carriage returns, so the latest line is what follows the last one
+    let mut stderr = Vec::new();
+    let mut line_start = 0;
+    let mut stderr_pipe = child.stderr.take().expect("stderr is piped");
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = match stderr_pipe.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        stderr.extend_from_slice(&buf[..n]);
+        for (i, byte) in stderr.iter().enumerate().skip(line_start) {
+            if *byte == b'\r' || *byte == b'\n' {
+                let line = String::from_utf8_lossy(&stderr[line_start..i]);
+                if !line.trim().is_empty() {
+                    spinner.set_message(format!("{}: {}", label, line.trim()));
+                }
+                line_start = i + 1;
+            }
+        }
+    }
+    spinner.finish_and_clear();
+    GIT_PROGRESS.remove(&spinner);
+
+    let status = child.wait()?;
+    Ok(std::process::Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr,
+    })
+}
+
+/// The local repository a new clone of the dependency borrows its objects from, if any
+fn clone_reference(dep: &TestDepedency, options: &ResolveOptions) -> Option<std::path::PathBuf> {
+    match &dep.reference {
+        Some(reference) => Some(expand_path(reference)),
+        None => options.reference_repo.map(|path| path.to_path_buf()),
+    }
+}
+
+/// Whether the recorded state of a dependency can be reused for its current config, along with the rule that
+/// decided it, e.g. for `--explain`
+pub fn match_state(
+    dep: &TestDepedency,
+    dep_state: &TestDepedencyState,
+    branch_head_sha: &str,
+) -> (bool, String) {
+    // A ref may be relative to the head of the branch (e.g. `HEAD~1`), so it is only reused as long as the
+    // head has not moved
+    if let Some(git_ref) = &dep.git_ref {
+        let matches =
+            dep_state.git_ref.as_ref() == Some(git_ref) && dep_state.hash == branch_head_sha;
+        return (
+            matches,
+            format!(
+                "ref {}: recorded ref {} and branch head {} {}",
+                git_ref,
+                dep_state.git_ref.as_deref().unwrap_or("<none>"),
+                dep_state.hash,
+                if matches { "match" } else { "do not match" }
+            ),
+        );
+    }
+
+    if let Some(sha) = &dep.sha {
+        let matches = dep_state.sha.as_ref() == Some(sha);
+        return (
+            matches,
+            format!(
+                "pinned sha {}: recorded sha {} {}",
+                sha,
+                dep_state.sha.as_deref().unwrap_or("<none>"),
+                if matches { "matches" } else { "does not match" }
+            ),
+        );
+    }
+
+    if dep.branch.is_some() && dep_state.branch != dep.branch {
+        return (
+            false,
+            format!(
+                "branch {}: recorded branch is {}",
+                dep.branch.as_deref().unwrap_or_default(),
+                dep_state.branch.as_deref().unwrap_or("the default branch")
+            ),
+        );
+    }
+
+    let matches = dep_state.hash == branch_head_sha;
+    (
+        matches,
+        format!(
+            "branch head: recorded hash {} {} the remote",
+            dep_state.hash,
+            if matches { "matches" } else { "differs from" }
+        ),
+    )
+}
+
+/// Resolve an external dependency, (re-)cloning it if there is no up-to-date clone in the cache.
+/// The new state of the dependency is recorded in `new_state`. Returns the path of the clone
+pub fn resolve_external_dep(
+    dep: &TestDepedency,
+    uri: &str,
+    state: &State,
+    new_state: &mut State,
+    options: &ResolveOptions,
+) -> Result<(std::path::PathBuf, DepResolution), RunnerError> {
+    let mut explanation = Vec::new();
+    let result = resolve_with_explanation(dep, uri, state, new_state, options, &mut explanation)
+        .and_then(|(dep_path, resolution)| {
+            // An extracted archive has no history to verify it against
+            if resolution == DepResolution::Reused
+                && (options.verify_clones || options.repair_clones)
+                && archive_format(uri).is_none()
+            {
+                verify_clone(dep, uri, &dep_path, state, options)?;
+            }
+            run_post_install(dep, uri, &dep_path, new_state, options)?;
+            Ok((dep_path, resolution))
+        });
+    if options.explain {
+        let action = match &result {
+            Ok((_, DepResolution::Reused)) => "reuse",
+            Ok((_, DepResolution::Updated)) => "update",
+            Ok((_, DepResolution::Cloned)) => "clone",
+            Err(_) => "failed",
+        };
+        println!("{}:", dep.uri);
+        for line in &explanation {
+            println!("  {}", line);
+        }
+        println!("  action: {}", action);
+        info!(
+            "explain {}: {}, action: {}",
+            dep.uri,
+            explanation.join(", "),
+            action
+        );
+    }
+    result
+}
+
+/// Run the `postInstall` commands of a dependency in its clone, unless they already ran successfully in it (as
+/// recorded in the state). Fails with the output of the first command that fails, so that they run again next
+/// time. In the global cache they ran before the clone was complete (see [`link_global_clone`])
+fn run_post_install(
+    dep: &TestDepedency,
+    uri: &str,
+    dep_path: &std::path::Path,
+    new_state: &mut State,
+    options: &ResolveOptions,
+) -> Result<(), RunnerError> {
+    let Some(commands) = dep
+        .post_install
+        .as_ref()
+        .filter(|commands| !commands.is_empty())
+    else {
+        return Ok(());
+    };
+    let Some(dep_state) = new_state
+        .test_dependencies
+        .iter_mut()
+        .find(|dep_state| dep_state.uri == uri)
+    else {
+        return Ok(());
+    };
+    if dep_state.post_install.as_ref() == Some(commands) || options.global_cache.is_some() {
+        debug!("The post-install commands of {} already ran", dep.uri);
+        dep_state.post_install = Some(commands.clone());
+        return Ok(());
+    }
+    dep_state.post_install = None;
+    run_post_install_commands(dep, dep_path)?;
+    dep_state.post_install = Some(commands.clone());
+    Ok(())
+}
+
+/// Run the `postInstall` commands of a dependency in the given clone, one after another
+fn run_post_install_commands(
+    dep: &TestDepedency,
+    dep_path: &std::path::Path,
+) -> Result<(), RunnerError> {
+    for command in dep.post_install.iter().flatten() {
+        println!(
+            "{}",
+            Colour::Yellow.paint(format!(
+                "Running post-install command of {}: {}",
+                dep.uri, command
+            ))
+        );
+        info!("Running post-install command of {}: {}", dep.uri, command);
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .current_dir(dep_path)
+            .output()
+            .map_err(|e| {
+                RunnerError::Hook(format!(
+                    "Failed to run the post-install command {:?} of test dependency {}: {}",
+                    command, dep.uri, e
+                ))
+            })?;
+        if !output.status.success() {
+            return Err(RunnerError::Hook(format!(
+                "The post-install command {:?} of test dependency {} failed with {}:\n{}{}",
+                command,
+                dep.uri,
+                output.status,
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        debug!(
+            "Post-install command {:?} of {}:\n{}{}",
+            command,
+            dep.uri,
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(())
+}
+
+/// [`resolve_external_dep`], recording the steps of the decision in `explanation`
+fn resolve_with_explanation(
+    dep: &TestDepedency,
+    uri: &str,
+    state: &State,
+    new_state: &mut State,
+    options: &ResolveOptions,
+    explanation: &mut Vec<String>,
+) -> Result<(std::path::PathBuf, DepResolution), RunnerError> {
+    if archive_format(uri).is_some() {
+        return resolve_archive_dep(dep, uri, state, new_state, options, explanation);
+    }
+    let recorded = state
+        .test_dependencies
+        .iter()
+        .find(|dep_state| dep_state.uri == uri);
+    explanation.push(match recorded {
+        Some(dep_state) => format!("state hash: {}", dep_state.hash),
+        None => "state hash: <none>, the dependency has not been cloned before".to_string(),
+    });
+    let maybe_dep_path = dep_relative_path(uri);
+    if maybe_dep_path.is_none() {
+        return Err(RunnerError::Config(format!("Invalid uri: {}", dep.uri)));
+    }
+    let dep_path = options
+        .cache_dir
+        .join("external-dep")
+        .join(maybe_dep_path.unwrap());
+
+    if !options.skip_remote_check {
+        git_version(options.git)?;
+
+        let cached_refs = state.remote_refs.get(uri).filter(|cached| {
+            options
+                .remote_ttl
+                .is_some_and(|remote_ttl| cached.is_fresh(remote_ttl))
+        });
+        let remote_refs = match cached_refs {
+            Some(cached) => {
+                debug!("Reusing the listed refs of {}", dep.uri);
+                cached.refs.clone()
+            }
+            None => {
+                let remote_refs = list_remote_refs(&dep.uri, options.git)?;
+                new_state
+                    .remote_refs
+                    .insert(uri.to_string(), CachedRemoteRefs::new(remote_refs.clone()));
+                remote_refs
+            }
+        };
+
+        let ref_name = match &dep.branch {
+            Some(branch) => format!("refs/heads/{}", branch),
+            None => "HEAD".to_string(),
+        };
+        match remote_refs.resolve(dep.branch.as_deref()) {
+            Some(branch_head_sha) => {
+                explanation.push(format!("remote hash: {} ({})", branch_head_sha, ref_name));
+                // Check if state matches
+                let state_matches = state.test_dependencies.iter().any(|dep_state| {
+                    dep_state.uri == uri
+                        && dep_state.branch == dep.branch
+                        && dep_state.sha == dep.sha
+                        && dep_state.git_ref == dep.git_ref
+                });
+
+                debug!(
+                    "state_matches: {}, uri: {}, branch: {}, sha: {}",
+                    state_matches,
+                    dep.uri,
+                    dep.branch.clone().unwrap_or("HEAD".to_string()),
+                    dep.sha.clone().unwrap_or("<none>".to_string())
+                );
+
+                // Whether an existing clone is replaced
+                let mut overwritten = false;
+
+                if !state_matches && recorded.is_some() {
+                    explanation.push(
+                        "the branch, sha or ref in the config changed since the last clone"
+                            .to_string(),
+                    );
+                }
+
+                if !state_matches && dep_path.exists() {
+                    println!(
+                        "{}",
+                        Colour::Yellow.paint(format!(
+                            "Overwriting existing test dependency at path {}",
+                            dep_path.display()
+                        ))
+                    );
+                    info!(
+                        "Overwriting existing test dependency at path {}",
+                        dep_path.display()
+                    );
+
+                    std::fs::remove_dir_all(&dep_path)?;
+                    overwritten = true;
+
+                    // Remove from state
+                    new_state
+                        .test_dependencies
+                        .retain(|dep_state| dep_state.uri != uri);
+                }
+
+                let reusable = state
+                    .test_dependencies
+                    .iter()
+                    .filter(|dep_state| dep_state.uri == uri)
+                    .any(|dep_state| {
+                        let (matches, predicate) = match_state(dep, dep_state, branch_head_sha);
+                        explanation.push(format!("predicate: {}", predicate));
+                        matches
+                    });
+                if reusable {
+                    if dep_path.exists() {
+                        return Ok((dep_path, DepResolution::Reused));
+                    }
+                    explanation.push(format!("the clone at {} is missing", dep_path.display()));
+                }
+
+                // The clone is outdated (e.g. the branch has moved on)
+                if dep_path.exists() {
+                    info!(
+                        "Removing outdated test dependency at path {}",
+                        dep_path.display()
+                    );
+                    std::fs::remove_dir_all(&dep_path)?;
+                    overwritten = true;
+                }
+
+                match options.global_cache {
+                    Some(global_cache) => link_global_clone(
+                        dep,
+                        uri,
+                        &dep_path,
+                        branch_head_sha,
+                        global_cache,
+                        options,
+                    )?,
+                    None => clone_dep(dep, &dep_path, branch_head_sha, options)?,
+                }
+
+                new_state
+                    .test_dependencies
+                    .retain(|dep_state| dep_state.uri != uri);
+                new_state.test_dependencies.push(TestDepedencyState {
+                    uri: uri.to_string(),
+                    hash: branch_head_sha.clone(),
+                    branch: dep.branch.clone(),
+                    sha: dep.sha.clone(),
+                    git_ref: dep.git_ref.clone(),
+                    post_install: None,
+                });
+
+                if overwritten {
+                    Ok((dep_path, DepResolution::Updated))
+                } else {
+                    Ok((dep_path, DepResolution::Cloned))
+                }
+            }
+            None => Err(RunnerError::Git(format!(
+                "Branch {} does not exist in repository {}",
+                ref_name, dep.uri
+            ))),
+        }
+    } else {
+        // skip_remote_check option is off
+        explanation.push("remote not checked (skipRemoteCheck, --offline or --frozen)".to_string());
+        // Check if state exists with uri and branch
+        let exists = state
+            .test_dependencies
+            .iter()
+            .any(|dep_state| dep_state.uri == uri && dep_state.branch == dep.branch);
+        if !exists {
+            return Err(RunnerError::Git(format!(
+                "State does not exist for test dependency {} @ branch {}",
+                dep.uri,
+                dep.branch.clone().unwrap_or("HEAD".to_string())
+            )));
+        }
+        if options.frozen {
+            let matches = state.test_dependencies.iter().any(|dep_state| {
+                dep_state.uri == uri
+                    && dep_state.branch == dep.branch
+                    && dep_state.sha == dep.sha
+                    && dep_state.git_ref == dep.git_ref
+            });
+            if !matches {
+                return Err(RunnerError::Git(format!(
+                    "Test dependency {} was cloned with another branch, sha or ref and cannot be cloned again with --frozen",
+                    dep.uri
+                )));
+            }
+        }
+        if (options.offline || options.frozen) && !dep_path.exists() {
+            return Err(RunnerError::Git(format!(
+                "Test dependency {} is not cached at {} and cannot be cloned {}",
+                dep.uri,
+                dep_path.display(),
+                if options.frozen {
+                    "with --frozen"
+                } else {
+                    "in offline mode"
+                }
+            )));
+        }
+        Ok((dep_path, DepResolution::Reused))
+    }
+}
+
+/// Check that a reused clone is still as the runner left it: without local modifications, and with HEAD at the
+/// commit it was reset to. Clones that are not are warned about, or reset with `repair_clones`
+fn verify_clone(
+    dep: &TestDepedency,
+    uri: &str,
+    dep_path: &std::path::Path,
+    state: &State,
+    options: &ResolveOptions,
+) -> Result<(), RunnerError> {
+    let git = |args: &[&str]| -> Result<String, RunnerError> {
+        let output = Command::new(options.git)
+            .current_dir(dep_path)
+            .args(args)
+            .output()
+            .map_err(|e| RunnerError::Git(format!("Failed to execute git {}: {}", args[0], e)))?;
+        if !output.status.success() {
+            return Err(RunnerError::Git(format!(
+                "git {} failed in {}:\n{}",
+                args.join(" "),
+                dep_path.display(),
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+
+    // A ref may be relative to HEAD itself, so the commit of a dependency given by ref is not checked
+    let expected = match &dep.sha {
+        Some(sha) => Some(sha.clone()),
+        None if dep.git_ref.is_none() => state
+            .test_dependencies
+            .iter()
+            .find(|dep_state| dep_state.uri == uri)
+            .map(|dep_state| dep_state.hash.clone()),
+        None => None,
+    };
+    let expected_commit = expected
+        .as_deref()
+        .map(|expected| resolve_ref(dep, dep_path, expected, options.git));
+
+    let mut problems = Vec::new();
+    if !git(&["status", "--porcelain"])?.is_empty() {
+        problems.push("has local modifications".to_string());
+    }
+    let head = git(&["rev-parse", "HEAD"])?;
+    match &expected_commit {
+        Some(Ok(commit)) if *commit != head => {
+            problems.push(format!("is at {} rather than {}", head, commit))
+        }
+        Some(Err(_)) => problems.push(format!(
+            "does not contain the recorded commit {}",
+            expected.as_deref().unwrap_or_default()
+        )),
+        _ => {}
+    }
+    if problems.is_empty() {
+        debug!("The clone of {} is unmodified", dep.uri);
+        return Ok(());
+    }
+    let problems = problems.join(" and ");
+
+    if !options.repair_clones {
+        crate::warnings::warn(format!(
+            "The clone of test dependency {} at {} {}; pass --repair-deps to reset it",
+            dep.uri,
+            dep_path.display(),
+            problems
+        ));
+        return Ok(());
+    }
+
+    println!(
+        "{}",
+        Colour::Yellow.paint(format!(
+            "Resetting the clone of test dependency {}, which {}",
+            dep.uri, problems
+        ))
+    );
+    info!(
+        "Resetting the clone of test dependency {}, which {}",
+        dep.uri, problems
+    );
+    let target = match expected_commit {
+        Some(Ok(commit)) => commit,
+        _ => head,
+    };
+    git(&["reset", "--hard", &target])?;
+    git(&["clean", "-fd"])?;
+    Ok(())
+}
+
+/// Whether a failure of git may be a network problem that goes away by itself, judging by what git printed on
+/// its stderr. A repository or ref that does not exist, or credentials that are refused, are not
+fn is_transient_git_error(stderr: &str) -> bool {
+    let stderr = stderr.to_lowercase();
+    const FATAL: &[&str] = &[
+        "not found",
+        "does not exist",
+        "does not appear to be a git repository",
+        "authentication failed",
+        "could not read username",
+        "permission denied",
+        "returned error: 401",
+        "returned error: 403",
+        "returned error: 404",
+    ];
+    const TRANSIENT: &[&str] = &[
+        "could not resolve host",
+        "couldn't resolve host",
+        "temporary failure in name resolution",
+        "connection timed out",
+        "operation timed out",
+        "connection reset",
+        "connection refused",
+        "network is unreachable",
+        "the remote end hung up unexpectedly",
+        "early eof",
+        "rpc failed",
+        "unexpected disconnect",
+        "gnutls",
+        "returned error: 429",
+        "returned error: 5",
+    ];
+    !FATAL.iter().any(|marker| stderr.contains(marker))
+        && TRANSIENT.iter().any(|marker| stderr.contains(marker))
+}
+
+/// Clone a dependency into the given path as [`clone_dep_once`] does, attempting it again (after a delay that
+/// doubles each time) as long as it fails for what may be a network problem, up to `clone_attempts` times
+fn clone_dep(
+    dep: &TestDepedency,
+    dep_path: &std::path::Path,
+    branch_head_sha: &str,
+    options: &ResolveOptions,
+) -> Result<(), RunnerError> {
+    let mut attempt = 1;
+    loop {
+        let e = match clone_dep_once(dep, dep_path, branch_head_sha, options) {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        let transient = matches!(&e, RunnerError::Git(message) if is_transient_git_error(message));
+        if !transient || attempt >= options.clone_attempts || crate::interrupt::is_interrupted() {
+            return Err(e);
+        }
+
+        let delay = std::time::Duration::from_secs(1 << (attempt - 1).min(5));
+        let message = format!(
+            "Cloning {} failed (attempt {} of {}), retrying in {:?}: {}",
+            dep.uri,
+            attempt,
+            options.clone_attempts,
+            delay,
+            e.to_string().lines().last().unwrap_or_default().trim()
+        );
+        println!("{}", Colour::Yellow.paint(&message));
+        warn!("{}\n{}", message, e);
+        // A failed clone may leave the directory behind, which git refuses to clone into
+        if dep_path.exists() {
+            std::fs::remove_dir_all(dep_path).map_err(|e| {
+                RunnerError::io(format!("Failed to remove {}", dep_path.display()), e)
+            })?;
+        }
+        std::thread::sleep(delay);
+        attempt += 1;
+    }
+}
+
+/// Clone a dependency into the given path, and check out the commit it is pinned to (or the head of its branch)
+fn clone_dep_once(
+    dep: &TestDepedency,
+    dep_path: &std::path::Path,
+    branch_head_sha: &str,
+    options: &ResolveOptions,
+) -> Result<(), RunnerError> {
+    let git = options.git;
+    println!(
+        "{}",
+        Colour::Yellow.paint(format!(
+            "Cloning repo {} @ {}-{} into path {}...",
+            dep.uri,
+            dep.branch.clone().unwrap_or("HEAD".to_string()),
+            dep.sha.clone().unwrap_or("<none>".to_string()),
+            dep_path.display()
+        ))
+    );
+    info!(
+        "Cloning repository {} @ {}-{} into path {}",
+        dep.uri,
+        dep.branch.clone().unwrap_or("HEAD".to_string()),
+        dep.sha.clone().unwrap_or("<none>".to_string()),
+        dep_path.display()
+    );
+
+    let mut cmd = Command::new(git);
+
+    cmd.arg("clone");
+    if dep.submodules.unwrap_or(false) {
+        cmd.arg("--recurse-submodules");
+    }
+    if let Some(reference) = clone_reference(dep, options) {
+        debug!("Cloning {} with reference {}", dep.uri, reference.display());
+        cmd.arg("--reference").arg(reference);
+        if options.dissociate {
+            cmd.arg("--dissociate");
+        }
+    }
+
+    if let Some(depth) = dep.depth {
+        cmd.arg("--depth").arg(depth.to_string());
+    }
+    match dep.single_branch {
+        Some(true) => {
+            cmd.arg("--single-branch");
+        }
+        Some(false) => {
+            cmd.arg("--no-single-branch");
+        }
+        None => {}
+    }
+    // Otherwise the history of the default branch would be the one cloned
+    if dep.depth.is_some() || dep.single_branch == Some(true) {
+        if let Some(branch) = &dep.branch {
+            cmd.arg("--branch").arg(branch);
+        }
+    }
+
+    cmd.arg(&dep.uri).arg(dep_path);
+    let output = output_with_progress(&mut cmd, &format!("Cloning {}", dep.uri), options.progress)
+        .map_err(|e| RunnerError::Git(format!("Failed to execute git clone: {}", e)))?;
+
+    if !output.status.success() {
+        return Err(RunnerError::Git(format!(
+            "Failed to clone repository {}:\n{}",
+            dep.uri,
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+
+    // With a limited history, the commit to check out may be missing from the clone
+    let limited_history_hint = || {
+        match (dep.depth, dep.single_branch) {
+        (Some(depth), _) => format!(
+            "\nThe clone only has the last {} commit(s) of history; increase the depth of the dependency (or remove it) to clone further back",
+            depth
+        ),
+        (None, Some(true)) => "\nThe clone only has the history of a single branch; set singleBranch to false to clone every branch".to_string(),
+        _ => String::new(),
+    }
+    };
+
+    let sha = match &dep.git_ref {
+        Some(git_ref) => resolve_ref(dep, dep_path, git_ref, git).map_err(|e| match e {
+            RunnerError::Git(message) => {
+                RunnerError::Git(format!("{}{}", message, limited_history_hint()))
+            }
+            e => e,
+        })?,
+        None => dep
+            .sha
+            .clone()
+            .unwrap_or_else(|| branch_head_sha.to_string()),
+    };
+
+    let mut cmd = Command::new(git);
+    cmd.current_dir(dep_path);
+    cmd.arg("reset").arg("--hard");
+    cmd.arg(&sha);
+
+    let output = cmd
+        .output()
+        .map_err(|e| RunnerError::Git(format!("Failed to execute git reset: {}", e)))?;
+
+    if !output.status.success() {
+        error!(
+            "Failed to reset repository {} to revision {}:\n{}",
+            dep.uri,
+            &sha,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return Err(RunnerError::Git(format!(
+            "Failed to reset repository {} to revision {}{}",
+            dep.uri,
+            &sha,
+            limited_history_hint()
+        )));
+    }
+
+    // The reset may have moved the submodules to other commits (or added new ones)
+    if dep.submodules.unwrap_or(false) {
+        let mut cmd = Command::new(git);
+        cmd.current_dir(dep_path)
+            .arg("submodule")
+            .arg("update")
+            .arg("--init")
+            .arg("--recursive");
+        let output = output_with_progress(
+            &mut cmd,
+            &format!("Updating the submodules of {}", dep.uri),
+            options.progress,
+        )
+        .map_err(|e| RunnerError::Git(format!("Failed to execute git submodule update: {}", e)))?;
+
+        if !output.status.success() {
+            error!(
+                "Failed to update the submodules of repository {}:\n{}",
+                dep.uri,
+                String::from_utf8_lossy(&output.stderr)
+            );
+            return Err(RunnerError::Git(format!(
+                "Failed to update the submodules of repository {}:\n{}",
+                dep.uri,
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Link the dependency into the given path from the global cache, cloning it into the global cache first unless
+/// another project has already. Clones are keyed by uri and commit, so they are never modified once complete
+fn link_global_clone(
+    dep: &TestDepedency,
+    uri: &str,
+    dep_path: &std::path::Path,
+    branch_head_sha: &str,
+    global_cache: &std::path::Path,
+    options: &ResolveOptions,
+) -> Result<(), RunnerError> {
+    // A ref may be relative to the head of the branch, so it is keyed by both
+    let key = match (&dep.git_ref, &dep.sha) {
+        (Some(git_ref), _) => format!(
+            "{}-{}",
+            branch_head_sha,
+            git_ref.replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+        ),
+        (None, Some(sha)) => sha.clone(),
+        (None, None) => branch_head_sha.to_string(),
+    };
+    // Built by the post-install commands, which are keyed by a hash (FNV-1a) of them
+    let key = match dep
+        .post_install
+        .as_ref()
+        .filter(|commands| !commands.is_empty())
+    {
+        Some(commands) => {
+            let hash = commands
+                .join("\n")
+                .bytes()
+                .fold(0xcbf29ce484222325u64, |hash, byte| {
+                    (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+                });
+            format!("{}-post-install-{:016x}", key, hash)
+        }
+        None => key,
+    };
+    // A shallow clone lacks the history others may need, e.g. to resolve a ref
+    let key = match (dep.depth, dep.single_branch) {
+        (None, None) => key,
+        (depth, single_branch) => format!(
+            "{}-{}{}",
+            key,
+            depth.map_or("full".to_string(), |depth| format!("depth{}", depth)),
+            match single_branch {
+                Some(true) => "-single",
+                Some(false) => "-all",
+                None => "",
+            }
+        ),
+    };
+    let dir = global_cache.join("external-dep").join(
+        dep_relative_path(uri)
+            .ok_or_else(|| RunnerError::Config(format!("Invalid uri: {}", dep.uri)))?,
+    );
+    let shared_path = dir.join(&key);
+
+    if !shared_path.exists() {
+        std::fs::create_dir_all(&dir)?;
+        // Held while cloning, so that concurrent runners do not clone the same commit at once
+        let lock_path = dir.join(format!("{}.lock", key));
+        let lock = std::fs::File::create(&lock_path).map_err(|e| {
+            RunnerError::io(
+                format!("Failed to create lock file {}", lock_path.display()),
+                e,
+            )
+        })?;
+        lock.lock()
+            .map_err(|e| RunnerError::io(format!("Failed to lock {}", lock_path.display()), e))?;
+
+        // Another runner may have cloned it while this one waited for the lock
+        if !shared_path.exists() {
+            // Cloned next to its final path and renamed once complete, so that an interrupted clone is never
+            // mistaken for a complete one
+            let partial_path = dir.join(format!("{}.partial", key));
+            if partial_path.exists() {
+                std::fs::remove_dir_all(&partial_path)?;
+            }
+            clone_dep(dep, &partial_path, branch_head_sha, options)?;
+            run_post_install_commands(dep, &partial_path)?;
+            std::fs::rename(&partial_path, &shared_path)?;
+        }
+        let _ = std::fs::remove_file(&lock_path);
+    } else {
+        info!(
+            "Reusing the clone of {} in the global cache at {}",
+            dep.uri,
+            shared_path.display()
+        );
+    }
+
+    if let Some(parent) = dep_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // A relative link would be resolved against the directory of the link
+    let shared_path = std::path::absolute(&shared_path)?;
+    link_dir(&shared_path, dep_path).map_err(|e| {
+        RunnerError::io(
+            format!(
+                "Failed to link {} to {}",
+                dep_path.display(),
+                shared_path.display()
+            ),
+            e,
+        )
+    })
+}
+
+#[cfg(unix)]
+fn link_dir(target: &std::path::Path, link: &std::path::Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn link_dir(target: &std::path::Path, link: &std::path::Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_dir(target, link)
+}
+
+/// Resolve a revision expression to the commit hash it points to in the clone of a dependency
+fn resolve_ref(
+    dep: &TestDepedency,
+    dep_path: &std::path::Path,
+    git_ref: &str,
+    git: &std::path::Path,
+) -> Result<String, RunnerError> {
+    let output = Command::new(git)
+        .current_dir(dep_path)
+        .arg("rev-parse")
+        .arg("--verify")
+        .arg("--quiet")
+        .arg(format!("{}^{{commit}}", git_ref))
+        .output()
+        .map_err(|e| RunnerError::Git(format!("Failed to execute git rev-parse: {}", e)))?;
+
+    if !output.status.success() {
+        return Err(RunnerError::Git(format!(
+            "Ref {} does not resolve to a commit in repository {}",
+            git_ref, dep.uri
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// List the refs of a remote with `git ls-remote`, which also checks that it is a valid git repository
+fn list_remote_refs(uri: &str, git: &std::path::Path) -> Result<RemoteRefs, RunnerError> {
+    let output = Command::new(git)
+        .arg("ls-remote")
+        .arg("--symref")
+        .arg(uri)
+        .output()
+        .map_err(|e| RunnerError::Git(format!("Failed to execute git ls-remote: {}", e)))?;
+
+    if !output.status.success() {
+        return Err(RunnerError::Git(format!(
+            "{} is not a valid git repository",
+            uri
+        )));
+    }
+
+    Ok(RemoteRefs::parse(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// The directory of a dependency that is added to the runtimepath: its subdir if given, otherwise its root.
+/// Fails if the subdir is not a directory
+pub fn plugin_root(
+    dep: &TestDepedency,
+    dep_path: &std::path::Path,
+) -> Result<std::path::PathBuf, RunnerError> {
+    let Some(subdir) = &dep.subdir else {
+        return Ok(dep_path.to_path_buf());
+    };
+    let root = dep_path.join(subdir);
+    if !root.is_dir() {
+        return Err(RunnerError::Config(format!(
+            "Subdir {} of test dependency {} does not exist at {}",
+            subdir,
+            dep.uri,
+            root.display()
+        )));
+    }
+    Ok(root)
+}
+
+/// The immediate subdirectories of the given directory that look like plugins, i.e. that have a `lua` or a
+/// `plugin` directory, in alphabetical order
+pub fn discover_plugins(dir: &std::path::Path) -> Result<Vec<std::path::PathBuf>, RunnerError> {
+    let mut plugins = Vec::new();
+    for entry in std::fs::read_dir(dir)
+        .map_err(|e| RunnerError::io(format!("Failed to read directory {}", dir.display()), e))?
+    {
+        let path = entry?.path();
+        if path.join("lua").is_dir() || path.join("plugin").is_dir() {
+            plugins.push(path);
+        }
+    }
+    plugins.sort();
+    Ok(plugins)
+}
+
+/// Check that git is installed, returning its version
+pub fn git_version(git: &std::path::Path) -> Result<String, RunnerError> {
+    match Command::new(git).arg("--version").output() {
+        Ok(output) if output.status.success() => {
+            Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        }
+        _ if git == std::path::Path::new("git") => {
+            Err(RunnerError::Git("git is not installed".to_string()))
+        }
+        _ => Err(RunnerError::Git(format!(
+            "git at {} cannot be run (gitPath or --git)",
+            git.display()
+        ))),
+    }
+}
+
+/// Normalize the uri of an external dependency, such that e.g. `https://GitHub.com/foo/bar.git/` and
+/// `https://github.com/foo/bar` are considered the same dependency. This strips trailing slashes and
+/// the `.git` suffix, and lowercases the host
+pub fn normalize_uri(uri: &str) -> String {
+    let uri = uri.trim().trim_end_matches('/');
+    let uri = uri
+        .strip_suffix(".git")
+        .unwrap_or(uri)
+        .trim_end_matches('/');
+
+    let (scheme, rest) = match uri.split_once("://") {
+        Some((scheme, rest)) => (format!("{}://", scheme.to_lowercase()), rest),
+        None => (String::new(), uri),
+    };
+    // The host ends at the first `/`, or at the `:` of the scp-like syntax (e.g. `git@github.com:foo/bar`)
+    let host_end = if scheme.is_empty() {
+        rest.find([':', '/'])
+    } else {
+        rest.find('/')
+    }
+    .unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(host_end);
+    // Keep the user info as is (e.g. `git@`)
+    let authority = match authority.rsplit_once('@') {
+        Some((user, host)) => format!("{}@{}", user, host.to_lowercase()),
+        None => authority.to_lowercase(),
+    };
+
+    format!("{}{}{}", scheme, authority, path)
+}
+
+/// Whether a dependency goes by the given name, before it is resolved: its uri (as given or normalized), the last
+/// segment of its uri (e.g. `utils.nvim` for `https://github.com/samsze0/utils.nvim.git`), or that of its subdir,
+/// as in the `requires` directive of the tests
+pub fn dep_has_name(dep: &TestDepedency, name: &str) -> bool {
+    let uri = normalize_uri(&dep.uri);
+    let last_segment = |path: &str| {
+        path.trim_end_matches('/')
+            .rsplit(['/', ':'])
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    };
+    name == dep.uri
+        || name == uri
+        || name == last_segment(&uri)
+        || dep
+            .subdir
+            .as_deref()
+            .is_some_and(|subdir| name == last_segment(subdir))
+}
+
+/// The top-level Lua modules a runtimepath entry provides: the files and directories in its `lua` directory, e.g.
+/// `telescope` for `lua/telescope/init.lua`
+fn lua_namespaces(entry: &std::path::Path) -> Vec<String> {
+    let Ok(dir) = std::fs::read_dir(entry.join("lua")) else {
+        return Vec::new();
+    };
+    dir.filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let path = entry.path();
+            if path.is_dir() {
+                path.file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+            } else if path.extension().is_some_and(|ext| ext == "lua") {
+                path.file_stem()
+                    .map(|name| name.to_string_lossy().to_string())
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// The dependencies (given by their runtimepath entry and uri) none of whose Lua modules were required, judging by
+/// the top-level names of the required modules, so that a module required under another name of the same
+/// namespace still counts. Dependencies without a `lua` directory (e.g. Vimscript plugins) cannot be judged this
+/// way and are never reported
+pub fn unused_dependencies(
+    deps: &[(std::path::PathBuf, String)],
+    required_modules: &std::collections::HashSet<&str>,
+) -> Vec<String> {
+    let required_namespaces: std::collections::HashSet<&str> = required_modules
+        .iter()
+        .map(|module| module.split('.').next().unwrap_or(module))
+        .collect();
+    deps.iter()
+        .filter_map(|(entry, uri)| {
+            let namespaces = lua_namespaces(entry);
+            if namespaces.is_empty() {
+                debug!(
+                    "{} provides no Lua modules, not checking whether it is used",
+                    entry.display()
+                );
+                return None;
+            }
+            if namespaces
+                .iter()
+                .any(|namespace| required_namespaces.contains(namespace.as_str()))
+            {
+                return None;
+            }
+            Some(format!("{} ({})", uri, entry.display()))
+        })
+        .collect()
+}
+
+/// Derive the path (relative to the external dependency directory) that a dependency is cloned into,
+/// e.g. `https://github.com/foo/bar` becomes `github.com/foo/bar`. Including the host and the owner
+/// keeps apart dependencies that share the same name
+pub fn dep_relative_path(uri: &str) -> Option<std::path::PathBuf> {
+    let rest = match uri.split_once("://") {
+        Some((_, rest)) => {
+            let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+            // Strip the user info (e.g. `git@`), and the port (e.g. `:2222`), which does not tell repositories apart
+            let host = authority
+                .rsplit_once('@')
+                .map_or(authority, |(_, host)| host);
+            let host = match host.rsplit_once(':') {
+                Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+                _ => host,
+            };
+            format!("{}{}", host, path)
+        }
+        None => {
+            let rest = match uri.split_once('@') {
+                Some((user, rest)) if !user.contains('/') => rest,
+                _ => uri,
+            };
+            // The scp-like syntax (e.g. `github.com:foo/bar`) separates the host with a colon, which comes before
+            // any slash
+            match rest.find([':', '/']) {
+                Some(i) if rest[i..].starts_with(':') => {
+                    format!("{}/{}", &rest[..i], &rest[i + 1..])
+                }
+                _ => rest.to_string(),
+            }
+        }
+    };
+
+    let path: std::path::PathBuf = rest
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != "." && *part != "..")
+        .collect();
+    path.file_name()?;
+    Some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn resolves_the_default_branch_with_head() {
+        let refs = RemoteRefs::parse(
+            "ref: refs/heads/main\tHEAD\n\
+             1111111111111111111111111111111111111111\tHEAD\n\
+             1111111111111111111111111111111111111111\trefs/heads/main\n\
+             2222222222222222222222222222222222222222\trefs/heads/dev\n",
+        );
+        assert_eq!(
+            refs.symrefs.get("HEAD").map(String::as_str),
+            Some("refs/heads/main")
+        );
+        assert_eq!(
+            refs.resolve(None).map(String::as_str),
+            Some("1111111111111111111111111111111111111111")
+        );
+        assert_eq!(
+            refs.resolve(Some("dev")).map(String::as_str),
+            Some("2222222222222222222222222222222222222222")
+        );
+        assert_eq!(refs.resolve(Some("missing")), None);
+    }
+
+    #[test]
+    fn resolves_the_default_branch_through_the_symref() {
+        // The symref points to a branch other than main, without a bare HEAD line
+        let refs = RemoteRefs::parse(
+            "ref: refs/heads/trunk\tHEAD\n\
+             1111111111111111111111111111111111111111\trefs/heads/main\n\
+             3333333333333333333333333333333333333333\trefs/heads/trunk\n",
+        );
+        assert_eq!(
+            refs.resolve(None).map(String::as_str),
+            Some("3333333333333333333333333333333333333333")
+        );
+    }
+
+    #[test]
+    fn resolves_the_default_branch_without_head() {
+        let refs = RemoteRefs::parse(
+            "1111111111111111111111111111111111111111\trefs/heads/main\n\
+             2222222222222222222222222222222222222222\trefs/heads/dev\n",
+        );
+        assert_eq!(
+            refs.resolve(None).map(String::as_str),
+            Some("1111111111111111111111111111111111111111")
+        );
+
+        let refs = RemoteRefs::parse(
+            "4444444444444444444444444444444444444444\trefs/heads/master\n\
+             2222222222222222222222222222222222222222\trefs/heads/dev\n",
+        );
+        assert_eq!(
+            refs.resolve(None).map(String::as_str),
+            Some("4444444444444444444444444444444444444444")
+        );
+
+        let refs = RemoteRefs::parse("2222222222222222222222222222222222222222\trefs/heads/dev\n");
+        assert_eq!(refs.resolve(None), None);
+    }
+
+    #[test]
+    fn normalizes_uris() {
+        let cases = [
+            ("https://github.com/foo/bar", "https://github.com/foo/bar"),
+            (
+                "https://github.com/foo/bar.git",
+                "https://github.com/foo/bar",
+            ),
+            ("https://github.com/foo/bar/", "https://github.com/foo/bar"),
+            (
+                "https://github.com/foo/bar.git/",
+                "https://github.com/foo/bar",
+            ),
+            (
+                "https://github.com/foo/bar/.git",
+                "https://github.com/foo/bar",
+            ),
+            (
+                "  https://github.com/foo/bar  ",
+                "https://github.com/foo/bar",
+            ),
+            ("HTTPS://GitHub.COM/foo/bar", "https://github.com/foo/bar"),
+            // Only the scheme and the host are case-insensitive
+            ("https://github.com/Foo/Bar", "https://github.com/Foo/Bar"),
+            (
+                "ssh://Git@GitHub.com/foo/bar.git",
+                "ssh://Git@github.com/foo/bar",
+            ),
+            ("git@GitHub.com:Foo/bar.git", "git@github.com:Foo/bar"),
+            ("GitHub.com:foo/bar/", "github.com:foo/bar"),
+            ("GitHub.com/foo/bar", "github.com/foo/bar"),
+        ];
+        for (uri, expected) in cases {
+            assert_eq!(normalize_uri(uri), expected, "{}", uri);
+        }
+        assert_ne!(
+            normalize_uri("https://github.com/foo/bar"),
+            normalize_uri("git@github.com:foo/bar")
+        );
+    }
+
+    #[test]
+    fn relative_paths_of_deps() {
+        let cases = [
+            ("https://github.com/foo/bar", "github.com/foo/bar"),
+            ("https://github.com/foo/bar.git", "github.com/foo/bar.git"),
+            ("https://user@gitlab.com/foo/bar", "gitlab.com/foo/bar"),
+            ("https://host:8080/a/b", "host/a/b"),
+            ("ssh://git@host:2222/x/y", "host/x/y"),
+            ("git@github.com:foo/bar", "github.com/foo/bar"),
+            ("github.com:foo/bar", "github.com/foo/bar"),
+            ("../local/../bar", "local/bar"),
+        ];
+        for (uri, expected) in cases {
+            assert_eq!(
+                dep_relative_path(uri),
+                Some(PathBuf::from(expected)),
+                "{}",
+                uri
+            );
+        }
+    }
+
+    #[test]
+    fn deps_of_the_same_name_are_apart() {
+        let foo = dep_relative_path("https://github.com/foo/utils.nvim").unwrap();
+        let bar = dep_relative_path("https://github.com/bar/utils.nvim").unwrap();
+        let gitlab = dep_relative_path("git@gitlab.com:foo/utils.nvim").unwrap();
+        assert_eq!(foo.file_name(), bar.file_name());
+        assert_ne!(foo, bar);
+        assert_ne!(foo, gitlab);
+        assert_ne!(bar, gitlab);
+    }
+}
@@ -0,0 +1,347 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufRead;
+use std::process::Command;
+
+use crate::config::TestOverride;
+use crate::error::RunnerError;
+
+/// A test file to be run
+#[derive(Debug, Clone)]
+pub struct TestFile {
+    pub path: std::path::PathBuf,
+    /// The working directory to run the test in. Defaults to the current directory
+    pub cwd: Option<std::path::PathBuf>,
+    pub directives: TestDirectives,
+    /// The environment variables set for the test by the `overrides` of the config
+    pub env: BTreeMap<String, String>,
+}
+
+impl TestFile {
+    /// Apply the overrides of the config whose glob matches the path of the test. Later overrides win over
+    /// earlier ones, and the directives of the test file over both. The environment variables of the overrides
+    /// are merged
+    pub fn apply_overrides(&mut self, overrides: &[TestOverride]) {
+        let mut timeout = None;
+        let mut serial = None;
+        let mut group = None;
+        let mut serial_group = None;
+        for test_override in overrides.iter().filter(|test_override| {
+            glob::Pattern::new(&test_override.pattern)
+                .is_ok_and(|pattern| pattern.matches_path(&self.path))
+        }) {
+            // Validated with the config
+            if let Some(value) = &test_override.timeout {
+                timeout = parse_duration(value).ok().or(timeout);
+            }
+            serial = test_override.serial.or(serial);
+            group = test_override.group.clone().or(group);
+            serial_group = test_override.serial_group.clone().or(serial_group);
+            for (name, value) in test_override.env.iter().flatten() {
+                self.env.insert(name.clone(), value.clone());
+            }
+        }
+
+        let directives = &mut self.directives;
+        directives.timeout = directives.timeout.or(timeout);
+        directives.serial = directives.serial || serial.unwrap_or(false);
+        directives.group = directives.group.take().or(group);
+        directives.serial_group = directives.serial_group.take().or(serial_group);
+    }
+}
+
+/// Directives given in the header comments of a test file, one per line in the form of `-- <name>: <value>`.
+/// The header ends at the first line that is neither a comment nor blank
+///
+/// ```lua
+/// -- requires: utils.nvim, nui.nvim
+/// local utils = require("utils")
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TestDirectives {
+    /// The names of the dependencies the test requires. The test is skipped if any of them is not resolved
+    pub requires: Vec<String>,
+    /// The tags of the test, for selecting tests with --tags and --exclude-tags
+    pub tags: Vec<String>,
+    /// Why the test is skipped, e.g. because it is known to be broken. Skipped tests are run with --run-skipped
+    pub skip: Option<String>,
+    /// Why the test is expected to fail, e.g. because it documents a known bug. A failure of the test does not
+    /// fail the run, but a pass does
+    pub xfail: Option<String>,
+    /// A regular expression the stdout of the test has to match, for tests that print their result rather than
+    /// asserting it. The test passes if the pattern matches and nvim did not fail
+    pub expect_output: Option<String>,
+    /// The concurrency group of the test, of which at most the number given by the `concurrencyGroups` config
+    /// field run at once
+    pub group: Option<String>,
+    /// Whether the test runs on its own once all other tests have finished, e.g. as it uses a resource that
+    /// cannot be shared
+    pub serial: bool,
+    /// Whether the messages of nvim (`:messages`) are not checked for errors, for tests that report errors on
+    /// purpose, e.g. to test the error handling of the plugin
+    pub allow_messages: bool,
+    /// The serial group of the test, of which only one test runs at a time, while the tests of other groups run
+    /// alongside
+    pub serial_group: Option<String>,
+    /// The working directory of the test, relative to the current directory, instead of that of the `testCwd`
+    /// config field
+    pub cwd: Option<String>,
+    /// How long the test may run before nvim is killed and the test fails
+    pub timeout: Option<std::time::Duration>,
+    /// Why the directives that could not be parsed (e.g. a timeout that is not a duration) were ignored
+    pub errors: Vec<String>,
+}
+
+impl TestDirectives {
+    pub fn parse<'a>(lines: impl Iterator<Item = &'a str>) -> TestDirectives {
+        let mut directives = TestDirectives::default();
+        let list = |value: &str| -> Vec<String> {
+            value
+                .split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect()
+        };
+
+        for line in lines {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let Some(comment) = line.strip_prefix("--") else {
+                break;
+            };
+            // The directives without a value
+            match comment.trim() {
+                "serial" => {
+                    directives.serial = true;
+                    continue;
+                }
+                "allow-messages" => {
+                    directives.allow_messages = true;
+                    continue;
+                }
+                _ => {}
+            }
+            let Some((name, value)) = comment.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            // Comments that are not directives are ignored
+            match name.trim() {
+                "requires" => directives.requires.extend(list(value)),
+                "tags" => directives
+                    .tags
+                    .extend(list(value).iter().map(|tag| normalize_tag(tag))),
+                "skip" => directives.skip = Some(value.to_string()),
+                "xfail" => directives.xfail = Some(value.to_string()),
+                "expect-output" => directives.expect_output = Some(value.to_string()),
+                "group" if !value.is_empty() => directives.group = Some(value.to_string()),
+                "serial-group" if !value.is_empty() => {
+                    directives.serial_group = Some(value.to_string())
+                }
+                "cwd" if !value.is_empty() => directives.cwd = Some(value.to_string()),
+                "timeout" => match parse_duration(value) {
+                    Ok(timeout) if !timeout.is_zero() => directives.timeout = Some(timeout),
+                    Ok(_) => directives
+                        .errors
+                        .push("the timeout directive has to be longer than 0".to_string()),
+                    Err(e) => directives
+                        .errors
+                        .push(format!("invalid timeout directive: {}", e)),
+                },
+                _ => {}
+            }
+        }
+
+        directives
+    }
+
+    /// Read the directives from the header of a test file. Fails if the file cannot be read
+    pub fn read(path: &std::path::Path) -> std::io::Result<TestDirectives> {
+        let file = File::open(path)?;
+        if !file.metadata()?.is_file() {
+            return Err(std::io::Error::other("not a regular file"));
+        }
+        let mut header = Vec::new();
+        for line in std::io::BufReader::new(file).lines() {
+            let line = line?;
+            let trimmed = line.trim();
+            if !trimmed.is_empty() && !trimmed.starts_with("--") {
+                break;
+            }
+            header.push(line);
+        }
+        Ok(TestDirectives::parse(header.iter().map(|l| l.as_str())))
+    }
+}
+
+/// Parse a duration like `500ms`, `90s`, `10m`, `2h` or `1d`. A number without a unit is in seconds
+pub fn parse_duration(duration: &str) -> Result<std::time::Duration, String> {
+    let duration = duration.trim();
+    let unit_start = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (amount, unit) = duration.split_at(unit_start);
+    let invalid = || format!("Invalid duration {:?}, expected e.g. 10m or 2h", duration);
+    let amount: u64 = amount.parse().map_err(|_| invalid())?;
+    let millis = match unit.trim() {
+        "ms" => 1,
+        "" | "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => 24 * 60 * 60 * 1000,
+        unit => {
+            return Err(format!(
+                "Invalid duration unit {:?}, expected one of ms, s, m, h and d",
+                unit
+            ))
+        }
+    };
+    let millis = amount.checked_mul(millis).ok_or_else(invalid)?;
+    Ok(std::time::Duration::from_millis(millis))
+}
+
+/// One of the parts the test files are split into with --shard, e.g. `2/4` for the second of four
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    /// From 1 up to and including `total`
+    pub index: usize,
+    pub total: usize,
+}
+
+impl Shard {
+    /// Whether the test file at the given position among all test files (sorted by path) belongs to this shard.
+    /// The files are dealt out in turn, so that the sizes of the shards differ by at most one
+    pub fn contains(&self, position: usize) -> bool {
+        position % self.total == self.index - 1
+    }
+}
+
+/// Parse a shard like `2/4`
+pub fn parse_shard(shard: &str) -> Result<Shard, String> {
+    let invalid = || format!("Invalid shard {:?}, expected e.g. 1/4", shard);
+    let (index, total) = shard.trim().split_once('/').ok_or_else(invalid)?;
+    let index: usize = index.trim().parse().map_err(|_| invalid())?;
+    let total: usize = total.trim().parse().map_err(|_| invalid())?;
+    if total == 0 || index == 0 || index > total {
+        return Err(format!(
+            "Invalid shard {:?}, the index has to be between 1 and the number of shards",
+            shard
+        ));
+    }
+    Ok(Shard { index, total })
+}
+
+/// Tags may be written with a leading `@` (e.g. `@slow`), which is not part of the tag
+pub fn normalize_tag(tag: &str) -> String {
+    tag.trim().trim_start_matches('@').to_string()
+}
+
+/// Parse a comma-separated list of tags as given to --tags and --exclude-tags
+pub fn parse_tags(tags: &str) -> Vec<String> {
+    tags.split(',')
+        .map(normalize_tag)
+        .filter(|tag| !tag.is_empty())
+        .collect()
+}
+
+/// Run the `discoveryCommand` of the config with `sh -c`, returning the test files it printed on its stdout, one
+/// per line. Its stderr is passed through. Fails if the command fails
+pub fn run_discovery_command(command: &str) -> Result<Vec<std::path::PathBuf>, RunnerError> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .map_err(|e| RunnerError::Hook(format!("Failed to run the discovery command: {}", e)))?;
+    if !output.status.success() {
+        return Err(RunnerError::Hook(format!(
+            "The discovery command {:?} failed with {}",
+            command, output.status
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(std::path::PathBuf::from)
+        .collect())
+}
+
+/// List the files (relative to the current directory) that changed compared to the given git ref,
+/// including untracked files. Returns `None` if the current directory is not in a git repository
+pub fn git_changed_files(
+    git: &std::path::Path,
+    base: &str,
+) -> Result<Option<Vec<std::path::PathBuf>>, RunnerError> {
+    let in_repo = Command::new(git)
+        .arg("rev-parse")
+        .arg("--is-inside-work-tree")
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false);
+    if !in_repo {
+        return Ok(None);
+    }
+
+    let mut files = Vec::new();
+    for git_args in [
+        vec!["diff", "--name-only", "--relative", base],
+        vec!["ls-files", "--others", "--exclude-standard"],
+    ] {
+        let output = Command::new(git).args(&git_args).output().map_err(|e| {
+            RunnerError::Git(format!("Failed to execute git {}: {}", git_args[0], e))
+        })?;
+        if !output.status.success() {
+            return Err(RunnerError::Git(format!(
+                "git {} failed:\n{}",
+                git_args.join(" "),
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        files.extend(
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(std::path::PathBuf::from),
+        );
+    }
+    Ok(Some(files))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_duration;
+    use std::time::Duration;
+
+    #[test]
+    fn parses_durations() {
+        let cases = [
+            ("250ms", Duration::from_millis(250)),
+            ("30", Duration::from_secs(30)),
+            ("30s", Duration::from_secs(30)),
+            (" 10m ", Duration::from_secs(600)),
+            ("2h", Duration::from_secs(7200)),
+            ("1d", Duration::from_secs(86400)),
+        ];
+        for (duration, expected) in cases {
+            assert_eq!(parse_duration(duration), Ok(expected), "{:?}", duration);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        for duration in [
+            "",
+            "m",
+            "1.5s",
+            "-1s",
+            "10y",
+            "99999999999999999999",
+            "99999999999999999d",
+        ] {
+            assert!(parse_duration(duration).is_err(), "{:?}", duration);
+        }
+    }
+}
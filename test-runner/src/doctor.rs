@@ -0,0 +1,115 @@
+use ansi_term::Colour;
+use log::{info, warn};
+use std::fs::File;
+
+use crate::config::{apply_env_overrides, find_config, load_config};
+use crate::deps::git_version;
+use crate::error::RunnerError;
+use crate::nvim::{parse_version, probe_nvim_version};
+use crate::test_utils::{http_client, test_utils_uri};
+use crate::{resolve_cache_dir, resolve_git_path, RunOptions};
+
+/// Run the checks of the `doctor` subcommand, printing a checklist of the results. The config is looked
+/// for unless `config_path` is given
+pub fn run_doctor(config_path: Option<&str>, options: &RunOptions) -> Result<(), RunnerError> {
+    let mut num_failed_checks = 0;
+    let mut report = |name: &str, result: Result<String, String>, hint: &str| match result {
+        Ok(detail) => {
+            println!(
+                "{}",
+                Colour::Green.paint(format!("✓ {} ({})", name, detail))
+            );
+            info!("doctor: {} ok ({})", name, detail);
+        }
+        Err(e) => {
+            num_failed_checks += 1;
+            println!("{}", Colour::Red.paint(format!("✗ {}: {}", name, e)));
+            println!("  {}", hint);
+            warn!("doctor: {} failed: {}", name, e);
+        }
+    };
+
+    let config_path = match config_path {
+        Some(path) => Some(path),
+        None => find_config(),
+    };
+    let config = config_path.map(load_config).transpose();
+    report(
+        "config",
+        match (&config, config_path) {
+            (Ok(_), Some(path)) => Ok(format!("{} parsed", path)),
+            (Ok(_), None) => Ok("not found, using the default config".to_string()),
+            (Err(e), _) => Err(e.to_string()),
+        },
+        "Fix the config file according to nvim-test-runner.schema.json",
+    );
+    let mut config = config.ok().flatten().unwrap_or_default();
+    apply_env_overrides(&mut config);
+
+    let nvim_version = probe_nvim_version(config.settings.runner_wrapper.as_deref())
+        .map_err(|e| e.to_string())
+        .and_then(|actual| {
+            let version = format!("v{}.{}.{}", actual.0, actual.1, actual.2);
+            match config
+                .settings
+                .min_nvim_version
+                .as_ref()
+                .map(|v| (v, parse_version(v)))
+            {
+                Some((v, None)) => Err(format!("invalid minNvimVersion {}", v)),
+                Some((v, Some(required))) if actual < required => {
+                    Err(format!("{} is older than minNvimVersion {}", version, v))
+                }
+                _ => Ok(version),
+            }
+        });
+    report(
+        "nvim",
+        nvim_version,
+        "Install Neovim (https://github.com/neovim/neovim/releases) and make sure it is on PATH",
+    );
+
+    report(
+        "git",
+        git_version(&resolve_git_path(options, &config)).map_err(|e| e.to_string()),
+        "Install git and make sure it is on PATH, or point gitPath (or --git) at it",
+    );
+
+    let cache_dir = resolve_cache_dir(options, &config);
+    let writable = std::fs::create_dir_all(&cache_dir)
+        .and_then(|_| {
+            let probe = cache_dir.join(".doctor");
+            File::create(&probe)?;
+            std::fs::remove_file(&probe)
+        })
+        .map(|_| format!("{} is writable", cache_dir.display()))
+        .map_err(|e| format!("{} is not writable: {}", cache_dir.display(), e));
+    report(
+        "cache dir",
+        writable,
+        "Check the permissions of the cache dir, or relocate it with --cache-dir",
+    );
+
+    let uri = test_utils_uri(env!("CARGO_PKG_VERSION"));
+    let reachable = http_client(options.proxy.as_deref())
+        .map_err(|e| e.to_string())
+        .and_then(|client| client.head(&uri).send().map_err(|e| e.to_string()))
+        .and_then(|response| match response.status().is_success() {
+            true => Ok(format!("{} is reachable", uri)),
+            false => Err(format!("{} responded with {}", uri, response.status())),
+        });
+    report(
+        "network",
+        reachable,
+        "Check your network connection, or use --offline if test-utils is already cached",
+    );
+
+    if num_failed_checks > 0 {
+        return Err(RunnerError::TestFailures(format!(
+            "{} check(s) failed",
+            num_failed_checks
+        )));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,162 @@
+//! The errors of a run, by what went wrong, so that callers (and the exit code) can tell e.g. an invalid config
+//! from a network failure
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum RunnerError {
+    /// The config (or the command line options) is invalid
+    #[error("{0}")]
+    Config(String),
+    /// Something could not be downloaded, e.g. test-utils
+    #[error("{0}")]
+    Network(String),
+    /// A git command failed, e.g. while resolving a test dependency
+    #[error("{0}")]
+    Git(String),
+    /// nvim could not be run, or is not suitable for the tests
+    #[error("{0}")]
+    Nvim(String),
+    /// A beforeAll or afterAll hook failed
+    #[error("{0}")]
+    Hook(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// The tests (or the checks of the doctor) did not pass before the run completed, e.g. a test failed in a
+    /// warmup round or fewer tests than expected matched. Failures of a completed run are reported in its
+    /// summary instead
+    #[error("{0}")]
+    TestFailures(String),
+}
+
+impl RunnerError {
+    /// An io error, prefixed with what failed, e.g. `Failed to open config file foo.json: ...`
+    pub fn io(context: impl std::fmt::Display, error: std::io::Error) -> RunnerError {
+        RunnerError::Io(std::io::Error::new(
+            error.kind(),
+            format!("{}: {}", context, error),
+        ))
+    }
+
+    /// The exit code of the runner when it fails with this error: 1 as for failed tests, 2 for an invalid
+    /// config, and 3 for a problem with the environment (nvim, git, the network, the file system or a hook)
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RunnerError::TestFailures(_) => 1,
+            RunnerError::Config(_) => 2,
+            RunnerError::Network(_)
+            | RunnerError::Git(_)
+            | RunnerError::Nvim(_)
+            | RunnerError::Hook(_)
+            | RunnerError::Io(_) => 3,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RunnerError;
+    use crate::config::{check_config, load_config, TestConfig};
+
+    fn temp_file(name: &str, contents: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "nvim-test-runner-test-{}-{}",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn exit_codes() {
+        let io = std::io::Error::from(std::io::ErrorKind::NotFound);
+        assert_eq!(RunnerError::TestFailures(String::new()).exit_code(), 1);
+        assert_eq!(RunnerError::Config(String::new()).exit_code(), 2);
+        assert_eq!(RunnerError::Network(String::new()).exit_code(), 3);
+        assert_eq!(RunnerError::Git(String::new()).exit_code(), 3);
+        assert_eq!(RunnerError::Nvim(String::new()).exit_code(), 3);
+        assert_eq!(RunnerError::Hook(String::new()).exit_code(), 3);
+        assert_eq!(RunnerError::Io(io).exit_code(), 3);
+    }
+
+    #[test]
+    fn io_keeps_the_kind() {
+        let error = RunnerError::io(
+            "Failed to open foo",
+            std::io::Error::from(std::io::ErrorKind::PermissionDenied),
+        );
+        let RunnerError::Io(e) = &error else {
+            panic!("Expected an io error, got {:?}", error);
+        };
+        assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(error.to_string().starts_with("Failed to open foo: "));
+    }
+
+    #[test]
+    fn missing_config_file_is_io() {
+        let Err(error) = load_config("/nonexistent/nvim-test-runner.json") else {
+            panic!("Expected an error");
+        };
+        assert!(matches!(error, RunnerError::Io(_)), "{:?}", error);
+    }
+
+    #[test]
+    fn malformed_config_file_is_config() {
+        for (name, contents) in [
+            ("malformed.json", "{ \"testPaths\": "),
+            ("malformed.toml", "testPaths = ["),
+            ("malformed.yaml", "testPaths: [a"),
+            ("mistyped.json", "{ \"testPaths\": 1 }"),
+        ] {
+            let path = temp_file(name, contents);
+            let result = load_config(path.to_str().unwrap());
+            let _ = std::fs::remove_file(&path);
+            let Err(error) = result else {
+                panic!("Expected {} to fail to load", name);
+            };
+            assert!(
+                matches!(error, RunnerError::Config(_)),
+                "{}: {:?}",
+                name,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_config_is_config() {
+        let mut config = TestConfig::new();
+        config.settings.clone_attempts = Some(0);
+        let error = check_config(&config).unwrap_err();
+        assert!(matches!(error, RunnerError::Config(_)), "{:?}", error);
+        assert!(check_config(&TestConfig::new()).is_ok());
+    }
+
+    #[test]
+    fn error_patterns_are_standard_regexes() {
+        let mut config = TestConfig::new();
+        config.settings.error_patterns =
+            Some(vec![r"^E\d+:\s".to_string(), r"\bPANIC\b".to_string()]);
+        assert!(check_config(&config).is_ok());
+        config.settings.error_patterns = Some(vec!["(unclosed".to_string()]);
+        let error = check_config(&config).unwrap_err();
+        assert!(matches!(error, RunnerError::Config(_)), "{:?}", error);
+    }
+
+    #[test]
+    fn failed_hook_is_hook() {
+        let error = crate::run_hook("beforeAll", "exit 3").unwrap_err();
+        assert!(matches!(error, RunnerError::Hook(_)), "{:?}", error);
+        assert!(crate::run_hook("beforeAll", "true").is_ok());
+    }
+
+    #[test]
+    fn missing_secret_is_config() {
+        let error = crate::secrets::read_secret("TOKEN", "$NVIM_TEST_RUNNER_TEST_UNSET_SECRET")
+            .unwrap_err();
+        assert!(matches!(error, RunnerError::Config(_)), "{:?}", error);
+        let error = crate::secrets::read_secret("TOKEN", "@/nonexistent/secret").unwrap_err();
+        assert!(matches!(error, RunnerError::Config(_)), "{:?}", error);
+    }
+}
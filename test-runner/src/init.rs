@@ -0,0 +1,115 @@
+//! The `init` subcommand, which sets up a plugin to be tested with the runner: a starter config, a first test
+//! showing the API of test-utils, and the cache directory ignored by git
+
+use ansi_term::Colour;
+use indoc::indoc;
+use log::info;
+use std::path::Path;
+
+use crate::config::CONFIG_PATHS;
+use crate::error::RunnerError;
+
+const CONFIG_PATH: &str = "nvim-test-runner.json";
+
+/// JSON has no comments, so the examples of the other fields are left to the schema, which editors complete
+/// and describe the fields from
+const CONFIG: &str = indoc! {r#"
+    {
+      "$schema": "https://raw.githubusercontent.com/samsze0/test.nvim/main/nvim-test-runner.schema.json",
+      "testPaths": ["tests/**/*_spec.lua"],
+      "testDependencies": []
+    }
+"#};
+
+const TEST_PATH: &str = "tests/example_spec.lua";
+
+const TEST: &str = indoc! {r#"
+    -- Run with `nvim-test-runner`. Each test file is run in a nvim of its own, with the plugin and the
+    -- testDependencies of nvim-test-runner.json on the runtimepath, and test-utils loaded as the global `T`.
+    -- Directives in this header (e.g. `-- tags: slow` or `-- timeout: 30s`) configure how the file is run
+
+    -- local plugin = require("my-plugin")
+
+    T.describe("example", function()
+      T.it("compares values", function()
+        T.assert(vim.fn.has("nvim") == 1)
+        T.assert_eq(1 + 1, 2)
+        T.assert_deep_eq({ a = { 1, 2 } }, { a = { 1, 2 } })
+        T.assert_contains({ "a", "b" }, "b")
+      end)
+
+      T.it("edits a buffer", function()
+        vim.api.nvim_buf_set_lines(0, 0, -1, false, { "hello" })
+        T.assert_deep_eq(vim.api.nvim_buf_get_lines(0, 0, -1, false), { "hello" })
+      end)
+
+      T.it("raises an error", function()
+        T.assert_error(function()
+          error("boom")
+        end)
+      end)
+    end)
+"#};
+
+/// The cache directory of the runner, which is not to be committed
+const GITIGNORE_ENTRY: &str = ".test/";
+
+/// Write the starter config and test into the current directory, and add the cache directory to `.gitignore`.
+/// Fails without writing anything if the config or the test already exist (or another config would be ignored
+/// in favour of the new one), unless `force` is given
+pub fn run_init(force: bool) -> Result<(), RunnerError> {
+    if !force {
+        let existing: Vec<&str> = CONFIG_PATHS
+            .iter()
+            .copied()
+            .chain([TEST_PATH])
+            .filter(|path| Path::new(path).exists())
+            .collect();
+        if !existing.is_empty() {
+            return Err(RunnerError::Config(format!(
+                "{} already exist(s), pass --force to overwrite",
+                existing.join(", ")
+            )));
+        }
+    }
+
+    write_file(CONFIG_PATH, CONFIG)?;
+    std::fs::create_dir_all("tests")
+        .map_err(|e| RunnerError::io("Failed to create the tests directory", e))?;
+    write_file(TEST_PATH, TEST)?;
+
+    let gitignore = match std::fs::read_to_string(".gitignore") {
+        Ok(gitignore) => gitignore,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(RunnerError::io("Failed to read .gitignore", e)),
+    };
+    let ignored = gitignore.lines().any(|line| {
+        matches!(
+            line.trim().trim_start_matches('/').trim_end_matches('/'),
+            ".test"
+        )
+    });
+    if !ignored {
+        let mut updated = gitignore;
+        if !updated.is_empty() && !updated.ends_with('\n') {
+            updated.push('\n');
+        }
+        updated.push_str(GITIGNORE_ENTRY);
+        updated.push('\n');
+        write_file(".gitignore", &updated)?;
+    }
+
+    println!(
+        "{}",
+        Colour::Blue.paint("Run nvim-test-runner to run the example test")
+    );
+    Ok(())
+}
+
+fn write_file(path: &str, contents: &str) -> Result<(), RunnerError> {
+    std::fs::write(path, contents)
+        .map_err(|e| RunnerError::io(format!("Failed to write {}", path), e))?;
+    println!("{}", Colour::Blue.paint(format!("Wrote {}", path)));
+    info!("Wrote {}", path);
+    Ok(())
+}
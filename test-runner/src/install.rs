@@ -0,0 +1,93 @@
+//! Running the tests against the plugin as installed, with --installed: the files of the plugin are copied into a
+//! directory of their own (as a plugin manager or a package would install them), which is added to the
+//! runtimepath instead of the current directory. Files the install leaves out, e.g. a directory missing from a
+//! package, then fail the tests rather than being picked up from the source tree
+
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::RunnerError;
+
+/// The directories of a plugin that are installed if the `installFiles` config field is not given, those nvim
+/// looks for on the runtimepath
+pub const DEFAULT_INSTALL_DIRS: &[&str] = &[
+    "after", "autoload", "colors", "compiler", "doc", "ftdetect", "ftplugin", "indent", "keymap",
+    "lsp", "lua", "parser", "plugin", "queries", "rplugin", "spell", "syntax",
+];
+
+/// Whether a path of `installFiles` names something within the directory it is relative to (but not the directory
+/// itself, as installing the whole source directory would copy the install directory into itself)
+pub fn is_relative_within(path: &str) -> bool {
+    let path = Path::new(path);
+    path.components()
+        .any(|component| matches!(component, Component::Normal(_)))
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// Copy a file, or a directory with everything in it. Symlinks are followed. Returns the number of files copied
+fn copy_recursively(source: &Path, destination: &Path) -> std::io::Result<usize> {
+    if !source.is_dir() {
+        if let Some(parent) = destination.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::copy(source, destination)?;
+        return Ok(1);
+    }
+    std::fs::create_dir_all(destination)?;
+    let mut num_files = 0;
+    for entry in std::fs::read_dir(source)? {
+        let entry = entry?;
+        num_files += copy_recursively(&entry.path(), &destination.join(entry.file_name()))?;
+    }
+    Ok(num_files)
+}
+
+/// Install the plugin in the source directory into the install directory, replacing what was installed there
+/// before. `files` maps the files and directories to install (relative to the source directory) to their path in
+/// the install directory; without it the [`DEFAULT_INSTALL_DIRS`] that exist are installed as they are. Returns
+/// the number of files installed
+pub fn install_plugin(
+    source: &Path,
+    install_dir: &Path,
+    files: Option<&BTreeMap<String, String>>,
+) -> Result<usize, RunnerError> {
+    let files: Vec<(PathBuf, PathBuf)> = match files {
+        Some(files) => files
+            .iter()
+            .map(|(from, to)| (source.join(from), install_dir.join(to)))
+            .collect(),
+        None => DEFAULT_INSTALL_DIRS
+            .iter()
+            .map(|dir| (source.join(dir), install_dir.join(dir)))
+            .filter(|(from, _)| from.exists())
+            .collect(),
+    };
+    // Missing files are what the install is meant to catch, so they fail it rather than being skipped
+    if let Some((missing, _)) = files.iter().find(|(from, _)| !from.exists()) {
+        return Err(RunnerError::Config(format!(
+            "{} is to be installed (installFiles), but does not exist",
+            missing.display()
+        )));
+    }
+
+    if install_dir.exists() {
+        std::fs::remove_dir_all(install_dir).map_err(|e| {
+            RunnerError::io(format!("Failed to remove {}", install_dir.display()), e)
+        })?;
+    }
+    std::fs::create_dir_all(install_dir)
+        .map_err(|e| RunnerError::io(format!("Failed to create {}", install_dir.display()), e))?;
+
+    let mut num_files = 0;
+    for (from, to) in &files {
+        num_files += copy_recursively(from, to).map_err(|e| {
+            RunnerError::io(
+                format!("Failed to install {} into {}", from.display(), to.display()),
+                e,
+            )
+        })?;
+    }
+    Ok(num_files)
+}
@@ -0,0 +1,465 @@
+//! The child processes of a run: the number of them running at once is capped (see [`set_max_processes`]), as is
+//! the output captured from each of them (see [`set_max_output`]), and when the run is interrupted (e.g. with
+//! Ctrl-C) those that are still running are killed, so that they do not outlive the runner
+
+use log::{info, warn};
+use regex::Regex;
+use std::collections::{HashSet, VecDeque};
+use std::io::Read;
+use std::process::{Command, ExitStatus, Output};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// The exit code of an interrupted run, as for a process killed by SIGINT
+pub const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// The ids of the child processes that are running
+static CHILDREN: Mutex<Option<HashSet<u32>>> = Mutex::new(None);
+
+/// The default number of child processes that may run at once. Each of them holds three pipes, so running
+/// thousands at once (e.g. with a large --jobs) would exhaust the file descriptors of the runner
+pub const DEFAULT_MAX_PROCESSES: usize = 64;
+
+static MAX_PROCESSES: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_PROCESSES);
+
+/// The number of child processes running, guarded by [`SLOT_FREED`]
+static RUNNING: Mutex<usize> = Mutex::new(0);
+static SLOT_FREED: Condvar = Condvar::new();
+
+/// Set the number of child processes that may run at once, independently of the number of threads that start them
+pub fn set_max_processes(max: usize) {
+    MAX_PROCESSES.store(max.max(1), Ordering::SeqCst);
+    SLOT_FREED.notify_all();
+}
+
+/// The ramp-up of the number of child processes that may run at once, see [`start_ramp_up`]
+struct RampUp {
+    start: Instant,
+    initial: usize,
+    interval: Duration,
+}
+
+static RAMP_UP: Mutex<Option<RampUp>> = Mutex::new(None);
+
+/// Ramp up the number of child processes that may run at once from now on: `initial` at first, doubling every
+/// `interval` until it reaches the maximum of [`set_max_processes`]. Staggers the start of a large run, whose
+/// nvims would otherwise all start at the same moment and contend for the disk and the CPU
+pub fn start_ramp_up(initial: usize, interval: Duration) {
+    *RAMP_UP.lock().unwrap_or_else(|e| e.into_inner()) = Some(RampUp {
+        start: Instant::now(),
+        initial: initial.max(1),
+        interval,
+    });
+    SLOT_FREED.notify_all();
+}
+
+/// The number of child processes that may run at once while ramping up, and how long until it doubles. `None`
+/// once the ramp-up has reached the maximum (or if there is none)
+fn ramp_up_limit(max: usize) -> Option<(usize, Duration)> {
+    let mut ramp_up = RAMP_UP.lock().unwrap_or_else(|e| e.into_inner());
+    let ramp = ramp_up.as_ref()?;
+    let elapsed = ramp.start.elapsed();
+    let steps = match ramp.interval.is_zero() {
+        true => u32::MAX,
+        false => (elapsed.as_nanos() / ramp.interval.as_nanos()).min(63) as u32,
+    };
+    let limit = ramp
+        .initial
+        .saturating_mul(1usize.checked_shl(steps).unwrap_or(usize::MAX));
+    if limit >= max {
+        *ramp_up = None;
+        return None;
+    }
+    let next_step = ramp.interval * (steps + 1);
+    Some((limit, next_step.saturating_sub(elapsed)))
+}
+
+/// The default number of bytes captured from each of stdout and stderr of a child process
+pub const DEFAULT_MAX_OUTPUT: usize = 8 * 1024 * 1024;
+
+static MAX_OUTPUT: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_OUTPUT);
+
+/// The lines matching any of these are kept even from the omitted part of the output, as they tell a failed test
+/// from a passing one. See [`set_error_patterns`]
+static ERROR_PATTERNS: Mutex<Vec<Regex>> = Mutex::new(Vec::new());
+
+/// At most this many lines matching the [`ERROR_PATTERNS`] are kept from the omitted part of the output
+const MAX_KEPT_ERROR_LINES: usize = 10;
+
+/// Set the patterns of the lines that report an error, which are kept even from the omitted part of the output
+pub fn set_error_patterns(patterns: Vec<Regex>) {
+    *ERROR_PATTERNS.lock().unwrap_or_else(|e| e.into_inner()) = patterns;
+}
+
+/// Set the number of bytes captured from each of stdout and stderr of a child process, 0 for no limit. Of a
+/// longer output only the start and the end are kept, so that a test printing gigabytes does not exhaust the
+/// memory of the runner
+pub fn set_max_output(max: usize) {
+    MAX_OUTPUT.store(max, Ordering::SeqCst);
+}
+
+/// The output of a child process that completed, see [`output_with_usage`]
+pub struct Captured {
+    pub output: Output,
+    /// The peak resident set size of the process in bytes, where it is known
+    pub peak_rss: Option<u64>,
+    /// Whether part of stdout or stderr was omitted, being longer than the limit of [`set_max_output`]
+    pub truncated: bool,
+    /// Whether the process was killed for running past its deadline, see [`output_with_deadline`]
+    pub timed_out: bool,
+}
+
+/// The output read from a pipe, up to the limit: the start of it, then the end of it once the limit is reached
+struct CappedOutput {
+    limit: usize,
+    head: Vec<u8>,
+    tail: VecDeque<u8>,
+    omitted: usize,
+    /// The line of the omitted part being read, up to the length of a line that is kept
+    omitted_line: Vec<u8>,
+    error_lines: Vec<Vec<u8>>,
+    error_patterns: Vec<Regex>,
+}
+
+impl CappedOutput {
+    fn new(limit: usize) -> CappedOutput {
+        CappedOutput {
+            limit,
+            head: Vec::new(),
+            tail: VecDeque::new(),
+            omitted: 0,
+            omitted_line: Vec::new(),
+            error_lines: Vec::new(),
+            error_patterns: ERROR_PATTERNS
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone(),
+        }
+    }
+
+    fn push(&mut self, mut bytes: &[u8]) {
+        if self.limit == 0 {
+            self.head.extend_from_slice(bytes);
+            return;
+        }
+        let head_room = (self.limit / 2).saturating_sub(self.head.len());
+        let (head, rest) = bytes.split_at(head_room.min(bytes.len()));
+        self.head.extend_from_slice(head);
+        bytes = rest;
+
+        self.tail.extend(bytes);
+        let excess = self.tail.len().saturating_sub(self.limit - self.limit / 2);
+        for byte in self.tail.drain(..excess) {
+            self.omitted += 1;
+            if byte == b'\n' {
+                let line = std::mem::take(&mut self.omitted_line);
+                if self.error_lines.len() < MAX_KEPT_ERROR_LINES
+                    && is_error_line(&self.error_patterns, &line)
+                {
+                    self.error_lines.push(line);
+                }
+            } else if self.omitted_line.len() < 1024 {
+                self.omitted_line.push(byte);
+            }
+        }
+    }
+
+    /// The output with a note in place of the omitted part, which is followed by the error lines it contained.
+    /// Returns whether anything was omitted
+    fn finish(mut self) -> (Vec<u8>, bool) {
+        if self.omitted == 0 {
+            self.head.extend(self.tail);
+            return (self.head, false);
+        }
+        if is_error_line(&self.error_patterns, &self.omitted_line) {
+            self.error_lines.push(self.omitted_line);
+        }
+        let mut output = self.head;
+        output.extend_from_slice(
+            format!(
+                "\n… {} bytes of output omitted, over the limit of {} bytes (--max-output) …\n",
+                self.omitted, self.limit
+            )
+            .as_bytes(),
+        );
+        for line in self.error_lines {
+            output.extend(line);
+            output.push(b'\n');
+        }
+        output.extend(self.tail);
+        (output, true)
+    }
+}
+
+/// Whether a line of output matches any of the patterns of an error
+fn is_error_line(patterns: &[Regex], line: &[u8]) -> bool {
+    let line = String::from_utf8_lossy(line);
+    patterns.iter().any(|pattern| pattern.is_match(&line))
+}
+
+/// Read the pipe on a thread while the child runs, so that it does not block on a full pipe
+fn read_capped(
+    pipe: Option<impl Read + Send + 'static>,
+) -> std::thread::JoinHandle<(Vec<u8>, bool)> {
+    let mut output = CappedOutput::new(MAX_OUTPUT.load(Ordering::SeqCst));
+    std::thread::spawn(move || {
+        if let Some(mut pipe) = pipe {
+            let mut buf = [0; 64 * 1024];
+            loop {
+                match pipe.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => output.push(&buf[..n]),
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(_) => break,
+                }
+            }
+        }
+        output.finish()
+    })
+}
+
+/// The permission to run a child process, given back when dropped
+struct ProcessSlot;
+
+impl ProcessSlot {
+    /// Wait until fewer than the maximum number of child processes are running
+    fn acquire() -> ProcessSlot {
+        let mut running = RUNNING.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            let max = MAX_PROCESSES.load(Ordering::SeqCst);
+            match ramp_up_limit(max) {
+                Some((limit, _)) if *running < limit => break,
+                // Woken up either by a process that finished, or once the ramp-up allows more processes
+                Some((_, next_step)) => {
+                    running = SLOT_FREED
+                        .wait_timeout(running, next_step)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0;
+                }
+                None if *running < max => break,
+                None => {
+                    running = SLOT_FREED.wait(running).unwrap_or_else(|e| e.into_inner());
+                }
+            }
+        }
+        *running += 1;
+        ProcessSlot
+    }
+}
+
+impl Drop for ProcessSlot {
+    fn drop(&mut self) {
+        *RUNNING.lock().unwrap_or_else(|e| e.into_inner()) -= 1;
+        SLOT_FREED.notify_one();
+    }
+}
+
+/// Run the command to completion like [`Command::output`], killing it if the run is interrupted meanwhile
+pub fn output(cmd: &mut Command) -> std::io::Result<Output> {
+    output_with_usage(cmd).map(|captured| captured.output)
+}
+
+/// Like [`output`], but also returns the peak resident set size of the process, and whether its output was
+/// truncated
+pub fn output_with_usage(cmd: &mut Command) -> std::io::Result<Captured> {
+    output_with_deadline(cmd, None)
+}
+
+/// Like [`output_with_usage`], but kills the process (with the processes it spawned) once it has run for longer
+/// than the timeout, if one is given
+pub fn output_with_deadline(
+    cmd: &mut Command,
+    timeout: Option<Duration>,
+) -> std::io::Result<Captured> {
+    cmd.stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped());
+    // In its own process group, so that the processes it spawns in turn (e.g. jobs of nvim) are killed with it
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(cmd, 0);
+    // Held until the pipes of the child are closed
+    let _slot = ProcessSlot::acquire();
+    let mut child = cmd.spawn()?;
+    let id = child.id();
+    CHILDREN
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get_or_insert_with(HashSet::new)
+        .insert(id);
+    // The interrupt may have come in before the child was registered
+    if is_interrupted() {
+        kill(id);
+    }
+    let stdout = read_capped(child.stdout.take());
+    let stderr = read_capped(child.stderr.take());
+    // Waits for the deadline, or for the sender to be dropped once the process has exited
+    let (exited, exit) = std::sync::mpsc::channel::<()>();
+    let watchdog = timeout.map(|timeout| {
+        std::thread::spawn(move || match exit.recv_timeout(timeout) {
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+                info!(
+                    "Killing process {}, which ran for longer than {:?}",
+                    id, timeout
+                );
+                kill(id);
+                true
+            }
+            _ => false,
+        })
+    });
+    let status = wait_with_usage(child);
+    drop(exited);
+    let timed_out = watchdog.is_some_and(|watchdog| watchdog.join().unwrap_or(false));
+    if let Some(children) = CHILDREN.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        children.remove(&id);
+    }
+    let (status, peak_rss) = status?;
+    let (stdout, stdout_truncated) = stdout.join().unwrap_or_default();
+    let (stderr, stderr_truncated) = stderr.join().unwrap_or_default();
+    Ok(Captured {
+        output: Output {
+            status,
+            stdout,
+            stderr,
+        },
+        peak_rss,
+        truncated: stdout_truncated || stderr_truncated,
+        timed_out,
+    })
+}
+
+#[cfg(unix)]
+fn wait_with_usage(child: std::process::Child) -> std::io::Result<(ExitStatus, Option<u64>)> {
+    use std::os::unix::process::ExitStatusExt;
+
+    // wait4 (rather than Child::wait) reports the resource usage of the child
+    let mut status: libc::c_int = 0;
+    // SAFETY: rusage is plain data, for which all zeroes is a valid value
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+        // SAFETY: status and usage are valid for writes for the duration of the call
+        let pid = unsafe { libc::wait4(child.id() as libc::pid_t, &mut status, 0, &mut usage) };
+        if pid >= 0 {
+            break;
+        }
+        let error = std::io::Error::last_os_error();
+        if error.kind() != std::io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+
+    // ru_maxrss is in bytes on macOS, and in kilobytes elsewhere
+    let max_rss = usage.ru_maxrss.max(0) as u64;
+    let peak_rss = if cfg!(target_os = "macos") {
+        max_rss
+    } else {
+        max_rss * 1024
+    };
+    Ok((ExitStatus::from_raw(status), Some(peak_rss)))
+}
+
+#[cfg(not(unix))]
+fn wait_with_usage(mut child: std::process::Child) -> std::io::Result<(ExitStatus, Option<u64>)> {
+    child.wait().map(|status| (status, None))
+}
+
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Mark the run as interrupted and kill the running child processes. Returns false if the run had already
+/// been interrupted, in which case nothing is done
+pub fn interrupt() -> bool {
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        return false;
+    }
+    info!("Interrupted, killing the running nvim processes");
+    let children = CHILDREN
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .take()
+        .unwrap_or_default();
+    for id in children {
+        kill(id);
+    }
+    true
+}
+
+#[cfg(unix)]
+fn kill(id: u32) {
+    // A negative id stands for the process group of the child
+    // SAFETY: kill has no memory safety requirements
+    if unsafe { libc::kill(-(id as libc::pid_t), libc::SIGKILL) } != 0 {
+        warn!(
+            "Failed to kill process {}: {}",
+            id,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(unix))]
+fn kill(id: u32) {
+    if let Err(e) = Command::new("taskkill")
+        .arg("/F")
+        .arg("/T")
+        .arg("/PID")
+        .arg(id.to_string())
+        .output()
+    {
+        warn!("Failed to kill process {}: {}", id, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn process_slots_limit_the_running_processes() {
+        set_max_processes(3);
+
+        let holding = std::sync::Arc::new(AtomicUsize::new(0));
+        let peak = std::sync::Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..16)
+            .map(|_| {
+                let (holding, peak) = (holding.clone(), peak.clone());
+                std::thread::spawn(move || {
+                    for _ in 0..10 {
+                        let _slot = ProcessSlot::acquire();
+                        let now = holding.fetch_add(1, Ordering::SeqCst) + 1;
+                        peak.fetch_max(now, Ordering::SeqCst);
+                        std::thread::sleep(Duration::from_millis(1));
+                        holding.fetch_sub(1, Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert!(peak.load(Ordering::SeqCst) <= 3, "{:?}", peak);
+
+        // Many more processes than slots all run to completion, with their own output
+        let threads: Vec<_> = (0..32)
+            .map(|i| {
+                std::thread::spawn(move || {
+                    let output =
+                        output(Command::new("sh").arg("-c").arg(format!("echo {}", i))).unwrap();
+                    assert!(output.status.success());
+                    assert_eq!(
+                        String::from_utf8_lossy(&output.stdout).trim(),
+                        i.to_string()
+                    );
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        set_max_processes(DEFAULT_MAX_PROCESSES);
+    }
+}
@@ -0,0 +1,49 @@
+//! Detecting the files a test leaves behind, with --detect-leaks: the watched directory (the system temp
+//! directory by default) is listed before and after each nvim, and the files that appeared and were not removed
+//! are reported with the test. Tests running at the same time share the directory, so with more than one job a
+//! file may be attributed to another test running alongside the one that wrote it
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// The files (not directories) under a directory, recursively. Symlinks are listed but not followed, and
+/// directories that cannot be read are left out, as are those in `excluded` and the files of the runner itself
+/// (e.g. the result files of test-utils)
+pub fn snapshot(dir: &Path, excluded: &[PathBuf]) -> HashSet<PathBuf> {
+    let mut files = HashSet::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if excluded.contains(&path)
+                || entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with("nvim-test-runner-")
+            {
+                continue;
+            }
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => dirs.push(path),
+                Ok(_) => {
+                    files.insert(path);
+                }
+                Err(_) => {}
+            }
+        }
+    }
+    files
+}
+
+/// The files of a snapshot taken after a test that were not in the one taken before it, sorted by path
+pub fn leaked_files(before: &HashSet<PathBuf>, after: HashSet<PathBuf>) -> Vec<PathBuf> {
+    let mut leaked: Vec<PathBuf> = after
+        .into_iter()
+        .filter(|path| !before.contains(path))
+        .collect();
+    leaked.sort();
+    leaked
+}
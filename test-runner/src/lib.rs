@@ -0,0 +1,3749 @@
+//! Run tests for Neovim plugins
+//!
+//! The `nvim-test-runner` binary is a thin command line wrapper around [`run`], which can also be called
+//! to embed the runner in other tools
+//!
+//! ```no_run
+//! use nvim_test_runner::{config, RunOptions};
+//!
+//! let config = config::load_config("nvim-test-runner.json").unwrap();
+//! let summary = nvim_test_runner::run(config, RunOptions::default()).unwrap();
+//! assert!(summary.is_success());
+//! ```
+
+pub mod archive;
+pub mod baseline;
+pub mod bench;
+pub mod concurrency;
+pub mod config;
+pub mod coverage;
+pub mod deps;
+pub mod discovery;
+pub mod doctor;
+pub mod error;
+pub mod init;
+pub mod install;
+pub mod interrupt;
+pub mod leaks;
+pub mod lockfile;
+pub mod nvim;
+pub mod report;
+pub mod reporter;
+pub mod repro;
+pub mod secrets;
+pub mod self_update;
+pub mod state;
+pub mod test_utils;
+pub mod tui;
+pub mod user_config;
+pub mod warnings;
+
+use ansi_term::Colour;
+use glob::glob;
+use log::{debug, error, info, warn};
+use rayon::prelude::*;
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use baseline::{Baseline, BaselineSettings};
+use concurrency::GroupLimits;
+use config::{check_config, default_test_paths, expand_path};
+use deps::{
+    dep_has_name, discover_plugins, normalize_uri, plugin_root, resolve_external_dep,
+    DepResolution, ResolveOptions,
+};
+use discovery::{
+    git_changed_files, parse_tags, run_discovery_command, Shard, TestDirectives, TestFile,
+};
+use lockfile::Lockfile;
+use nvim::{
+    check_syntax, describe_command, escape_file_name, new_command, nvim_capabilities,
+    parse_version, set_rtp_command,
+};
+use report::{
+    exit_signal, write_output_logs, FlakyTest, TestFailure, TestUtilsReport,
+    QUARANTINE_RELEASE_RUNS,
+};
+use repro::{Invocation, RecordedDependency, ReproBundle};
+use secrets::redact_secrets;
+use state::{lock_state, LuaTestUtilsState, RecordedRun, ResultsCache, RunDelta, State};
+use test_utils::{
+    check_batch_support, download_test_utils, http_client, is_protocol_compatible,
+    test_utils_module, test_utils_path, validate_test_utils, TEST_UTILS_PROTOCOL_VERSION,
+};
+
+pub use config::{TestConfig, TestDepedency};
+pub use error::RunnerError;
+pub use report::{MatrixCellResult, RunSummary, TestOutcome, TestResult};
+pub use reporter::{
+    output_formats, ConsoleReporter, EventsReporter, GithubAnnotationsReporter, OutputFormat,
+    ReportFormat, Reporter, SummaryReporter,
+};
+
+/// Options controlling a test run, as given on the command line
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    /// Whether to skip checking the local clone of the external dependency is up-to-date with the remote repository
+    /// `None` defers to the `skipRemoteCheck` config field
+    pub skip_remote_check: Option<bool>,
+    /// Where the runner keeps its state, the external dependencies and test-utils. Overrides `cacheDir` of the config
+    pub cache_dir: Option<std::path::PathBuf>,
+    /// Never touch the network. Implies `skip_remote_check`
+    pub offline: bool,
+    /// Fail if test-utils or an external dependency would have to be fetched, without accessing the network
+    pub frozen: bool,
+    /// Fail if the external dependencies do not resolve as the lockfile records, see [`lockfile`]
+    pub locked: bool,
+    /// Write the revisions the external dependencies resolved to into the lockfile
+    pub write_lockfile: bool,
+    /// Only print failed tests and the final summary
+    pub quiet: bool,
+    /// Fold the results of passing tests into the progress bar
+    pub compact: bool,
+    /// Print more details about the run, such as the runtimepath
+    pub verbose: bool,
+    /// Resolve the dependencies and discover the tests without running them
+    pub dry_run: bool,
+    /// Only check that the test files parse, reporting their syntax errors without running them
+    pub check: bool,
+    /// Continue without a dependency that fails to resolve
+    pub keep_going: bool,
+    /// Only run the test files that changed compared to `changed_base`
+    pub changed: bool,
+    /// The git ref that `changed` compares against
+    pub changed_base: String,
+    /// With `changed`, also run the test files in a directory containing a changed file
+    pub changed_dirs: bool,
+    /// Overwrite snapshots instead of asserting against them
+    pub update: bool,
+    /// Where to write the captured output of each test
+    pub output_dir: Option<std::path::PathBuf>,
+    /// Where to write a summary of the run in Markdown, as with a `markdown` format written to the file
+    pub markdown_report: Option<PathBuf>,
+    /// The formats the results are reported in, each to stdout or to a file. The human output is printed to
+    /// stdout unless another format is (see [`output_formats`])
+    pub formats: Vec<OutputFormat>,
+    /// The file the one format of `formats` not given a file of its own is written to
+    pub output: Option<PathBuf>,
+    /// Only run the test files tagged with any of these comma-separated tags
+    pub tags: Option<String>,
+    /// Only resolve the test dependencies with these comma-separated names, skipping the others
+    pub only_deps: Option<String>,
+    /// Do not run the test files tagged with any of these comma-separated tags
+    pub exclude_tags: Option<String>,
+    /// Print the results as a TAP stream, as with a `tap` format printed to stdout
+    pub tap: bool,
+    /// Print the failures as GitHub Actions annotations. Defaults to whether the runner is running in GitHub
+    /// Actions
+    pub github_annotations: Option<bool>,
+    /// Stop starting new tests once this many tests have failed
+    pub bail: Option<usize>,
+    /// The number of failing tests that does not fail the run yet, e.g. the known failures during a migration
+    pub allowed_failures: Option<usize>,
+    /// Only run the `T.it` blocks whose full name contains this pattern
+    pub grep: Option<String>,
+    /// Fail if the test paths do not match any test file, rather than succeeding without running any tests
+    pub fail_on_empty: bool,
+    /// Run no tests if the config gives no test paths, rather than falling back to the default test paths
+    pub no_default_paths: bool,
+    /// Do not write a `.gitignore` into the cache directory when creating it
+    pub no_gitignore: bool,
+    /// Copy the plugin under test into a directory of its own (see [`install`]) and add that to the
+    /// runtimepath instead of the current directory
+    pub installed: bool,
+    /// Run each test with a fresh temporary HOME and XDG directories
+    pub isolate: bool,
+    /// Skip the tests that passed in the previous run and have not been modified since
+    pub resume: bool,
+    /// Only run the tests that failed in the last run
+    pub failed: bool,
+    /// Only run the tests whose `requires` directive gives a dependency that resolved to another revision than
+    /// in the last run, and those that do not give their dependencies
+    pub affected_by_deps: bool,
+    /// Run the tests that are marked with a `skip` directive as well
+    pub run_skipped: bool,
+    /// Only run the test files modified within this duration
+    pub since: Option<Duration>,
+    /// Only run the part of the test files that belongs to this shard
+    pub shard: Option<Shard>,
+    /// The number of times the tests are run without recording the results before the recorded run
+    pub warmup: usize,
+    /// The number of times the whole suite is run, to find the tests whose outcome differs between the runs.
+    /// Only the last run is reported as usual
+    pub repeat: usize,
+    /// How long the listed refs of the dependency remotes are reused before they are listed again
+    pub remote_ttl: Option<Duration>,
+    /// The number of slowest tests listed once the run has finished
+    pub slowest: Option<usize>,
+    /// The name of the timing baseline the durations of the tests are compared to, failing the run if any got
+    /// slower than the config allows
+    pub baseline: Option<String>,
+    /// The name of the timing baseline the durations of the tests are saved as
+    pub save_baseline: Option<String>,
+    /// Print which tests newly failed, newly passed or changed duration significantly since the previous run
+    pub compare: bool,
+    /// The file (or FIFO) the events of the run are written to as newline-delimited JSON
+    pub events: Option<std::path::PathBuf>,
+    /// The number of failures whose output is printed in full, the others are only counted
+    pub max_failures_output: Option<usize>,
+    /// The proxy all downloads go through, in place of those given by the environment variables
+    pub proxy: Option<String>,
+    /// Download test-utils again even if the cached one is up-to-date
+    pub refresh_test_utils: bool,
+    /// Run up to this many test files one after another in each nvim, rather than one per nvim
+    pub batch: Option<usize>,
+    /// Fail unless exactly this many test files match the test paths
+    pub expect_tests: Option<usize>,
+    /// Fail unless at least this many test files match the test paths
+    pub min_tests: Option<usize>,
+    /// The number of tests that used the most memory listed once the run has finished
+    pub most_memory: Option<usize>,
+    /// Summarize the results by the directory of the test files once the run has finished
+    pub group_by_dir: bool,
+    /// The number of nvim processes that may run at once. Defaults to [`interrupt::DEFAULT_MAX_PROCESSES`]
+    pub max_processes: Option<usize>,
+    /// Ramp up the number of nvim processes running at once at the start of the run, doubling it every this
+    /// often (see [`interrupt::start_ramp_up`]). No ramp-up by default
+    pub ramp_up: Option<Duration>,
+    /// The number of nvim processes running at once at the start of the ramp-up. Defaults to 1
+    pub ramp_up_start: Option<usize>,
+    /// Print why each external dependency is reused, updated or cloned
+    pub explain: bool,
+    /// Fail the tests that pass without running any assertion of test-utils, rather than only warning about them
+    pub fail_on_no_assertions: bool,
+    /// Trace the lines of Lua code the tests run, and write their merged coverage to `coverage_output`
+    pub coverage: bool,
+    /// The file the coverage is written to, in the stats format of luacov. Defaults to `luacov.stats.out`
+    pub coverage_output: Option<PathBuf>,
+    /// The directory the result files of test-utils are written to. Defaults to the system temp directory
+    pub result_dir: Option<PathBuf>,
+    /// Keep the result files once they are read, rather than removing them
+    pub keep_results: bool,
+    /// Report the files each test leaves behind in `leak_dir`
+    pub detect_leaks: bool,
+    /// The directory `detect_leaks` watches. Defaults to the system temp directory
+    pub leak_dir: Option<PathBuf>,
+    /// Fail the run if there were warnings about its setup (see [`warnings`]), e.g. a skipped dependency
+    pub fail_on_warning: bool,
+    /// Fail the run if the tests changed the nvim config or data of the user (see [`user_config`])
+    pub guard_user_config: bool,
+    /// Report the dependencies none of whose Lua modules were required by the tests once the run has finished
+    pub check_unused_deps: bool,
+    /// Fail the run if there are unused dependencies, rather than only reporting them. Implies
+    /// `check_unused_deps`
+    pub fail_on_unused_deps: bool,
+    /// The number of lines of the stderr of a failed test printed to the console, 0 for all of them. Defaults to
+    /// [`reporter::DEFAULT_MAX_STDERR_LINES`]
+    pub max_stderr_lines: Option<usize>,
+    /// How the console prints the failures of a test. Defaults to [`reporter::FailureFormat::detect`]
+    pub failure_format: Option<reporter::FailureFormat>,
+    /// The number of bytes captured from each of stdout and stderr of a test, 0 for no limit. Defaults to
+    /// [`interrupt::DEFAULT_MAX_OUTPUT`]
+    pub max_output: Option<usize>,
+    /// Run the failed tests again once the run has finished, with more verbosity, printing their output in full
+    pub debug_failures: bool,
+    /// The directory a reproduction bundle is written to for each failed test, see [`repro`]
+    pub record_failures: Option<PathBuf>,
+    /// Secrets set in the environment of the tests, by name, on top of those of the config. See [`secrets`] for
+    /// the forms of their sources
+    pub secrets: Vec<(String, String)>,
+    /// The git executable used instead of `git` on the PATH, overriding the gitPath config field
+    pub git: Option<std::path::PathBuf>,
+    /// Warn about reused clones of external dependencies that were modified or moved to another commit
+    pub verify_deps: bool,
+    /// Reset the reused clones that were modified or moved to another commit. Implies `verify_deps`
+    pub repair_deps: bool,
+    /// Share the clones of the external dependencies between projects through this directory, see
+    /// [`default_global_cache_dir`]
+    pub global_cache: Option<std::path::PathBuf>,
+    /// A local repository new clones of the external dependencies borrow their objects from
+    pub reference_repo: Option<std::path::PathBuf>,
+    /// Make the clones made with a reference repository independent of it
+    pub dissociate: bool,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        RunOptions {
+            skip_remote_check: None,
+            cache_dir: None,
+            offline: false,
+            frozen: false,
+            locked: false,
+            write_lockfile: false,
+            quiet: false,
+            compact: false,
+            verbose: false,
+            dry_run: false,
+            check: false,
+            keep_going: false,
+            changed: false,
+            changed_base: "HEAD".to_string(),
+            changed_dirs: false,
+            update: false,
+            output_dir: None,
+            markdown_report: None,
+            formats: Vec::new(),
+            output: None,
+            tags: None,
+            only_deps: None,
+            exclude_tags: None,
+            tap: false,
+            github_annotations: None,
+            bail: None,
+            allowed_failures: None,
+            grep: None,
+            fail_on_empty: false,
+            no_default_paths: false,
+            no_gitignore: false,
+            installed: false,
+            isolate: false,
+            resume: false,
+            failed: false,
+            affected_by_deps: false,
+            run_skipped: false,
+            since: None,
+            shard: None,
+            warmup: 0,
+            repeat: 1,
+            remote_ttl: None,
+            slowest: None,
+            baseline: None,
+            save_baseline: None,
+            compare: false,
+            events: None,
+            max_failures_output: None,
+            proxy: None,
+            refresh_test_utils: false,
+            batch: None,
+            expect_tests: None,
+            min_tests: None,
+            most_memory: None,
+            group_by_dir: false,
+            max_processes: None,
+            ramp_up: None,
+            ramp_up_start: None,
+            explain: false,
+            global_cache: None,
+            reference_repo: None,
+            dissociate: false,
+            git: None,
+            verify_deps: false,
+            repair_deps: false,
+            fail_on_no_assertions: false,
+            coverage: false,
+            coverage_output: None,
+            result_dir: None,
+            keep_results: false,
+            detect_leaks: false,
+            leak_dir: None,
+            fail_on_warning: false,
+            guard_user_config: false,
+            check_unused_deps: false,
+            fail_on_unused_deps: false,
+            max_stderr_lines: None,
+            failure_format: None,
+            max_output: None,
+            debug_failures: false,
+            record_failures: None,
+            secrets: Vec::new(),
+        }
+    }
+}
+
+/// All runner-managed files (state, external dependencies and test-utils) live under this directory
+pub fn resolve_cache_dir(options: &RunOptions, config: &TestConfig) -> std::path::PathBuf {
+    options
+        .cache_dir
+        .clone()
+        .or(config
+            .settings
+            .cache_dir
+            .as_ref()
+            .map(std::path::PathBuf::from))
+        .unwrap_or(std::path::PathBuf::from(".test"))
+}
+
+/// The global cache used by `--global-cache` without a path: `$XDG_CACHE_HOME/nvim-test-runner`, or
+/// `~/.cache/nvim-test-runner`
+pub fn default_global_cache_dir() -> std::path::PathBuf {
+    match std::env::var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => std::path::PathBuf::from(dir).join("nvim-test-runner"),
+        None => expand_path("~/.cache/nvim-test-runner"),
+    }
+}
+
+/// The git executable: the option takes precedence over the config, which defaults to `git` on the PATH
+pub fn resolve_git_path(options: &RunOptions, config: &TestConfig) -> std::path::PathBuf {
+    options
+        .git
+        .clone()
+        .or(config.settings.git_path.as_deref().map(expand_path))
+        .unwrap_or(std::path::PathBuf::from("git"))
+}
+
+/// Whether the remote check is skipped: the option takes precedence over the config, which defaults to false
+pub fn resolve_skip_remote_check(options: &RunOptions, config: &TestConfig) -> bool {
+    options
+        .skip_remote_check
+        .or(config.settings.skip_remote_check)
+        .unwrap_or(false)
+}
+
+/// The test paths of a run: those of the config, otherwise the default test paths unless they are disabled by the
+/// option or the config
+pub fn resolve_test_paths(options: &RunOptions, config: &TestConfig) -> Vec<String> {
+    match &config.settings.test_paths {
+        Some(test_paths) => test_paths.clone(),
+        None if options.no_default_paths || !config.settings.default_test_paths.unwrap_or(true) => {
+            Vec::new()
+        }
+        None => default_test_paths(),
+    }
+}
+
+/// The config that a run with the given options effectively uses, with the defaults filled in. The values of the
+/// secrets given in the config or with --secret are redacted, while their files and variables are shown
+pub fn effective_config(mut config: TestConfig, options: &RunOptions) -> TestConfig {
+    if !options.secrets.is_empty() {
+        config
+            .settings
+            .secrets
+            .get_or_insert_with(HashMap::new)
+            .extend(options.secrets.iter().cloned());
+    }
+    secrets::redact_sources(&mut config);
+    config.settings.cache_dir = Some(resolve_cache_dir(options, &config).display().to_string());
+    config.settings.test_paths = Some(resolve_test_paths(options, &config));
+    config.settings.default_test_paths =
+        Some(!options.no_default_paths && config.settings.default_test_paths.unwrap_or(true));
+    config.settings.add_cwd_to_rtp.get_or_insert(true);
+    for dep in config.settings.test_dependencies.iter_mut().flatten() {
+        dep.submodules.get_or_insert(false);
+        dep.enabled.get_or_insert(true);
+    }
+    config.settings.namespace_test_utils.get_or_insert(false);
+    config.settings.skip_remote_check = Some(resolve_skip_remote_check(options, &config));
+    config
+}
+
+/// Run a hook command with `sh -c`, printing its output. Returns an error if the command fails
+pub fn run_hook(name: &str, command: &str) -> Result<(), RunnerError> {
+    run_hook_with_input(name, command, &[], None)
+}
+
+/// Run the afterRun hook, with the summary of the run as JSON on its stdin and its counts in the
+/// `NVIM_TEST_RUNNER_PASSED`, `NVIM_TEST_RUNNER_FAILED`, `NVIM_TEST_RUNNER_SKIPPED` and
+/// `NVIM_TEST_RUNNER_SUCCESS` environment variables
+pub fn run_after_run_hook(command: &str, summary: &RunSummary) -> Result<(), RunnerError> {
+    let input = report::summary_json(summary);
+    let env = [
+        ("NVIM_TEST_RUNNER_PASSED", summary.passed.to_string()),
+        ("NVIM_TEST_RUNNER_FAILED", summary.failed.to_string()),
+        ("NVIM_TEST_RUNNER_SKIPPED", summary.skipped.to_string()),
+        ("NVIM_TEST_RUNNER_SUCCESS", summary.is_success().to_string()),
+    ];
+    run_hook_with_input(
+        "afterRun",
+        command,
+        &env,
+        Some(input.to_string().as_bytes()),
+    )
+}
+
+/// Run a hook command with `sh -c` like [`run_hook`], with the given environment variables and stdin
+fn run_hook_with_input(
+    name: &str,
+    command: &str,
+    env: &[(&str, String)],
+    stdin: Option<&[u8]>,
+) -> Result<(), RunnerError> {
+    println!(
+        "{}",
+        Colour::Yellow.paint(format!("Running {} hook: {}", name, command))
+    );
+    info!("Running {} hook: {}", name, command);
+
+    let hook_error =
+        |e: std::io::Error| RunnerError::Hook(format!("Failed to run {} hook: {}", name, e));
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(env.iter().map(|(key, value)| (key, value)))
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::inherit()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(hook_error)?;
+    if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+        // A hook that does not read its stdin closes it early, which is not an error
+        if let Err(e) = pipe.write_all(input) {
+            debug!("{} hook did not read its stdin: {}", name, e);
+        }
+    }
+    let output = child.wait_with_output().map_err(hook_error)?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !stdout.is_empty() {
+        print!("{}", stdout);
+    }
+    if !stderr.is_empty() {
+        print!("{}", Colour::Yellow.paint(stderr.to_string()));
+    }
+    debug!("{} hook stdout:\n{}", name, stdout);
+    debug!("{} hook stderr:\n{}", name, stderr);
+
+    if !output.status.success() {
+        return Err(RunnerError::Hook(format!(
+            "{} hook failed with {}",
+            name, output.status
+        )));
+    }
+
+    Ok(())
+}
+
+/// Resolve the dependencies of the tests described by `config`, then run the tests, reporting the results
+/// with the reporters picked by the options. Test failures are not errors; they are reported in the returned
+/// summary
+pub fn run(config: TestConfig, options: RunOptions) -> Result<RunSummary, RunnerError> {
+    let reporters = default_reporters(&options)?;
+    run_with_reporters(config, options, &reporters)
+}
+
+/// Run the suite once for each cell of the test matrix of the config, printing which cells passed once all of
+/// them have run. A cell that cannot be run (e.g. as its nvim does not exist) does not stop the other cells
+pub fn run_matrix(
+    config: TestConfig,
+    options: RunOptions,
+) -> Result<Vec<MatrixCellResult>, RunnerError> {
+    // Every cell would fail the same way
+    check_config(&config)?;
+    let cells = config.matrix.clone().unwrap_or_default().cells();
+
+    let mut results = Vec::with_capacity(cells.len());
+    for (index, cell) in cells.iter().enumerate() {
+        println!(
+            "{}",
+            Colour::Yellow.paint(format!(
+                "Matrix cell {}/{}: {}",
+                index + 1,
+                cells.len(),
+                cell.name
+            ))
+        );
+        info!("Matrix cell {}/{}: {}", index + 1, cells.len(), cell.name);
+        let result = run(cell.apply(&config), options.clone());
+        if let Err(e) = &result {
+            println!("{}", Colour::Red.paint(format!("{}", e)));
+            error!("Matrix cell {}: {}", cell.name, e);
+        }
+        results.push(MatrixCellResult {
+            name: cell.name.clone(),
+            result,
+        });
+    }
+
+    let num_passed = results
+        .iter()
+        .filter(|cell| {
+            cell.result
+                .as_ref()
+                .is_ok_and(|summary| summary.is_success())
+        })
+        .count();
+    let colour = if num_passed == results.len() {
+        Colour::Green
+    } else {
+        Colour::Red
+    };
+    println!(
+        "{}",
+        colour.paint(format!(
+            "{} of {} matrix cell(s) passed",
+            num_passed,
+            results.len()
+        ))
+    );
+    info!("{} of {} matrix cell(s) passed", num_passed, results.len());
+    for cell in &results {
+        let line = match &cell.result {
+            Ok(summary) if summary.is_success() => Colour::Green.paint(format!(
+                "  ✓ {} ({} passed, {} skipped)",
+                cell.name, summary.passed, summary.skipped
+            )),
+            Ok(summary) => Colour::Red.paint(format!(
+                "  ✗ {} ({} failed, {} passed, {} skipped)",
+                cell.name,
+                summary.failed + summary.unexpected_passes + summary.flaky.len(),
+                summary.passed,
+                summary.skipped
+            )),
+            Err(e) => Colour::Red.paint(format!("  ✗ {} ({})", cell.name, e)),
+        };
+        println!("{}", line);
+    }
+    Ok(results)
+}
+
+/// The reporters a run with the given options reports to: one for each of its [`output_formats`], and the events
+/// file with `events`
+pub fn default_reporters(options: &RunOptions) -> Result<Vec<Box<dyn Reporter>>, RunnerError> {
+    let formats = output_formats(options)?;
+    let mut reporters: Vec<Box<dyn Reporter>> = formats
+        .iter()
+        .cloned()
+        .map(|format| -> Box<dyn Reporter> {
+            match format.format {
+                ReportFormat::Human => Box::new(ConsoleReporter::new(options)),
+                _ => Box::new(SummaryReporter::new(format)),
+            }
+        })
+        .collect();
+    if let Some(events) = &options.events {
+        reporters.push(Box::new(EventsReporter::open(events)?));
+    }
+    if options
+        .github_annotations
+        .unwrap_or_else(GithubAnnotationsReporter::detect)
+    {
+        // Keeping a report printed to stdout parseable
+        let to_stderr = formats
+            .iter()
+            .any(|format| format.output.is_none() && format.format != ReportFormat::Human);
+        reporters.push(Box::new(GithubAnnotationsReporter::new(to_stderr)));
+    }
+    Ok(reporters)
+}
+
+/// Check that nvim can be run as configured, and is recent enough for the tests
+pub(crate) fn check_nvim(
+    config: &TestConfig,
+    cache_dir: &std::path::Path,
+) -> Result<(), RunnerError> {
+    if config
+        .settings
+        .runner_wrapper
+        .as_ref()
+        .is_some_and(|wrapper| wrapper.is_empty())
+    {
+        return Err(RunnerError::Config(
+            "runnerWrapper is empty, it has to give at least the program to run".to_string(),
+        ));
+    }
+
+    if let Some(min_nvim_version) = &config.settings.min_nvim_version {
+        let required = parse_version(min_nvim_version).ok_or_else(|| {
+            RunnerError::Config(format!("Invalid minNvimVersion: {}", min_nvim_version))
+        })?;
+        let actual =
+            nvim_capabilities(config.settings.runner_wrapper.as_deref(), cache_dir)?.version;
+        if actual < required {
+            return Err(RunnerError::Nvim(format!(
+                "nvim v{}.{}.{} is installed, but the tests require at least v{}.{}.{}",
+                actual.0, actual.1, actual.2, required.0, required.1, required.2
+            )));
+        }
+        info!(
+            "nvim v{}.{}.{} satisfies minNvimVersion {}",
+            actual.0, actual.1, actual.2, min_nvim_version
+        );
+    }
+
+    Ok(())
+}
+
+/// The test dependencies of a run, resolved by [`resolve_dependencies`]
+pub(crate) struct ResolvedDependencies {
+    cache_dir: std::path::PathBuf,
+    namespace_test_utils: bool,
+    external_deps: Vec<std::path::PathBuf>,
+    local_deps: Vec<std::path::PathBuf>,
+    /// Local dependencies whose own tests should also be run, along with their test paths
+    local_dep_tests: Vec<(std::path::PathBuf, Vec<String>)>,
+    /// The names a test can refer to a resolved dependency by: its uri, normalized uri, and the name of its
+    /// directory
+    resolved_dep_names: std::collections::HashSet<String>,
+    /// With --keep-going, the dependencies that failed to resolve
+    unresolved_deps: Vec<String>,
+    /// The runtimepath entries of the dependencies, with the uri of the dependency each belongs to
+    dep_entries: Vec<(std::path::PathBuf, String)>,
+    /// With --installed, the directory the plugin under test was installed into
+    installed_plugin: Option<std::path::PathBuf>,
+    /// The revision (commit or archive checksum) each external dependency resolved to, by normalized uri
+    dep_revisions: std::collections::BTreeMap<String, String>,
+}
+
+impl ResolvedDependencies {
+    /// The runtimepath entries, in order. The plugin under test comes first so that it shadows its dependencies
+    pub(crate) fn runtimepath(&self, config: &TestConfig) -> Vec<std::path::PathBuf> {
+        let mut rtp: Vec<std::path::PathBuf> = Vec::new();
+        if config.settings.add_cwd_to_rtp.unwrap_or(true) {
+            match &self.installed_plugin {
+                Some(installed_plugin) => rtp.push(installed_plugin.clone()),
+                None => rtp.push(std::path::PathBuf::from(".")),
+            }
+        }
+        rtp.extend(self.external_deps.iter().cloned());
+        rtp.extend(self.local_deps.iter().cloned());
+        if let Some(extra_rtp) = &config.settings.extra_rtp {
+            rtp.extend(extra_rtp.iter().map(|entry| expand_path(entry)));
+        }
+        // For test-utils.lua
+        rtp.push(self.cache_dir.clone());
+        rtp
+    }
+}
+
+/// Download test-utils and resolve the test dependencies, cloning or updating the external ones as needed
+pub(crate) fn resolve_dependencies(
+    config: &TestConfig,
+    options: &RunOptions,
+    current_dir: &std::path::Path,
+) -> Result<ResolvedDependencies, RunnerError> {
+    let cache_dir = resolve_cache_dir(options, config);
+    debug!("cache dir: {}", cache_dir.display());
+    let created_cache_dir = !cache_dir.exists();
+
+    // Held until the state is written for the last time, so that concurrent runners sharing the cache dir
+    // do not overwrite each other's state (nor clone into the same directory)
+    let state_lock = lock_state(&cache_dir)?;
+
+    // So that the clones in the cache are not committed by accident. Only when creating the cache dir, so that
+    // a removed .gitignore stays removed
+    if created_cache_dir && !options.no_gitignore {
+        let gitignore = cache_dir.join(".gitignore");
+        if let Err(e) = std::fs::write(&gitignore, "*\n") {
+            warnings::warn(format!("Failed to write {}: {}", gitignore.display(), e));
+        }
+    }
+
+    // Check if state.json exists and is readable and writable, if not readable/writable, throw error
+    let state_path = cache_dir.join("state.json");
+    let state = State::load(&state_path)?;
+
+    let mut new_state: State = state.clone(); // For storing the new state (and we overwrite state.json once in the end)
+
+    let skip_remote_check = resolve_skip_remote_check(options, config)
+        || options.offline
+        || options.frozen
+        || options.check;
+    let offline_mode = if options.frozen {
+        "with --frozen"
+    } else {
+        "in offline mode"
+    };
+
+    let namespace_test_utils = config.settings.namespace_test_utils.unwrap_or(false);
+    let test_utils_path = test_utils_path(&cache_dir, namespace_test_utils);
+
+    if options.offline || options.frozen {
+        if !test_utils_path.exists() {
+            return Err(RunnerError::Network(format!(
+                "test-utils is not cached at {} and cannot be downloaded {}",
+                test_utils_path.display(),
+                offline_mode
+            )));
+        }
+        let cached_version = state
+            .lua_test_utils
+            .as_ref()
+            .map(|lua_test_utils_state| lua_test_utils_state.version.as_str());
+        let cached_protocol = state
+            .lua_test_utils
+            .as_ref()
+            .and_then(|lua_test_utils_state| lua_test_utils_state.protocol.as_deref());
+        let protocol_compatible = cached_protocol
+            .is_some_and(|protocol| is_protocol_compatible(protocol, TEST_UTILS_PROTOCOL_VERSION));
+        // An online run would download test-utils again
+        if options.frozen
+            && (!protocol_compatible || cached_version != Some(env!("CARGO_PKG_VERSION")))
+        {
+            return Err(RunnerError::Network(format!(
+                "Cached test-utils is version {} (protocol {}) but version {} (protocol {}) is expected; it cannot be downloaded with --frozen",
+                cached_version.unwrap_or("unknown"),
+                cached_protocol.unwrap_or("unknown"),
+                env!("CARGO_PKG_VERSION"),
+                TEST_UTILS_PROTOCOL_VERSION
+            )));
+        }
+        if !protocol_compatible {
+            warnings::warn(format!(
+                "Cached test-utils speaks protocol {} but protocol {} is expected; it cannot be upgraded in offline mode",
+                cached_protocol.unwrap_or("unknown"),
+                TEST_UTILS_PROTOCOL_VERSION
+            ));
+        }
+    }
+
+    if !skip_remote_check {
+        // Check if state exists for lua-test-utils. If so, compare against its version with the version of this program.
+        // If they are different, overwrite the state with the new version.
+        // A cached test-utils speaking an incompatible protocol is re-downloaded even if the version matches
+        let version = env!("CARGO_PKG_VERSION");
+        let client = http_client(options.proxy.as_deref())?;
+        // With --refresh-test-utils (or if it was removed, or is namespaced differently), the cached test-utils is
+        // downloaded again as if there were none
+        let cached_test_utils = state
+            .lua_test_utils
+            .as_ref()
+            .filter(|_| !options.refresh_test_utils && test_utils_path.exists());
+        if let Some(lua_test_utils_state) = cached_test_utils {
+            let protocol_compatible =
+                lua_test_utils_state
+                    .protocol
+                    .as_deref()
+                    .is_some_and(|protocol| {
+                        is_protocol_compatible(protocol, TEST_UTILS_PROTOCOL_VERSION)
+                    });
+            if lua_test_utils_state.version != version || !protocol_compatible {
+                println!(
+                    "{}",
+                    Colour::Yellow.paint(format!(
+                        "Upgrading test-utils from version {} (protocol {}) to version {} (protocol {})",
+                        lua_test_utils_state.version,
+                        lua_test_utils_state.protocol.as_deref().unwrap_or("unknown"),
+                        version,
+                        TEST_UTILS_PROTOCOL_VERSION
+                    ))
+                );
+                info!(
+                    "Upgrading test-utils from version {} (protocol {}) to version {} (protocol {})",
+                    lua_test_utils_state.version,
+                    lua_test_utils_state.protocol.as_deref().unwrap_or("unknown"),
+                    version,
+                    TEST_UTILS_PROTOCOL_VERSION
+                );
+
+                let protocol = download_test_utils(&client, version, &test_utils_path)?;
+
+                new_state.lua_test_utils = Some(LuaTestUtilsState {
+                    version: version.to_string(),
+                    protocol: Some(protocol),
+                });
+            }
+        } else {
+            println!(
+                "{}",
+                Colour::Yellow.paint(format!(
+                    "Downloading test-utils.lua into {}",
+                    cache_dir.display()
+                ))
+            );
+            info!("Downloading test-utils.lua into {}", cache_dir.display());
+
+            let protocol = download_test_utils(&client, version, &test_utils_path)?;
+
+            info!(
+                "Downloaded test-utils.lua (protocol {}) into {}",
+                protocol,
+                cache_dir.display()
+            );
+
+            new_state.lua_test_utils = Some(LuaTestUtilsState {
+                version: version.to_string(),
+                protocol: Some(protocol),
+            });
+        }
+
+        new_state.save(&state_path)?;
+    }
+
+    let mut external_deps: Vec<std::path::PathBuf> = Vec::new();
+    let mut dep_resolutions: Vec<(String, DepResolution)> = Vec::new();
+    // The names a test can refer to a resolved dependency by: its uri, normalized uri, and the name of its directory
+    let mut resolved_dep_names: std::collections::HashSet<String> =
+        std::collections::HashSet::new();
+    // With --keep-going, the dependencies that failed to resolve
+    let mut unresolved_deps: Vec<String> = Vec::new();
+    let mut dep_entries: Vec<(std::path::PathBuf, String)> = Vec::new();
+    let git = resolve_git_path(options, config);
+    let resolve_options = ResolveOptions {
+        cache_dir: &cache_dir,
+        skip_remote_check,
+        offline: options.offline,
+        frozen: options.frozen,
+        remote_ttl: options.remote_ttl,
+        explain: options.explain,
+        global_cache: options.global_cache.as_deref(),
+        git: &git,
+        verify_clones: options.verify_deps,
+        repair_clones: options.repair_deps,
+        reference_repo: options.reference_repo.as_deref(),
+        dissociate: options.dissociate,
+        progress: !options.quiet && std::io::IsTerminal::is_terminal(&std::io::stdout()),
+        clone_attempts: config
+            .settings
+            .clone_attempts
+            .unwrap_or(config::DEFAULT_CLONE_ATTEMPTS),
+        proxy: options.proxy.as_deref(),
+    };
+    // The clones of the external dependencies by normalized uri, which the dependencies sharing a uri (e.g.
+    // plugins in different subdirs of a monorepo) share
+    let mut clones: HashMap<String, std::path::PathBuf> = HashMap::new();
+    let mut local_deps: Vec<std::path::PathBuf> = Vec::new();
+    // Local dependencies whose own tests should also be run, along with their test paths
+    let mut local_dep_tests: Vec<(std::path::PathBuf, Vec<String>)> = Vec::new();
+
+    let only_deps: Vec<&str> = options
+        .only_deps
+        .iter()
+        .flat_map(|names| names.split(','))
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty())
+        .collect();
+    let deps = config
+        .settings
+        .test_dependencies
+        .as_deref()
+        .unwrap_or_default();
+    let unknown: Vec<&str> = only_deps
+        .iter()
+        .filter(|name| !deps.iter().any(|dep| dep_has_name(dep, name)))
+        .copied()
+        .collect();
+    if !unknown.is_empty() {
+        return Err(RunnerError::Config(format!(
+            "--only-deps names {} that are not in testDependencies",
+            unknown.join(", ")
+        )));
+    }
+
+    if let Some(deps) = &config.settings.test_dependencies {
+        for dep in deps {
+            debug!(
+                "uri: {}, branch: {}, sha: {}",
+                dep.uri,
+                dep.branch.clone().unwrap_or("<none>".to_string()),
+                dep.sha.clone().unwrap_or("<none>".to_string())
+            );
+
+            if !dep.enabled.unwrap_or(true) {
+                println!(
+                    "{}",
+                    Colour::Yellow.paint(format!("Skipping disabled dependency {}", dep.uri))
+                );
+                info!("Skipping disabled dependency {}", dep.uri);
+                continue;
+            }
+            if !only_deps.is_empty() && !only_deps.iter().any(|name| dep_has_name(dep, name)) {
+                println!(
+                    "{}",
+                    Colour::Yellow.paint(format!(
+                        "Skipping dependency {} (not in --only-deps)",
+                        dep.uri
+                    ))
+                );
+                info!("Skipping dependency {} (not in --only-deps)", dep.uri);
+                continue;
+            }
+
+            // Checks if url starts with "file:", if so, treat it as a local directory
+            if dep.uri.starts_with("file:") {
+                let path = match dep.uri.starts_with("file://") {
+                    true => {
+                        let abs_path = dep.uri.strip_prefix("file://").unwrap();
+                        std::path::PathBuf::from(&abs_path)
+                    }
+                    false => {
+                        let rel_path = dep.uri.strip_prefix("file:").unwrap();
+                        current_dir.join(rel_path)
+                    }
+                };
+
+                if !path.exists() {
+                    warnings::warn(format!("Path {} does not exist, skipping", dep.uri));
+                    continue;
+                }
+                if !path.is_dir() {
+                    warnings::warn(format!(
+                        "{} does not point to a directory, skipping",
+                        dep.uri
+                    ));
+                    continue;
+                }
+
+                info!("Path {} exists", dep.uri);
+                if let Some(dep_test_paths) = &dep.test_paths {
+                    local_dep_tests.push((path.clone(), dep_test_paths.clone()));
+                }
+                let root = match plugin_root(dep, &path) {
+                    Ok(root) => root,
+                    Err(e) => {
+                        warnings::warn(format!("{}, skipping", e));
+                        continue;
+                    }
+                };
+                resolved_dep_names.insert(dep.uri.clone());
+                if let Some(name) = path.file_name() {
+                    resolved_dep_names.insert(name.to_string_lossy().to_string());
+                }
+
+                if !dep.recursive.unwrap_or(false) {
+                    dep_entries.push((root.clone(), dep.uri.clone()));
+                    local_deps.push(root);
+                    continue;
+                }
+                let plugins = match discover_plugins(&root) {
+                    Ok(plugins) => plugins,
+                    Err(e) => {
+                        warnings::warn(format!("{}, skipping", e));
+                        continue;
+                    }
+                };
+                if plugins.is_empty() {
+                    warnings::warn(format!("No plugins found in {}", root.display()));
+                }
+                for plugin in plugins {
+                    info!("Discovered plugin {} in {}", plugin.display(), dep.uri);
+                    if let Some(name) = plugin.file_name() {
+                        resolved_dep_names.insert(name.to_string_lossy().to_string());
+                    }
+                    dep_entries.push((plugin.clone(), dep.uri.clone()));
+                    local_deps.push(plugin);
+                }
+                continue;
+            }
+
+            if dep.recursive.unwrap_or(false) {
+                warnings::warn(format!(
+                    "recursive is only supported for local dependencies, ignoring it for {}",
+                    dep.uri
+                ));
+            }
+
+            if dep.test_paths.is_some() {
+                warnings::warn(format!(
+                    "testPaths is only supported for local dependencies, ignoring it for {}",
+                    dep.uri
+                ));
+            }
+
+            // Treating as external dependency
+
+            // The normalized uri is what gets stored in (and compared against) the state
+            let uri = normalize_uri(&dep.uri);
+            // A branch given by the dependency takes precedence over the default branch of the config
+            let dep = &TestDepedency {
+                branch: dep
+                    .branch
+                    .clone()
+                    .or(config.settings.default_branch.clone()),
+                ..dep.clone()
+            };
+            // Cloned (and reset) once, however many subdirs of it are on the runtimepath
+            let resolved = match clones.get(&uri) {
+                Some(dep_path) => {
+                    info!("Sharing the clone of {} for {}", uri, dep.uri);
+                    plugin_root(dep, dep_path).map(|root| (dep_path.clone(), root, None))
+                }
+                None => resolve_external_dep(dep, &uri, &state, &mut new_state, &resolve_options)
+                    .and_then(|(dep_path, resolution)| {
+                        let root = plugin_root(dep, &dep_path)?;
+                        Ok((dep_path, root, Some(resolution)))
+                    }),
+            };
+            match resolved {
+                Ok((dep_path, root, resolution)) => {
+                    resolved_dep_names.insert(dep.uri.clone());
+                    if let Some(name) = dep_path.file_name() {
+                        resolved_dep_names.insert(name.to_string_lossy().to_string());
+                    }
+                    if let Some(name) = root.file_name() {
+                        resolved_dep_names.insert(name.to_string_lossy().to_string());
+                    }
+                    resolved_dep_names.insert(uri.clone());
+                    if let Some(resolution) = resolution {
+                        dep_resolutions.push((uri.clone(), resolution));
+                    }
+                    clones.insert(uri, dep_path);
+                    dep_entries.push((root.clone(), dep.uri.clone()));
+                    external_deps.push(root);
+                }
+                Err(e) if options.keep_going => {
+                    warnings::warn(format!(
+                        "Failed to resolve test dependency {}, continuing without it: {}",
+                        dep.uri, e
+                    ));
+                    unresolved_deps.push(dep.uri.clone());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    if !dep_resolutions.is_empty() || !local_deps.is_empty() {
+        let count = |resolution: DepResolution| {
+            dep_resolutions
+                .iter()
+                .filter(|(_, r)| *r == resolution)
+                .count()
+        };
+        let message = format!(
+            "{} dep(s) reused, {} updated, {} cloned, {} local",
+            count(DepResolution::Reused),
+            count(DepResolution::Updated),
+            count(DepResolution::Cloned),
+            local_deps.len()
+        );
+        println!("{}", Colour::Blue.paint(&message));
+        info!("{}", message);
+        for (uri, resolution) in &dep_resolutions {
+            debug!("{}: {:?}", uri, resolution);
+        }
+    }
+
+    new_state.save(&state_path)?;
+    // Other runners may use the state from here on
+    drop(state_lock);
+
+    // Recorded with the run for --affected-by-deps, which can do without them
+    let resolved = match Lockfile::resolved(&clones, &new_state, &git) {
+        Ok(resolved) => resolved,
+        Err(e) if !options.locked && !options.write_lockfile => {
+            warn!("Failed to find the revisions of the dependencies: {}", e);
+            Lockfile::default()
+        }
+        Err(e) => return Err(e),
+    };
+    if options.write_lockfile {
+        resolved.write(std::path::Path::new(lockfile::LOCKFILE_PATH))?;
+    } else if options.locked {
+        // Dependencies left out of the run are not resolved, and so cannot be checked
+        let partial = !only_deps.is_empty() || !unresolved_deps.is_empty();
+        Lockfile::load(std::path::Path::new(lockfile::LOCKFILE_PATH))?
+            .verify(&resolved, partial)?;
+    }
+
+    let installed_plugin = match options.installed {
+        true => {
+            // Named after the plugin, as a plugin manager would
+            let name = current_dir
+                .file_name()
+                .map(|name| name.to_os_string())
+                .unwrap_or_else(|| "plugin".into());
+            let install_dir = cache_dir.join("install").join(name);
+            let num_files = install::install_plugin(
+                current_dir,
+                &install_dir,
+                config.settings.install_files.as_ref(),
+            )?;
+            let message = format!(
+                "Installed the plugin ({} file(s)) into {}",
+                num_files,
+                install_dir.display()
+            );
+            println!("{}", Colour::Blue.paint(&message));
+            info!("{}", message);
+            Some(install_dir)
+        }
+        false => None,
+    };
+
+    Ok(ResolvedDependencies {
+        cache_dir,
+        namespace_test_utils,
+        external_deps,
+        local_deps,
+        local_dep_tests,
+        resolved_dep_names,
+        unresolved_deps,
+        dep_entries,
+        installed_plugin,
+        dep_revisions: resolved.dependencies,
+    })
+}
+
+/// Like [`run`], but reports the results to the given reporters
+pub fn run_with_reporters(
+    config: TestConfig,
+    options: RunOptions,
+    reporters: &[Box<dyn Reporter>],
+) -> Result<RunSummary, RunnerError> {
+    let current_dir = std::env::current_dir()?;
+
+    let setup = validate_run(&config, &options)?;
+    let resolved = resolve_dependencies(&config, &options, &current_dir)?;
+    let rtp = resolved.runtimepath(&config);
+    let mut selected = select_tests(&config, &options, &current_dir, &resolved)?;
+
+    debug!("runtimepath: {:?}", rtp);
+
+    let nvim_args = config.settings.nvim_args.as_deref().unwrap_or_default();
+    debug!("extra nvim args: {:?}", nvim_args);
+
+    if options.verbose || options.dry_run {
+        println!("Runtimepath:");
+        for entry in &rtp {
+            println!("  {}", entry.display());
+        }
+        if !nvim_args.is_empty() {
+            println!("Extra nvim args: {:?}", nvim_args);
+        }
+        if !setup.secrets.is_empty() {
+            let names: Vec<&str> = setup.secrets.keys().map(String::as_str).collect();
+            println!("Secrets: {}", names.join(", "));
+        }
+    }
+
+    if options.dry_run {
+        println!("Tests:");
+        for test in &selected.runnable_files {
+            match &test.cwd {
+                Some(cwd) => println!("  {} (in {})", test.path.display(), cwd.display()),
+                None => println!("  {}", test.path.display()),
+            }
+        }
+        return Ok(RunSummary::new(selected.test_results, Duration::ZERO));
+    }
+
+    if options.check {
+        return check_tests(&config, selected, reporters);
+    }
+
+    // A broken test-utils would fail every test with a confusing error
+    if !selected.runnable_files.is_empty() {
+        validate_test_utils(
+            &resolved.cache_dir,
+            resolved.namespace_test_utils,
+            config.settings.runner_wrapper.as_deref(),
+        )?;
+        // Otherwise every batch would fail on its first line
+        if options.batch.is_some() {
+            check_batch_support(&resolved.cache_dir, resolved.namespace_test_utils)?;
+        }
+    }
+
+    if let Some(before_all) = &config.settings.before_all {
+        run_hook("beforeAll", before_all)?;
+    }
+
+    let runner = TestRunner::new(
+        &config,
+        &options,
+        &setup,
+        &current_dir,
+        &resolved,
+        rtp,
+        &selected.runnable_files,
+    )?;
+    let mut executed = execute_tests(&runner, &mut selected, reporters)?;
+    let outcome = summarize_run(&runner, &resolved, &mut selected, &mut executed)?;
+    report_run(&runner, &selected, &executed, &outcome, reporters);
+
+    Ok(outcome.summary)
+}
+
+/// What a run was checked to need by [`validate_run`]
+struct RunSetup {
+    baseline_settings: BaselineSettings,
+    /// With --baseline, the baseline the run is compared to
+    baseline: Option<Baseline>,
+    error_patterns: Vec<Regex>,
+    /// The secrets of the config and of --secret, by name
+    secrets: BTreeMap<String, String>,
+}
+
+/// Check the config and the options, and that nvim can be run, before anything is downloaded or cloned. Also
+/// sets the limits of the processes the tests run in
+fn validate_run(config: &TestConfig, options: &RunOptions) -> Result<RunSetup, RunnerError> {
+    check_config(config)?;
+
+    if options.repeat == 0 {
+        return Err(RunnerError::Config(
+            "--repeat has to be at least 1".to_string(),
+        ));
+    }
+    if options.batch.is_some() && !config.settings.test_utils_require.unwrap_or(true) {
+        return Err(RunnerError::Config(
+            "--batch runs the tests with test-utils, which testUtilsRequire disables".to_string(),
+        ));
+    }
+    if options.installed && !config.settings.add_cwd_to_rtp.unwrap_or(true) {
+        return Err(RunnerError::Config(
+            "--installed puts the installed plugin on the runtimepath in place of the current directory, \
+             which addCwdToRtp disables"
+                .to_string(),
+        ));
+    }
+    // Every test would be skipped, as none failed yet
+    if options.bail == Some(0) {
+        return Err(RunnerError::Config(
+            "--bail has to be at least 1".to_string(),
+        ));
+    }
+    if options.max_processes == Some(0) {
+        return Err(RunnerError::Config(
+            "--max-processes has to be at least 1".to_string(),
+        ));
+    }
+    if options.ramp_up_start == Some(0) {
+        return Err(RunnerError::Config(
+            "--ramp-up-start has to be at least 1".to_string(),
+        ));
+    }
+    let baseline_settings = BaselineSettings::new(config);
+    if (options.baseline.is_some() || options.save_baseline.is_some())
+        && options.repeat < baseline_settings.samples
+    {
+        return Err(RunnerError::Config(format!(
+            "--baseline and --save-baseline take the median of {} durations of each test (baselineSamples), \
+             run with --repeat {}",
+            baseline_settings.samples, baseline_settings.samples
+        )));
+    }
+    // Loaded before the suite runs, so that a missing baseline does not take a whole run to notice
+    let baseline = match &options.baseline {
+        Some(name) => Some(Baseline::load(&resolve_cache_dir(options, config), name)?),
+        None => None,
+    };
+    interrupt::set_max_processes(
+        options
+            .max_processes
+            .unwrap_or(interrupt::DEFAULT_MAX_PROCESSES),
+    );
+    interrupt::set_max_output(options.max_output.unwrap_or(interrupt::DEFAULT_MAX_OUTPUT));
+    // Checked by validate_config
+    let error_patterns: Vec<Regex> = match &config.settings.error_patterns {
+        Some(patterns) => patterns.iter().map(String::as_str).collect::<Vec<_>>(),
+        None => config::DEFAULT_ERROR_PATTERNS.to_vec(),
+    }
+    .into_iter()
+    .filter_map(|pattern| Regex::new(pattern).ok())
+    .collect();
+    interrupt::set_error_patterns(error_patterns.clone());
+    // Read before anything is logged that could contain them
+    let secrets = secrets::resolve_secrets(config, &options.secrets)?;
+
+    check_nvim(config, &resolve_cache_dir(options, config))?;
+
+    Ok(RunSetup {
+        baseline_settings,
+        baseline,
+        error_patterns,
+        secrets,
+    })
+}
+
+/// The tests of a run, selected by [`select_tests`]
+struct SelectedTests {
+    /// The tests to run, with their directives
+    runnable_files: Vec<TestFile>,
+    /// The results of the tests, starting with those that are not run (e.g. skipped by their directives)
+    test_results: Vec<TestResult>,
+    /// The number of test files matched, before the filters. The tests left out from there are counted as
+    /// filtered
+    num_matched: usize,
+    /// The outcomes of the previous runs
+    results_cache: ResultsCache,
+    results_cache_path: PathBuf,
+}
+
+/// Find the test files (with the discovery command, or the test paths and the tests of local dependencies), read
+/// their directives, and filter them with the options, e.g. --shard, --tags and --failed. Fails if the number of
+/// files matched is not what --expect-tests or --min-tests expect, or if none matched with --fail-on-empty
+fn select_tests(
+    config: &TestConfig,
+    options: &RunOptions,
+    current_dir: &std::path::Path,
+    resolved: &ResolvedDependencies,
+) -> Result<SelectedTests, RunnerError> {
+    // Only the patterns given explicitly are expected to match something
+    let explicit_test_paths = config.settings.test_paths.is_some();
+    // The discovery command replaces the globs of the test paths
+    let discovered_files = match &config.settings.discovery_command {
+        Some(command) => {
+            info!("Discovering the test files with {}", command);
+            Some(run_discovery_command(command)?)
+        }
+        None => None,
+    };
+    let test_paths = match discovered_files {
+        Some(_) => Vec::new(),
+        None => resolve_test_paths(options, config),
+    };
+
+    for path in &test_paths {
+        debug!("test path: {}", path);
+    }
+
+    let mut matched_files: Vec<TestFile> = Vec::new();
+
+    for path in discovered_files.iter().flatten() {
+        debug!("Discovered test file: {:?}", path.display());
+        if !path.exists() {
+            warnings::warn(format!(
+                "The discovery command printed {}, which does not exist",
+                path.display()
+            ));
+            continue;
+        }
+        matched_files.push(TestFile {
+            path: path.clone(),
+            cwd: None,
+            directives: TestDirectives::default(),
+            env: BTreeMap::new(),
+        });
+    }
+
+    for path in &test_paths {
+        let mut num_matches = 0;
+        for entry in glob(path)
+            .map_err(|e| RunnerError::Config(format!("Invalid test path {}: {}", path, e)))?
+        {
+            match entry {
+                Ok(path) => {
+                    debug!("Matched test file: {:?}", path.display());
+                    num_matches += 1;
+                    matched_files.push(TestFile {
+                        path,
+                        cwd: None,
+                        directives: TestDirectives::default(),
+                        env: BTreeMap::new(),
+                    });
+                }
+                Err(e) => error!("Error with matched file {}: {:?}", path, e),
+            }
+        }
+        if num_matches == 0 && explicit_test_paths {
+            warnings::warn(format!("Test path {} did not match any file", path));
+        }
+    }
+
+    // Tests of local dependencies are run with the dependency as the working directory
+    for (dep_path, dep_test_paths) in &resolved.local_dep_tests {
+        for dep_test_path in dep_test_paths {
+            let pattern = dep_path.join(dep_test_path);
+            let pattern = pattern.to_string_lossy();
+            for entry in glob(&pattern)
+                .map_err(|e| RunnerError::Config(format!("Invalid test path {}: {}", pattern, e)))?
+            {
+                match entry {
+                    Ok(path) => {
+                        debug!("Matched dependency test file: {:?}", path.display());
+                        matched_files.push(TestFile {
+                            path,
+                            cwd: Some(dep_path.clone()),
+                            directives: TestDirectives::default(),
+                            env: BTreeMap::new(),
+                        });
+                    }
+                    Err(e) => error!("Error with matched file {}: {:?}", pattern, e),
+                }
+            }
+        }
+    }
+
+    // A safety net against test paths that silently stopped matching part of the suite
+    if let Some(expected) = options.expect_tests {
+        if matched_files.len() != expected {
+            return Err(RunnerError::TestFailures(format!(
+                "Expected {} test file(s), but {} matched the test paths",
+                expected,
+                matched_files.len()
+            )));
+        }
+    }
+    if let Some(min) = options.min_tests {
+        if matched_files.len() < min {
+            return Err(RunnerError::TestFailures(format!(
+                "Expected at least {} test file(s), but {} matched the test paths",
+                min,
+                matched_files.len()
+            )));
+        }
+    }
+
+    if matched_files.is_empty() && discovered_files.is_some() {
+        if options.fail_on_empty {
+            return Err(RunnerError::TestFailures(
+                "The discovery command printed no test files".to_string(),
+            ));
+        }
+        warnings::warn("The discovery command printed no test files");
+    } else if matched_files.is_empty() && test_paths.is_empty() {
+        if options.fail_on_empty {
+            return Err(RunnerError::TestFailures(
+                "No test paths given, and the default test paths are disabled".to_string(),
+            ));
+        }
+        warnings::warn(
+            "No test paths given, and the default test paths are disabled. Set the testPaths config field to run tests",
+        );
+    } else if matched_files.is_empty() {
+        if options.fail_on_empty {
+            return Err(RunnerError::TestFailures(format!(
+                "No test files matched the test paths {}",
+                test_paths.join(", ")
+            )));
+        }
+        // Otherwise an empty run would look like a passing one
+        warnings::warn(format!(
+            "No test files matched the test paths {}. Check the testPaths config field, or pass --fail-on-empty to fail in this case",
+            test_paths.join(", ")
+        ));
+    }
+
+    // The tests left out from here on are counted as filtered
+    let num_matched = matched_files.len();
+
+    // The shards partition all matched files, regardless of the other filters. The files are sorted by their path
+    // relative to the current directory, so that the partition is the same on every machine
+    if let Some(shard) = options.shard {
+        let relative_path = |test: &TestFile| {
+            let path = test.path.strip_prefix(current_dir).unwrap_or(&test.path);
+            path.strip_prefix(".").unwrap_or(path).to_path_buf()
+        };
+        let mut paths: Vec<PathBuf> = matched_files.iter().map(relative_path).collect();
+        paths.sort();
+        matched_files.retain(|test| {
+            let path = relative_path(test);
+            paths
+                .binary_search(&path)
+                .is_ok_and(|position| shard.contains(position))
+        });
+        info!(
+            "Shard {}/{}: {} of {} test file(s)",
+            shard.index,
+            shard.total,
+            matched_files.len(),
+            num_matched
+        );
+    }
+
+    if options.changed {
+        match git_changed_files(&resolve_git_path(options, config), &options.changed_base)? {
+            Some(changed_files) => {
+                debug!("Changed files: {:?}", changed_files);
+                let changed_dirs: Vec<&std::path::Path> =
+                    changed_files.iter().filter_map(|f| f.parent()).collect();
+                matched_files.retain(|test| {
+                    // Compare paths relative to the current directory, as git reports them
+                    let path = test.path.strip_prefix(current_dir).unwrap_or(&test.path);
+                    let path = path.strip_prefix(".").unwrap_or(path);
+                    changed_files.iter().any(|f| f == path)
+                        || (options.changed_dirs
+                            && changed_dirs.iter().any(|d| path.parent() == Some(d)))
+                });
+                info!(
+                    "{} test file(s) changed compared to {}",
+                    matched_files.len(),
+                    options.changed_base
+                );
+            }
+            None => {
+                warnings::warn("Not in a git repository, ignoring --changed and running all tests");
+            }
+        }
+    }
+
+    if let Some(since) = options.since {
+        let cutoff = std::time::SystemTime::now()
+            .checked_sub(since)
+            .unwrap_or(std::time::UNIX_EPOCH);
+        matched_files.retain(|test| {
+            std::fs::metadata(&test.path)
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| modified > cutoff)
+        });
+        info!(
+            "{} test file(s) modified in the last {:?}",
+            matched_files.len(),
+            since
+        );
+    }
+
+    // Files might have been removed (or have their permissions changed) since they were matched.
+    // Filter such files out beforehand so that they are not reported as test failures.
+    // The directives are read from their headers at the same time
+    let mut test_results: Vec<TestResult> = Vec::new();
+    let mut runnable_files: Vec<TestFile> = Vec::new();
+
+    for mut test in matched_files {
+        match TestDirectives::read(&test.path) {
+            Ok(directives) => {
+                for error in &directives.errors {
+                    warnings::warn(format!("{}: {}", test.path.display(), error));
+                }
+                test.directives = directives;
+                test.apply_overrides(config.settings.overrides.as_deref().unwrap_or_default());
+                if let Some(cwd) = &test.directives.cwd {
+                    let cwd = current_dir.join(expand_path(cwd));
+                    if cwd.is_dir() {
+                        test.cwd = Some(cwd);
+                    } else {
+                        warnings::warn(format!(
+                            "{}: ignoring the cwd directive, {} is not a directory",
+                            test.path.display(),
+                            cwd.display()
+                        ));
+                    }
+                }
+                // The tests of local dependencies run in the dependency otherwise
+                if let (None, Some(test_cwd)) = (&test.cwd, &config.settings.test_cwd) {
+                    test.cwd = Some(current_dir.join(expand_path(test_cwd)));
+                }
+                runnable_files.push(test);
+            }
+            Err(e) => {
+                warnings::warn(format!(
+                    "Skipping unreadable test file {}: {}",
+                    test.path.display(),
+                    e
+                ));
+                test_results.push(TestResult::skipped(test.path, format!("unreadable: {}", e)));
+            }
+        }
+    }
+
+    // Select tests by their tags. Tests that are not selected are left out of the run entirely
+    if options.tags.is_some() || options.exclude_tags.is_some() {
+        let include = options.tags.as_deref().map(parse_tags);
+        let exclude = options
+            .exclude_tags
+            .as_deref()
+            .map(parse_tags)
+            .unwrap_or_default();
+        runnable_files.retain(|test| {
+            let tags = &test.directives.tags;
+            let included = include
+                .as_ref()
+                .is_none_or(|include| include.iter().any(|tag| tags.contains(tag)));
+            let excluded = exclude.iter().any(|tag| tags.contains(tag));
+            included && !excluded
+        });
+        info!("{} test file(s) selected by tags", runnable_files.len());
+    }
+
+    // Skip tests that are marked as skipped in their header
+    if !options.run_skipped {
+        runnable_files.retain(|test| {
+            let Some(reason) = &test.directives.skip else {
+                return true;
+            };
+            let reason = match reason.as_str() {
+                "" => "skip directive".to_string(),
+                reason => reason.to_string(),
+            };
+            println!(
+                "{}",
+                Colour::Yellow.paint(format!("⊘ {} (skipped, {})", test.path.display(), reason))
+            );
+            info!("Skipping test file {}: {}", test.path.display(), reason);
+            test_results.push(TestResult::skipped(test.path.clone(), reason));
+            false
+        });
+    }
+
+    // Skip tests that require a dependency that is not resolved
+    runnable_files.retain(|test| {
+        let missing: Vec<&str> = test
+            .directives
+            .requires
+            .iter()
+            .filter(|name| !resolved.resolved_dep_names.contains(name.as_str()))
+            .map(|name| name.as_str())
+            .collect();
+        if missing.is_empty() {
+            return true;
+        }
+        let reason = format!("requires unresolved dependency {}", missing.join(", "));
+        warnings::warn(format!(
+            "Skipping test file {}: {}",
+            test.path.display(),
+            reason
+        ));
+        test_results.push(TestResult::skipped(test.path.clone(), reason));
+        false
+    });
+
+    // The outcomes of previous runs are always recorded, but only used with --resume
+    let results_cache_path = resolved.cache_dir.join("results.json");
+    let results_cache = ResultsCache::load(&results_cache_path);
+    if options.resume {
+        let num_runnable = runnable_files.len();
+        runnable_files.retain(|test| {
+            if !results_cache.passed_unchanged(&test.path) {
+                return true;
+            }
+            test_results.push(TestResult::skipped(
+                test.path.clone(),
+                "passed in the previous run".to_string(),
+            ));
+            false
+        });
+        let num_resumed = num_runnable - runnable_files.len();
+        if num_resumed > 0 {
+            println!(
+                "{}",
+                Colour::Yellow.paint(format!(
+                    "Resuming: {} test(s) passed in the previous run and are not run again",
+                    num_resumed
+                ))
+            );
+            info!(
+                "Resuming: {} test(s) passed in the previous run and are not run again",
+                num_resumed
+            );
+        }
+    }
+
+    if options.failed {
+        match &results_cache.last_run {
+            Some(last_run) => {
+                let failed: Vec<&std::path::Path> = last_run
+                    .results
+                    .iter()
+                    .filter(|result| result.outcome.is_failure())
+                    .map(|result| result.path.as_path())
+                    .collect();
+                runnable_files.retain(|test| {
+                    if failed.contains(&test.path.as_path()) {
+                        return true;
+                    }
+                    test_results.push(TestResult::skipped(
+                        test.path.clone(),
+                        "did not fail in the last run".to_string(),
+                    ));
+                    false
+                });
+                let message = match runnable_files.len() {
+                    0 => "No tests failed in the last run".to_string(),
+                    num => format!("Running the {} test(s) that failed in the last run", num),
+                };
+                println!("{}", Colour::Yellow.paint(&message));
+                info!("{}", message);
+            }
+            None => {
+                println!(
+                    "{}",
+                    Colour::Yellow
+                        .paint("There is no previous run for --failed, running all the tests")
+                );
+                warn!("There is no previous run for --failed, running all the tests");
+            }
+        }
+    }
+
+    // Tests that do not give the dependencies they require may use any of them, so they always run
+    if options.affected_by_deps {
+        if results_cache.dependencies.is_empty() {
+            println!(
+                "{}",
+                Colour::Yellow.paint(
+                    "No previous run recorded the dependencies for --affected-by-deps, running all the tests"
+                )
+            );
+            warn!("No previous run recorded the dependencies for --affected-by-deps, running all the tests");
+        } else {
+            let changed: Vec<&String> = resolved
+                .dep_revisions
+                .iter()
+                .filter(|(uri, revision)| results_cache.dependencies.get(*uri) != Some(revision))
+                .map(|(uri, _)| uri)
+                .collect();
+            let changed_deps: Vec<&TestDepedency> = config
+                .settings
+                .test_dependencies
+                .iter()
+                .flatten()
+                .filter(|dep| changed.contains(&&normalize_uri(&dep.uri)))
+                .collect();
+            runnable_files.retain(|test| {
+                let requires = &test.directives.requires;
+                if requires.is_empty()
+                    || requires
+                        .iter()
+                        .any(|name| changed_deps.iter().any(|dep| dep_has_name(dep, name)))
+                {
+                    return true;
+                }
+                test_results.push(TestResult::skipped(
+                    test.path.clone(),
+                    "none of its required dependencies changed".to_string(),
+                ));
+                false
+            });
+            let message = match changed.as_slice() {
+                [] => format!(
+                    "No dependencies changed since the last run, running the {} test(s) that do not give their dependencies",
+                    runnable_files.len()
+                ),
+                changed => format!(
+                    "Running the {} test(s) that may be affected by the changed dependencies: {}",
+                    runnable_files.len(),
+                    changed
+                        .iter()
+                        .map(|uri| uri.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            };
+            println!("{}", Colour::Yellow.paint(&message));
+            info!("{}", message);
+        }
+    }
+
+    Ok(SelectedTests {
+        runnable_files,
+        test_results,
+        num_matched,
+        results_cache,
+        results_cache_path,
+    })
+}
+
+/// Check the syntax of the selected tests with --check, in a single nvim for all the files, without test-utils or
+/// the hooks. The results are not recorded, as the tests did not run. The nvim exited normally, so the errors are
+/// reported as failures of the tests rather than of nvim
+fn check_tests(
+    config: &TestConfig,
+    selected: SelectedTests,
+    reporters: &[Box<dyn Reporter>],
+) -> Result<RunSummary, RunnerError> {
+    let SelectedTests {
+        runnable_files,
+        mut test_results,
+        ..
+    } = selected;
+    let check_start = Instant::now();
+    let test_paths: Vec<PathBuf> = runnable_files
+        .iter()
+        .map(|test| test.path.clone())
+        .collect();
+    let errors = check_syntax(&test_paths, config.settings.runner_wrapper.as_deref())?;
+    for reporter in reporters {
+        reporter.on_run_start(&runnable_files);
+    }
+    for test in &runnable_files {
+        let result = match errors.get(&test.path) {
+            Some(error) => TestResult {
+                outcome: TestOutcome::Failed,
+                exit_code: Some(0),
+                report: TestUtilsReport {
+                    failures: vec![syntax_failure(error)],
+                    ..TestUtilsReport::default()
+                },
+                ..TestResult::skipped(test.path.clone(), String::new())
+            },
+            None => TestResult {
+                outcome: TestOutcome::Passed,
+                exit_code: Some(0),
+                ..TestResult::skipped(test.path.clone(), String::new())
+            },
+        };
+        for reporter in reporters {
+            reporter.on_test_complete(&result);
+        }
+        test_results.push(result);
+    }
+    let summary = RunSummary::new(test_results, check_start.elapsed());
+    for reporter in reporters {
+        reporter.on_run_complete(&summary);
+    }
+    print_outcome(&summary);
+    Ok(summary)
+}
+
+/// Runs the tests of a run in nvim, with the runtimepath, the secrets and the options of the run. Shared by the
+/// threads the tests run on
+struct TestRunner<'a> {
+    config: &'a TestConfig,
+    options: &'a RunOptions,
+    setup: &'a RunSetup,
+    current_dir: &'a std::path::Path,
+    cache_dir: PathBuf,
+    rtp: Vec<PathBuf>,
+    check_unused_deps: bool,
+    require_test_utils: bool,
+    /// The command requiring test-utils, before the init file with --cmd by default, or after it with -c
+    test_utils_require: String,
+    /// The coverage of each nvim process is written into a file of its own, merged once the run has finished
+    coverage_dir: PathBuf,
+    num_coverage_files: AtomicUsize,
+    /// Set for the debug run of the failed tests with `debug_failures`
+    debugging: AtomicBool,
+    /// Absolute, as the tests may run in other directories
+    result_dir: PathBuf,
+    /// Absolute, as the cache directory is excluded by its path
+    leak_dir: PathBuf,
+    leak_excluded: [PathBuf; 1],
+    /// The commits the external dependencies were resolved to, for the reproduction bundles
+    recorded_deps: Vec<RecordedDependency>,
+    group_limits: GroupLimits,
+    serial_groups: GroupLimits,
+}
+
+impl<'a> TestRunner<'a> {
+    /// Create the directories the tests write their results (and coverage) into
+    fn new(
+        config: &'a TestConfig,
+        options: &'a RunOptions,
+        setup: &'a RunSetup,
+        current_dir: &'a std::path::Path,
+        resolved: &ResolvedDependencies,
+        rtp: Vec<PathBuf>,
+        runnable_files: &[TestFile],
+    ) -> Result<TestRunner<'a>, RunnerError> {
+        let cache_dir = resolved.cache_dir.clone();
+
+        let coverage_dir =
+            std::env::temp_dir().join(format!("nvim-test-runner-{}-coverage", std::process::id()));
+        if options.coverage {
+            std::fs::create_dir_all(&coverage_dir).map_err(|e| {
+                RunnerError::io(format!("Failed to create {}", coverage_dir.display()), e)
+            })?;
+        }
+
+        let result_dir = std::path::absolute(
+            options
+                .result_dir
+                .clone()
+                .unwrap_or_else(std::env::temp_dir),
+        )
+        .map_err(|e| RunnerError::io("Failed to resolve the result directory", e))?;
+        std::fs::create_dir_all(&result_dir).map_err(|e| {
+            RunnerError::io(format!("Failed to create {}", result_dir.display()), e)
+        })?;
+
+        let leak_dir =
+            std::path::absolute(options.leak_dir.clone().unwrap_or_else(std::env::temp_dir))
+                .map_err(|e| {
+                    RunnerError::io("Failed to resolve the directory for --detect-leaks", e)
+                })?;
+        let leak_excluded = [std::path::absolute(&cache_dir)
+            .map_err(|e| RunnerError::io("Failed to resolve the cache directory", e))?];
+
+        let recorded_deps: Vec<RecordedDependency> = match &options.record_failures {
+            Some(_) => std::fs::read_to_string(cache_dir.join("state.json"))
+                .ok()
+                .and_then(|contents| serde_json::from_str::<State>(&contents).ok())
+                .map(|state| {
+                    state
+                        .test_dependencies
+                        .into_iter()
+                        .map(|dep| RecordedDependency {
+                            uri: dep.uri,
+                            sha: dep.hash,
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            None => Vec::new(),
+        };
+
+        let group_limits = GroupLimits::new(
+            &config
+                .settings
+                .concurrency_groups
+                .clone()
+                .unwrap_or_default(),
+        );
+        let serial_groups = GroupLimits::new(
+            &runnable_files
+                .iter()
+                .filter_map(|test_file| test_file.directives.serial_group.clone())
+                .map(|group| (group, 1))
+                .collect(),
+        );
+        let mut unlimited_groups: Vec<&str> = runnable_files
+            .iter()
+            .filter_map(|test_file| test_file.directives.group.as_deref())
+            .filter(|group| !group_limits.contains(group))
+            .collect();
+        unlimited_groups.sort_unstable();
+        unlimited_groups.dedup();
+        for group in unlimited_groups {
+            warn!(
+                "Concurrency group {} is not in concurrencyGroups, its tests are not limited",
+                group
+            );
+        }
+
+        Ok(TestRunner {
+            config,
+            options,
+            setup,
+            current_dir,
+            cache_dir,
+            rtp,
+            check_unused_deps: options.check_unused_deps || options.fail_on_unused_deps,
+            require_test_utils: config.settings.test_utils_require.unwrap_or(true),
+            test_utils_require: format!(
+                "lua require(\"{}\")",
+                test_utils_module(resolved.namespace_test_utils)
+            ),
+            coverage_dir,
+            num_coverage_files: AtomicUsize::new(0),
+            debugging: AtomicBool::new(false),
+            result_dir,
+            leak_dir,
+            leak_excluded,
+            recorded_deps,
+            group_limits,
+            serial_groups,
+        })
+    }
+
+    fn require_after_init(&self, cmd: &mut Command) {
+        if self.require_test_utils && self.config.settings.test_utils_after_init.unwrap_or(false) {
+            cmd.arg("-c").arg(&self.test_utils_require);
+        }
+    }
+
+    /// The nvim command to run tests in the given directory with, up to the files to load
+    fn nvim_command(&self, cwd: Option<&std::path::PathBuf>) -> Command {
+        let config = self.config;
+        let options = self.options;
+        let mut cmd = new_command(config.settings.runner_wrapper.as_deref());
+        if self.debugging.load(Ordering::SeqCst) {
+            cmd.arg("-V1").env("NVIM_TEST_RUNNER_DEBUG", "1");
+        }
+        // With an init file, the plugin scripts are loaded as in a real startup
+        if config.settings.init_file.is_none() {
+            cmd.arg("--noplugin");
+        }
+        cmd.arg("--headless")
+            // Disable backup and swap
+            .arg("--cmd")
+            .arg("set nobackup nowritebackup noswapfile")
+            // Prevent shada files from being generated or read
+            .arg("--cmd")
+            .arg("set shada=\"NONE\"")
+            // Disable viminfo
+            .arg("-i")
+            .arg("NONE");
+
+        // Add plugin, its dependencies and test-utils.lua to runtimepath
+        // Using --cmd to run vim scripts before the test file is loaded
+        for entry in &self.rtp {
+            // Relative entries have to be resolved if the test is run from another directory
+            let entry = match cwd {
+                Some(_) if entry.as_os_str() == "." => self.current_dir.to_path_buf(),
+                Some(_) => self.current_dir.join(entry),
+                None => entry.clone(),
+            };
+            cmd.arg("--cmd").arg(set_rtp_command(&entry));
+        }
+        if self.require_test_utils && !config.settings.test_utils_after_init.unwrap_or(false) {
+            cmd.arg("--cmd").arg(&self.test_utils_require);
+        }
+        cmd.args(config.settings.nvim_args.as_deref().unwrap_or_default());
+
+        if let Some(cwd) = cwd {
+            cmd.current_dir(cwd);
+        }
+
+        if options.update {
+            cmd.env("NVIM_TEST_RUNNER_UPDATE_SNAPSHOTS", "1");
+        }
+        if let Some(grep) = &options.grep {
+            cmd.env("NVIM_TEST_RUNNER_GREP", grep);
+        }
+        if let Some(plugin_module) = &config.settings.plugin_module {
+            cmd.env("NVIM_TEST_RUNNER_PLUGIN_MODULE", plugin_module);
+        }
+        if self.check_unused_deps {
+            cmd.env("NVIM_TEST_RUNNER_REPORT_REQUIRES", "1");
+        }
+        if config.settings.capture_messages.unwrap_or(true) {
+            cmd.env("NVIM_TEST_RUNNER_CAPTURE_MESSAGES", "1");
+        }
+        if options.coverage {
+            let index = self.num_coverage_files.fetch_add(1, Ordering::SeqCst);
+            cmd.env(
+                "NVIM_TEST_RUNNER_COVERAGE_FILE",
+                self.coverage_dir.join(format!("{}.out", index)),
+            );
+        }
+        cmd
+    }
+
+    /// The init file nvim is started with, resolved against the directory the tests are run in
+    fn init_file(&self, cwd: Option<&std::path::PathBuf>) -> Option<PathBuf> {
+        self.config
+            .settings
+            .init_file
+            .as_ref()
+            .map(|init_file| match cwd {
+                Some(_) => self.current_dir.join(init_file),
+                None => std::path::PathBuf::from(init_file),
+            })
+    }
+
+    fn take_report(&self, report_path: &std::path::Path) -> TestUtilsReport {
+        let mut report = if self.options.keep_results {
+            TestUtilsReport::read(report_path)
+        } else {
+            TestUtilsReport::take(report_path)
+        };
+        for message in &mut report.messages {
+            *message = redact_secrets(message).into_owned();
+        }
+        report
+    }
+
+    /// Run the command, with a fresh HOME if isolated. Returns its output, how long it took, and with
+    /// --detect-leaks the files it left behind
+    fn run_nvim(
+        &self,
+        index: usize,
+        cmd: &mut Command,
+        timeout: Option<Duration>,
+    ) -> Result<(interrupt::Captured, Duration, Vec<PathBuf>), String> {
+        let options = self.options;
+        // A fresh HOME keeps the user's config, data and state out of the test, and the tests apart
+        let isolated_home = std::env::temp_dir().join(format!(
+            "nvim-test-runner-{}-{}-home",
+            std::process::id(),
+            index
+        ));
+        if options.isolate {
+            let _ = std::fs::remove_dir_all(&isolated_home);
+            for (var, dir) in [
+                ("XDG_CONFIG_HOME", ".config"),
+                ("XDG_DATA_HOME", ".local/share"),
+                ("XDG_STATE_HOME", ".local/state"),
+                ("XDG_CACHE_HOME", ".cache"),
+            ] {
+                let path = isolated_home.join(dir);
+                if let Err(e) = std::fs::create_dir_all(&path) {
+                    warn!("Failed to create {}: {}", path.display(), e);
+                }
+                cmd.env(var, path);
+            }
+            cmd.env("HOME", &isolated_home);
+        }
+
+        for (name, value) in &self.setup.secrets {
+            cmd.env(name, value);
+        }
+
+        debug!("Running command: {:?}", cmd);
+
+        let before = options
+            .detect_leaks
+            .then(|| leaks::snapshot(&self.leak_dir, &self.leak_excluded));
+        let start = Instant::now();
+        let captured = interrupt::output_with_deadline(cmd, timeout);
+        let duration = start.elapsed();
+
+        if options.isolate {
+            if let Err(e) = std::fs::remove_dir_all(&isolated_home) {
+                warn!("Failed to clean up {}: {}", isolated_home.display(), e);
+            }
+        }
+        let captured = captured.map_err(|e| {
+            let reason = format!(
+                "Failed to run {}: {}",
+                describe_command(self.config.settings.runner_wrapper.as_deref()),
+                e
+            );
+            error!("{}", reason);
+            reason
+        })?;
+        let leaked_files = match &before {
+            Some(before) => {
+                leaks::leaked_files(before, leaks::snapshot(&self.leak_dir, &self.leak_excluded))
+            }
+            None => Vec::new(),
+        };
+        Ok((captured, duration, leaked_files))
+    }
+
+    /// Run a single test file in nvim
+    fn run_test(&self, index: usize, test_file: &TestFile) -> TestResult {
+        let config = self.config;
+        let error_patterns = &self.setup.error_patterns;
+        let test = &test_file.path;
+
+        let report_path = self.result_dir.join(format!(
+            "nvim-test-runner-{}-{}.json",
+            std::process::id(),
+            index
+        ));
+        let _ = std::fs::remove_file(&report_path);
+
+        let mut cmd = self.nvim_command(test_file.cwd.as_ref());
+        cmd.envs(&test_file.env);
+        // Relative to the current directory, which is not the one nvim runs in with a working directory
+        let test_arg = match &test_file.cwd {
+            Some(_) => self.current_dir.join(test),
+            None => test.clone(),
+        };
+        let escaped_test = escape_file_name(&test_arg);
+        match self.init_file(test_file.cwd.as_ref()) {
+            // The test is sourced once the init file (and the plugins) are loaded
+            Some(init_file) => {
+                cmd.arg("-u").arg(init_file);
+                self.require_after_init(&mut cmd);
+                cmd.arg("-c")
+                    .arg(format!("luafile {}", escaped_test))
+                    .arg("+qa");
+            }
+            // Without an init file to load test-utils after, the test is sourced once test-utils is loaded
+            None if self.require_test_utils
+                && config.settings.test_utils_after_init.unwrap_or(false) =>
+            {
+                cmd.arg("-u").arg("NONE");
+                self.require_after_init(&mut cmd);
+                cmd.arg("-c")
+                    .arg(format!("luafile {}", escaped_test))
+                    .arg("+qa");
+            }
+            None => {
+                cmd.arg("-u").arg(&test_arg).arg("+qa");
+            }
+        }
+
+        cmd.env("NVIM_TEST_RUNNER_RESULT_FILE", &report_path);
+
+        let (
+            interrupt::Captured {
+                output,
+                peak_rss,
+                truncated,
+                timed_out,
+            },
+            duration,
+            leaked_files,
+        ) = match self.run_nvim(index, &mut cmd, test_file.directives.timeout) {
+            Ok(run) => run,
+            Err(reason) => {
+                return TestResult {
+                    stderr: reason,
+                    outcome: TestOutcome::Failed,
+                    ..TestResult::skipped(test.clone(), String::new())
+                }
+            }
+        };
+        debug!("Test {} finished in {:?}", test.display(), duration);
+        let invocation = Some(Invocation::new(&cmd, self.current_dir, &self.rtp));
+
+        // A test printing a secret (e.g. in the message of a failed request) must not show it in the reports
+        let stdout = redact_secrets(&String::from_utf8_lossy(&output.stdout)).into_owned();
+        let stderr = redact_secrets(&String::from_utf8_lossy(&output.stderr)).into_owned();
+        let report = self.take_report(&report_path);
+        let result = |outcome: TestOutcome| TestResult {
+            path: test.clone(),
+            outcome,
+            duration,
+            stdout: stdout.clone(),
+            stderr: stderr.clone(),
+            report: report.clone(),
+            exit_code: output.status.code(),
+            signal: exit_signal(&output.status),
+            peak_rss,
+            output_truncated: truncated,
+            timed_out,
+            invocation: invocation.clone(),
+            leaked_files: leaked_files.clone(),
+        };
+
+        if timed_out {
+            error!(
+                "Test {} timed out after {:.2?}",
+                test.display(),
+                test_file.directives.timeout.unwrap_or_default()
+            );
+            let mut failed = result(TestOutcome::Failed);
+            failed.report.failures.push(timeout_failure(test_file));
+            return failed;
+        }
+
+        if !output.status.success() {
+            match exit_signal(&output.status) {
+                Some(signal) => error!(
+                    "nvim crashed (signal {}) running command: {:?}",
+                    signal, cmd
+                ),
+                None => error!("Failed to run command: {:?}", cmd),
+            }
+            return result(TestOutcome::Failed);
+        }
+
+        // E.g. the plugin module could not be required once the test had finished
+        if !report.failures.is_empty() {
+            return result(TestOutcome::Failed);
+        }
+
+        if let Some(failure) = messages_failure(test_file, &report.messages, error_patterns) {
+            let mut failed = result(TestOutcome::Failed);
+            failed.report.failures.push(failure);
+            return failed;
+        }
+
+        if let Some(failure) = self.stderr_failure(&stderr) {
+            let mut failed = result(TestOutcome::Failed);
+            failed.report.failures.push(failure);
+            return failed;
+        }
+
+        result(TestOutcome::Passed)
+    }
+
+    /// Why the output of a test on stderr fails it, if it does: a line matching the error patterns, or in strict
+    /// mode any line but the allowed ones. Batched tests share the stderr of their batch
+    fn stderr_failure(&self, stderr: &str) -> Option<TestFailure> {
+        if let Some(line) = find_error_line(&self.setup.error_patterns, stderr) {
+            return Some(TestFailure {
+                message: format!("Reported an error on stderr: {}", line.trim()),
+                ..TestFailure::default()
+            });
+        }
+        if !self.config.settings.strict_stderr.unwrap_or(false) {
+            return None;
+        }
+        let allowed = self
+            .config
+            .settings
+            .allowed_stderr
+            .as_deref()
+            .unwrap_or_default();
+        let line = stderr.lines().find(|line| {
+            !line.trim().is_empty() && !allowed.iter().any(|allowed| line.contains(allowed))
+        })?;
+        Some(TestFailure {
+            message: format!("Wrote to stderr (strictStderr): {}", line.trim()),
+            ..TestFailure::default()
+        })
+    }
+
+    /// Run a batch of test files (sharing the same working directory) one after another in a single nvim, with
+    /// the driver of test-utils. Tests that finished before nvim exited are reported as if nvim had exited
+    /// normally
+    fn run_batch(&self, index: usize, batch: &[&TestFile]) -> Vec<TestResult> {
+        let cwd = batch[0].cwd.as_ref();
+        let report_path = |position: usize| {
+            self.result_dir.join(format!(
+                "nvim-test-runner-{}-batch-{}-{}.json",
+                std::process::id(),
+                index,
+                position
+            ))
+        };
+        let batch_path = std::env::temp_dir().join(format!(
+            "nvim-test-runner-{}-batch-{}.json",
+            std::process::id(),
+            index
+        ));
+        let tests: Vec<serde_json::Value> = batch
+            .iter()
+            .enumerate()
+            .map(|(position, test_file)| {
+                let _ = std::fs::remove_file(report_path(position));
+                let path = match &test_file.cwd {
+                    Some(_) => self.current_dir.join(&test_file.path),
+                    None => test_file.path.clone(),
+                };
+                serde_json::json!({
+                    "path": path,
+                    "resultFile": report_path(position),
+                })
+            })
+            .collect();
+
+        let mut cmd = self.nvim_command(cwd);
+        // Batches only hold tests with the same environment
+        cmd.envs(&batch[0].env);
+        match self.init_file(cwd) {
+            Some(init_file) => cmd.arg("-u").arg(init_file),
+            None => cmd.arg("-u").arg("NONE"),
+        };
+        self.require_after_init(&mut cmd);
+        cmd.arg("-c").arg("lua T.run_batch()").arg("+qa");
+        cmd.env("NVIM_TEST_RUNNER_BATCH_FILE", &batch_path);
+
+        // Only the peak RSS of the whole batch is known, which is not attributed to its tests
+        let batch_contents = serde_json::Value::from(tests).to_string();
+        let run = match std::fs::write(&batch_path, &batch_contents) {
+            // A test with a timeout is batched on its own
+            Ok(()) => self.run_nvim(
+                index,
+                &mut cmd,
+                batch
+                    .iter()
+                    .find_map(|test_file| test_file.directives.timeout),
+            ),
+            Err(e) => {
+                let reason = format!("Failed to write batch file {}: {}", batch_path.display(), e);
+                error!("{}", reason);
+                Err(reason)
+            }
+        };
+        let _ = std::fs::remove_file(&batch_path);
+        let (captured, duration, leaked_files) = match run {
+            Ok(run) => run,
+            Err(reason) => {
+                return batch
+                    .iter()
+                    .map(|test_file| TestResult {
+                        stderr: reason.clone(),
+                        outcome: TestOutcome::Failed,
+                        ..TestResult::skipped(test_file.path.clone(), String::new())
+                    })
+                    .collect();
+            }
+        };
+        let output = captured.output;
+        debug!("Batch {} finished in {:?}", index, duration);
+        let mut invocation = Invocation::new(&cmd, self.current_dir, &self.rtp);
+        invocation.files.insert(batch_path.clone(), batch_contents);
+        let invocation = Some(invocation);
+        if !output.status.success() {
+            match exit_signal(&output.status) {
+                Some(signal) => error!(
+                    "nvim crashed (signal {}) running command: {:?}",
+                    signal, cmd
+                ),
+                None => error!("Failed to run command: {:?}", cmd),
+            }
+        }
+
+        let stdout = redact_secrets(&String::from_utf8_lossy(&output.stdout)).into_owned();
+        let stderr = redact_secrets(&String::from_utf8_lossy(&output.stderr)).into_owned();
+        // The stderr of the batch cannot be attributed to one of its tests, so an error on it fails them all, as it
+        // would fail each of them run on its own
+        let stderr_failure = self.stderr_failure(&stderr);
+        batch
+            .iter()
+            .enumerate()
+            .map(|(position, test_file)| {
+                let report_path = report_path(position);
+                let finished = report_path.exists();
+                let mut report = self.take_report(&report_path);
+                if captured.timed_out && !finished {
+                    report.failures.push(timeout_failure(test_file));
+                }
+                // Unlike stderr, the messages are those of the test, as test-utils clears them between tests
+                if let Some(failure) =
+                    messages_failure(test_file, &report.messages, &self.setup.error_patterns)
+                {
+                    report.failures.push(failure);
+                }
+                if let Some(failure) = &stderr_failure {
+                    report.failures.push(failure.clone());
+                }
+                let outcome = if finished && report.failures.is_empty() {
+                    TestOutcome::Passed
+                } else {
+                    TestOutcome::Failed
+                };
+                TestResult {
+                    path: test_file.path.clone(),
+                    outcome,
+                    duration: report
+                        .duration_ms
+                        .map_or(Duration::ZERO, Duration::from_millis),
+                    stdout: stdout.clone(),
+                    stderr: stderr.clone(),
+                    report,
+                    exit_code: if finished {
+                        Some(0)
+                    } else {
+                        output.status.code()
+                    },
+                    signal: if finished {
+                        None
+                    } else {
+                        exit_signal(&output.status)
+                    },
+                    peak_rss: None,
+                    output_truncated: captured.truncated,
+                    timed_out: captured.timed_out && !finished,
+                    invocation: invocation.clone(),
+                    // The files left behind by the batch cannot be attributed to one of its tests
+                    leaked_files: leaked_files.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Run a group of tests: a batch with --batch, a single test otherwise. The outcomes of the tests that are
+    /// expected to fail are turned into expected failures and unexpected passes
+    fn run_group(&self, index: usize, group: &[&TestFile]) -> Vec<TestResult> {
+        let options = self.options;
+        // Held until the tests have run
+        let _permits = self.group_limits.acquire(
+            group
+                .iter()
+                .filter_map(|test_file| test_file.directives.group.as_deref()),
+        );
+        // Always taken after those of the concurrency groups, so that two groups of tests waiting for each other's
+        // permits cannot each hold what the other waits for
+        let _serial_permits = self.serial_groups.acquire(
+            group
+                .iter()
+                .filter_map(|test_file| test_file.directives.serial_group.as_deref()),
+        );
+        let mut results: Vec<TestResult> = match options.batch {
+            Some(_) => self.run_batch(index, group),
+            None => group
+                .iter()
+                .map(|test_file| self.run_test(index, test_file))
+                .collect(),
+        };
+        for (result, test_file) in results.iter_mut().zip(group) {
+            // The output of a test with an expect-output directive is what it asserts
+            if options.fail_on_no_assertions
+                && result.has_no_assertions()
+                && test_file.directives.expect_output.is_none()
+            {
+                result.report.failures.push(TestFailure {
+                    message: "No assertions were run".to_string(),
+                    ..TestFailure::default()
+                });
+                result.outcome = TestOutcome::Failed;
+            }
+            // A test that already failed fails regardless of its output
+            if let (Some(pattern), TestOutcome::Passed) =
+                (&test_file.directives.expect_output, &result.outcome)
+            {
+                let message = match Regex::new(pattern) {
+                    Ok(regex) if regex.is_match(&result.stdout) => None,
+                    Ok(_) => Some(format!("Expected the output to match /{}/", pattern)),
+                    Err(e) => Some(format!(
+                        "Invalid expect-output pattern /{}/: {}",
+                        pattern, e
+                    )),
+                };
+                if let Some(message) = message {
+                    result.report.failures.push(TestFailure {
+                        message,
+                        ..TestFailure::default()
+                    });
+                    result.outcome = TestOutcome::Failed;
+                }
+            }
+            if result.output_truncated {
+                println!(
+                    "{}",
+                    Colour::Yellow.paint(format!(
+                        "The output of {} was truncated, being longer than --max-output",
+                        result.path.display()
+                    ))
+                );
+                warn!(
+                    "The output of {} was truncated, being longer than --max-output",
+                    result.path.display()
+                );
+            }
+            // In full, as the console may only print part of it
+            if result.outcome == TestOutcome::Failed && !result.stderr.is_empty() {
+                info!("stderr of {}:\n{}", result.path.display(), result.stderr);
+            }
+            if let Some(reason) = &test_file.directives.xfail {
+                result.outcome = result.outcome.clone().expecting_failure(reason);
+            }
+            if let (Some(dir), Some(invocation)) = (&options.record_failures, &result.invocation) {
+                if result.outcome.is_failure() {
+                    let bundle =
+                        ReproBundle::new(result, invocation, &self.rtp, &self.recorded_deps);
+                    match bundle.write(dir) {
+                        Ok(path) => info!(
+                            "Recorded the failure of {} in {}",
+                            result.path.display(),
+                            path.display()
+                        ),
+                        Err(e) => {
+                            println!("{}", Colour::Red.paint(format!("{}", e)));
+                            error!("{}", e);
+                        }
+                    }
+                }
+            }
+        }
+        results
+    }
+}
+
+/// How often each test passed and how long its passing runs took, over the rounds of --repeat, for the flaky tests
+/// and the timing baselines. A skipped test is not counted
+#[derive(Default)]
+struct OutcomeCounts {
+    /// The passes and the runs of each test
+    pass_counts: HashMap<PathBuf, (usize, usize)>,
+    duration_samples: HashMap<PathBuf, Vec<Duration>>,
+}
+
+impl OutcomeCounts {
+    fn count(&mut self, result: &TestResult) {
+        if matches!(result.outcome, TestOutcome::Skipped(_)) {
+            return;
+        }
+        if !result.outcome.is_failure() {
+            self.duration_samples
+                .entry(result.path.clone())
+                .or_default()
+                .push(result.duration);
+        }
+        let (passes, runs) = self.pass_counts.entry(result.path.clone()).or_default();
+        *runs += 1;
+        if !result.outcome.is_failure() {
+            *passes += 1;
+        }
+    }
+}
+
+/// What [`execute_tests`] ran, besides the results of the last round
+struct ExecutedTests {
+    duration: Duration,
+    /// Of the rounds before the last one; those of the last are counted with the summary
+    counts: OutcomeCounts,
+    /// The number of tests not run, as the run bailed out
+    bailed: usize,
+    /// The number of groups the tests ran in, the indices of which are taken
+    num_groups: usize,
+    user_dirs: Vec<PathBuf>,
+    /// With --guard-user-config, the user's config before the run
+    user_config_before: Option<user_config::Snapshot>,
+}
+
+/// Run the selected tests: the warmup rounds, the rounds of --repeat, then the round that is reported, whose
+/// results are added to those of the selected tests. Fails if a test fails in a warmup round
+fn execute_tests(
+    runner: &TestRunner,
+    selected: &mut SelectedTests,
+    reporters: &[Box<dyn Reporter>],
+) -> Result<ExecutedTests, RunnerError> {
+    let config = runner.config;
+    let options = runner.options;
+    let runnable_files = &selected.runnable_files;
+
+    // With --batch, up to N tests sharing the same working directory are grouped together. A test with a timeout
+    // is grouped on its own, so that the timeout applies to that test only. The serial tests come last, grouped
+    // apart from the others; the first `num_parallel` groups run in parallel, and the rest one after another once
+    // those have finished
+    let batch_size = options.batch.unwrap_or(1).max(1);
+    let mut groups: Vec<Vec<&TestFile>> = Vec::new();
+    let (serial_files, parallel_files): (Vec<&TestFile>, Vec<&TestFile>) = runnable_files
+        .iter()
+        .partition(|test_file| test_file.directives.serial);
+    for test_file in parallel_files.into_iter().chain(serial_files) {
+        match groups.last_mut() {
+            Some(group)
+                if group.len() < batch_size
+                    && group[0].cwd == test_file.cwd
+                    && group[0].env == test_file.env
+                    && group[0].directives.serial == test_file.directives.serial
+                    && group[0].directives.timeout.is_none()
+                    && test_file.directives.timeout.is_none() =>
+            {
+                group.push(test_file)
+            }
+            _ => groups.push(vec![test_file]),
+        }
+    }
+    let num_parallel = groups
+        .iter()
+        .take_while(|group| !group[0].directives.serial)
+        .count();
+
+    let user_dirs = user_config::user_dirs();
+    let user_config_before = options
+        .guard_user_config
+        .then(|| user_config::snapshot(&user_dirs));
+
+    // Warmup rounds are not recorded, but bail out of the run if a test fails; there is no point in timing a
+    // broken suite
+    for round in 1..=options.warmup {
+        println!(
+            "{}",
+            Colour::Yellow.paint(format!("Warmup round {}/{}", round, options.warmup))
+        );
+        info!("Warmup round {}/{}", round, options.warmup);
+        let failure = groups[..num_parallel]
+            .par_iter()
+            .enumerate()
+            .flat_map_iter(|(index, group)| runner.run_group(index, group))
+            .find_any(|result| result.outcome.is_failure())
+            .or_else(|| {
+                groups
+                    .iter()
+                    .enumerate()
+                    .skip(num_parallel)
+                    .flat_map(|(index, group)| runner.run_group(index, group))
+                    .find(|result| result.outcome.is_failure())
+            });
+        if let Some(failure) = failure {
+            if let Some(after_all) = &config.settings.after_all {
+                if let Err(e) = run_hook("afterAll", after_all) {
+                    println!("{}", Colour::Red.paint(format!("{}", e)));
+                    error!("{}", e);
+                }
+            }
+            return Err(RunnerError::TestFailures(format!(
+                "Test {} failed in warmup round {}:\n{}",
+                failure.path.display(),
+                round,
+                failure.stderr
+            )));
+        }
+    }
+
+    // With --repeat, the runs before the last one are not reported, but count how often each test passed, and
+    // give the durations of the tests for the timing baselines
+    let mut counts = OutcomeCounts::default();
+    for round in 1..options.repeat {
+        println!(
+            "{}",
+            Colour::Yellow.paint(format!("Repeat round {}/{}", round, options.repeat))
+        );
+        info!("Repeat round {}/{}", round, options.repeat);
+        let mut results: Vec<TestResult> = groups[..num_parallel]
+            .par_iter()
+            .enumerate()
+            .flat_map_iter(|(index, group)| runner.run_group(index, group))
+            .collect();
+        results.extend(
+            groups
+                .iter()
+                .enumerate()
+                .skip(num_parallel)
+                .flat_map(|(index, group)| runner.run_group(index, group)),
+        );
+        for result in &results {
+            counts.count(result);
+        }
+    }
+    if options.repeat > 1 {
+        println!(
+            "{}",
+            Colour::Yellow.paint(format!(
+                "Repeat round {}/{}",
+                options.repeat, options.repeat
+            ))
+        );
+        info!("Repeat round {}/{}", options.repeat, options.repeat);
+    }
+
+    for reporter in reporters {
+        reporter.on_run_start(runnable_files);
+    }
+
+    if let Some(interval) = options.ramp_up {
+        interrupt::start_ramp_up(options.ramp_up_start.unwrap_or(1), interval);
+    }
+    let run_start = Instant::now();
+    // Counts the failures so far, and the tests not run because of them, for --bail
+    let failure_count = AtomicUsize::new(0);
+    let bailed_count = AtomicUsize::new(0);
+
+    // The workers send the events of the tests over a channel to this thread, which reports them (and records the
+    // results) one at a time, in the order the tests finish
+    enum RunEvent<'a> {
+        Started(&'a TestFile),
+        Completed(Box<TestResult>),
+    }
+    let (sender, receiver) = std::sync::mpsc::channel::<RunEvent>();
+
+    let results_cache = &mut selected.results_cache;
+    let results_cache_path = &selected.results_cache_path;
+    let mut run_results: Vec<TestResult> = Vec::new();
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            // Dropped once all groups have run, which ends the reporting below
+            let sender = sender;
+            let run_scheduled = |index: usize| {
+                let group = &groups[index];
+                if let Some(bail) = options.bail {
+                    if failure_count.load(Ordering::SeqCst) >= bail {
+                        for test_file in group {
+                            debug!(
+                                "Not running test {:?}, bailed out",
+                                test_file.path.display()
+                            );
+                            bailed_count.fetch_add(1, Ordering::SeqCst);
+                            let _ =
+                                sender.send(RunEvent::Completed(Box::new(TestResult::skipped(
+                                    test_file.path.clone(),
+                                    format!("bailed out after {} failure(s)", bail),
+                                ))));
+                        }
+                        return;
+                    }
+                }
+
+                for test_file in group {
+                    debug!("Running test: {:?}", test_file.path.display());
+                    let _ = sender.send(RunEvent::Started(test_file));
+                }
+
+                for result in runner.run_group(index, group) {
+                    if result.outcome.is_failure() {
+                        failure_count.fetch_add(1, Ordering::SeqCst);
+                    }
+                    let _ = sender.send(RunEvent::Completed(Box::new(result)));
+                }
+            };
+            (0..num_parallel).into_par_iter().for_each(run_scheduled);
+            // The serial tests, once all others have finished
+            (num_parallel..groups.len()).for_each(run_scheduled);
+        });
+
+        // Ends once all workers are done and have dropped their senders
+        for event in receiver {
+            let result = match event {
+                RunEvent::Started(test_file) => {
+                    for reporter in reporters {
+                        reporter.on_test_start(test_file);
+                    }
+                    continue;
+                }
+                RunEvent::Completed(result) => *result,
+            };
+            for reporter in reporters {
+                reporter.on_test_complete(&result);
+            }
+            // Recorded as soon as each test finishes, so that an interrupted run can be resumed
+            let passed = match result.outcome {
+                TestOutcome::Passed | TestOutcome::ExpectedFailure(_) => Some(true),
+                TestOutcome::Failed | TestOutcome::UnexpectedPass(_) => Some(false),
+                TestOutcome::Skipped(_) => None,
+            };
+            if let Some(passed) = passed {
+                results_cache.record(&result.path, passed);
+                if let Err(e) = results_cache.save(results_cache_path) {
+                    warn!(
+                        "Failed to write the results cache {}: {}",
+                        results_cache_path.display(),
+                        e
+                    );
+                }
+            }
+            run_results.push(result);
+        }
+    });
+
+    let num_groups = groups.len();
+    selected.test_results.extend(run_results);
+    Ok(ExecutedTests {
+        duration: run_start.elapsed(),
+        counts,
+        bailed: bailed_count.load(Ordering::SeqCst),
+        num_groups,
+        user_dirs,
+        user_config_before,
+    })
+}
+
+/// The summary of a run, with what is reported along with it by [`report_run`]
+struct RunOutcome {
+    summary: RunSummary,
+    /// The run recorded before this one, for --compare
+    previous_run: Option<RecordedRun>,
+    /// With --check-unused-deps, the number of tests that reported the modules they required, and the
+    /// dependencies none of them required. `None` if no test reported its modules
+    unused_deps: Option<(usize, Vec<String>)>,
+}
+
+/// Summarize the results of a run: the flaky tests, the timing regressions, the unused dependencies, the warnings,
+/// the quarantined tests and the changes to the user's config, all of which the exit code of the run depends on.
+/// The run is recorded in the results cache, and with --save-baseline its durations are saved
+fn summarize_run(
+    runner: &TestRunner,
+    resolved: &ResolvedDependencies,
+    selected: &mut SelectedTests,
+    executed: &mut ExecutedTests,
+) -> Result<RunOutcome, RunnerError> {
+    let config = runner.config;
+    let options = runner.options;
+    let setup = runner.setup;
+    let results_cache = &mut selected.results_cache;
+
+    let mut summary = RunSummary::new(
+        std::mem::take(&mut selected.test_results),
+        executed.duration,
+    );
+    summary.unresolved_deps = resolved.unresolved_deps.clone();
+    summary.filtered = selected.num_matched.saturating_sub(summary.results.len());
+    for result in &summary.results {
+        executed.counts.count(result);
+    }
+    if options.repeat > 1 {
+        let mut pass_rates: Vec<(PathBuf, (usize, usize))> =
+            std::mem::take(&mut executed.counts.pass_counts)
+                .into_iter()
+                .collect();
+        pass_rates.sort();
+        for (path, (passes, runs)) in pass_rates {
+            info!("Test {} passed {} of {} runs", path.display(), passes, runs);
+            if passes > 0 && passes < runs {
+                summary.flaky.push(FlakyTest { path, passes, runs });
+            }
+        }
+    }
+    if setup.baseline.is_some() || options.save_baseline.is_some() {
+        let current = Baseline::from_samples(
+            &executed.counts.duration_samples,
+            setup.baseline_settings.samples,
+        );
+        if let Some(baseline) = &setup.baseline {
+            summary.regressions = baseline.regressions(&current, &setup.baseline_settings);
+        }
+        if let Some(name) = &options.save_baseline {
+            // The durations of a failing suite are not worth comparing to
+            if summary.failed > 0 {
+                let message = format!("Not saving the baseline {}, tests failed", name);
+                println!("{}", Colour::Yellow.paint(&message));
+                warn!("{}", message);
+            } else {
+                current.save(&resolved.cache_dir, name)?;
+            }
+        }
+    }
+
+    // Judged by the tests that reported their modules, as the others (e.g. without test-utils) give no clue
+    let unused_deps = if runner.check_unused_deps {
+        let reported: Vec<&TestResult> = summary
+            .results
+            .iter()
+            .filter(|result| result.report.written)
+            .collect();
+        if reported.is_empty() {
+            None
+        } else {
+            let required_modules = reported
+                .iter()
+                .flat_map(|result| &result.report.required_modules)
+                .map(|module| module.as_str())
+                .collect();
+            Some((
+                reported.len(),
+                deps::unused_dependencies(&resolved.dep_entries, &required_modules),
+            ))
+        }
+    } else {
+        None
+    };
+    if options.fail_on_unused_deps {
+        if let Some((_, unused_deps)) = &unused_deps {
+            summary.unused_deps = unused_deps.clone();
+        }
+    }
+    // Including those of loading the config, which happened before the run
+    let setup_warnings = warnings::take();
+    if options.fail_on_warning {
+        summary.warnings = setup_warnings;
+    }
+    summary.allowed_failures = options.allowed_failures;
+    let quarantine: Vec<glob::Pattern> = config
+        .settings
+        .quarantine
+        .iter()
+        .flatten()
+        .filter_map(|pattern| glob::Pattern::new(pattern).ok())
+        .collect();
+    summary.quarantined = summary
+        .results
+        .iter()
+        .filter(|result| !matches!(result.outcome, TestOutcome::Skipped(_)))
+        .filter(|result| {
+            quarantine
+                .iter()
+                .any(|pattern| pattern.matches_path(&result.path))
+        })
+        .map(|result| result.path.clone())
+        .collect();
+    for path in &summary.quarantined {
+        // With --repeat, a test that failed in any of the runs did not pass
+        let passed = summary
+            .results
+            .iter()
+            .any(|result| &result.path == path && !result.outcome.is_failure())
+            && !summary.flaky.iter().any(|flaky| &flaky.path == path);
+        let passes = results_cache
+            .quarantine_passes
+            .entry(path.to_string_lossy().to_string())
+            .or_default();
+        *passes = if passed { *passes + 1 } else { 0 };
+        if *passes >= QUARANTINE_RELEASE_RUNS {
+            summary.releasable.push(path.clone());
+        }
+    }
+    if let Some(before) = &executed.user_config_before {
+        summary.user_config_changes =
+            user_config::changed_files(before, &user_config::snapshot(&executed.user_dirs));
+        if summary.user_config_changes.is_empty() {
+            info!(
+                "The tests did not change {}",
+                executed
+                    .user_dirs
+                    .iter()
+                    .map(|dir| dir.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(" or ")
+            );
+        }
+    }
+
+    let previous_run = results_cache.last_run.replace(RecordedRun::new(&summary));
+    results_cache.dependencies = resolved.dep_revisions.clone();
+    if let Err(e) = results_cache.save(&selected.results_cache_path) {
+        warn!(
+            "Failed to write the results cache {}: {}",
+            selected.results_cache_path.display(),
+            e
+        );
+    }
+
+    Ok(RunOutcome {
+        summary,
+        previous_run,
+        unused_deps,
+    })
+}
+
+/// Report the outcome of a run: log the failures, hand the summary to the reporters, write the outputs of the
+/// options (e.g. --output-dir and --coverage), run the failed tests again with --debug-failures, run the afterAll
+/// and afterRun hooks, and print the delta to the previous run and the outcome
+fn report_run(
+    runner: &TestRunner,
+    selected: &SelectedTests,
+    executed: &ExecutedTests,
+    outcome: &RunOutcome,
+    reporters: &[Box<dyn Reporter>],
+) {
+    let config = runner.config;
+    let options = runner.options;
+    let summary = &outcome.summary;
+
+    for failure in summary.failures() {
+        error!(
+            "Test {} failed after {:.2?}\nstdout:\n{}\nstderr:\n{}",
+            failure.path.display(),
+            failure.duration,
+            failure.stdout,
+            failure.stderr
+        );
+        for test_failure in &failure.report.failures {
+            error!("Test {} failure: {}", failure.path.display(), test_failure);
+        }
+    }
+
+    for reporter in reporters {
+        reporter.on_run_complete(summary);
+    }
+
+    if let Some(output_dir) = &options.output_dir {
+        match write_output_logs(output_dir, summary) {
+            Ok(()) => info!("Wrote test outputs to {}", output_dir.display()),
+            Err(e) => {
+                println!(
+                    "{}",
+                    Colour::Red.paint(format!(
+                        "Failed to write test outputs to {}: {}",
+                        output_dir.display(),
+                        e
+                    ))
+                );
+                error!(
+                    "Failed to write test outputs to {}: {}",
+                    output_dir.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    if options.keep_results {
+        let message = format!(
+            "The result files of the tests were kept in {}",
+            runner.result_dir.display()
+        );
+        println!("{}", Colour::Blue.paint(&message));
+        info!("{}", message);
+    }
+
+    if options.detect_leaks {
+        let leaking: Vec<&TestResult> = summary
+            .results
+            .iter()
+            .filter(|result| !result.leaked_files.is_empty())
+            .collect();
+        if leaking.is_empty() {
+            let message = format!("No test left files behind in {}", runner.leak_dir.display());
+            println!("{}", Colour::Blue.paint(&message));
+            info!("{}", message);
+        }
+        for result in leaking {
+            let message = format!(
+                "{} left {} file(s) behind in {}:",
+                result.path.display(),
+                result.leaked_files.len(),
+                runner.leak_dir.display()
+            );
+            println!("{}", Colour::Yellow.paint(&message));
+            warn!("{}", message);
+            for file in &result.leaked_files {
+                println!("{}", Colour::Yellow.paint(format!("  {}", file.display())));
+                warn!("  {}", file.display());
+            }
+        }
+    }
+
+    if options.coverage {
+        let coverage_output = options
+            .coverage_output
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("luacov.stats.out"));
+        match write_coverage(
+            &runner.coverage_dir,
+            &coverage_output,
+            runner.current_dir,
+            &runner.cache_dir,
+        ) {
+            Ok(coverage) => {
+                let message = format!(
+                    "Coverage of {} file(s) ({} line(s) run) written to {}",
+                    coverage.num_files(),
+                    coverage.num_lines_hit(),
+                    coverage_output.display()
+                );
+                println!("{}", Colour::Blue.paint(&message));
+                info!("{}", message);
+            }
+            Err(e) => {
+                println!("{}", Colour::Red.paint(format!("{}", e)));
+                error!("{}", e);
+            }
+        }
+        if let Err(e) = std::fs::remove_dir_all(&runner.coverage_dir) {
+            warn!(
+                "Failed to clean up {}: {}",
+                runner.coverage_dir.display(),
+                e
+            );
+        }
+    }
+
+    if let Some(grep) = &options.grep {
+        let filtered_tests: usize = summary
+            .results
+            .iter()
+            .map(|r| r.report.filtered_tests.len())
+            .sum();
+        println!(
+            "{}",
+            Colour::Yellow.paint(format!(
+                "{} test block(s) filtered out by --grep {:?}",
+                filtered_tests, grep
+            ))
+        );
+        info!(
+            "{} test block(s) filtered out by --grep {:?}",
+            filtered_tests, grep
+        );
+    }
+
+    if options.update {
+        let updated_snapshots: Vec<&String> = summary
+            .results
+            .iter()
+            .flat_map(|r| &r.report.updated_snapshots)
+            .collect();
+        println!(
+            "{}",
+            Colour::Yellow.paint(format!("{} snapshot(s) updated", updated_snapshots.len()))
+        );
+        for snapshot in updated_snapshots {
+            println!("  {}", snapshot);
+            info!("Updated snapshot {}", snapshot);
+        }
+    }
+
+    if runner.check_unused_deps {
+        match &outcome.unused_deps {
+            None => {
+                println!(
+                    "{}",
+                    Colour::Yellow.paint(
+                        "No test reported the modules it required, cannot check for unused dependencies"
+                    )
+                );
+                warn!("No test reported the modules it required, cannot check for unused dependencies");
+            }
+            Some((_, unused_deps)) if unused_deps.is_empty() => {
+                info!("Every dependency with Lua modules was required by a test");
+            }
+            Some((num_reported, unused_deps)) => {
+                let colour = match options.fail_on_unused_deps {
+                    true => Colour::Red,
+                    false => Colour::Yellow,
+                };
+                println!(
+                    "{}",
+                    colour.paint(format!(
+                        "{} dependency(ies) not required by any of the {} test(s) that ran (modules required \
+                         dynamically, e.g. with a computed name, are not seen):",
+                        unused_deps.len(),
+                        num_reported
+                    ))
+                );
+                for unused_dep in unused_deps {
+                    println!("  {}", unused_dep);
+                    warn!("Dependency {} is not required by any test", unused_dep);
+                }
+            }
+        }
+    }
+
+    if let Some(bail) = options.bail {
+        if executed.bailed > 0 {
+            println!(
+                "{}",
+                Colour::Red.paint(format!(
+                    "Bailed out after {} failure(s), {} test(s) not run",
+                    bail, executed.bailed
+                ))
+            );
+            warn!(
+                "Bailed out after {} failure(s), {} test(s) not run",
+                bail, executed.bailed
+            );
+        }
+    }
+
+    if summary.skipped > 0 {
+        println!(
+            "{}",
+            Colour::Yellow.paint(format!("{} test(s) skipped", summary.skipped))
+        );
+    }
+
+    // Before the afterAll hook, which may tear down what the tests need. One at a time, so that the output of a
+    // test is not interleaved with that of another
+    let failed_tests: Vec<&TestFile> = selected
+        .runnable_files
+        .iter()
+        .filter(|test| summary.failures().any(|failure| failure.path == test.path))
+        .collect();
+    if options.debug_failures && !failed_tests.is_empty() {
+        let message = format!(
+            "Running the {} failed test(s) again with more verbosity",
+            failed_tests.len()
+        );
+        println!("{}", Colour::Yellow.paint(&message));
+        info!("{}", message);
+        runner.debugging.store(true, Ordering::SeqCst);
+        interrupt::set_max_output(0);
+        for (position, test_file) in failed_tests.iter().enumerate() {
+            let result = runner.run_test(executed.num_groups + position, test_file);
+            let outcome = match result.outcome {
+                TestOutcome::Passed => "passed".to_string(),
+                _ => match (result.exit_code, result.signal) {
+                    (_, Some(signal)) => format!("failed, nvim crashed with signal {}", signal),
+                    (Some(code), None) => format!("failed, nvim exited with {}", code),
+                    (None, None) => "failed".to_string(),
+                },
+            };
+            let header = format!("Debug run of {} ({}):", test_file.path.display(), outcome);
+            println!("{}", Colour::Yellow.paint(&header));
+            for failure in &result.report.failures {
+                println!("{}", Colour::Red.paint(format!("  {}", failure)));
+            }
+            println!("--- stdout ---\n{}", result.stdout.trim_end());
+            println!("--- stderr ---\n{}", result.stderr.trim_end());
+            info!(
+                "{}\nstdout:\n{}\nstderr:\n{}",
+                header, result.stdout, result.stderr
+            );
+        }
+    } else if !failed_tests.is_empty()
+        && !options.quiet
+        && std::io::IsTerminal::is_terminal(&std::io::stdout())
+    {
+        println!(
+            "{}",
+            Colour::Yellow.paint(
+                "Run `nvim-test-runner --failed --debug-failures` to run the failed tests again with more verbosity"
+            )
+        );
+    }
+
+    // Run regardless of the test results. A failing afterAll hook is reported but does not mask the test results
+    if let Some(after_all) = &config.settings.after_all {
+        if let Err(e) = run_hook("afterAll", after_all) {
+            println!("{}", Colour::Red.paint(format!("{}", e)));
+            error!("{}", e);
+        }
+    }
+
+    // Likewise, e.g. to send a notification with the results
+    if let Some(after_run) = &config.settings.after_run {
+        if let Err(e) = run_after_run_hook(after_run, summary) {
+            println!("{}", Colour::Red.paint(format!("{}", e)));
+            error!("{}", e);
+        }
+    }
+
+    if options.compare {
+        match &outcome.previous_run {
+            Some(previous_run) => print_delta(&previous_run.delta(summary)),
+            None => println!(
+                "{}",
+                Colour::Yellow.paint("No previous run recorded to compare with")
+            ),
+        }
+    } else if options.allowed_failures.is_some() {
+        // The count alone would not notice a test failing in place of one that was fixed
+        let newly_failed = outcome
+            .previous_run
+            .as_ref()
+            .map(|previous_run| previous_run.delta(summary).newly_failed)
+            .unwrap_or_default();
+        if !newly_failed.is_empty() {
+            println!(
+                "{}",
+                Colour::Red.paint(format!(
+                    "Newly failing since the previous run ({}):",
+                    newly_failed.len()
+                ))
+            );
+            for path in &newly_failed {
+                println!("  {}", path.display());
+                warn!(
+                    "Test {} newly failing since the previous run",
+                    path.display()
+                );
+            }
+        }
+    }
+
+    print_outcome(summary);
+}
+
+/// Merge the coverage files the nvim processes wrote into the given directory, and write the coverage of the files
+/// of the plugin under test (i.e. in the current directory, but not in the cache dir) to the output file, with
+/// their paths relative to the current directory. Coverage files that cannot be read (e.g. of a test that was
+/// killed while writing it) are left out
+fn write_coverage(
+    coverage_dir: &std::path::Path,
+    output: &std::path::Path,
+    current_dir: &std::path::Path,
+    cache_dir: &std::path::Path,
+) -> Result<coverage::Coverage, RunnerError> {
+    let entries = std::fs::read_dir(coverage_dir)
+        .map_err(|e| RunnerError::io(format!("Failed to read {}", coverage_dir.display()), e))?;
+    let mut merged = coverage::Coverage::default();
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        match coverage::Coverage::read(&entry.path()) {
+            Ok(coverage) => merged.merge(coverage),
+            Err(e) => warn!("Skipping coverage file {}: {}", entry.path().display(), e),
+        }
+    }
+
+    let cache_dir = current_dir.join(cache_dir);
+    let merged = merged.filter_map_paths(|path| {
+        if path.starts_with(&cache_dir) {
+            return None;
+        }
+        path.strip_prefix(current_dir)
+            .ok()
+            .map(|path| path.to_path_buf())
+    });
+    merged
+        .write(output)
+        .map_err(|e| RunnerError::io(format!("Failed to write {}", output.display()), e))?;
+    Ok(merged)
+}
+
+/// Print the differences to the previous run, the newly failed tests first as they matter the most
+fn print_delta(delta: &RunDelta) {
+    if delta.is_empty() {
+        println!("No changes since the previous run");
+        info!("No changes since the previous run");
+        return;
+    }
+    let print_paths = |title: String, colour: Colour, paths: &[PathBuf]| {
+        if paths.is_empty() {
+            return;
+        }
+        println!("{}", colour.paint(title));
+        for path in paths {
+            println!("  {}", path.display());
+        }
+    };
+    print_paths(
+        format!(
+            "Newly failing since the previous run ({}):",
+            delta.newly_failed.len()
+        ),
+        Colour::Red,
+        &delta.newly_failed,
+    );
+    print_paths(
+        format!(
+            "Newly passing since the previous run ({}):",
+            delta.newly_passed.len()
+        ),
+        Colour::Green,
+        &delta.newly_passed,
+    );
+    if !delta.duration_changes.is_empty() {
+        println!(
+            "{}",
+            Colour::Yellow.paint(format!(
+                "Duration changed since the previous run ({}):",
+                delta.duration_changes.len()
+            ))
+        );
+        for (path, before, after) in &delta.duration_changes {
+            println!("  {} {:.2?} -> {:.2?}", path.display(), before, after);
+        }
+    }
+    if !delta.added.is_empty() || !delta.removed.is_empty() {
+        println!(
+            "{}",
+            Colour::Yellow.paint(format!(
+                "{} test(s) not in the previous run, {} test(s) of the previous run not run",
+                delta.added.len(),
+                delta.removed.len()
+            ))
+        );
+    }
+    for path in &delta.newly_failed {
+        info!(
+            "Test {} newly failing since the previous run",
+            path.display()
+        );
+    }
+}
+
+/// The failure of a syntax error like `tests/a_spec.lua:3: '=' expected near 'x'`, attributed to its file and line.
+/// The file is taken from the error rather than the test, as LuaJIT shortens long paths to `...` and a leading part
+fn syntax_failure(error: &str) -> TestFailure {
+    let location = error.match_indices(':').find_map(|(start, _)| {
+        let rest = &error[start + 1..];
+        let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+        let line = rest[..digits].parse::<u32>().ok()?;
+        let message = rest[digits..].strip_prefix(": ")?;
+        Some((&error[..start], line, message))
+    });
+    match location {
+        Some((file, line, message)) => TestFailure {
+            message: message.to_string(),
+            file: Some(file.to_string()),
+            line: Some(line),
+            ..TestFailure::default()
+        },
+        None => TestFailure {
+            message: error.to_string(),
+            ..TestFailure::default()
+        },
+    }
+}
+
+/// The failure of a test that ran for longer than its timeout directive allows
+fn timeout_failure(test_file: &TestFile) -> TestFailure {
+    TestFailure {
+        message: format!(
+            "Timed out after {:.2?} (timeout directive), nvim was killed",
+            test_file.directives.timeout.unwrap_or_default()
+        ),
+        ..TestFailure::default()
+    }
+}
+
+/// The first line of the output matching any of the error patterns. Matched line by line, so that `^` and `$` of
+/// a pattern are the start and the end of a line
+fn find_error_line<'a>(error_patterns: &[Regex], output: &'a str) -> Option<&'a str> {
+    output
+        .lines()
+        .find(|line| error_patterns.iter().any(|pattern| pattern.is_match(line)))
+}
+
+/// The failure of a test whose messages (`:messages`) contain an error, i.e. a line matching any of the error
+/// patterns, unless the test allows them with the `allow-messages` directive
+fn messages_failure(
+    test_file: &TestFile,
+    messages: &[String],
+    error_patterns: &[Regex],
+) -> Option<TestFailure> {
+    if test_file.directives.allow_messages {
+        return None;
+    }
+    let line = messages
+        .iter()
+        .find_map(|message| find_error_line(error_patterns, message))?;
+    Some(TestFailure {
+        message: format!("Reported an error in :messages: {}", line.trim()),
+        ..TestFailure::default()
+    })
+}
+
+/// Print whether the run failed, and why
+fn print_outcome(summary: &RunSummary) {
+    info!(
+        "{} passed, {} failed, {} skipped, {} expected failure(s), {} unexpected pass(es) in {:.2?}",
+        summary.passed,
+        summary.failed,
+        summary.skipped,
+        summary.expected_failures,
+        summary.unexpected_passes,
+        summary.duration
+    );
+    let (assertions_passed, assertions_total) = summary
+        .results
+        .iter()
+        .filter_map(|result| result.report.assertion_counts())
+        .fold((0, 0), |(passed, total), (p, t)| (passed + p, total + t));
+    if assertions_total > 0 {
+        println!(
+            "{} of {} assertions passed",
+            assertions_passed, assertions_total
+        );
+        info!(
+            "{} of {} assertions passed",
+            assertions_passed, assertions_total
+        );
+    }
+    let num_without_assertions = summary
+        .results
+        .iter()
+        .filter(|result| result.has_no_assertions())
+        .count();
+    if num_without_assertions > 0 {
+        println!(
+            "{}",
+            Colour::Yellow.paint(format!(
+                "{} test(s) passed without running any assertions",
+                num_without_assertions
+            ))
+        );
+    }
+    // Otherwise a run in which nothing ran would look like a passing one
+    if summary.num_run() == 0 && summary.skipped + summary.filtered > 0 {
+        println!(
+            "{}",
+            Colour::Yellow.paint(format!(
+                "0 tests run ({} skipped, {} filtered)",
+                summary.skipped, summary.filtered
+            ))
+        );
+    }
+    if summary.expected_failures > 0 {
+        println!(
+            "{}",
+            Colour::Yellow.paint(format!(
+                "{} test(s) failed as expected (xfail)",
+                summary.expected_failures
+            ))
+        );
+    }
+    if summary.failed > 0 {
+        println!(
+            "{}",
+            Colour::Red.paint(format!("{} test(s) failed", summary.failed))
+        );
+    }
+    if summary.unexpected_passes > 0 {
+        println!(
+            "{}",
+            Colour::Red.paint(format!(
+                "{} test(s) marked xfail passed unexpectedly",
+                summary.unexpected_passes
+            ))
+        );
+    }
+    let quarantined_failures: Vec<&TestResult> = summary.quarantined_failures().collect();
+    if !quarantined_failures.is_empty() {
+        println!(
+            "{}",
+            Colour::Yellow.paint(format!(
+                "{} quarantined test(s) failed, which does not fail the run:",
+                quarantined_failures.len()
+            ))
+        );
+        for result in &quarantined_failures {
+            println!("  {}", result.path.display());
+            info!("Quarantined test {} failed", result.path.display());
+        }
+    }
+    if !summary.releasable.is_empty() {
+        println!(
+            "{}",
+            Colour::Green.paint(format!(
+                "{} quarantined test(s) passed in each of their last {} runs, and may be taken out of quarantine:",
+                summary.releasable.len(),
+                QUARANTINE_RELEASE_RUNS
+            ))
+        );
+        for path in &summary.releasable {
+            println!("  {}", path.display());
+            info!(
+                "Quarantined test {} passed in each of its last {} runs",
+                path.display(),
+                QUARANTINE_RELEASE_RUNS
+            );
+        }
+    }
+    if let Some(allowed) = summary.allowed_failures {
+        let failures = summary.num_failures();
+        let message = match failures.cmp(&allowed) {
+            std::cmp::Ordering::Greater => format!(
+                "{} failure(s), {} more than the {} allowed by --allowed-failures",
+                failures,
+                failures - allowed,
+                allowed
+            ),
+            std::cmp::Ordering::Equal => format!(
+                "{} failure(s), as many as allowed by --allowed-failures",
+                failures
+            ),
+            std::cmp::Ordering::Less => format!(
+                "{} failure(s), {} fewer than the {} allowed by --allowed-failures",
+                failures,
+                allowed - failures,
+                allowed
+            ),
+        };
+        let colour = match failures > allowed {
+            true => Colour::Red,
+            false => Colour::Yellow,
+        };
+        println!("{}", colour.paint(&message));
+        info!("{}", message);
+    }
+    if !summary.flaky.is_empty() {
+        println!(
+            "{}",
+            Colour::Red.paint(format!(
+                "{} flaky test(s), whose outcome differed between the runs:",
+                summary.flaky.len()
+            ))
+        );
+        for flaky in &summary.flaky {
+            println!(
+                "  {} passed {} of {} runs ({:.0}%)",
+                flaky.path.display(),
+                flaky.passes,
+                flaky.runs,
+                flaky.pass_rate() * 100.0
+            );
+        }
+        warn!("{} flaky test(s)", summary.flaky.len());
+    }
+    if !summary.regressions.is_empty() {
+        println!(
+            "{}",
+            Colour::Red.paint(format!(
+                "{} test(s) got slower than the timing baseline allows (--baseline), the worst first:",
+                summary.regressions.len()
+            ))
+        );
+        for regression in &summary.regressions {
+            println!(
+                "  {}: {:.0}ms → {:.0}ms (+{:.0}%)",
+                regression.path.display(),
+                regression.baseline_ms,
+                regression.current_ms,
+                regression.change_percent()
+            );
+        }
+        warn!("{} timing regression(s)", summary.regressions.len());
+    }
+    if summary.failed == 0 && summary.unexpected_passes == 0 && !summary.unresolved_deps.is_empty()
+    {
+        println!(
+            "{}",
+            Colour::Red.paint(format!(
+                "{} test dependency(ies) could not be resolved: {}",
+                summary.unresolved_deps.len(),
+                summary.unresolved_deps.join(", ")
+            ))
+        );
+    }
+    if !summary.warnings.is_empty() {
+        println!(
+            "{}",
+            Colour::Red.paint(format!(
+                "{} warning(s) about the setup of the run (--fail-on-warning):",
+                summary.warnings.len()
+            ))
+        );
+        for warning in &summary.warnings {
+            println!("  {}", warning);
+        }
+    }
+    if !summary.user_config_changes.is_empty() {
+        println!(
+            "{}",
+            Colour::Red.paint(format!(
+                "{} file(s) of the nvim config or data of the user changed during the run (--guard-user-config):",
+                summary.user_config_changes.len()
+            ))
+        );
+        for path in &summary.user_config_changes {
+            println!("  {}", path.display());
+            error!("Changed during the run: {}", path.display());
+        }
+    }
+    if summary.failed == 0 && summary.unexpected_passes == 0 && !summary.unused_deps.is_empty() {
+        println!(
+            "{}",
+            Colour::Red.paint(format!(
+                "{} test dependency(ies) are not used (--fail-on-unused-deps)",
+                summary.unused_deps.len()
+            ))
+        );
+    }
+}
+
+/// Report the results of the most recent run again, as recorded in the results cache, without running
+/// anything. Fails if no run has been recorded
+pub fn replay_last_run(
+    config: &TestConfig,
+    options: &RunOptions,
+) -> Result<RunSummary, RunnerError> {
+    let results_cache_path = resolve_cache_dir(options, config).join("results.json");
+    let Some(last_run) = ResultsCache::load(&results_cache_path).last_run else {
+        return Err(RunnerError::Config(format!(
+            "No previous run recorded in {}, run the tests first",
+            results_cache_path.display()
+        )));
+    };
+    let summary = last_run.summary();
+
+    let reporters = default_reporters(options)?;
+    let tests: Vec<TestFile> = summary
+        .results
+        .iter()
+        .map(|result| TestFile {
+            path: result.path.clone(),
+            cwd: None,
+            directives: TestDirectives::default(),
+            env: BTreeMap::new(),
+        })
+        .collect();
+    for reporter in &reporters {
+        reporter.on_run_start(&tests);
+    }
+    for result in &summary.results {
+        for reporter in &reporters {
+            reporter.on_test_complete(result);
+        }
+    }
+    for reporter in &reporters {
+        reporter.on_run_complete(&summary);
+    }
+
+    if summary.skipped > 0 {
+        println!(
+            "{}",
+            Colour::Yellow.paint(format!("{} test(s) skipped", summary.skipped))
+        );
+    }
+    print_outcome(&summary);
+    Ok(summary)
+}
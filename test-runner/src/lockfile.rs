@@ -0,0 +1,146 @@
+//! The lockfile of the external dependencies: the commit (or, for an archive, the checksum) each of them resolved
+//! to, written with --write-lockfile and committed along with the config. A run with --locked fails if the
+//! dependencies resolve to anything else than the lockfile records, e.g. because a branch moved, so that CI
+//! tests against exactly the pinned versions
+
+use ansi_term::Colour;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::archive::archive_format;
+use crate::error::RunnerError;
+use crate::state::{write_json_atomically, State};
+
+/// Where the lockfile is, relative to the current directory
+pub const LOCKFILE_PATH: &str = "nvim-test-runner.lock.json";
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct Lockfile {
+    /// The commit (or checksum) of each external dependency, by normalized uri
+    pub dependencies: BTreeMap<String, String>,
+}
+
+impl Lockfile {
+    /// The revisions the external dependencies resolved to: the commit checked out in each clone, given by
+    /// normalized uri, or the checksum recorded in the state for an archive
+    pub fn resolved(
+        clones: &HashMap<String, PathBuf>,
+        state: &State,
+        git: &Path,
+    ) -> Result<Lockfile, RunnerError> {
+        let mut dependencies = BTreeMap::new();
+        for (uri, dep_path) in clones {
+            let revision = match archive_format(uri) {
+                Some(_) => state
+                    .test_dependencies
+                    .iter()
+                    .find(|dep_state| &dep_state.uri == uri)
+                    .map(|dep_state| dep_state.hash.clone())
+                    .unwrap_or_default(),
+                None => head_commit(uri, dep_path, git)?,
+            };
+            dependencies.insert(uri.clone(), revision);
+        }
+        Ok(Lockfile { dependencies })
+    }
+
+    pub fn load(path: &Path) -> Result<Lockfile, RunnerError> {
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            RunnerError::Config(format!(
+                "Failed to read the lockfile {} (write it with --write-lockfile): {}",
+                path.display(),
+                e
+            ))
+        })?;
+        serde_json::from_str(&contents).map_err(|e| {
+            RunnerError::Config(format!("{} is not a valid lockfile: {}", path.display(), e))
+        })
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), RunnerError> {
+        write_json_atomically(path, self)?;
+        let message = format!(
+            "Wrote the lockfile {} ({} dependencies)",
+            path.display(),
+            self.dependencies.len()
+        );
+        println!("{}", Colour::Blue.paint(&message));
+        info!("{}", message);
+        Ok(())
+    }
+
+    /// Check that the dependencies resolved as the lockfile records, failing with the uri, the locked and the
+    /// resolved revision of each that did not: a different commit, or a dependency that is missing from either.
+    /// With `partial` (e.g. with --only-deps), the locked dependencies that were not resolved are not reported
+    pub fn verify(&self, resolved: &Lockfile, partial: bool) -> Result<(), RunnerError> {
+        const MISSING: &str = "<none>";
+        let mut mismatches: Vec<(&str, &str, &str)> = Vec::new();
+        for (uri, actual) in &resolved.dependencies {
+            match self.dependencies.get(uri) {
+                Some(expected) if expected.eq_ignore_ascii_case(actual) => {}
+                Some(expected) => mismatches.push((uri, expected, actual)),
+                None => mismatches.push((uri, MISSING, actual)),
+            }
+        }
+        if !partial {
+            for (uri, expected) in &self.dependencies {
+                if !resolved.dependencies.contains_key(uri) {
+                    mismatches.push((uri, expected, MISSING));
+                }
+            }
+        }
+        if mismatches.is_empty() {
+            info!("The dependencies match the lockfile");
+            return Ok(());
+        }
+
+        mismatches.sort();
+        let uri_width = mismatches
+            .iter()
+            .map(|(uri, _, _)| uri.len())
+            .max()
+            .unwrap_or_default()
+            .max("uri".len());
+        let revision_width = mismatches
+            .iter()
+            .map(|(_, expected, _)| expected.len())
+            .max()
+            .unwrap_or_default()
+            .max("expected".len());
+        let mut message = format!(
+            "{} dependencies do not match the lockfile (update it with --write-lockfile):\n  {:<uri_width$}  {:<revision_width$}  actual",
+            mismatches.len(),
+            "uri",
+            "expected",
+        );
+        for (uri, expected, actual) in mismatches {
+            message.push_str(&format!(
+                "\n  {:<uri_width$}  {:<revision_width$}  {}",
+                uri, expected, actual
+            ));
+        }
+        Err(RunnerError::Config(message))
+    }
+}
+
+/// The commit checked out in the clone of a dependency
+fn head_commit(uri: &str, dep_path: &Path, git: &Path) -> Result<String, RunnerError> {
+    let output = Command::new(git)
+        .current_dir(dep_path)
+        .arg("rev-parse")
+        .arg("HEAD")
+        .output()
+        .map_err(|e| RunnerError::Git(format!("Failed to execute git rev-parse: {}", e)))?;
+    if !output.status.success() {
+        return Err(RunnerError::Git(format!(
+            "Failed to find the commit checked out for {} in {}:\n{}",
+            uri,
+            dep_path.display(),
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
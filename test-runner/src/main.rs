@@ -1,109 +1,728 @@
 use ansi_term::Colour;
-use clap::Parser;
-use glob::glob;
-use indoc::indoc;
-use log::{self, debug, error, info, warn};
+use clap::{builder::FalseyValueParser, Parser, Subcommand};
+use log::{self, error, info};
 use log4rs::{
     append::file::FileAppender,
     config::{Appender, Config, Root},
     encode::pattern::PatternEncoder,
 };
-use rayon::prelude::*;
-use serde::{Deserialize, Serialize};
-use std::io::{BufWriter, Read};
-use std::process::Command;
-use std::{collections::HashMap, io::Write};
-use std::{env, fs::File};
+use nvim_test_runner::baseline::parse_name;
+use nvim_test_runner::bench::{run_bench, BenchOptions, BenchTarget};
+use nvim_test_runner::config::{
+    apply_env_overrides, find_config, load_config, parse_dep_spec, CONFIG_PATHS,
+};
+use nvim_test_runner::discovery::{parse_duration, parse_shard, Shard};
+use nvim_test_runner::doctor::run_doctor;
+use nvim_test_runner::init::run_init;
+use nvim_test_runner::interrupt;
+use nvim_test_runner::reporter::{
+    parse_failure_format, parse_output_format, FailureFormat, OutputFormat,
+};
+use nvim_test_runner::repro::replay;
+use nvim_test_runner::secrets::{parse_secret, RedactingLogger};
+use nvim_test_runner::self_update::run_self_update;
+use nvim_test_runner::tui;
+use nvim_test_runner::{
+    default_global_cache_dir, effective_config, replay_last_run, run, run_matrix, RunOptions,
+    RunSummary, RunnerError, TestConfig, TestDepedency,
+};
+use std::env;
+
+/// The file the runner logs to, in more detail than it prints
+const LOG_FILE: &str = "/tmp/nvim-test-runner.log";
+
+/// The number of lines of the log printed by --show-log without a value
+const DEFAULT_SHOW_LOG_LINES: usize = 20;
 
 /// Run tests for Neovim plugins
+///
+/// Every option can also be given as an environment variable prefixed with `NVIM_TEST_RUNNER_`
+/// (e.g. `NVIM_TEST_RUNNER_SKIP_REMOTE_CHECK=1`, where `0`, `false`, `no` and `off` are falsey for flags), except
+/// for the one-off actions `--write-lockfile`, `--print-config`, `--refresh-test-utils`, `--last` and `--replay`,
+/// the repeatable `--dep` and `--secret`, and `--no-resume`, which overrides `NVIM_TEST_RUNNER_RESUME`. Command line
+/// arguments take precedence over environment variables, which in turn take precedence over the config file
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// Whether to skip checking the local clone of the external dependency is up-to-date with the remote repository
-    #[arg(short, long)]
-    skip_remote_check: bool,
-}
-
-#[derive(Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct TestDepedency {
-    uri: String,
-    branch: Option<String>,
-    sha: Option<String>,
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    /// Whether to skip checking the local clone of the external dependency is up-to-date with the remote repository.
+    /// Overrides the skipRemoteCheck config field; pass `--skip-remote-check=false` to check regardless of the config
+    #[arg(
+        short,
+        long,
+        env = "NVIM_TEST_RUNNER_SKIP_REMOTE_CHECK",
+        value_parser = FalseyValueParser::new(),
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    skip_remote_check: Option<bool>,
+
+    /// The config file to use instead of looking for nvim-test-runner.json, nvim-test-runner.toml or
+    /// nvim-test-runner.yaml. `-` reads the JSON config from stdin
+    #[arg(long, value_name = "PATH", env = "NVIM_TEST_RUNNER_CONFIG")]
+    config: Option<String>,
+
+    /// Fail if there is no config file, rather than running with the default config, e.g. to catch a run from the
+    /// wrong directory
+    #[arg(long, env = "NVIM_TEST_RUNNER_REQUIRE_CONFIG", value_parser = FalseyValueParser::new())]
+    require_config: bool,
+
+    /// The directory in which the state, external dependencies and test-utils are stored. Defaults to `.test`
+    #[arg(long, value_name = "PATH", env = "NVIM_TEST_RUNNER_CACHE_DIR")]
+    cache_dir: Option<std::path::PathBuf>,
+
+    /// Never access the network: skips the remote check of the external dependencies and the download of
+    /// test-utils, failing if either of them is not already cached
+    #[arg(long, alias = "no-download", env = "NVIM_TEST_RUNNER_OFFLINE", value_parser = FalseyValueParser::new())]
+    offline: bool,
+
+    /// Fail if anything would have to be fetched, rather than fetching it: test-utils that is missing or of
+    /// another version, or an external dependency that is not cloned as configured. Never accesses the network,
+    /// e.g. to check that the cache was primed in an earlier CI step
+    #[arg(long, env = "NVIM_TEST_RUNNER_FROZEN", value_parser = FalseyValueParser::new())]
+    frozen: bool,
+
+    /// Fail if the external dependencies do not resolve to the commits (or archive checksums) recorded in the
+    /// lockfile (nvim-test-runner.lock.json), listing the uri, the locked and the resolved commit of each that
+    /// differs, e.g. because a branch moved. Checks the dependencies without checking them out at the locked
+    /// commits
+    #[arg(long, env = "NVIM_TEST_RUNNER_LOCKED", value_parser = FalseyValueParser::new(), conflicts_with = "write_lockfile")]
+    locked: bool,
+
+    /// Write the commits (or archive checksums) the external dependencies resolved to into the lockfile
+    /// (nvim-test-runner.lock.json), to be committed and checked with --locked
+    #[arg(long)]
+    write_lockfile: bool,
+
+    /// Only print failed tests and the final summary. Also disables the progress bar
+    #[arg(short, long, env = "NVIM_TEST_RUNNER_QUIET", value_parser = FalseyValueParser::new())]
+    quiet: bool,
+
+    /// Fold the results of passing tests into the progress bar instead of printing a line for each of them
+    #[arg(long, env = "NVIM_TEST_RUNNER_COMPACT", value_parser = FalseyValueParser::new())]
+    compact: bool,
+
+    /// Print more details about the run, such as the runtimepath the tests are run with
+    #[arg(short, long, env = "NVIM_TEST_RUNNER_VERBOSE", value_parser = FalseyValueParser::new())]
+    verbose: bool,
+
+    /// Resolve the dependencies and discover the tests, printing the runtimepath and the tests that would
+    /// run, without running them
+    #[arg(long, env = "NVIM_TEST_RUNNER_DRY_RUN", value_parser = FalseyValueParser::new())]
+    dry_run: bool,
+
+    /// Only check that the test files parse, reporting the file and line of each syntax error, without running
+    /// the tests. Quicker than a run, e.g. for a pre-commit hook
+    #[arg(long, env = "NVIM_TEST_RUNNER_CHECK", value_parser = FalseyValueParser::new())]
+    check: bool,
+
+    /// Continue without a dependency that fails to resolve, rather than aborting the run. The run still
+    /// fails in the end
+    #[arg(long, env = "NVIM_TEST_RUNNER_KEEP_GOING", value_parser = FalseyValueParser::new())]
+    keep_going: bool,
+
+    /// Only run the test files that changed compared to --changed-base, including untracked ones.
+    /// Falls back to running all tests outside of a git repository
+    #[arg(long, env = "NVIM_TEST_RUNNER_CHANGED", value_parser = FalseyValueParser::new())]
+    changed: bool,
+
+    /// The git ref that --changed compares against
+    #[arg(
+        long,
+        value_name = "REF",
+        default_value = "HEAD",
+        env = "NVIM_TEST_RUNNER_CHANGED_BASE"
+    )]
+    changed_base: String,
+
+    /// With --changed, also run the test files that live in a directory containing a changed file
+    #[arg(long, env = "NVIM_TEST_RUNNER_CHANGED_DIRS", value_parser = FalseyValueParser::new())]
+    changed_dirs: bool,
+
+    /// Overwrite the snapshots compared against by `T.assert_snapshot` instead of asserting against them
+    #[arg(short, long, env = "NVIM_TEST_RUNNER_UPDATE", value_parser = FalseyValueParser::new())]
+    update: bool,
+
+    /// Write the captured output of each test to `<DIR>/<test>-<hash>.log`, along with all of them combined into
+    /// `<DIR>/combined.log`
+    #[arg(long, value_name = "DIR", env = "NVIM_TEST_RUNNER_OUTPUT_DIR")]
+    output_dir: Option<std::path::PathBuf>,
+
+    /// The format to report the results in: human, json, tap, junit or markdown, printed to stdout or, given as
+    /// e.g. `junit=report.xml`, written to a file. Can be given more than once, with at most one format printed to
+    /// stdout. The human output is printed unless another format is
+    #[arg(long, value_name = "FORMAT[=PATH]", env = "NVIM_TEST_RUNNER_FORMAT", value_delimiter = ',', value_parser = parse_output_format)]
+    format: Vec<OutputFormat>,
+
+    /// The file to write the one --format that is not given a file of its own to
+    #[arg(long, value_name = "PATH", env = "NVIM_TEST_RUNNER_OUTPUT")]
+    output: Option<std::path::PathBuf>,
+
+    /// Write a summary of the run in Markdown to the given file, e.g. `$GITHUB_STEP_SUMMARY` or a file to post as
+    /// a comment on a pull request: a table of the results, the slowest tests and the output of each failing test.
+    /// The same as `--format markdown=PATH`
+    #[arg(long, value_name = "PATH", env = "NVIM_TEST_RUNNER_MARKDOWN_REPORT")]
+    markdown_report: Option<std::path::PathBuf>,
+
+    /// The maximum number of tests to run in parallel. Defaults to the number of CPUs
+    #[arg(short, long, value_name = "N", env = "NVIM_TEST_RUNNER_JOBS")]
+    jobs: Option<usize>,
+
+    /// Only run the test files tagged with any of the given comma-separated tags (see the `tags` directive)
+    #[arg(long, value_name = "TAGS", env = "NVIM_TEST_RUNNER_TAGS")]
+    tags: Option<String>,
+
+    /// Only resolve the test dependencies with the given comma-separated names (by uri or directory name, as in
+    /// the `requires` directive), skipping the others, e.g. for a quick run of a part of the suite. Tests
+    /// requiring a skipped dependency are skipped
+    #[arg(long, value_name = "NAMES", env = "NVIM_TEST_RUNNER_ONLY_DEPS")]
+    only_deps: Option<String>,
+
+    /// Do not run the test files tagged with any of the given comma-separated tags. Takes precedence over --tags
+    #[arg(long, value_name = "TAGS", env = "NVIM_TEST_RUNNER_EXCLUDE_TAGS")]
+    exclude_tags: Option<String>,
+
+    /// Print the results as a TAP version 13 stream once all tests have run, in place of the per-test output
+    /// and the progress bar. The same as `--format tap`
+    #[arg(long, env = "NVIM_TEST_RUNNER_TAP", value_parser = FalseyValueParser::new())]
+    tap: bool,
+
+    /// Print the failures as GitHub Actions annotations, which GitHub shows on the lines of the diff they point
+    /// at. On by default when running in GitHub Actions (`GITHUB_ACTIONS=true`); pass `--github-annotations=false`
+    /// to turn them off there
+    #[arg(
+        long,
+        env = "NVIM_TEST_RUNNER_GITHUB_ANNOTATIONS",
+        value_parser = FalseyValueParser::new(),
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    github_annotations: Option<bool>,
+
+    /// Stop starting new tests once N tests have failed. The tests that are not started are reported as skipped
+    #[arg(long, value_name = "N", env = "NVIM_TEST_RUNNER_BAIL")]
+    bail: Option<usize>,
+
+    /// Pass the run as long as at most N tests fail, e.g. the known failures during a migration, printing how
+    /// far the failures are from N and the tests that newly fail since the previous run
+    #[arg(long, value_name = "N", env = "NVIM_TEST_RUNNER_ALLOWED_FAILURES")]
+    allowed_failures: Option<usize>,
+
+    /// Only run the `T.it` blocks of test-utils whose full name (including the enclosing `T.describe` blocks)
+    /// contains the given pattern
+    #[arg(long, value_name = "PATTERN", env = "NVIM_TEST_RUNNER_GREP")]
+    grep: Option<String>,
+
+    /// Fail if the test paths do not match any test file, rather than succeeding without running any tests
+    #[arg(long, env = "NVIM_TEST_RUNNER_FAIL_ON_EMPTY", value_parser = FalseyValueParser::new())]
+    fail_on_empty: bool,
+
+    /// Run no tests if the config gives no test paths, rather than falling back to the default test paths
+    #[arg(long, env = "NVIM_TEST_RUNNER_NO_DEFAULT_PATHS", value_parser = FalseyValueParser::new())]
+    no_default_paths: bool,
+
+    /// Do not write a `.gitignore` ignoring everything into the cache directory when creating it
+    #[arg(long, env = "NVIM_TEST_RUNNER_NO_GITIGNORE", value_parser = FalseyValueParser::new())]
+    no_gitignore: bool,
+
+    /// Run the tests against the plugin as installed: copy the files given by the installFiles config field (by
+    /// default the runtime directories such as lua, plugin and doc) into a directory under the cache directory,
+    /// and put that on the runtimepath instead of the current directory
+    #[arg(long, env = "NVIM_TEST_RUNNER_INSTALLED", value_parser = FalseyValueParser::new())]
+    installed: bool,
+
+    /// Run each test with a fresh temporary HOME (and XDG_CONFIG_HOME, XDG_DATA_HOME, XDG_STATE_HOME and
+    /// XDG_CACHE_HOME under it), removed after the test, so that tests cannot read or write the user's files
+    #[arg(long, env = "NVIM_TEST_RUNNER_ISOLATE", value_parser = FalseyValueParser::new())]
+    isolate: bool,
+
+    /// Only run the tests that did not pass in the previous run, skipping those that passed and have not been
+    /// modified since. Note that this may hide new failures in unchanged tests caused by changes elsewhere
+    /// (e.g. in the plugin or its dependencies); use --no-resume to force a full run
+    #[arg(long, env = "NVIM_TEST_RUNNER_RESUME", value_parser = FalseyValueParser::new(), overrides_with = "no_resume")]
+    resume: bool,
+
+    /// Only run the tests that failed in the last run that finished, e.g. to re-check them after a fix. Runs
+    /// all the tests (with a warning) if there is no previous run
+    #[arg(long, env = "NVIM_TEST_RUNNER_FAILED", value_parser = FalseyValueParser::new())]
+    failed: bool,
+
+    /// Only run the tests that require (with the `requires` directive) a dependency whose commit changed since the
+    /// last run that finished, e.g. after bumping a dependency. Tests that do not give the dependencies they
+    /// require are always run. Runs all the tests (with a warning) if no previous run recorded the dependencies
+    #[arg(long, env = "NVIM_TEST_RUNNER_AFFECTED_BY_DEPS", value_parser = FalseyValueParser::new())]
+    affected_by_deps: bool,
+
+    /// Print the effective config (the config file merged with the environment variables and the command
+    /// line arguments, with the defaults filled in) as JSON and exit
+    #[arg(long)]
+    print_config: bool,
+
+    /// Only run the test files modified within the given duration (e.g. `10m`, `2h` or `1d`). Combines with
+    /// the other filters such as --changed
+    #[arg(long, value_name = "DURATION", env = "NVIM_TEST_RUNNER_SINCE", value_parser = parse_duration)]
+    since: Option<std::time::Duration>,
+
+    /// Only run the given shard of the test files, e.g. `2/4` for the second of four, to split the suite across
+    /// CI machines. The files are sorted by path and dealt out in turn, so the shards are disjoint, stable and of
+    /// the same size (give or take one)
+    #[arg(long, value_name = "INDEX/TOTAL", env = "NVIM_TEST_RUNNER_SHARD", value_parser = parse_shard)]
+    shard: Option<Shard>,
+
+    /// Run all tests N times before the recorded run, without recording their results, e.g. to warm up the disk
+    /// caches when timing the tests. Fails if a test fails during the warmup
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 0,
+        env = "NVIM_TEST_RUNNER_WARMUP"
+    )]
+    warmup: usize,
+
+    /// Run the whole suite N times and report the tests that did not pass in all of the runs as flaky, failing
+    /// the run. Only the last run is reported in full
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        env = "NVIM_TEST_RUNNER_REPEAT"
+    )]
+    repeat: usize,
+
+    /// Reuse the refs listed by `git ls-remote` for a dependency for the given duration (e.g. `300` seconds or
+    /// `5m`) rather than asking the remote on every run. Unlike --skip-remote-check, the remote is still checked
+    /// once the duration has passed
+    #[arg(long, value_name = "DURATION", env = "NVIM_TEST_RUNNER_REMOTE_TTL", value_parser = parse_duration)]
+    remote_ttl: Option<std::time::Duration>,
+
+    /// List the N slowest tests with their durations once the run has finished
+    #[arg(long, value_name = "N", env = "NVIM_TEST_RUNNER_SLOWEST")]
+    slowest: Option<usize>,
+
+    /// Compare the median duration of each test over the rounds of --repeat to the timing baseline of the given
+    /// name, failing the run if any got slower than the baselineThreshold and baselineTolerance config fields
+    /// allow
+    #[arg(long, value_name = "NAME", env = "NVIM_TEST_RUNNER_BASELINE", value_parser = parse_name)]
+    baseline: Option<String>,
+
+    /// Save the median duration of each test over the rounds of --repeat as the timing baseline of the given
+    /// name, for --baseline to compare later runs to
+    #[arg(long, value_name = "NAME", env = "NVIM_TEST_RUNNER_SAVE_BASELINE", value_parser = parse_name)]
+    save_baseline: Option<String>,
+
+    /// Print which tests newly failed, newly passed or got significantly slower or faster since the previous run
+    #[arg(long, env = "NVIM_TEST_RUNNER_COMPARE", value_parser = FalseyValueParser::new())]
+    compare: bool,
+
+    /// Write the events of the run (test_started, test_passed, test_failed and test_skipped) as newline-delimited
+    /// JSON to the given file or FIFO as they happen, e.g. for an editor to show the results live
+    #[arg(long, value_name = "PATH", env = "NVIM_TEST_RUNNER_EVENTS")]
+    events: Option<std::path::PathBuf>,
+
+    /// Only print the output of the first N failures, and the number of the other failures. The output of all
+    /// failures is still written to the log (and with --output-dir, to the output directory)
+    #[arg(long, value_name = "N", env = "NVIM_TEST_RUNNER_MAX_FAILURES_OUTPUT")]
+    max_failures_output: Option<usize>,
+
+    /// The proxy to download test-utils through, e.g. `http://proxy.example.com:8080`. Without it, the proxies
+    /// given by the HTTP_PROXY, HTTPS_PROXY and NO_PROXY environment variables are used
+    #[arg(long, value_name = "URL", env = "NVIM_TEST_RUNNER_PROXY")]
+    proxy: Option<String>,
+
+    /// Download test-utils again even if the cached one is up-to-date, e.g. if it is broken
+    #[arg(long)]
+    refresh_test_utils: bool,
+
+    /// Run up to N test files one after another in each nvim, rather than starting nvim for each test. This
+    /// saves the startup time of nvim, at the cost of isolation: the modules loaded and the buffers opened by a
+    /// test are cleared before the next one, but any other global state is shared
+    #[arg(long, value_name = "N", env = "NVIM_TEST_RUNNER_BATCH")]
+    batch: Option<usize>,
+
+    /// Print the results of the most recent run again, from the results cache, without running any tests.
+    /// Exits with the exit code of that run
+    #[arg(long)]
+    last: bool,
+
+    /// An extra dependency, as `<uri>[@branch][#sha]` (e.g. `https://github.com/foo/bar@dev`), added to those of
+    /// the config. Can be given more than once
+    #[arg(long = "dep", value_name = "SPEC", value_parser = parse_dep_spec)]
+    deps: Vec<TestDepedency>,
+
+    /// Fail unless exactly N test files match the test paths, e.g. to catch test paths that silently stopped
+    /// matching part of the suite. Counted before any filters (such as --changed or --tags) are applied
+    #[arg(long, value_name = "N", env = "NVIM_TEST_RUNNER_EXPECT_TESTS")]
+    expect_tests: Option<usize>,
+
+    /// Fail unless at least N test files match the test paths. Counted like --expect-tests
+    #[arg(long, value_name = "N", env = "NVIM_TEST_RUNNER_MIN_TESTS")]
+    min_tests: Option<usize>,
+
+    /// List the N tests whose nvim used the most memory (peak RSS) once the run has finished. Only measured on
+    /// Unix, and not for tests run with --batch
+    #[arg(long, value_name = "N", env = "NVIM_TEST_RUNNER_MOST_MEMORY")]
+    most_memory: Option<usize>,
+
+    /// Summarize the results by the directory of the test files once the run has finished, with the counts of
+    /// each directory and those with failures in red, e.g. to see which feature area of a large suite is broken
+    #[arg(long, env = "NVIM_TEST_RUNNER_GROUP_BY_DIR", value_parser = FalseyValueParser::new())]
+    group_by_dir: bool,
+
+    /// The profile of the config to merge over it, e.g. `ci`. The environment variables and the command line
+    /// arguments still take precedence over the profile
+    #[arg(long, value_name = "NAME", env = "NVIM_TEST_RUNNER_PROFILE")]
+    profile: Option<String>,
+
+    /// Run all tests, overriding --resume (e.g. when given as NVIM_TEST_RUNNER_RESUME)
+    #[arg(long)]
+    no_resume: bool,
+
+    /// The exit code when no test was run because all of them were skipped or filtered out, e.g. 1 to fail CI
+    /// rather than mistake an empty run for a passing one
+    #[arg(
+        long,
+        value_name = "CODE",
+        default_value_t = 0,
+        env = "NVIM_TEST_RUNNER_NO_TESTS_EXIT_CODE"
+    )]
+    no_tests_exit_code: i32,
+
+    /// Run the tests that are marked with a `-- skip: <reason>` directive as well
+    #[arg(long, env = "NVIM_TEST_RUNNER_RUN_SKIPPED", value_parser = FalseyValueParser::new())]
+    run_skipped: bool,
+
+    /// The maximum number of nvim processes running at once, regardless of --jobs. Defaults to 64, which keeps
+    /// large suites from exhausting the file descriptors of the runner
+    #[arg(long, value_name = "N", env = "NVIM_TEST_RUNNER_MAX_PROCESSES")]
+    max_processes: Option<usize>,
+
+    /// Stagger the start of the run rather than starting --jobs nvims at once: start --ramp-up-start of them
+    /// (1 by default), and double the number running at once every given interval (e.g. `200ms`) until the full
+    /// parallelism is reached. Avoids the spike of disk and CPU contention of a large run starting on a cold cache
+    #[arg(long, value_name = "INTERVAL", env = "NVIM_TEST_RUNNER_RAMP_UP", value_parser = parse_duration)]
+    ramp_up: Option<std::time::Duration>,
+
+    /// The number of nvims started at once at the beginning of --ramp-up. Defaults to 1
+    #[arg(
+        long,
+        value_name = "N",
+        env = "NVIM_TEST_RUNNER_RAMP_UP_START",
+        requires = "ramp_up"
+    )]
+    ramp_up_start: Option<usize>,
+
+    /// Print why each external dependency is reused, updated or cloned: the hash of the remote branch, the
+    /// recorded hash, the rule that decided it and the resulting action
+    #[arg(long, env = "NVIM_TEST_RUNNER_EXPLAIN", value_parser = FalseyValueParser::new())]
+    explain: bool,
+
+    /// Share the clones of the external dependencies between projects through the given directory (by default
+    /// `$XDG_CACHE_HOME/nvim-test-runner`), linking to them from the cache dir of the project
+    #[arg(
+        long,
+        value_name = "PATH",
+        env = "NVIM_TEST_RUNNER_GLOBAL_CACHE",
+        num_args = 0..=1,
+        require_equals = true
+    )]
+    global_cache: Option<Option<std::path::PathBuf>>,
+
+    /// A local repository (e.g. a mirror kept in the CI cache) that new clones of the external dependencies
+    /// borrow their objects from with `git clone --reference`, fetching only what it lacks. A dependency can
+    /// give its own with the reference field
+    #[arg(long, value_name = "PATH", env = "NVIM_TEST_RUNNER_REFERENCE_REPO")]
+    reference_repo: Option<std::path::PathBuf>,
+
+    /// Copy the objects borrowed from the reference repository into the new clones (`git clone --dissociate`),
+    /// so that they keep working once it is removed
+    #[arg(long, env = "NVIM_TEST_RUNNER_DISSOCIATE", value_parser = FalseyValueParser::new())]
+    dissociate: bool,
+
+    /// The git executable to use instead of `git` on the PATH. Overrides the gitPath config field
+    #[arg(long, value_name = "PATH", env = "NVIM_TEST_RUNNER_GIT")]
+    git: Option<std::path::PathBuf>,
+
+    /// Check that the cached clones of the external dependencies have no local modifications and are at the
+    /// recorded commit, warning about those that are not
+    #[arg(long, env = "NVIM_TEST_RUNNER_VERIFY_DEPS", value_parser = FalseyValueParser::new())]
+    verify_deps: bool,
+
+    /// Like --verify-deps, but reset the clones that were modified (discarding the changes) instead of warning
+    #[arg(long, env = "NVIM_TEST_RUNNER_REPAIR_DEPS", value_parser = FalseyValueParser::new())]
+    repair_deps: bool,
+
+    /// Fail the tests that pass without running a single assertion of test-utils, rather than only warning
+    /// about them
+    #[arg(long, env = "NVIM_TEST_RUNNER_FAIL_ON_NO_ASSERTIONS", value_parser = FalseyValueParser::new())]
+    fail_on_no_assertions: bool,
+
+    /// Trace which lines of Lua code the tests run (with a debug hook in test-utils), and write the coverage
+    /// of the files in the current directory to --coverage-output once the run has finished
+    #[arg(long, env = "NVIM_TEST_RUNNER_COVERAGE", value_parser = FalseyValueParser::new())]
+    coverage: bool,
+
+    /// The file the coverage is written to (in the stats format of luacov, so that `luacov` can make a report
+    /// of it). Implies --coverage. Defaults to luacov.stats.out
+    #[arg(long, value_name = "PATH", env = "NVIM_TEST_RUNNER_COVERAGE_OUTPUT")]
+    coverage_output: Option<std::path::PathBuf>,
+
+    /// The directory the result file of each test (with its failures and assertions, written by test-utils) is
+    /// written to, e.g. a tmpfs. Defaults to the system temp directory
+    #[arg(long, value_name = "DIR", env = "NVIM_TEST_RUNNER_RESULT_DIR")]
+    result_dir: Option<std::path::PathBuf>,
+
+    /// Keep the result files of the tests in --result-dir once they are read, e.g. to debug what test-utils
+    /// reported, rather than removing them
+    #[arg(long, env = "NVIM_TEST_RUNNER_KEEP_RESULTS", value_parser = FalseyValueParser::new())]
+    keep_results: bool,
+
+    /// Report the files each test leaves behind in --leak-dir, by listing it before and after each test. Adds
+    /// the time it takes to list the directory to every test
+    #[arg(long, env = "NVIM_TEST_RUNNER_DETECT_LEAKS", value_parser = FalseyValueParser::new())]
+    detect_leaks: bool,
+
+    /// The directory (with its subdirectories) in which --detect-leaks looks for the files left behind, e.g. `.`
+    /// for the current directory. Implies --detect-leaks. Defaults to the system temp directory
+    #[arg(long, value_name = "DIR", env = "NVIM_TEST_RUNNER_LEAK_DIR")]
+    leak_dir: Option<std::path::PathBuf>,
+
+    /// Fail the run if there were warnings about its setup, e.g. a dependency that was skipped, a test path that
+    /// did not match any file or a test skipped for requiring an unresolved dependency
+    #[arg(long, env = "NVIM_TEST_RUNNER_FAIL_ON_WARNING", value_parser = FalseyValueParser::new())]
+    fail_on_warning: bool,
+
+    /// Fail the run if the tests changed the nvim config or data of the user (e.g. `~/.config/nvim` or
+    /// `~/.local/share/nvim`), by comparing the modification times and sizes of their files before and after
+    /// the tests
+    #[arg(long, env = "NVIM_TEST_RUNNER_GUARD_USER_CONFIG", value_parser = FalseyValueParser::new())]
+    guard_user_config: bool,
+
+    /// Once the run has finished, report the dependencies none of whose Lua modules were required by the tests
+    /// that ran, e.g. to prune the dependency list. Only advisory: modules required dynamically (e.g. with a
+    /// computed name) are not seen, and dependencies without Lua modules are not checked
+    #[arg(long, env = "NVIM_TEST_RUNNER_CHECK_UNUSED_DEPS", value_parser = FalseyValueParser::new())]
+    check_unused_deps: bool,
+
+    /// Like --check-unused-deps, but fail the run if there are unused dependencies
+    #[arg(long, env = "NVIM_TEST_RUNNER_FAIL_ON_UNUSED_DEPS", value_parser = FalseyValueParser::new())]
+    fail_on_unused_deps: bool,
+
+    /// The number of lines of the stderr of a failed test that are printed, omitting those in the middle of
+    /// longer output (the log has it in full). 0 prints it in full. Defaults to 50
+    #[arg(long, value_name = "N", env = "NVIM_TEST_RUNNER_MAX_STDERR_LINES")]
+    max_stderr_lines: Option<usize>,
+
+    /// How the failures of a test are printed: `compact` prints one line per failure (`path:line: message`),
+    /// `verbose` the stderr of the test and for each failure the diff of the expected and the actual value with
+    /// the lines of the test around it. Defaults to verbose in a terminal and compact otherwise (or with `CI`)
+    #[arg(long, value_name = "FORMAT", env = "NVIM_TEST_RUNNER_FAILURE_FORMAT", value_parser = parse_failure_format)]
+    failure_format: Option<FailureFormat>,
+
+    /// The number of bytes of stdout and of stderr captured from each test, keeping the start and the end of
+    /// longer output (and the lines with nvim errors). 0 captures it in full. Defaults to 8 MiB
+    #[arg(long, value_name = "BYTES", env = "NVIM_TEST_RUNNER_MAX_OUTPUT")]
+    max_output: Option<usize>,
+
+    /// Once the run has finished, run the failed tests again one at a time with more verbosity (nvim's `-V1`,
+    /// and `NVIM_TEST_RUNNER_DEBUG=1` for the tests and plugins to log more), printing their output in full. The
+    /// run is judged by the first run of the tests
+    #[arg(long, env = "NVIM_TEST_RUNNER_DEBUG_FAILURES", value_parser = FalseyValueParser::new())]
+    debug_failures: bool,
+
+    /// Write a reproduction bundle for each failed test into the given directory: the exact nvim command with
+    /// the environment variables set by the runner, the runtimepath, the commits of the dependencies and the
+    /// output of the test. Secrets in the recorded environment are redacted
+    #[arg(long, value_name = "DIR", env = "NVIM_TEST_RUNNER_RECORD_FAILURES")]
+    record_failures: Option<std::path::PathBuf>,
+
+    /// A secret set as an environment variable of every test, as `NAME=@FILE` (read from a file), `NAME=$VAR`
+    /// (read from an environment variable of the runner) or `NAME=VALUE`, on top of the secrets of the config.
+    /// Its value is redacted from the log, the output of the tests and the reproduction bundles. Can be given
+    /// more than once
+    #[arg(long = "secret", value_name = "NAME=SOURCE", value_parser = parse_secret)]
+    secrets: Vec<(String, String)>,
+
+    /// Print the last N lines of the log once the runner has finished, whatever the outcome (20 without a value).
+    /// A run that fails always prints where the log is
+    #[arg(
+        long,
+        value_name = "N",
+        env = "NVIM_TEST_RUNNER_SHOW_LOG",
+        num_args = 0..=1,
+        require_equals = true
+    )]
+    show_log: Option<Option<usize>>,
+
+    /// Run the nvim command of a bundle written by --record-failures again, exiting with the exit code of nvim
+    #[arg(long, value_name = "BUNDLE")]
+    replay: Option<std::path::PathBuf>,
+
+    /// Once the tests have run, browse their results in the terminal, running the selected test again with `r`.
+    /// The exit code is that of the results as they are when quitting
+    #[arg(long, env = "NVIM_TEST_RUNNER_TUI", value_parser = FalseyValueParser::new())]
+    tui: bool,
 }
 
-#[derive(Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct TestConfig {
-    test_dependencies: Option<Vec<TestDepedency>>,
-    test_paths: Option<Vec<String>>,
-}
-
-impl TestConfig {
-    pub fn default() -> TestConfig {
-        return TestConfig {
-            test_dependencies: None,
-            test_paths: None,
-        };
+impl Args {
+    /// The exit code of a completed run
+    fn exit_code(&self, summary: &RunSummary) -> i32 {
+        if summary.is_success()
+            && summary.num_run() == 0
+            && summary.skipped + summary.filtered > 0
+            && !self.dry_run
+        {
+            return self.no_tests_exit_code;
+        }
+        summary.exit_code()
     }
-}
 
-impl Default for TestConfig {
-    fn default() -> Self {
-        Self::default()
-    }
-}
-
-#[derive(Serialize, Deserialize, Clone)]
-#[serde(rename_all = "camelCase")]
-struct TestDepedencyState {
-    uri: String,
-    hash: String,
-    branch: Option<String>,
-    sha: Option<String>,
-}
-
-#[derive(Serialize, Deserialize, Clone)]
-#[serde(rename_all = "camelCase")]
-struct LuaTestUtilsState {
-    version: String,
-}
-
-#[derive(Serialize, Deserialize, Clone)]
-#[serde(rename_all = "camelCase")]
-struct State {
-    test_dependencies: Vec<TestDepedencyState>,
-    lua_test_utils: Option<LuaTestUtilsState>,
-}
-
-impl State {
-    pub fn new() -> State {
-        return State {
-            test_dependencies: vec![],
-            lua_test_utils: None,
-        };
+    fn run_options(&self) -> RunOptions {
+        RunOptions {
+            skip_remote_check: self.skip_remote_check,
+            cache_dir: self.cache_dir.clone(),
+            offline: self.offline,
+            frozen: self.frozen,
+            locked: self.locked,
+            write_lockfile: self.write_lockfile,
+            quiet: self.quiet,
+            compact: self.compact,
+            verbose: self.verbose,
+            dry_run: self.dry_run,
+            check: self.check,
+            keep_going: self.keep_going,
+            changed: self.changed,
+            changed_base: self.changed_base.clone(),
+            changed_dirs: self.changed_dirs,
+            update: self.update,
+            output_dir: self.output_dir.clone(),
+            markdown_report: self.markdown_report.clone(),
+            formats: self.format.clone(),
+            output: self.output.clone(),
+            tags: self.tags.clone(),
+            only_deps: self.only_deps.clone(),
+            exclude_tags: self.exclude_tags.clone(),
+            tap: self.tap,
+            github_annotations: self.github_annotations,
+            bail: self.bail,
+            allowed_failures: self.allowed_failures,
+            grep: self.grep.clone(),
+            fail_on_empty: self.fail_on_empty,
+            no_default_paths: self.no_default_paths,
+            no_gitignore: self.no_gitignore,
+            installed: self.installed,
+            isolate: self.isolate,
+            resume: self.resume && !self.no_resume,
+            failed: self.failed,
+            affected_by_deps: self.affected_by_deps,
+            run_skipped: self.run_skipped,
+            since: self.since,
+            shard: self.shard,
+            warmup: self.warmup,
+            repeat: self.repeat,
+            remote_ttl: self.remote_ttl,
+            slowest: self.slowest,
+            baseline: self.baseline.clone(),
+            save_baseline: self.save_baseline.clone(),
+            compare: self.compare,
+            events: self.events.clone(),
+            max_failures_output: self.max_failures_output,
+            proxy: self.proxy.clone(),
+            refresh_test_utils: self.refresh_test_utils,
+            batch: self.batch,
+            expect_tests: self.expect_tests,
+            min_tests: self.min_tests,
+            most_memory: self.most_memory,
+            group_by_dir: self.group_by_dir,
+            max_processes: self.max_processes,
+            ramp_up: self.ramp_up,
+            ramp_up_start: self.ramp_up_start,
+            explain: self.explain,
+            global_cache: self
+                .global_cache
+                .as_ref()
+                .map(|dir| dir.clone().unwrap_or_else(default_global_cache_dir)),
+            reference_repo: self.reference_repo.clone(),
+            dissociate: self.dissociate,
+            fail_on_no_assertions: self.fail_on_no_assertions,
+            coverage: self.coverage || self.coverage_output.is_some(),
+            coverage_output: self.coverage_output.clone(),
+            result_dir: self.result_dir.clone(),
+            keep_results: self.keep_results,
+            detect_leaks: self.detect_leaks || self.leak_dir.is_some(),
+            leak_dir: self.leak_dir.clone(),
+            fail_on_warning: self.fail_on_warning,
+            guard_user_config: self.guard_user_config,
+            check_unused_deps: self.check_unused_deps,
+            fail_on_unused_deps: self.fail_on_unused_deps,
+            git: self.git.clone(),
+            verify_deps: self.verify_deps,
+            repair_deps: self.repair_deps,
+            max_stderr_lines: self.max_stderr_lines,
+            failure_format: self.failure_format,
+            max_output: self.max_output,
+            debug_failures: self.debug_failures,
+            record_failures: self.record_failures.clone(),
+            secrets: self.secrets.clone(),
+        }
     }
 }
 
-impl Default for State {
-    fn default() -> Self {
-        Self::new()
-    }
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Diagnose common problems with the environment the tests are run in
+    Doctor,
+    /// Set up the current directory (the root of a plugin) to be tested: write a starter nvim-test-runner.json
+    /// and an example test in tests/, and add the cache directory to .gitignore
+    Init {
+        /// Overwrite the config and the example test if they exist
+        #[arg(long)]
+        force: bool,
+    },
+    /// Measure how long nvim takes to start with the plugin and its dependencies, run a file or Lua snippet,
+    /// and quit
+    Bench {
+        /// The Lua (or Vim script) file to source once nvim has started
+        #[arg(required_unless_present = "lua", conflicts_with = "lua")]
+        file: Option<std::path::PathBuf>,
+
+        /// A Lua snippet to run once nvim has started, e.g. `require("my-plugin").setup()`
+        #[arg(long, value_name = "CODE")]
+        lua: Option<String>,
+
+        /// The number of times nvim is started
+        #[arg(short = 'n', long, value_name = "N", default_value_t = 10)]
+        runs: usize,
+
+        /// Print the statistics as JSON on the last line of the output
+        #[arg(long)]
+        json: bool,
+    },
+    /// Update the runner to its latest release, if it is newer. The runner downloads the test-utils of its own
+    /// version, so that updating it updates test-utils too. The binary replaced is kept for --rollback
+    SelfUpdate {
+        /// Update without asking for a confirmation
+        #[arg(short, long)]
+        yes: bool,
+
+        /// Restore the version the last update replaced
+        #[arg(long, conflicts_with = "yes")]
+        rollback: bool,
+    },
 }
 
-fn run_test_runner() -> Result<(), Box<dyn std::error::Error>> {
+fn run_test_runner(args: &Args) -> Result<i32, RunnerError> {
     env::set_var("RUST_BACKTRACE", "1");
 
-    let args = Args::parse();
-
-    let current_dir = std::env::current_dir()?;
-
     let file_appender = FileAppender::builder()
         // Pattern: https://docs.rs/log4rs/*/log4rs/encode/pattern/index.html
         .encoder(Box::new(PatternEncoder::new(
             "[{l}] {d(%Y-%m-%d %H:%M:%S)} {m}\n",
         )))
-        .build("/tmp/nvim-test-runner.log")?;
+        .build(LOG_FILE)?;
 
     let log_config = Config::builder()
         .appender(Appender::builder().build("file", Box::new(file_appender)))
@@ -113,495 +732,209 @@ fn run_test_runner() -> Result<(), Box<dyn std::error::Error>> {
                 .build(log::LevelFilter::Debug),
         )
         .expect("Failed to create log config");
-    let _ = log4rs::init_config(log_config)?;
+    // Every record goes through the redaction of the secrets, whichever module logs it
+    let logger = log4rs::Logger::new(log_config);
+    log::set_max_level(logger.max_log_level());
+    log::set_boxed_logger(Box::new(RedactingLogger(logger))).map_err(std::io::Error::other)?;
 
     log_panics::init();
 
-    let config_path = "nvim-test-runner.json";
-    let config = if let Ok(mut file) = File::open(&config_path) {
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-        serde_json::from_str(&contents)?
-    } else {
-        println!(
-            "{}",
-            Colour::Yellow.paint("Config file not found, using default config")
-        );
-        info!("Config file not found, using default config");
-        let config = TestConfig::default();
-        config
-    };
+    // The state is written atomically, so exiting at any point leaves it either fully written or untouched
+    ctrlc::set_handler(|| {
+        if interrupt::interrupt() {
+            println!("{}", Colour::Red.paint("Interrupted"));
+            log::logger().flush();
+            std::process::exit(interrupt::INTERRUPTED_EXIT_CODE);
+        }
+    })
+    .map_err(std::io::Error::other)?;
 
-    // Check if state.json exists and is readable and writable, if not readable/writable, throw error
-    let state_path = ".test/state.json";
-    let state = if let Ok(mut file) = File::open(&state_path) {
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-        serde_json::from_str(&contents)?
-    } else {
-        println!(
-            "{}",
-            Colour::Yellow.paint("State file not found, creating new state")
-        );
-        info!("State file not found, creating new state");
-        let state = State::default();
-        state
-    };
+    let options = args.run_options();
+
+    if let Some(Commands::Doctor) = &args.command {
+        run_doctor(args.config.as_deref(), &options)?;
+        return Ok(0);
+    }
+
+    if let Some(Commands::Init { force }) = &args.command {
+        run_init(*force)?;
+        return Ok(0);
+    }
+
+    if let Some(Commands::SelfUpdate { yes, rollback }) = &args.command {
+        run_self_update(&options, *yes, *rollback)?;
+        return Ok(0);
+    }
 
-    let mut new_state: State = state.clone(); // For storing the new state (and we overwrite state.json once in the end)
+    // Needs neither the config nor the dependencies, which are recorded in the bundle
+    if let Some(bundle) = &args.replay {
+        return replay(bundle);
+    }
 
-    if !args.skip_remote_check {
-        // Check if state exists for lua-test-utils. If so, compare against its version with the version of this program.
-        // If they are different, overwrite the state with the new version.
-        let version = env!("CARGO_PKG_VERSION");
-        if let Some(lua_test_utils_state) = &state.lua_test_utils {
-            if lua_test_utils_state.version != version {
+    let config_path = match args.config.as_deref() {
+        Some(path) => Some(path),
+        None => find_config(),
+    };
+    let mut config = match config_path {
+        Some(path) => load_config(path)?,
+        None if args.require_config => {
+            let current_dir = env::current_dir()?;
+            return Err(RunnerError::Config(format!(
+                "No config file ({}) found in {}, and --require-config is given",
+                CONFIG_PATHS.join(", "),
+                current_dir.display()
+            )));
+        }
+        None => {
+            // Keep the output of --print-config valid JSON
+            if !args.print_config {
                 println!(
                     "{}",
-                    Colour::Yellow.paint(format!(
-                        "Upgrading test-utils from version {} to version {}",
-                        lua_test_utils_state.version, version
-                    ))
-                );
-                info!(
-                    "Upgrading test-utils from version {} to version {}",
-                    lua_test_utils_state.version, version
+                    Colour::Yellow.paint("Config file not found, using default config")
                 );
-
-                // https://raw.githubusercontent.com/samsze0/test.nvim/{version}/lua/test/init.lua
-                let uri = format!(
-                    "https://raw.githubusercontent.com/samsze0/test.nvim/{}/lua/test/init.lua",
-                    version
-                );
-                let client = reqwest::blocking::Client::new();
-                let content = client
-                    .get(&uri)
-                    .header(reqwest::header::USER_AGENT, "private, no-store, max-age=0")
-                    .send()?
-                    .text()?;
-                let path = std::path::PathBuf::from(".test/lua/test-utils.lua");
-                let mut file = File::create(&path)?;
-                file.write_all(content.as_bytes())?;
-
-                new_state.lua_test_utils = Some(LuaTestUtilsState {
-                    version: version.to_string(),
-                });
             }
+            info!("Config file not found, using default config");
+            TestConfig::default()
+        }
+    };
+    if let Some(profile) = &args.profile {
+        config.apply_profile(profile)?;
+    }
+    apply_env_overrides(&mut config);
+    if !args.deps.is_empty() {
+        config
+            .settings
+            .test_dependencies
+            .get_or_insert_with(Vec::new)
+            .extend(args.deps.iter().cloned());
+    }
+
+    if let Some(Commands::Bench {
+        file,
+        lua,
+        runs,
+        json,
+    }) = &args.command
+    {
+        let target = match (file, lua) {
+            (Some(file), _) => BenchTarget::File(file.clone()),
+            (None, Some(lua)) => BenchTarget::Lua(lua.clone()),
+            // Enforced by clap
+            (None, None) => unreachable!(),
+        };
+        let bench = BenchOptions {
+            target,
+            runs: *runs,
+        };
+        let stats = run_bench(&config, &options, &bench)?;
+        if *json {
+            println!("{}", stats.to_json());
         } else {
             println!(
                 "{}",
-                Colour::Yellow.paint("Downloading test-utils.lua into .test/")
+                Colour::Blue.paint(format!(
+                    "{} run(s): min {:.1}ms, median {:.1}ms, max {:.1}ms, mean {:.1}ms, stddev {:.1}ms",
+                    stats.samples.len(),
+                    stats.min_ms(),
+                    stats.median_ms(),
+                    stats.max_ms(),
+                    stats.mean_ms(),
+                    stats.stddev_ms()
+                ))
             );
-            info!("Downloading test-utils.lua into .test/");
-
-            // https://raw.githubusercontent.com/samsze0/test.nvim/{version}/lua/test/init.lua
-            let uri = format!(
-                "https://raw.githubusercontent.com/samsze0/test.nvim/{}/lua/test/init.lua",
-                version
-            );
-            let content = reqwest::blocking::get(&uri)?.text()?;
-            let path = std::path::PathBuf::from(".test/lua/test-utils.lua");
-            if !path.exists() {
-                std::fs::create_dir_all(path.parent().unwrap())?;
-            }
-            let mut file = File::create(&path)?;
-            file.write_all(content.as_bytes())?;
-
-            info!("Downloaded test-utils.lua into .test/");
-
-            new_state.lua_test_utils = Some(LuaTestUtilsState {
-                version: version.to_string(),
-            });
         }
-
-        // Write new_state to state.json; creating the ".tests/" directory if not already exists
-        let state_dir = std::path::Path::new(&state_path).parent().unwrap();
-        std::fs::create_dir_all(state_dir)?;
-        let mut w = BufWriter::new(File::create(&state_path)?);
-        serde_json::to_writer_pretty(&mut w, &new_state)?;
-        w.write(b"\n")?;
-        w.flush()?;
+        return Ok(0);
     }
 
-    let mut external_deps: Vec<std::path::PathBuf> = Vec::new();
-    let mut local_deps: Vec<std::path::PathBuf> = Vec::new();
-
-    if let Some(deps) = &config.test_dependencies {
-        for dep in deps {
-            debug!(
-                "uri: {}, branch: {}, sha: {}",
-                dep.uri,
-                dep.branch.clone().unwrap_or("<none>".to_string()),
-                dep.sha.clone().unwrap_or("<none>".to_string())
-            );
-
-            // Checks if url starts with "file:", if so, treat it as a local directory
-            if dep.uri.starts_with("file:") {
-                let path = match dep.uri.starts_with("file://") {
-                    true => {
-                        let abs_path = dep.uri.strip_prefix("file://").unwrap();
-                        std::path::PathBuf::from(&abs_path)
-                    }
-                    false => {
-                        let rel_path = dep.uri.strip_prefix("file:").unwrap();
-                        current_dir.join(rel_path)
-                    }
-                };
-
-                if !path.exists() {
-                    println!(
-                        "{}",
-                        Colour::Yellow.paint(format!("Path {} does not exist, skipping", dep.uri))
-                    );
-                    warn!("Path {} does not exist, skipping", dep.uri);
-                    continue;
-                }
-                if !path.is_dir() {
-                    println!(
-                        "{}",
-                        Colour::Yellow.paint(format!(
-                            "{} does not point to a directory, skipping",
-                            dep.uri
-                        ))
-                    );
-                    warn!("{} does not point to a directory, skipping", dep.uri);
-                    continue;
-                }
-
-                info!("Path {} exists", dep.uri);
-                local_deps.push(path);
-                continue;
-            }
-
-            // Treating as external dependency
-
-            let maybe_dep_name = std::path::Path::new(&dep.uri).file_name();
-            if maybe_dep_name.is_none() {
-                return Err(format!("Invalid uri: {}", dep.uri).into());
-            }
-            let dep_name = maybe_dep_name.unwrap().to_str().unwrap();
-            let dep_path = std::path::PathBuf::from(format!(".test/external-dep/{}", dep_name));
-
-            if !args.skip_remote_check {
-                // Check if git is installed
-                if let Err(_) = Command::new("git").arg("--version").output() {
-                    return Err("git is not installed".into());
-                }
-
-                // Check if url is a valid git repository, if so,
-                // get the HEAD commit hash
-                let output = Command::new("git")
-                    .arg("ls-remote")
-                    .arg(&dep.uri)
-                    .output()
-                    .expect("Failed to execute git ls-remote");
-
-                if !output.status.success() {
-                    return Err(format!("{} is not a valid git repository", dep.uri).into());
-                }
-
-                let git_ls_remote_output = String::from_utf8_lossy(&output.stdout);
-                let mut ref_name_hash_map = HashMap::new();
-
-                for line in git_ls_remote_output.lines() {
-                    let mut parts = line.split_whitespace();
-                    if let (Some(hash), Some(ref_name)) = (parts.next(), parts.next()) {
-                        ref_name_hash_map.insert(ref_name.to_string(), hash.to_string());
-                    }
-                }
-
-                // Let ref name equals HEAD if branch is not specified, else use "refs/head/branch"
-                let ref_name = match &dep.branch {
-                    Some(branch) => format!("refs/heads/{}", branch),
-                    None => "HEAD".to_string(),
-                };
-                match ref_name_hash_map.get(&ref_name) {
-                    Some(branch_head_sha) => {
-                        // Check if state matches
-                        let state_matches = state.test_dependencies.iter().any(|dep_state| {
-                            dep_state.uri == dep.uri
-                                && dep_state.branch == dep.branch
-                                && dep_state.sha == dep.sha
-                        });
-
-                        debug!(
-                            "state_matches: {}, uri: {}, branch: {}, sha: {}",
-                            state_matches,
-                            dep.uri,
-                            dep.branch.clone().unwrap_or("HEAD".to_string()),
-                            dep.sha.clone().unwrap_or("<none>".to_string())
-                        );
-
-                        let dep_path_str = format!(".test/external-dep/{}", dep_name);
-                        let dep_path = std::path::Path::new(&dep_path_str);
-
-                        if !state_matches && dep_path.exists() {
-                            println!(
-                                "{}",
-                                Colour::Yellow.paint(format!(
-                                    "Overwriting existing test dependency at path {}",
-                                    dep_path.display()
-                                ))
-                            );
-                            info!(
-                                "Overwriting existing test dependency at path {}",
-                                dep_path.display()
-                            );
-
-                            std::fs::remove_dir_all(&dep_path)?;
-
-                            // Remove from state
-                            new_state
-                                .test_dependencies
-                                .retain(|dep_state| dep_state.uri != dep.uri);
-                        }
-
-                        if state.test_dependencies.iter().any(|dep_state| {
-                            if dep_state.uri != dep.uri {
-                                return false;
-                            }
-
-                            if dep.sha.is_some() {
-                                return dep_state.sha == dep.sha;
-                            }
-
-                            if dep.branch.is_some() && dep_state.branch != dep.branch {
-                                return false;
-                            }
-
-                            return dep_state.hash == *branch_head_sha;
-                        }) {
-                            external_deps.push(dep_path.to_path_buf());
-                            continue;
-                        }
-
-                        println!(
-                            "{}",
-                            Colour::Yellow.paint(format!(
-                                "Cloning repo {} @ {}-{} into path {}...",
-                                dep.uri,
-                                dep.branch.clone().unwrap_or("HEAD".to_string()),
-                                dep.sha.clone().unwrap_or("<none>".to_string()),
-                                dep_path.display()
-                            ))
-                        );
-                        info!(
-                            "Cloning repository {} @ {}-{} into path {}",
-                            dep.uri,
-                            dep.branch.clone().unwrap_or("HEAD".to_string()),
-                            dep.sha.clone().unwrap_or("<none>".to_string()),
-                            dep_path.display()
-                        );
-
-                        let mut cmd = Command::new("git");
-
-                        cmd.arg("clone");
-
-                        let output = cmd
-                            .arg(&dep.uri)
-                            .arg(&dep_path)
-                            .output()
-                            .expect("Failed to execute git clone");
-
-                        if !output.status.success() {
-                            return Err(format!(
-                                "Failed to clone repository {}:\n{}",
-                                dep.uri,
-                                String::from_utf8_lossy(&output.stderr)
-                            )
-                            .into());
-                        }
-
-                        let sha = dep.sha.as_ref().unwrap_or(branch_head_sha);
-
-                        let mut cmd = Command::new("git");
-                        cmd.current_dir(&dep_path);
-                        cmd.arg("reset").arg("--hard");
-                        cmd.arg(&sha);
-
-                        let output = cmd.output().expect("Failed to execute git reset");
-
-                        if !output.status.success() {
-                            error!(
-                                "Failed to reset repository {} to revision {}:\n{}",
-                                dep.uri,
-                                &sha,
-                                String::from_utf8_lossy(&output.stderr)
-                            );
-                            return Err(format!(
-                                "Failed to reset repository {} to revision {}",
-                                dep.uri, &sha
-                            )
-                            .into());
-                        }
-
-                        new_state.test_dependencies.push(TestDepedencyState {
-                            uri: dep.uri.clone(),
-                            hash: branch_head_sha.clone(),
-                            branch: dep.branch.clone(),
-                            sha: dep.sha.clone(),
-                        });
-                    }
-                    None => {
-                        return Err(format!(
-                            "Branch {} does not exist in repository {}",
-                            ref_name, dep.uri
-                        )
-                        .into());
-                    }
-                }
-            } else {
-                // skip_remote_check option is off
-                // Check if state exists with uri and branch
-                let exists = state
-                    .test_dependencies
-                    .iter()
-                    .any(|dep_state| dep_state.uri == dep.uri && dep_state.branch == dep.branch);
-                if !exists {
-                    return Err(format!(
-                        "State does not exist for test dependency {} @ branch {}",
-                        dep.uri,
-                        dep.branch.clone().unwrap_or("HEAD".to_string())
-                    )
-                    .into());
-                }
-            }
-
-            external_deps.push(dep_path.to_path_buf());
-        }
+    if args.print_config {
+        let config = effective_config(config, &options);
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&config).map_err(std::io::Error::from)?
+        );
+        return Ok(0);
     }
 
-    // Write new_state to state.json; creating the ".tests/" directory if not already exists
-    let state_dir = std::path::Path::new(&state_path).parent().unwrap();
-    std::fs::create_dir_all(state_dir)?;
-    let mut w = BufWriter::new(File::create(&state_path)?);
-    serde_json::to_writer_pretty(&mut w, &new_state)?;
-    w.write(b"\n")?;
-    w.flush()?;
-
-    // If test_paths is not given, then default to ["tests/**/*.lua", "test/**/*.lua", "lua/tests/**/*.lua", "lua/test/**/*.lua"]
-    let default_test_paths = vec![
-        "tests/**/*.lua".to_string(),
-        "test/**/*.lua".to_string(),
-        "lua/tests/**/*.lua".to_string(),
-        "lua/test/**/*.lua".to_string(),
-    ];
-    let test_paths = config.test_paths.unwrap_or(default_test_paths);
-
-    for path in &test_paths {
-        debug!("test path: {}", path);
+    if args.last {
+        let summary = replay_last_run(&config, &options)?;
+        return Ok(args.exit_code(&summary));
     }
 
-    let mut matched_files = Vec::new();
+    if let Some(jobs) = args.jobs {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build_global()
+            .map_err(|e| RunnerError::Config(format!("Invalid --jobs {}: {}", jobs, e)))?;
+    }
 
-    for path in &test_paths {
-        for entry in glob(&path)? {
-            match entry {
-                Ok(path) => {
-                    debug!("Matched test file: {:?}", path.display());
-                    matched_files.push(path);
-                }
-                Err(e) => error!("Error with matched file {}: {:?}", path, e),
-            }
+    if config.matrix.is_some() {
+        if args.tui {
+            return Err(RunnerError::Config(
+                "--tui cannot be used with a test matrix".to_string(),
+            ));
         }
+        let cells = run_matrix(config, options)?;
+        // The worst exit code of the cells
+        let code = cells
+            .iter()
+            .map(|cell| match &cell.result {
+                Ok(summary) => args.exit_code(summary),
+                Err(e) => e.exit_code(),
+            })
+            .max()
+            .unwrap_or(0);
+        return Ok(code);
     }
 
-    let test_results: Vec<bool> = matched_files
-        .par_iter()
-        .map(|test| {
-            debug!("Running test: {:?}", test.display());
-
-            let mut cmd = Command::new("nvim");
-            cmd.arg("--noplugin")
-                .arg("--headless")
-                // Disable backup and swap
-                .arg("--cmd")
-                .arg("set nobackup nowritebackup noswapfile")
-                // Prevent shada files from being generated or read
-                .arg("--cmd")
-                .arg("set shada=\"NONE\"")
-                // Disable viminfo
-                .arg("-i")
-                .arg("NONE");
-
-            // Add plugin to runtimepath
-            // Using --cmd to run vim scripts before the test file is loaded
-            cmd.arg("--cmd").arg("set rtp+=.");
-
-            debug!(
-                "Adding external dependencies to runtimepath: {:?}",
-                external_deps
-            );
-
-            // Add all external test dependencies to runtimepath
-            for dep in &external_deps {
-                cmd.arg("--cmd").arg(format!("set rtp+={}", dep.display()));
-            }
-
-            debug!("Adding local dependencies to runtimepath: {:?}", local_deps);
-
-            for dep in &local_deps {
-                cmd.arg("--cmd").arg(format!("set rtp+={}", dep.display()));
-            }
-
-            // Add test-utils.lua to runtimepath
-            cmd.arg("--cmd").arg("set rtp+=.test");
-            cmd.arg("--cmd").arg("lua require(\"test-utils\")");
-
-            cmd.arg("-u").arg(test).arg("+qa");
-
-            debug!("Running command: {:?}", cmd);
-
-            let output = cmd.output().expect("Failed to execute command");
+    if args.tui {
+        let summary = run(config.clone(), options.clone())?;
+        let summary = tui::browse(&config, &options, summary)?;
+        return Ok(args.exit_code(&summary));
+    }
 
-            if !output.status.success() {
-                println!(
-                    "{}",
-                    Colour::Red.paint(format!("Failed to run test {}", test.display()))
-                );
-                error!("Failed to run command: {:?}", cmd);
-                return false;
-            }
+    let summary = run(config, options)?;
+    Ok(args.exit_code(&summary))
+}
 
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            // TODO: Find more robust way to detect errors
-            if stderr.len() > 0 && stderr.contains("Error detected while processing") {
-                print!(
-                    "{}",
-                    Colour::Red.paint(format!(
-                        indoc! {"
-                        x {}
-                        {}
-                    "},
-                        test.display(),
-                        stderr
-                    ))
-                );
-                false
-            } else {
-                println!("{}", Colour::Blue.paint(format!("✓ {}", test.display())));
-                true
+/// Print where the log is, followed by its last lines with --show-log
+fn print_log(show_log: Option<Option<usize>>) {
+    log::logger().flush();
+    println!(
+        "{}",
+        Colour::Yellow.paint(format!("See the log at {}", LOG_FILE))
+    );
+    let Some(lines) = show_log else {
+        return;
+    };
+    let lines = lines.unwrap_or(DEFAULT_SHOW_LOG_LINES);
+    match std::fs::read_to_string(LOG_FILE) {
+        Ok(log) => {
+            let log: Vec<&str> = log.lines().collect();
+            for line in &log[log.len().saturating_sub(lines)..] {
+                println!("{}", line);
             }
-        })
-        .collect();
-
-    // Count the number of failed tests
-    let num_failed_tests = test_results.into_iter().filter(|x| !x).count();
-    if num_failed_tests > 0 {
-        println!(
+        }
+        Err(e) => println!(
             "{}",
-            Colour::Red.paint(format!("{} test(s) failed", num_failed_tests))
-        );
-        std::process::exit(1);
+            Colour::Red.paint(format!("Failed to read the log {}: {}", LOG_FILE, e))
+        ),
     }
-
-    Ok(())
 }
 
 fn main() {
-    if let Err(e) = run_test_runner() {
-        println!("{}", Colour::Red.paint(format!("{}", e)));
-        error!("{}", e);
-        std::process::exit(2);
+    let args = Args::parse();
+    let code = match run_test_runner(&args) {
+        Ok(code) => code,
+        Err(e) => {
+            println!("{}", Colour::Red.paint(format!("{}", e)));
+            error!("{}", e);
+            e.exit_code()
+        }
+    };
+    if code != 0 || args.show_log.is_some() {
+        print_log(args.show_log);
+    }
+    if code != 0 {
+        std::process::exit(code);
     }
 }
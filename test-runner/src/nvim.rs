@@ -0,0 +1,286 @@
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+
+use crate::error::RunnerError;
+use crate::state::{lock_state, CachedNvimProbe, State};
+
+/// Parse a version string like `0.10`, `v0.10.0` or `NVIM v0.10.0-dev-1234+g5678` into (major, minor, patch)
+pub fn parse_version(version: &str) -> Option<(u64, u64, u64)> {
+    let version = version.trim();
+    let version = version.strip_prefix("NVIM").unwrap_or(version).trim();
+    let version = version.strip_prefix('v').unwrap_or(version);
+    // Drop pre-release and build metadata
+    let version = version.split(['-', '+', ' ']).next()?;
+
+    let mut parts = version.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next().map_or(Some(0), |p| p.parse().ok())?;
+    let patch = parts.next().map_or(Some(0), |p| p.parse().ok())?;
+    Some((major, minor, patch))
+}
+
+/// The `:set rtp+=` command appending a directory to the runtimepath. The commas of the path are escaped as it
+/// is a list entry, then the backslashes, spaces, `|` and `"` as the `:set` command would take them apart
+pub fn set_rtp_command(entry: &std::path::Path) -> String {
+    let value = entry.display().to_string().replace(',', "\\,");
+    let mut command = String::from("set rtp+=");
+    for c in value.chars() {
+        if matches!(c, '\\' | ' ' | '\t' | '|' | '"') {
+            command.push('\\');
+        }
+        command.push(c);
+    }
+    command
+}
+
+/// Escape a path for an Ex command taking a file name (e.g. `:luafile`), as `fnameescape()` does, so that spaces
+/// do not split it and `%` or `#` are not expanded to the current or alternate file
+pub fn escape_file_name(path: &std::path::Path) -> String {
+    let mut escaped = String::new();
+    for c in path.display().to_string().chars() {
+        if " \t\n*?[{`$\\%#'\"|!<".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The command that runs nvim: `nvim`, or the given wrapper (e.g. `docker run --rm image nvim`) that the
+/// arguments of nvim are appended to
+pub fn new_command(wrapper: Option<&[String]>) -> Command {
+    match wrapper.and_then(|wrapper| wrapper.split_first()) {
+        Some((program, args)) => {
+            let mut cmd = Command::new(program);
+            cmd.args(args);
+            cmd
+        }
+        None => Command::new("nvim"),
+    }
+}
+
+/// A description of how nvim is run for error messages, e.g. `nvim` or `nvim (with runnerWrapper docker ...)`
+pub fn describe_command(wrapper: Option<&[String]>) -> String {
+    match wrapper {
+        Some(wrapper) if !wrapper.is_empty() => {
+            format!("nvim (with runnerWrapper {})", wrapper.join(" "))
+        }
+        _ => "nvim".to_string(),
+    }
+}
+
+/// Check that the given Lua files parse, by compiling each of them with `loadfile` (without running them) in a
+/// single nvim, so that they are checked by the LuaJIT of nvim rather than another Lua with another syntax.
+/// Returns the syntax error of each file that does not parse, e.g. `tests/a_spec.lua:3: '=' expected near 'x'`
+pub fn check_syntax(
+    files: &[std::path::PathBuf],
+    wrapper: Option<&[String]>,
+) -> Result<std::collections::HashMap<std::path::PathBuf, String>, RunnerError> {
+    let temp_file = |name: &str| {
+        std::env::temp_dir().join(format!(
+            "nvim-test-runner-{}-{}.json",
+            std::process::id(),
+            name
+        ))
+    };
+    let files_path = temp_file("check");
+    let result_path = temp_file("check-result");
+    let _ = std::fs::remove_file(&result_path);
+    std::fs::write(&files_path, serde_json::json!(files).to_string())
+        .map_err(|e| RunnerError::io(format!("Failed to write {}", files_path.display()), e))?;
+
+    // One line of JSON per file that does not parse
+    let check = format!(
+        "lua local files = io.open({:?}):read('*a') \
+         local result = io.open({:?}, 'w') \
+         for _, path in ipairs(vim.json.decode(files)) do \
+           local _, err = loadfile(path) \
+           if err then result:write(vim.json.encode({{ path = path, error = err }}), '\\n') end \
+         end \
+         result:close()",
+        files_path.display().to_string(),
+        result_path.display().to_string()
+    );
+    let mut cmd = new_command(wrapper);
+    cmd.arg("--headless")
+        .arg("--clean")
+        .arg("-i")
+        .arg("NONE")
+        .arg("--cmd")
+        .arg(check)
+        .arg("+qa");
+    let output = crate::interrupt::output(&mut cmd);
+    let _ = std::fs::remove_file(&files_path);
+    let output = output.map_err(|e| {
+        RunnerError::Nvim(format!(
+            "Failed to run {}: {}",
+            describe_command(wrapper),
+            e
+        ))
+    })?;
+
+    let result = std::fs::read_to_string(&result_path);
+    let _ = std::fs::remove_file(&result_path);
+    let result = match result {
+        Ok(result) if output.status.success() => result,
+        _ => {
+            return Err(RunnerError::Nvim(format!(
+                "Failed to check the syntax of the test files with {}:\n{}",
+                describe_command(wrapper),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )))
+        }
+    };
+    let mut errors = std::collections::HashMap::new();
+    for line in result.lines().filter(|line| !line.trim().is_empty()) {
+        let error: serde_json::Value = serde_json::from_str(line).map_err(|e| {
+            RunnerError::Nvim(format!("Invalid syntax check result {:?}: {}", line, e))
+        })?;
+        if let (Some(path), Some(error)) = (error["path"].as_str(), error["error"].as_str()) {
+            errors.insert(std::path::PathBuf::from(path), error.to_string());
+        }
+    }
+    Ok(errors)
+}
+
+/// Run `nvim --version` (with the wrapper, if any) and return the version from its first line
+pub fn probe_nvim_version(wrapper: Option<&[String]>) -> Result<(u64, u64, u64), RunnerError> {
+    probe_nvim(wrapper).map(|capabilities| capabilities.version)
+}
+
+/// What nvim reports about itself with `--version`, for features that depend on the version of nvim or on how
+/// it was built
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct NvimCapabilities {
+    /// (major, minor, patch)
+    pub version: (u64, u64, u64),
+    /// The version of LuaJIT nvim is built with, e.g. `2.1.1713484068`. None if it is built with PUC Lua
+    pub luajit: Option<String>,
+    /// E.g. `Release` or `Debug`
+    pub build_type: Option<String>,
+}
+
+/// Run `nvim --version` and parse what it prints
+pub fn probe_nvim(wrapper: Option<&[String]>) -> Result<NvimCapabilities, RunnerError> {
+    let output = new_command(wrapper)
+        .arg("--version")
+        .output()
+        .map_err(|e| {
+            RunnerError::Nvim(format!(
+                "Failed to run {} --version: {}",
+                describe_command(wrapper),
+                e
+            ))
+        })?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let first_line = stdout.lines().next().unwrap_or_default();
+    debug!("nvim --version: {}", first_line);
+
+    let version = parse_version(first_line).ok_or_else(|| {
+        RunnerError::Nvim(format!(
+            "Failed to parse nvim version from {:?}",
+            first_line
+        ))
+    })?;
+    let line_value = |prefix: &str| {
+        stdout
+            .lines()
+            .find_map(|line| line.trim().strip_prefix(prefix))
+            .map(|value| value.trim().to_string())
+    };
+    Ok(NvimCapabilities {
+        version,
+        luajit: line_value("LuaJIT "),
+        build_type: line_value("Build type:"),
+    })
+}
+
+/// The [`NvimCapabilities`] of the nvim the tests run with, cached in the state of the cache directory by the
+/// path of the nvim binary and probed again once the binary changes (by its modification time and size), so
+/// that repeated runs do not start nvim only to ask for its version. Not cached with a `runnerWrapper`, whose
+/// program (e.g. docker) says nothing about the nvim it runs, nor before the cache directory is created
+pub fn nvim_capabilities(
+    wrapper: Option<&[String]>,
+    cache_dir: &std::path::Path,
+) -> Result<NvimCapabilities, RunnerError> {
+    let binary = match wrapper {
+        Some(wrapper) if !wrapper.is_empty() => None,
+        _ => find_in_path("nvim"),
+    };
+    let Some((binary, metadata)) = binary
+        .filter(|_| cache_dir.is_dir())
+        .and_then(|binary| Some((binary.clone(), std::fs::metadata(&binary).ok()?)))
+    else {
+        return probe_nvim(wrapper);
+    };
+    let mtime_ns = metadata
+        .modified()
+        .ok()
+        .and_then(|mtime| mtime.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |mtime| mtime.as_nanos() as u64);
+    let key = binary.display().to_string();
+
+    let _state_lock = lock_state(cache_dir)?;
+    let state_path = cache_dir.join("state.json");
+    // A state that cannot be read is left for the resolution of the dependencies to report and replace
+    let mut state = match std::fs::read_to_string(&state_path) {
+        Ok(contents) => match serde_json::from_str::<State>(&contents) {
+            Ok(state) => state,
+            Err(_) => return probe_nvim(wrapper),
+        },
+        Err(_) => State::new(),
+    };
+    if let Some(cached) = state.nvim_probes.get(&key) {
+        if cached.mtime_ns == mtime_ns && cached.size == metadata.len() {
+            debug!("Using the cached capabilities of {}", key);
+            return Ok(cached.capabilities.clone());
+        }
+    }
+
+    let capabilities = probe_nvim(wrapper)?;
+    state.nvim_probes.insert(
+        key,
+        CachedNvimProbe {
+            mtime_ns,
+            size: metadata.len(),
+            capabilities: capabilities.clone(),
+        },
+    );
+    if let Err(e) = state.save(&state_path) {
+        warn!("Failed to cache the capabilities of nvim: {}", e);
+    }
+    Ok(capabilities)
+}
+
+/// The path of the given program as found on `$PATH`
+fn find_in_path(program: &str) -> Option<std::path::PathBuf> {
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::set_rtp_command;
+    use std::path::Path;
+
+    #[test]
+    fn escapes_runtimepath_entries() {
+        let cases = [
+            ("/plugins/foo.nvim", r"set rtp+=/plugins/foo.nvim"),
+            ("/my plugins/foo.nvim", r"set rtp+=/my\ plugins/foo.nvim"),
+            ("/my\tplugins", "set rtp+=/my\\\tplugins"),
+            // The comma is escaped for the list, then its backslash for `:set`
+            ("/plugins/a,b", r"set rtp+=/plugins/a\\,b"),
+            ("/plugins/a|b", r"set rtp+=/plugins/a\|b"),
+            (r#"/plugins/"a""#, r#"set rtp+=/plugins/\"a\""#),
+            (r"C:\plugins\foo", r"set rtp+=C:\\plugins\\foo"),
+            ("/a b,c|d", r"set rtp+=/a\ b\\,c\|d"),
+        ];
+        for (entry, expected) in cases {
+            assert_eq!(set_rtp_command(Path::new(entry)), expected, "{:?}", entry);
+        }
+    }
+}
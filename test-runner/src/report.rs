@@ -0,0 +1,1025 @@
+use indoc::indoc;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::Duration;
+
+use crate::error::RunnerError;
+
+/// The outcome of running a single test file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum TestOutcome {
+    Passed,
+    Failed,
+    /// The test was not run. Contains the reason why
+    Skipped(String),
+    /// The test failed as expected by its `xfail` directive. Contains the reason given by the directive
+    ExpectedFailure(String),
+    /// The test passed despite its `xfail` directive, which fails the run. Contains the reason given by the
+    /// directive
+    UnexpectedPass(String),
+}
+
+impl TestOutcome {
+    /// Whether the outcome fails the run
+    pub fn is_failure(&self) -> bool {
+        matches!(self, TestOutcome::Failed | TestOutcome::UnexpectedPass(_))
+    }
+
+    /// The outcome of a test with an `xfail` directive with the given reason, which is expected to fail
+    pub fn expecting_failure(self, reason: &str) -> TestOutcome {
+        match self {
+            TestOutcome::Passed => TestOutcome::UnexpectedPass(reason.to_string()),
+            TestOutcome::Failed => TestOutcome::ExpectedFailure(reason.to_string()),
+            outcome => outcome,
+        }
+    }
+}
+
+/// The result of running a single test file
+#[derive(Debug, Clone)]
+pub struct TestResult {
+    pub path: std::path::PathBuf,
+    pub outcome: TestOutcome,
+    pub duration: Duration,
+    pub stdout: String,
+    pub stderr: String,
+    pub report: TestUtilsReport,
+    /// The exit code of nvim, if it was run and exited normally
+    pub exit_code: Option<i32>,
+    /// The signal that terminated nvim, if it crashed (e.g. a segfault) or was killed
+    pub signal: Option<i32>,
+    /// The peak resident set size of nvim in bytes, if it was measured
+    pub peak_rss: Option<u64>,
+    /// Whether the middle of stdout or stderr was omitted, being longer than --max-output
+    pub output_truncated: bool,
+    /// Whether nvim was killed for running past the timeout directive of the test
+    pub timed_out: bool,
+    /// The nvim command the test was run with, if it was run
+    pub invocation: Option<crate::repro::Invocation>,
+    /// With --detect-leaks, the files that appeared in the watched directory while the test ran and were not
+    /// removed by the time it finished
+    pub leaked_files: Vec<std::path::PathBuf>,
+}
+
+impl TestResult {
+    /// Whether the test passed without running a single assertion of test-utils, e.g. because its assertions
+    /// were removed in a refactoring
+    pub fn has_no_assertions(&self) -> bool {
+        self.outcome == TestOutcome::Passed
+            && self.report.written
+            && self.report.assertions.is_empty()
+    }
+
+    pub fn skipped(path: std::path::PathBuf, reason: String) -> TestResult {
+        TestResult {
+            path,
+            outcome: TestOutcome::Skipped(reason),
+            duration: Duration::ZERO,
+            stdout: String::new(),
+            stderr: String::new(),
+            report: TestUtilsReport::default(),
+            exit_code: None,
+            signal: None,
+            peak_rss: None,
+            output_truncated: false,
+            timed_out: false,
+            invocation: None,
+            leaked_files: Vec::new(),
+        }
+    }
+}
+
+/// The signal that terminated a process. Always `None` on platforms without signals
+pub fn exit_signal(status: &std::process::ExitStatus) -> Option<i32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        status.signal()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = status;
+        None
+    }
+}
+
+/// The structured results test-utils writes (as JSON) to the file at `$NVIM_TEST_RUNNER_RESULT_FILE`
+/// when nvim exits. Empty fields may be omitted
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TestUtilsReport {
+    /// The snapshot files that were written in update mode
+    pub updated_snapshots: Vec<String>,
+    /// The full names of the `T.it` blocks that were skipped for not matching the --grep pattern
+    pub filtered_tests: Vec<String>,
+    /// The failed assertions and errors raised in `T.it` blocks, in the order they happened
+    pub failures: Vec<TestFailure>,
+    /// How long the test took, as measured by test-utils when running a batch of tests
+    pub duration_ms: Option<u64>,
+    /// Every assertion of test-utils that was run, passed or failed, in the order they happened. Absent in
+    /// reports of test-utils speaking protocol 1.3 or older
+    pub assertions: Vec<AssertionResult>,
+    /// Key/value pairs attached by the test with `T.metadata` (e.g. a category), which the runner does not
+    /// interpret but passes on to the events, the TAP stream and the results of the run
+    pub metadata: serde_json::Map<String, serde_json::Value>,
+    /// With --check-unused-deps, the names of the Lua modules that were loaded (`package.loaded`) when the
+    /// report was written, including those required by the plugin scripts at startup
+    pub required_modules: Vec<String>,
+    /// The message history of nvim (`:messages`) when the test finished, one entry per line, unless the
+    /// `captureMessages` config field is false. Absent in reports of test-utils speaking protocol 1.9 or older
+    pub messages: Vec<String>,
+    /// Whether test-utils wrote the report, rather than it being the default for a missing one
+    #[serde(skip)]
+    pub written: bool,
+}
+
+/// An assertion of test-utils, e.g. a call of `T.assert_eq`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AssertionResult {
+    /// The name of the assertion, e.g. `assert_eq`
+    pub name: String,
+    pub status: AssertionStatus,
+    /// Why the assertion failed
+    pub message: Option<String>,
+    /// The full name of the `T.it` block the assertion was run in, if any
+    pub test: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AssertionStatus {
+    #[default]
+    Passed,
+    Failed,
+}
+
+/// A failure recorded by test-utils
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TestFailure {
+    /// The full name of the `T.it` block the failure happened in, if any
+    pub name: Option<String>,
+    pub message: String,
+    /// The file and line that the failure is attributed to, if known
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    /// The expected and the actual value of a failed comparison (e.g. of `T.assert_eq`), as printed by
+    /// `vim.inspect`, for the verbose failure format to diff
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actual: Option<String>,
+}
+
+impl std::fmt::Display for TestFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => write!(f, "{}:{}: ", file, line)?,
+            (Some(file), None) => write!(f, "{}: ", file)?,
+            _ => {}
+        }
+        if let Some(name) = &self.name {
+            write!(f, "[{}] ", name)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+impl TestUtilsReport {
+    /// The number of assertions that passed, and that were run. None if test-utils did not report any
+    pub fn assertion_counts(&self) -> Option<(usize, usize)> {
+        if self.assertions.is_empty() {
+            return None;
+        }
+        let passed = self
+            .assertions
+            .iter()
+            .filter(|assertion| assertion.status == AssertionStatus::Passed)
+            .count();
+        Some((passed, self.assertions.len()))
+    }
+
+    /// Read the report file. Returns the default report if test-utils did not write one
+    pub fn read(path: &std::path::Path) -> TestUtilsReport {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map(|report| TestUtilsReport {
+                    written: true,
+                    ..report
+                })
+                .unwrap_or_else(|e| {
+                    warn!(
+                        "Failed to parse test-utils report {}: {}",
+                        path.display(),
+                        e
+                    );
+                    TestUtilsReport::default()
+                }),
+            Err(_) => TestUtilsReport::default(),
+        }
+    }
+
+    /// Read and remove the report file, as [`TestUtilsReport::read`]
+    pub fn take(path: &std::path::Path) -> TestUtilsReport {
+        let report = TestUtilsReport::read(path);
+        let _ = std::fs::remove_file(path);
+        report
+    }
+}
+
+/// A test that passed in some, but not all, of the runs of `--repeat`
+#[derive(Debug, Clone)]
+pub struct FlakyTest {
+    pub path: std::path::PathBuf,
+    pub passes: usize,
+    pub runs: usize,
+}
+
+impl FlakyTest {
+    /// The share of the runs the test passed in, from 0 to 1
+    pub fn pass_rate(&self) -> f64 {
+        self.passes as f64 / self.runs as f64
+    }
+}
+
+/// A test whose median duration exceeded that of the timing baseline by more than the config allows, with
+/// --baseline
+#[derive(Debug, Clone)]
+pub struct TimingRegression {
+    pub path: std::path::PathBuf,
+    pub baseline_ms: f64,
+    pub current_ms: f64,
+}
+
+impl TimingRegression {
+    /// By how many percent the test got slower
+    pub fn change_percent(&self) -> f64 {
+        (self.current_ms / self.baseline_ms - 1.0) * 100.0
+    }
+}
+
+/// The aggregated results of a test run
+#[derive(Debug, Clone)]
+pub struct RunSummary {
+    pub passed: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    /// The tests that failed as expected by their `xfail` directive, which are not counted as failed
+    pub expected_failures: usize,
+    /// The tests that passed despite their `xfail` directive, which fail the run
+    pub unexpected_passes: usize,
+    /// The matched tests that were left out of the run, e.g. by --tags or --changed
+    pub filtered: usize,
+    pub duration: Duration,
+    pub results: Vec<TestResult>,
+    /// With `keep_going`, the dependencies that failed to resolve. The run does not succeed if there are any
+    pub unresolved_deps: Vec<String>,
+    /// With `--repeat`, the tests whose outcome differed between the runs. The run does not succeed if there
+    /// are any, even if they passed in the last run
+    pub flaky: Vec<FlakyTest>,
+    /// With --baseline, the tests that got slower than the baseline allows, the worst first. The run does not
+    /// succeed if there are any
+    pub regressions: Vec<TimingRegression>,
+    /// With --fail-on-unused-deps, the dependencies none of whose modules any test required. The run does not
+    /// succeed if there are any
+    pub unused_deps: Vec<String>,
+    /// With --fail-on-warning, the warnings about the setup of the run (see [`crate::warnings`]). The run does
+    /// not succeed if there are any
+    pub warnings: Vec<String>,
+    /// With --allowed-failures, the number of failing tests (including the unexpected passes) that does not fail
+    /// the run yet
+    pub allowed_failures: Option<usize>,
+    /// With --guard-user-config, the files of the nvim config or data of the user that were added, removed or
+    /// modified while the tests ran. The run does not succeed if there are any
+    pub user_config_changes: Vec<std::path::PathBuf>,
+    /// The tests that ran and match the `quarantine` globs of the config, whose failures do not fail the run
+    pub quarantined: Vec<std::path::PathBuf>,
+    /// The quarantined tests that passed in each of their last [`QUARANTINE_RELEASE_RUNS`] runs, which may be
+    /// taken out of quarantine
+    pub releasable: Vec<std::path::PathBuf>,
+}
+
+/// How many runs in a row a quarantined test has to pass for it to be reported as one that may be taken out of
+/// quarantine
+pub const QUARANTINE_RELEASE_RUNS: usize = 5;
+
+impl RunSummary {
+    pub fn new(results: Vec<TestResult>, duration: Duration) -> RunSummary {
+        let mut summary = RunSummary {
+            passed: 0,
+            failed: 0,
+            skipped: 0,
+            expected_failures: 0,
+            unexpected_passes: 0,
+            filtered: 0,
+            duration,
+            results,
+            unresolved_deps: Vec::new(),
+            flaky: Vec::new(),
+            regressions: Vec::new(),
+            unused_deps: Vec::new(),
+            warnings: Vec::new(),
+            allowed_failures: None,
+            user_config_changes: Vec::new(),
+            quarantined: Vec::new(),
+            releasable: Vec::new(),
+        };
+        summary.recount();
+        summary
+    }
+
+    /// Count the outcomes of the results again, e.g. after one of them was replaced
+    pub fn recount(&mut self) {
+        let count =
+            |f: fn(&TestOutcome) -> bool| self.results.iter().filter(|r| f(&r.outcome)).count();
+        self.passed = count(|o| *o == TestOutcome::Passed);
+        self.failed = count(|o| *o == TestOutcome::Failed);
+        self.skipped = count(|o| matches!(o, TestOutcome::Skipped(_)));
+        self.expected_failures = count(|o| matches!(o, TestOutcome::ExpectedFailure(_)));
+        self.unexpected_passes = count(|o| matches!(o, TestOutcome::UnexpectedPass(_)));
+    }
+
+    /// The results that fail the run, including the unexpected passes
+    pub fn failures(&self) -> impl Iterator<Item = &TestResult> {
+        self.results.iter().filter(|r| r.outcome.is_failure())
+    }
+
+    /// The results of the quarantined tests that failed, which do not fail the run
+    pub fn quarantined_failures(&self) -> impl Iterator<Item = &TestResult> {
+        self.failures()
+            .filter(|r| self.quarantined.contains(&r.path))
+    }
+
+    /// The number of failing tests that count against the run, i.e. the failures (including the unexpected
+    /// passes) of the tests that are not quarantined
+    pub fn num_failures(&self) -> usize {
+        self.failed + self.unexpected_passes - self.quarantined_failures().count()
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.num_failures() <= self.allowed_failures.unwrap_or(0)
+            && self.unresolved_deps.is_empty()
+            && self.flaky.is_empty()
+            && self.regressions.is_empty()
+            && self.unused_deps.is_empty()
+            && self.warnings.is_empty()
+            && self.user_config_changes.is_empty()
+    }
+
+    /// The number of tests that were run, i.e. not skipped
+    pub fn num_run(&self) -> usize {
+        self.results.len() - self.skipped
+    }
+
+    /// The exit code the process should exit with
+    pub fn exit_code(&self) -> i32 {
+        if self.is_success() {
+            0
+        } else {
+            1
+        }
+    }
+}
+
+/// The outcome of running the suite for a cell of the test matrix: its summary, or why it could not be run
+#[derive(Debug)]
+pub struct MatrixCellResult {
+    pub name: String,
+    pub result: Result<RunSummary, RunnerError>,
+}
+
+/// Turn the path of a test file into a name that is safe to use as a file name, e.g. `tests/foo/bar.lua` becomes
+/// `tests_foo_bar.lua-1a2b3c4d`. The name ends with a hash of the path, as paths like `tests/foo_bar.lua` and
+/// `tests_foo/bar.lua` are otherwise sanitized to the same name
+pub fn sanitize_file_name(path: &std::path::Path) -> String {
+    let path = path.to_string_lossy();
+    format!(
+        "{}-{}",
+        sanitize_path(&path),
+        &crate::archive::sha256_hex(path.as_bytes())[..8]
+    )
+}
+
+fn sanitize_path(path: &str) -> String {
+    let name: String = path
+        .trim_start_matches("./")
+        .trim_start_matches('/')
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect();
+    name.trim_start_matches('.').to_string()
+}
+
+/// Write the captured output of every test into the given directory, one log file per test plus a combined one
+pub fn write_output_logs(dir: &std::path::Path, summary: &RunSummary) -> Result<(), RunnerError> {
+    std::fs::create_dir_all(dir)?;
+    let mut combined = BufWriter::new(File::create(dir.join("combined.log"))?);
+
+    for result in &summary.results {
+        let log = format!(
+            indoc! {"
+                test: {}
+                outcome: {:?}
+                duration: {:.2?}
+                --- stdout ---
+                {}
+                --- stderr ---
+                {}
+            "},
+            result.path.display(),
+            result.outcome,
+            result.duration,
+            result.stdout,
+            result.stderr
+        );
+        let path = dir.join(format!("{}.log", sanitize_file_name(&result.path)));
+        std::fs::write(&path, &log)?;
+        combined.write_all(log.as_bytes())?;
+        combined.write_all(b"\n")?;
+    }
+
+    combined.flush()?;
+    Ok(())
+}
+
+/// Write the results as a TAP version 13 stream, numbering the tests in the order of the results
+/// The metadata of a test as a line of its YAML block, in JSON (which is valid YAML). Nothing if it has none
+fn write_tap_metadata(out: &mut impl Write, result: &TestResult) -> std::io::Result<()> {
+    if result.report.metadata.is_empty() {
+        return Ok(());
+    }
+    writeln!(
+        out,
+        "  metadata: {}",
+        serde_json::Value::Object(result.report.metadata.clone())
+    )
+}
+
+pub fn write_tap(out: &mut impl Write, summary: &RunSummary) -> std::io::Result<()> {
+    writeln!(out, "TAP version 13")?;
+    writeln!(out, "1..{}", summary.results.len())?;
+    for (index, result) in summary.results.iter().enumerate() {
+        let number = index + 1;
+        let path = result.path.display();
+        match &result.outcome {
+            TestOutcome::Passed => {
+                writeln!(out, "ok {} - {}", number, path)?;
+                if !result.report.metadata.is_empty() {
+                    writeln!(out, "  ---")?;
+                    write_tap_metadata(out, result)?;
+                    writeln!(out, "  ...")?;
+                }
+            }
+            TestOutcome::Skipped(reason) => {
+                writeln!(out, "ok {} - {} # SKIP {}", number, path, reason)?
+            }
+            // As a TODO test, which TAP harnesses do not count as a failure
+            TestOutcome::ExpectedFailure(reason) => {
+                writeln!(out, "not ok {} - {} # TODO {}", number, path, reason)?
+            }
+            TestOutcome::UnexpectedPass(reason) => {
+                writeln!(out, "not ok {} - {}", number, path)?;
+                writeln!(out, "  ---")?;
+                writeln!(out, "  message: passed, but is marked xfail")?;
+                if !reason.is_empty() {
+                    writeln!(out, "  xfail: {}", reason)?;
+                }
+                writeln!(out, "  ...")?;
+            }
+            TestOutcome::Failed => {
+                writeln!(out, "not ok {} - {}", number, path)?;
+                writeln!(out, "  ---")?;
+                writeln!(out, "  duration_ms: {}", result.duration.as_millis())?;
+                if let Some(signal) = result.signal {
+                    writeln!(out, "  signal: {}", signal)?;
+                }
+                write_tap_metadata(out, result)?;
+                writeln!(out, "  stderr: |")?;
+                for line in result.stderr.lines() {
+                    writeln!(out, "    {}", line)?;
+                }
+                writeln!(out, "  ...")?;
+            }
+        }
+    }
+    out.flush()
+}
+
+/// Escape the characters Markdown would interpret in text, e.g. the `_` of a path or the `|` ending a table
+/// cell. Line breaks are folded into spaces, so that the text stays on a single line (e.g. of a table)
+pub fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '\r' => {}
+            '\n' => escaped.push(' '),
+            '\\' | '`' | '*' | '_' | '{' | '}' | '[' | ']' | '(' | ')' | '#' | '+' | '-' | '.'
+            | '!' | '|' | '~' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The number of lines of each of stdout and stderr of a failing test in the Markdown report, counted from
+/// the end, which is where the error usually is
+const MARKDOWN_OUTPUT_LINES: usize = 100;
+
+/// Write the output of a test as a fenced code block, keeping its last [`MARKDOWN_OUTPUT_LINES`] lines
+fn write_markdown_output(out: &mut impl Write, name: &str, output: &str) -> std::io::Result<()> {
+    let lines: Vec<&str> = output.trim_end().lines().collect();
+    if lines.is_empty() {
+        return Ok(());
+    }
+    // Longer than any run of backticks in the output, so that the output cannot end the block
+    let mut longest_run = 0;
+    let mut run = 0;
+    for c in output.chars() {
+        run = if c == '`' { run + 1 } else { 0 };
+        longest_run = longest_run.max(run);
+    }
+    let fence = "`".repeat(longest_run.max(2) + 1);
+
+    writeln!(out, "{}:", name)?;
+    writeln!(out)?;
+    writeln!(out, "{}text", fence)?;
+    let omitted = lines.len().saturating_sub(MARKDOWN_OUTPUT_LINES);
+    if omitted > 0 {
+        writeln!(out, "... ({} earlier line(s) omitted)", omitted)?;
+    }
+    for line in &lines[omitted..] {
+        writeln!(out, "{}", line)?;
+    }
+    writeln!(out, "{}", fence)?;
+    writeln!(out)
+}
+
+/// Write the results as a Markdown summary, e.g. for the job summary of GitHub Actions or a comment on a pull
+/// request: the counts, a table of the results, the slowest tests, and a collapsible section with the failures
+/// and the output of each failing test
+pub fn write_markdown(out: &mut impl Write, summary: &RunSummary) -> std::io::Result<()> {
+    writeln!(out, "## Test results")?;
+    writeln!(out)?;
+    let mut counts = vec![
+        format!("{} passed", summary.passed),
+        format!("{} failed", summary.failed),
+        format!("{} skipped", summary.skipped),
+    ];
+    if summary.expected_failures > 0 {
+        counts.push(format!("{} expected failure(s)", summary.expected_failures));
+    }
+    if summary.unexpected_passes > 0 {
+        counts.push(format!("{} unexpected pass(es)", summary.unexpected_passes));
+    }
+    if summary.filtered > 0 {
+        counts.push(format!("{} filtered", summary.filtered));
+    }
+    writeln!(
+        out,
+        "{} **{}** in {:.2?}",
+        if summary.is_success() { "✅" } else { "❌" },
+        counts.join(", "),
+        summary.duration
+    )?;
+    writeln!(out)?;
+
+    let problems = summary
+        .unresolved_deps
+        .iter()
+        .map(|dep| format!("Unresolved dependency: {}", dep))
+        .chain(summary.flaky.iter().map(|flaky| {
+            format!(
+                "Flaky: {} (passed {:.0}% of the runs)",
+                flaky.path.display(),
+                flaky.pass_rate() * 100.0
+            )
+        }))
+        .chain(summary.regressions.iter().map(|regression| {
+            format!(
+                "Slower than the baseline: {} ({:.0}ms → {:.0}ms, +{:.0}%)",
+                regression.path.display(),
+                regression.baseline_ms,
+                regression.current_ms,
+                regression.change_percent()
+            )
+        }))
+        .chain(
+            summary
+                .unused_deps
+                .iter()
+                .map(|dep| format!("Unused dependency: {}", dep)),
+        )
+        .chain(
+            summary
+                .warnings
+                .iter()
+                .map(|warning| format!("Warning: {}", warning)),
+        );
+    let mut any_problems = false;
+    for problem in problems {
+        writeln!(out, "- {}", escape_markdown(&problem))?;
+        any_problems = true;
+    }
+    if any_problems {
+        writeln!(out)?;
+    }
+
+    if !summary.results.is_empty() {
+        writeln!(out, "| Test | Outcome | Duration |")?;
+        writeln!(out, "| ---- | ------- | -------: |")?;
+        for result in &summary.results {
+            let (outcome, ran) = match &result.outcome {
+                TestOutcome::Passed => ("✅ passed".to_string(), true),
+                TestOutcome::Failed => ("❌ failed".to_string(), true),
+                TestOutcome::Skipped(reason) => (format!("⊘ skipped ({})", reason), false),
+                TestOutcome::ExpectedFailure(_) => ("✅ failed as expected".to_string(), true),
+                TestOutcome::UnexpectedPass(_) => {
+                    ("❌ passed, but is marked xfail".to_string(), true)
+                }
+            };
+            writeln!(
+                out,
+                "| {} | {} | {} |",
+                escape_markdown(&result.path.display().to_string()),
+                escape_markdown(&outcome),
+                if ran {
+                    format!("{:.2?}", result.duration)
+                } else {
+                    String::new()
+                }
+            )?;
+        }
+        writeln!(out)?;
+    }
+
+    let mut slowest: Vec<&TestResult> = summary
+        .results
+        .iter()
+        .filter(|result| !matches!(result.outcome, TestOutcome::Skipped(_)))
+        .collect();
+    slowest.sort_by_key(|result| std::cmp::Reverse(result.duration));
+    if slowest.len() > 1 {
+        writeln!(out, "### Slowest tests")?;
+        writeln!(out)?;
+        for result in slowest.iter().take(5) {
+            writeln!(
+                out,
+                "1. {} ({:.2?})",
+                escape_markdown(&result.path.display().to_string()),
+                result.duration
+            )?;
+        }
+        writeln!(out)?;
+    }
+
+    let failures: Vec<&TestResult> = summary.failures().collect();
+    if !failures.is_empty() {
+        writeln!(out, "### Failures")?;
+        writeln!(out)?;
+    }
+    for result in failures {
+        // The summary is HTML, in which Markdown is not rendered
+        writeln!(out, "<details>")?;
+        writeln!(
+            out,
+            "<summary><code>{}</code></summary>",
+            escape_html(&result.path.display().to_string())
+        )?;
+        writeln!(out)?;
+        let mut reasons: Vec<String> = result
+            .report
+            .failures
+            .iter()
+            .map(|failure| escape_markdown(&failure.to_string()))
+            .collect();
+        if let Some(signal) = result.signal {
+            reasons.insert(0, format!("nvim crashed (signal {})", signal));
+        }
+        if let TestOutcome::UnexpectedPass(_) = result.outcome {
+            reasons.insert(0, "The test passed, but is marked xfail".to_string());
+        }
+        for reason in &reasons {
+            writeln!(out, "- {}", reason)?;
+        }
+        if !reasons.is_empty() {
+            writeln!(out)?;
+        }
+        write_markdown_output(out, "stdout", &result.stdout)?;
+        write_markdown_output(out, "stderr", &result.stderr)?;
+        writeln!(out, "</details>")?;
+        writeln!(out)?;
+    }
+    out.flush()
+}
+
+/// Escape text for HTML, e.g. the `<summary>` of a collapsible section of the Markdown report
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The results as a single JSON document: the counts and the result of each test, as given to the afterRun hook
+pub fn summary_json(summary: &RunSummary) -> serde_json::Value {
+    let recorded = crate::state::RecordedRun::new(summary);
+    serde_json::json!({
+        "success": summary.is_success(),
+        "passed": summary.passed,
+        "failed": summary.failed,
+        "skipped": summary.skipped,
+        "expectedFailures": summary.expected_failures,
+        "unexpectedPasses": summary.unexpected_passes,
+        "durationMs": recorded.duration_ms,
+        "results": recorded.results,
+        "unresolvedDeps": recorded.unresolved_deps,
+        "quarantined": recorded.quarantined,
+    })
+}
+
+/// Write the results as a single JSON document, see [`summary_json`]
+pub fn write_json(out: &mut impl Write, summary: &RunSummary) -> std::io::Result<()> {
+    serde_json::to_writer_pretty(&mut *out, &summary_json(summary))?;
+    writeln!(out)?;
+    out.flush()
+}
+
+/// Escape text for XML. The control characters XML 1.0 does not allow (e.g. the escape sequences of colored
+/// output) are left out
+fn escape_xml(text: &str) -> String {
+    escape_html(text)
+        .replace('\'', "&apos;")
+        .chars()
+        .filter(|c| !c.is_control() || matches!(c, '\t' | '\n' | '\r'))
+        .collect()
+}
+
+/// Write the results as a JUnit XML report, e.g. for the test report of a CI service: a test case for each test
+/// file, named by its path. Expected failures are reported as skipped, as pytest does, and unexpected passes as
+/// failed
+pub fn write_junit(out: &mut impl Write, summary: &RunSummary) -> std::io::Result<()> {
+    let seconds = |duration: Duration| format!("{:.3}", duration.as_secs_f64());
+    let failures = summary.failed + summary.unexpected_passes;
+    let skipped = summary.skipped + summary.expected_failures;
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        out,
+        r#"<testsuites tests="{}" failures="{}" skipped="{}" time="{}">"#,
+        summary.results.len(),
+        failures,
+        skipped,
+        seconds(summary.duration)
+    )?;
+    writeln!(
+        out,
+        r#"  <testsuite name="nvim-test-runner" tests="{}" failures="{}" errors="0" skipped="{}" time="{}">"#,
+        summary.results.len(),
+        failures,
+        skipped,
+        seconds(summary.duration)
+    )?;
+    for result in &summary.results {
+        let path = escape_xml(&result.path.display().to_string());
+        write!(
+            out,
+            r#"    <testcase name="{}" classname="{}" time="{}""#,
+            path,
+            path,
+            seconds(result.duration)
+        )?;
+        match &result.outcome {
+            TestOutcome::Passed => {
+                writeln!(out, "/>")?;
+                continue;
+            }
+            TestOutcome::Skipped(reason) => {
+                writeln!(out, ">")?;
+                writeln!(out, r#"      <skipped message="{}"/>"#, escape_xml(reason))?;
+            }
+            TestOutcome::ExpectedFailure(reason) => {
+                writeln!(out, ">")?;
+                writeln!(
+                    out,
+                    r#"      <skipped message="expected failure: {}"/>"#,
+                    escape_xml(reason)
+                )?;
+            }
+            TestOutcome::UnexpectedPass(reason) => {
+                writeln!(out, ">")?;
+                writeln!(
+                    out,
+                    r#"      <failure message="passed, but is marked xfail: {}"/>"#,
+                    escape_xml(reason)
+                )?;
+            }
+            TestOutcome::Failed => {
+                writeln!(out, ">")?;
+                let message = match (result.report.failures.first(), result.exit_code) {
+                    (Some(failure), _) => failure.message.clone(),
+                    (None, Some(code)) => format!("nvim exited with {}", code),
+                    (None, None) => match result.signal {
+                        Some(signal) => format!("nvim was terminated by signal {}", signal),
+                        None => "failed".to_string(),
+                    },
+                };
+                let details: Vec<String> = result
+                    .report
+                    .failures
+                    .iter()
+                    .map(|failure| failure.to_string())
+                    .collect();
+                writeln!(
+                    out,
+                    r#"      <failure message="{}">{}</failure>"#,
+                    escape_xml(&message),
+                    escape_xml(&details.join("\n"))
+                )?;
+                if !result.stdout.is_empty() {
+                    writeln!(
+                        out,
+                        "      <system-out>{}</system-out>",
+                        escape_xml(&result.stdout)
+                    )?;
+                }
+                if !result.stderr.is_empty() {
+                    writeln!(
+                        out,
+                        "      <system-err>{}</system-err>",
+                        escape_xml(&result.stderr)
+                    )?;
+                }
+            }
+        }
+        writeln!(out, "    </testcase>")?;
+    }
+    writeln!(out, "  </testsuite>")?;
+    writeln!(out, "</testsuites>")?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn result(path: &str, outcome: TestOutcome) -> TestResult {
+        TestResult {
+            outcome,
+            duration: Duration::from_millis(10),
+            ..TestResult::skipped(PathBuf::from(path), String::new())
+        }
+    }
+
+    fn summary() -> RunSummary {
+        RunSummary::new(
+            vec![
+                result("tests/a.lua", TestOutcome::Passed),
+                result("tests/b.lua", TestOutcome::Failed),
+                result(
+                    "tests/c.lua",
+                    TestOutcome::Skipped("bailed out".to_string()),
+                ),
+                result("tests/d.lua", TestOutcome::Passed),
+                result(
+                    "tests/e.lua",
+                    TestOutcome::ExpectedFailure("#12".to_string()),
+                ),
+                result(
+                    "tests/f.lua",
+                    TestOutcome::UnexpectedPass("#13".to_string()),
+                ),
+            ],
+            Duration::from_secs(1),
+        )
+    }
+
+    #[test]
+    fn sanitized_file_names_are_apart() {
+        let name = sanitize_file_name(std::path::Path::new("./tests/foo/bar.lua"));
+        assert!(name.starts_with("tests_foo_bar.lua-"), "{}", name);
+        assert!(name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_')));
+        assert_ne!(
+            sanitize_file_name(std::path::Path::new("tests/foo_bar.lua")),
+            sanitize_file_name(std::path::Path::new("tests_foo/bar.lua"))
+        );
+        assert_eq!(
+            sanitize_file_name(std::path::Path::new("tests/foo.lua")),
+            sanitize_file_name(std::path::Path::new("tests/foo.lua"))
+        );
+    }
+
+    #[test]
+    fn counts_outcomes() {
+        let summary = summary();
+        assert_eq!(summary.passed, 2);
+        assert_eq!(summary.failed, 1);
+        assert_eq!(summary.skipped, 1);
+        assert_eq!(summary.expected_failures, 1);
+        assert_eq!(summary.unexpected_passes, 1);
+        assert_eq!(summary.num_run(), 5);
+        assert_eq!(summary.num_failures(), 2);
+    }
+
+    #[test]
+    fn recounts_replaced_results() {
+        let mut summary = summary();
+        summary.results[1].outcome = TestOutcome::Passed;
+        summary.recount();
+        assert_eq!(summary.passed, 3);
+        assert_eq!(summary.failed, 0);
+    }
+
+    #[test]
+    fn lists_failures_with_unexpected_passes() {
+        let summary = summary();
+        let failures: Vec<&PathBuf> = summary.failures().map(|r| &r.path).collect();
+        assert_eq!(
+            failures,
+            [&PathBuf::from("tests/b.lua"), &PathBuf::from("tests/f.lua")]
+        );
+    }
+
+    #[test]
+    fn exit_code() {
+        assert_eq!(summary().exit_code(), 1);
+
+        let passing = RunSummary::new(
+            vec![
+                result("tests/a.lua", TestOutcome::Passed),
+                result(
+                    "tests/e.lua",
+                    TestOutcome::ExpectedFailure("#12".to_string()),
+                ),
+                result("tests/c.lua", TestOutcome::Skipped("filtered".to_string())),
+            ],
+            Duration::ZERO,
+        );
+        assert!(passing.is_success());
+        assert_eq!(passing.exit_code(), 0);
+        assert_eq!(RunSummary::new(Vec::new(), Duration::ZERO).exit_code(), 0);
+    }
+
+    #[test]
+    fn allowed_and_quarantined_failures_do_not_fail_the_run() {
+        let mut summary = summary();
+        summary.allowed_failures = Some(1);
+        assert_eq!(summary.exit_code(), 1);
+        summary.allowed_failures = Some(2);
+        assert_eq!(summary.exit_code(), 0);
+
+        let mut summary = self::summary();
+        summary.quarantined = vec![PathBuf::from("tests/b.lua"), PathBuf::from("tests/f.lua")];
+        assert_eq!(summary.quarantined_failures().count(), 2);
+        assert_eq!(summary.num_failures(), 0);
+        assert_eq!(summary.exit_code(), 0);
+    }
+
+    #[test]
+    fn problems_other_than_failures_fail_the_run() {
+        let passing = || {
+            RunSummary::new(
+                vec![result("tests/a.lua", TestOutcome::Passed)],
+                Duration::ZERO,
+            )
+        };
+        let problems: [fn(&mut RunSummary); 6] = [
+            |s| s.unresolved_deps.push("https://github.com/a/b".to_string()),
+            |s| {
+                s.flaky.push(FlakyTest {
+                    path: PathBuf::from("tests/a.lua"),
+                    passes: 1,
+                    runs: 2,
+                })
+            },
+            |s| s.unused_deps.push("https://github.com/a/b".to_string()),
+            |s| s.warnings.push("Config file not found".to_string()),
+            |s| s.user_config_changes.push(PathBuf::from("init.lua")),
+            |s| {
+                s.regressions.push(TimingRegression {
+                    path: PathBuf::from("tests/a.lua"),
+                    baseline_ms: 100.0,
+                    current_ms: 200.0,
+                })
+            },
+        ];
+        for problem in problems {
+            let mut summary = passing();
+            problem(&mut summary);
+            assert_eq!(summary.exit_code(), 1);
+        }
+    }
+}
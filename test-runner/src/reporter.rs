@@ -0,0 +1,1006 @@
+use ansi_term::Colour;
+use indicatif::{ProgressBar, ProgressStyle};
+use indoc::indoc;
+use log::{error, info, warn};
+use serde_json::json;
+use std::fs::{File, OpenOptions};
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::discovery::TestFile;
+use crate::error::RunnerError;
+use crate::report::{
+    write_json, write_junit, write_markdown, write_tap, RunSummary, TestFailure, TestOutcome,
+    TestResult,
+};
+use crate::RunOptions;
+
+/// Receives the events of a test run, e.g. to print the results in some format. Several reporters can be active
+/// in the same run. Tests run in parallel, so the events of different tests may interleave
+pub trait Reporter: Send + Sync {
+    /// Called once before any test starts, with the tests that are about to run
+    fn on_run_start(&self, _tests: &[TestFile]) {}
+
+    fn on_test_start(&self, _test: &TestFile) {}
+
+    /// Called when a test finishes, or is skipped after the run started (e.g. because of --bail)
+    fn on_test_complete(&self, _result: &TestResult) {}
+
+    /// Called once all tests have finished, with the results of all tests including the skipped ones
+    fn on_run_complete(&self, _summary: &RunSummary) {}
+}
+
+/// How the console prints the failures of a test
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureFormat {
+    /// One line per failure: `path:line: message`
+    Compact,
+    /// The stderr of the test, and for each failure the diff of the expected and the actual value along with
+    /// the lines of the test around it
+    Verbose,
+}
+
+impl FailureFormat {
+    /// Verbose when run in a terminal, compact otherwise and in CI, where the output is mostly skimmed in logs
+    pub fn detect() -> FailureFormat {
+        let ci = std::env::var("CI").is_ok_and(|ci| !ci.is_empty() && ci != "0" && ci != "false");
+        if std::io::stdout().is_terminal() && !ci {
+            FailureFormat::Verbose
+        } else {
+            FailureFormat::Compact
+        }
+    }
+}
+
+pub fn parse_failure_format(format: &str) -> Result<FailureFormat, String> {
+    match format {
+        "compact" => Ok(FailureFormat::Compact),
+        "verbose" => Ok(FailureFormat::Verbose),
+        _ => Err(format!(
+            "invalid failure format {:?}, expected compact or verbose",
+            format
+        )),
+    }
+}
+
+/// The human-readable, colored output with a progress bar
+pub struct ConsoleReporter {
+    progress: ProgressBar,
+    /// Whether a line is printed for each passing test
+    print_passed: bool,
+    /// The number of slowest tests listed once the run has finished
+    slowest: Option<usize>,
+    /// The number of tests that used the most memory listed once the run has finished
+    most_memory: Option<usize>,
+    /// Whether the results are summarized by directory once the run has finished
+    group_by_dir: bool,
+    /// The number of failures printed in full, the others are only counted
+    max_failures_output: Option<usize>,
+    failure_count: AtomicUsize,
+    /// The number of tests to run, and of those that have finished, for the running index of each line
+    total: AtomicUsize,
+    completed: AtomicUsize,
+    /// Whether a warning is printed for each test that passed without running any assertions
+    warn_no_assertions: bool,
+    /// The number of lines of the stderr of a failed test that are printed, the others are omitted from the
+    /// middle. None prints it in full
+    max_stderr_lines: Option<usize>,
+    failure_format: FailureFormat,
+}
+
+impl ConsoleReporter {
+    pub fn new(options: &RunOptions) -> ConsoleReporter {
+        let quiet = options.quiet;
+        // The progress bar is only shown when attached to a terminal
+        let progress = if quiet || !std::io::stdout().is_terminal() {
+            ProgressBar::hidden()
+        } else {
+            ProgressBar::new(0)
+        };
+        progress.set_style(
+            ProgressStyle::with_template("{spinner} [{pos}/{len}] {wide_bar} {msg}")
+                .unwrap()
+                .progress_chars("=> "),
+        );
+        // In compact mode passing tests are folded into the progress bar, as long as it is visible
+        let print_passed = !quiet && (!options.compact || progress.is_hidden());
+        ConsoleReporter {
+            progress,
+            print_passed,
+            slowest: options.slowest,
+            most_memory: options.most_memory,
+            group_by_dir: options.group_by_dir,
+            max_failures_output: options.max_failures_output,
+            failure_count: AtomicUsize::new(0),
+            total: AtomicUsize::new(0),
+            completed: AtomicUsize::new(0),
+            warn_no_assertions: !quiet,
+            max_stderr_lines: match options.max_stderr_lines {
+                Some(0) => None,
+                Some(max) => Some(max),
+                None => Some(DEFAULT_MAX_STDERR_LINES),
+            },
+            failure_format: options.failure_format.unwrap_or_else(FailureFormat::detect),
+        }
+    }
+}
+
+impl Reporter for ConsoleReporter {
+    fn on_run_start(&self, tests: &[TestFile]) {
+        self.progress.set_length(tests.len() as u64);
+        self.total.store(tests.len(), Ordering::SeqCst);
+    }
+
+    fn on_test_complete(&self, result: &TestResult) {
+        self.progress.inc(1);
+        self.progress.set_message(result.path.display().to_string());
+        let completed = self.completed.fetch_add(1, Ordering::SeqCst) + 1;
+        // E.g. [12/200], so that the progress shows in the output even without the progress bar
+        let index = format!("[{}/{}]", completed, self.total.load(Ordering::SeqCst));
+
+        if result.outcome.is_failure() {
+            let failure_index = self.failure_count.fetch_add(1, Ordering::SeqCst);
+            if self
+                .max_failures_output
+                .is_some_and(|max| failure_index >= max)
+            {
+                return;
+            }
+        }
+
+        // E.g. `tests/foo_spec.lua (3 of 4 assertions passed)`, for test-utils reporting its assertions
+        if result.has_no_assertions() {
+            warn!("No assertions in {}", result.path.display());
+            if self.warn_no_assertions {
+                self.progress.suspend(|| {
+                    println!(
+                        "{}",
+                        Colour::Yellow.paint(format!(
+                            "{} ⚠ no assertions in {}",
+                            index,
+                            result.path.display()
+                        ))
+                    )
+                });
+            }
+            // In place of the line of a passed test
+            return;
+        }
+
+        let test = match result.report.assertion_counts() {
+            Some((passed, total)) => format!(
+                "{} ({} of {} assertions passed)",
+                result.path.display(),
+                passed,
+                total
+            ),
+            None => result.path.display().to_string(),
+        };
+        match (&result.outcome, result.signal) {
+            (TestOutcome::Failed, _) if result.timed_out => self.progress.suspend(|| {
+                println!(
+                    "{}",
+                    Colour::Red.paint(format!(
+                        "{} ⏱ {} timed out after {:.2?}",
+                        index, test, result.duration
+                    ))
+                )
+            }),
+            // nvim crashed (e.g. a segfault), which is likely a problem with the environment or a plugin
+            (TestOutcome::Failed, Some(signal)) => self.progress.suspend(|| {
+                println!(
+                    "{}",
+                    Colour::Red.paint(format!(
+                        "{} 💥 nvim crashed (signal {}) while running test {}",
+                        index, signal, test
+                    ))
+                )
+            }),
+            // nvim itself failed, rather than the test
+            (TestOutcome::Failed, None) if result.exit_code != Some(0) => {
+                self.progress.suspend(|| {
+                    println!(
+                        "{}",
+                        Colour::Red.paint(format!("{} Failed to run test {}", index, test))
+                    )
+                })
+            }
+            (TestOutcome::Failed, None) if self.failure_format == FailureFormat::Compact => {
+                self.progress.suspend(|| {
+                    if result.report.failures.is_empty() {
+                        println!("{}", Colour::Red.paint(format!("{} x {}", index, test)));
+                    }
+                    for failure in &result.report.failures {
+                        let line = match &failure.file {
+                            Some(_) => failure.to_string(),
+                            None => format!("{}: {}", result.path.display(), failure),
+                        };
+                        println!("{}", Colour::Red.paint(line));
+                    }
+                })
+            }
+            (TestOutcome::Failed, None) => self.progress.suspend(|| {
+                print!(
+                    "{}",
+                    Colour::Red.paint(format!(
+                        indoc! {"
+                        {} x {}
+                        {}
+                    "},
+                        index,
+                        test,
+                        truncate_lines(&result.stderr, self.max_stderr_lines)
+                    ))
+                );
+                if !result.report.messages.is_empty() {
+                    println!(
+                        "{}",
+                        Colour::Red.paint(format!(
+                            ":messages:\n{}",
+                            truncate_lines(
+                                &result.report.messages.join("\n"),
+                                self.max_stderr_lines
+                            )
+                        ))
+                    );
+                }
+                for failure in &result.report.failures {
+                    println!("{}", Colour::Red.paint(format!("  {}", failure)));
+                    print_failure_details(failure);
+                }
+            }),
+            (TestOutcome::UnexpectedPass(reason), _) => self.progress.suspend(|| {
+                println!(
+                    "{}",
+                    Colour::Red.paint(format!(
+                        "{} ! {} (passed unexpectedly, {})",
+                        index,
+                        test,
+                        xfail_reason(reason)
+                    ))
+                )
+            }),
+            (TestOutcome::Passed, _) if self.print_passed => self
+                .progress
+                .suspend(|| println!("{}", Colour::Blue.paint(format!("{} ✓ {}", index, test)))),
+            (TestOutcome::ExpectedFailure(reason), _) if self.print_passed => {
+                self.progress.suspend(|| {
+                    println!(
+                        "{}",
+                        Colour::Yellow.paint(format!(
+                            "{} x {} (failed as expected, {})",
+                            index,
+                            test,
+                            xfail_reason(reason)
+                        ))
+                    )
+                })
+            }
+            _ => {}
+        }
+    }
+
+    fn on_run_complete(&self, summary: &RunSummary) {
+        self.progress.finish_and_clear();
+        if let Some(max) = self.max_failures_output {
+            let hidden = self
+                .failure_count
+                .load(Ordering::SeqCst)
+                .saturating_sub(max);
+            if hidden > 0 {
+                println!(
+                    "{}",
+                    Colour::Red.paint(format!("...and {} more failed (see log)", hidden))
+                );
+            }
+        }
+        if let Some(count) = self.slowest {
+            print_slowest(summary, count);
+        }
+        if let Some(count) = self.most_memory {
+            print_most_memory(summary, count);
+        }
+        if self.group_by_dir {
+            print_by_dir(summary);
+        }
+    }
+}
+
+/// The number of lines of the stderr of a failed test printed by default
+pub const DEFAULT_MAX_STDERR_LINES: usize = 50;
+
+/// Keep the first and last lines of the text, replacing those in between with a marker if there are more than
+/// the given number of lines
+fn truncate_lines(text: &str, max_lines: Option<usize>) -> std::borrow::Cow<'_, str> {
+    let lines: Vec<&str> = text.lines().collect();
+    match max_lines {
+        Some(max) if lines.len() > max => {
+            let head = max.div_ceil(2);
+            let tail = max - head;
+            format!(
+                "{}\n… {} lines omitted (see log) …\n{}",
+                lines[..head].join("\n"),
+                lines.len() - max,
+                lines[lines.len() - tail..].join("\n")
+            )
+            .into()
+        }
+        _ => text.into(),
+    }
+}
+
+/// The number of lines of the test printed before and after the line of a failure
+const CONTEXT_LINES: usize = 2;
+
+/// Above this many pairs of lines, the expected and the actual value are printed in full rather than diffed
+const MAX_DIFF_PAIRS: usize = 1_000_000;
+
+/// Print the diff of the expected and the actual value of a failure, and the lines of the test around it
+fn print_failure_details(failure: &TestFailure) {
+    if let (Some(expected), Some(actual)) = (&failure.expected, &failure.actual) {
+        if !expected.contains('\n') && !actual.contains('\n') {
+            println!(
+                "{}",
+                Colour::Green.paint(format!("    expected: {}", expected))
+            );
+            println!("{}", Colour::Red.paint(format!("    actual:   {}", actual)));
+        } else {
+            println!(
+                "    {} {}",
+                Colour::Green.paint("- expected"),
+                Colour::Red.paint("+ actual")
+            );
+            for (change, line) in diff_lines(expected, actual) {
+                match change {
+                    '-' => println!("{}", Colour::Green.paint(format!("    - {}", line))),
+                    '+' => println!("{}", Colour::Red.paint(format!("    + {}", line))),
+                    _ => println!("      {}", line),
+                }
+            }
+        }
+    }
+
+    let (Some(file), Some(line)) = (&failure.file, failure.line) else {
+        return;
+    };
+    let Ok(source) = std::fs::read_to_string(file) else {
+        return;
+    };
+    let line = line as usize;
+    let first = line.saturating_sub(CONTEXT_LINES).max(1);
+    for (number, text) in source
+        .lines()
+        .enumerate()
+        .map(|(i, text)| (i + 1, text))
+        .skip(first - 1)
+        .take(line + CONTEXT_LINES + 1 - first)
+    {
+        let marker = if number == line { '>' } else { ' ' };
+        println!("    {} {:>4} | {}", marker, number, text);
+    }
+}
+
+/// The lines of the expected and the actual text, marked `-` if only in the expected one, `+` if only in the
+/// actual one and ` ` if in both, by their longest common subsequence
+fn diff_lines<'a>(expected: &'a str, actual: &'a str) -> Vec<(char, &'a str)> {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    if expected.len().saturating_mul(actual.len()) > MAX_DIFF_PAIRS {
+        let removed = expected.iter().map(|line| ('-', *line));
+        return removed
+            .chain(actual.iter().map(|line| ('+', *line)))
+            .collect();
+    }
+
+    // The length of the longest common subsequence of the suffixes starting at each pair of lines
+    let mut lengths = vec![vec![0usize; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            lengths[i][j] = if expected[i] == actual[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() && j < actual.len() {
+        if expected[i] == actual[j] {
+            diff.push((' ', expected[i]));
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            diff.push(('-', expected[i]));
+            i += 1;
+        } else {
+            diff.push(('+', actual[j]));
+            j += 1;
+        }
+    }
+    diff.extend(expected[i..].iter().map(|line| ('-', *line)));
+    diff.extend(actual[j..].iter().map(|line| ('+', *line)));
+    diff
+}
+
+/// The reason of an `xfail` directive, which may be left out
+fn xfail_reason(reason: &str) -> &str {
+    match reason {
+        "" => "xfail",
+        reason => reason,
+    }
+}
+
+/// The width the rankings are laid out in when stdout is not a terminal
+const DEFAULT_WIDTH: usize = 80;
+
+/// The width of the value column of the rankings
+const VALUE_WIDTH: usize = 10;
+
+/// Print the given number of slowest tests that were run
+fn print_slowest(summary: &RunSummary, count: usize) {
+    let mut results: Vec<&TestResult> = summary
+        .results
+        .iter()
+        .filter(|result| !matches!(result.outcome, TestOutcome::Skipped(_)))
+        .collect();
+    results.sort_by_key(|result| std::cmp::Reverse(result.duration));
+
+    let rows = results.into_iter().take(count).map(|result| {
+        let colour = if result.duration < Duration::from_secs(1) {
+            Colour::Green
+        } else if result.duration < Duration::from_secs(5) {
+            Colour::Yellow
+        } else {
+            Colour::Red
+        };
+        (format!("{:.2?}", result.duration), colour, result)
+    });
+    print_ranking("Slowest tests:", rows.collect());
+}
+
+/// Print the given number of tests that used the most memory (peak RSS). Tests run in a batch are left out, as
+/// only the usage of the whole batch is known
+fn print_most_memory(summary: &RunSummary, count: usize) {
+    let mut results: Vec<(&TestResult, u64)> = summary
+        .results
+        .iter()
+        .filter_map(|result| Some((result, result.peak_rss?)))
+        .collect();
+    if results.is_empty() {
+        // Not measured on this platform, or all tests were run in batches
+        if summary.passed + summary.failed > 0 {
+            println!("Most memory used (peak RSS): unavailable");
+        }
+        return;
+    }
+    results.sort_by_key(|(_, peak_rss)| std::cmp::Reverse(*peak_rss));
+
+    const MIB: u64 = 1024 * 1024;
+    let rows = results.into_iter().take(count).map(|(result, peak_rss)| {
+        let colour = if peak_rss < 100 * MIB {
+            Colour::Green
+        } else if peak_rss < 500 * MIB {
+            Colour::Yellow
+        } else {
+            Colour::Red
+        };
+        let value = format!("{:.1} MiB", peak_rss as f64 / MIB as f64);
+        (value, colour, result)
+    });
+    print_ranking("Most memory used (peak RSS):", rows.collect());
+}
+
+/// The counts of the results of the tests in a directory
+#[derive(Default)]
+struct DirCounts {
+    passed: usize,
+    failed: usize,
+    skipped: usize,
+}
+
+/// Print the counts of the results of the tests in each directory (the parent directory of each test file, not
+/// including its subdirectories), with the directories that have failures in red. Tests that failed as expected
+/// count as passed
+fn print_by_dir(summary: &RunSummary) {
+    let mut dirs: std::collections::BTreeMap<&std::path::Path, DirCounts> =
+        std::collections::BTreeMap::new();
+    for result in &summary.results {
+        let dir = result
+            .path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(std::path::Path::new("."));
+        let counts = dirs.entry(dir).or_default();
+        match &result.outcome {
+            TestOutcome::Skipped(_) => counts.skipped += 1,
+            outcome if outcome.is_failure() => counts.failed += 1,
+            _ => counts.passed += 1,
+        }
+    }
+    if dirs.is_empty() {
+        return;
+    }
+    let width = match terminal_size::terminal_size() {
+        Some((terminal_size::Width(width), _)) if std::io::stdout().is_terminal() => width as usize,
+        _ => DEFAULT_WIDTH,
+    };
+    // The widths of the count columns, e.g. `  12 passed   3 failed   1 skipped`
+    let count_width = dirs
+        .values()
+        .flat_map(|counts| [counts.passed, counts.failed, counts.skipped])
+        .max()
+        .unwrap_or_default()
+        .to_string()
+        .len();
+    let counts_width = 3 * count_width + " passed  failed  skipped  ".len();
+    let path_width = width.saturating_sub(counts_width).max(1);
+
+    println!("Results by directory:");
+    for (dir, counts) in dirs {
+        let colour = if counts.failed > 0 {
+            Colour::Red
+        } else if counts.passed > 0 {
+            Colour::Green
+        } else {
+            Colour::Yellow
+        };
+        let counts = format!(
+            "{:>w$} passed {:>w$} failed {:>w$} skipped",
+            counts.passed,
+            counts.failed,
+            counts.skipped,
+            w = count_width
+        );
+        println!(
+            "{}  {}",
+            colour.paint(counts),
+            truncate_start(&dir.display().to_string(), path_width)
+        );
+    }
+}
+
+/// Print a ranking of tests, as a column of values followed by a column of paths. Paths that do not fit the
+/// terminal are truncated from the start
+fn print_ranking(title: &str, rows: Vec<(String, Colour, &TestResult)>) {
+    if rows.is_empty() {
+        return;
+    }
+    let width = match terminal_size::terminal_size() {
+        Some((terminal_size::Width(width), _)) if std::io::stdout().is_terminal() => width as usize,
+        _ => DEFAULT_WIDTH,
+    };
+    let path_width = width.saturating_sub(VALUE_WIDTH + 2).max(1);
+
+    println!("{}", title);
+    for (value, colour, result) in rows {
+        // Pad before painting, as the escape codes would count towards the width
+        let value = format!("{:>width$}", value, width = VALUE_WIDTH);
+        println!(
+            "{}  {}",
+            colour.paint(value),
+            truncate_start(&result.path.display().to_string(), path_width)
+        );
+    }
+}
+
+/// Truncate the text to the given number of characters, replacing its start with an ellipsis
+fn truncate_start(text: &str, width: usize) -> String {
+    let len = text.chars().count();
+    if len <= width {
+        return text.to_string();
+    }
+    let tail: String = text.chars().skip(len - width + 1).collect();
+    format!("…{}", tail)
+}
+
+/// Newline-delimited JSON events written as the tests start and finish, e.g. to a FIFO an editor reads the
+/// results from while the run is in progress
+pub struct EventsReporter {
+    out: Mutex<File>,
+}
+
+impl EventsReporter {
+    /// Open the events file (or FIFO) at the given path. Opening a FIFO blocks until it has a reader
+    pub fn open(path: &std::path::Path) -> Result<EventsReporter, RunnerError> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(|e| {
+                RunnerError::io(format!("Failed to open events file {}", path.display()), e)
+            })?;
+        Ok(EventsReporter {
+            out: Mutex::new(file),
+        })
+    }
+
+    fn write_event(&self, event: serde_json::Value) {
+        // A poisoned lock only means that another write panicked, the file is still usable
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = writeln!(out, "{}", event).and_then(|_| out.flush()) {
+            error!("Failed to write event: {}", e);
+        }
+    }
+}
+
+impl Reporter for EventsReporter {
+    fn on_test_start(&self, test: &TestFile) {
+        self.write_event(json!({
+            "event": "test_started",
+            "path": test.path,
+        }));
+    }
+
+    fn on_test_complete(&self, result: &TestResult) {
+        let event = match &result.outcome {
+            TestOutcome::Passed => json!({
+                "event": "test_passed",
+                "path": result.path,
+                "durationMs": result.duration.as_millis() as u64,
+                "assertions": result.report.assertions,
+                "metadata": result.report.metadata,
+            }),
+            TestOutcome::Failed => json!({
+                "event": "test_failed",
+                "path": result.path,
+                "durationMs": result.duration.as_millis() as u64,
+                "exitCode": result.exit_code,
+                "signal": result.signal,
+                "stderr": result.stderr,
+                "messages": result.report.messages,
+                "failures": result.report.failures,
+                "assertions": result.report.assertions,
+                "metadata": result.report.metadata,
+                "invocation": result.invocation,
+            }),
+            TestOutcome::Skipped(reason) => json!({
+                "event": "test_skipped",
+                "path": result.path,
+                "reason": reason,
+            }),
+            TestOutcome::ExpectedFailure(reason) => json!({
+                "event": "test_expected_failure",
+                "path": result.path,
+                "durationMs": result.duration.as_millis() as u64,
+                "reason": reason,
+                "failures": result.report.failures,
+                "metadata": result.report.metadata,
+            }),
+            TestOutcome::UnexpectedPass(reason) => json!({
+                "event": "test_unexpected_pass",
+                "path": result.path,
+                "durationMs": result.duration.as_millis() as u64,
+                "reason": reason,
+                "metadata": result.report.metadata,
+            }),
+        };
+        self.write_event(event);
+    }
+}
+
+/// A format the results of a run are reported in, with --format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// The output of [`ConsoleReporter`], which is only printed to stdout
+    Human,
+    /// A single JSON document with the counts and the result of each test
+    Json,
+    /// A TAP version 13 stream
+    Tap,
+    /// A JUnit XML report
+    Junit,
+    /// A summary in Markdown, see [`crate::report::write_markdown`]
+    Markdown,
+}
+
+impl ReportFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReportFormat::Human => "human",
+            ReportFormat::Json => "json",
+            ReportFormat::Tap => "tap",
+            ReportFormat::Junit => "junit",
+            ReportFormat::Markdown => "markdown",
+        }
+    }
+}
+
+/// A format the results are reported in, and the file it is written to. Written to stdout without one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputFormat {
+    pub format: ReportFormat,
+    pub output: Option<std::path::PathBuf>,
+}
+
+/// Parse a format as given to --format: its name, optionally followed by `=` and the file to write it to, e.g.
+/// `junit=report.xml`
+pub fn parse_output_format(format: &str) -> Result<OutputFormat, String> {
+    let (name, output) = match format.split_once('=') {
+        Some((name, output)) => (name, Some(std::path::PathBuf::from(output))),
+        None => (format, None),
+    };
+    let format = match name.trim() {
+        "human" => ReportFormat::Human,
+        "json" => ReportFormat::Json,
+        "tap" => ReportFormat::Tap,
+        "junit" => ReportFormat::Junit,
+        "markdown" => ReportFormat::Markdown,
+        _ => {
+            return Err(format!(
+                "invalid format {:?}, expected human, json, tap, junit or markdown",
+                name
+            ))
+        }
+    };
+    if output
+        .as_ref()
+        .is_some_and(|output| output.as_os_str().is_empty())
+    {
+        return Err(format!("the file of the {} format is empty", format.name()));
+    }
+    Ok(OutputFormat { format, output })
+}
+
+/// The formats a run with the given options reports in: those of `formats`, with `output` as the file of the
+/// one not given a file of its own, along with those of `tap` and `markdown_report`. The human output is added
+/// unless another format is printed to stdout. Fails if the formats do not go together, e.g. two of them are
+/// printed to stdout
+pub fn output_formats(options: &RunOptions) -> Result<Vec<OutputFormat>, RunnerError> {
+    let mut formats = options.formats.clone();
+    if let Some(output) = &options.output {
+        let mut without_file = formats
+            .iter_mut()
+            .filter(|format| format.format != ReportFormat::Human && format.output.is_none());
+        match (without_file.next(), without_file.next()) {
+            (Some(format), None) => format.output = Some(output.clone()),
+            (None, _) => {
+                return Err(RunnerError::Config(
+                    "--output is given, but no format (other than human) to write to it"
+                        .to_string(),
+                ))
+            }
+            (Some(_), Some(_)) => {
+                return Err(RunnerError::Config(
+                    "--output is given for more than one format, give the file of each as e.g. \
+                     --format junit=report.xml"
+                        .to_string(),
+                ))
+            }
+        }
+    }
+    if options.tap {
+        formats.push(OutputFormat {
+            format: ReportFormat::Tap,
+            output: None,
+        });
+    }
+    if let Some(markdown_report) = &options.markdown_report {
+        formats.push(OutputFormat {
+            format: ReportFormat::Markdown,
+            output: Some(markdown_report.clone()),
+        });
+    }
+
+    if let Some(human) = formats
+        .iter()
+        .find(|format| format.format == ReportFormat::Human && format.output.is_some())
+    {
+        return Err(RunnerError::Config(format!(
+            "The human format is only printed to stdout, it cannot be written to {}",
+            human.output.as_ref().unwrap().display()
+        )));
+    }
+    let on_stdout: Vec<&str> = formats
+        .iter()
+        .filter(|format| format.output.is_none())
+        .map(|format| format.format.name())
+        .collect();
+    if on_stdout.len() > 1 {
+        return Err(RunnerError::Config(format!(
+            "Only one format can be printed to stdout, but {} are, write the others to files with e.g. \
+             --format junit=report.xml",
+            on_stdout.join(" and ")
+        )));
+    }
+    for (index, format) in formats.iter().enumerate() {
+        let Some(output) = &format.output else {
+            continue;
+        };
+        if let Some(other) = formats[..index]
+            .iter()
+            .find(|other| other.output.as_ref() == Some(output))
+        {
+            return Err(RunnerError::Config(format!(
+                "Both the {} and the {} format are written to {}",
+                other.format.name(),
+                format.format.name(),
+                output.display()
+            )));
+        }
+    }
+    if on_stdout.is_empty() {
+        formats.insert(
+            0,
+            OutputFormat {
+                format: ReportFormat::Human,
+                output: None,
+            },
+        );
+    }
+    Ok(formats)
+}
+
+/// A format that is written once all tests have finished, to stdout or to a file: JSON, TAP, JUnit or Markdown
+pub struct SummaryReporter {
+    format: OutputFormat,
+}
+
+impl SummaryReporter {
+    pub fn new(format: OutputFormat) -> SummaryReporter {
+        SummaryReporter { format }
+    }
+
+    fn write(&self, out: &mut impl Write, summary: &RunSummary) -> std::io::Result<()> {
+        match self.format.format {
+            // Printed by the console reporter instead
+            ReportFormat::Human => Ok(()),
+            ReportFormat::Json => write_json(out, summary),
+            ReportFormat::Tap => write_tap(out, summary),
+            ReportFormat::Junit => write_junit(out, summary),
+            ReportFormat::Markdown => write_markdown(out, summary),
+        }
+    }
+}
+
+impl Reporter for SummaryReporter {
+    fn on_run_complete(&self, summary: &RunSummary) {
+        let name = self.format.format.name();
+        let Some(output) = &self.format.output else {
+            if let Err(e) = self.write(&mut std::io::stdout().lock(), summary) {
+                error!("Failed to print the {} report: {}", name, e);
+            }
+            return;
+        };
+        let written = File::create(output)
+            .and_then(|file| self.write(&mut std::io::BufWriter::new(file), summary));
+        match written {
+            Ok(()) => info!("Wrote the {} report to {}", name, output.display()),
+            Err(e) => {
+                let message = format!(
+                    "Failed to write the {} report to {}: {}",
+                    name,
+                    output.display(),
+                    e
+                );
+                println!("{}", Colour::Red.paint(&message));
+                error!("{}", message);
+            }
+        }
+    }
+}
+
+/// The `::error` workflow commands of GitHub Actions for the failed tests, which GitHub shows as annotations on
+/// the lines of the diff they point at. Printed once all tests have finished, to stderr if a report is printed to
+/// stdout
+pub struct GithubAnnotationsReporter {
+    to_stderr: bool,
+}
+
+impl GithubAnnotationsReporter {
+    pub fn new(to_stderr: bool) -> GithubAnnotationsReporter {
+        GithubAnnotationsReporter { to_stderr }
+    }
+
+    /// Whether the runner is running in GitHub Actions, which sets `GITHUB_ACTIONS=true`
+    pub fn detect() -> bool {
+        std::env::var("GITHUB_ACTIONS").is_ok_and(|value| value == "true")
+    }
+}
+
+impl Reporter for GithubAnnotationsReporter {
+    fn on_run_complete(&self, summary: &RunSummary) {
+        let annotations: String = summary
+            .failures()
+            .flat_map(|result| {
+                // Shown, but not as errors, as they do not fail the run
+                let command = match summary.quarantined.contains(&result.path) {
+                    true => "warning",
+                    false => "error",
+                };
+                github_annotations(result, command)
+            })
+            .map(|annotation| annotation + "\n")
+            .collect();
+        let written = if self.to_stderr {
+            std::io::stderr().lock().write_all(annotations.as_bytes())
+        } else {
+            std::io::stdout().lock().write_all(annotations.as_bytes())
+        };
+        if let Err(e) = written {
+            error!("Failed to print the GitHub annotations: {}", e);
+        }
+    }
+}
+
+/// The annotations of a failed test: one for each failure recorded by test-utils, at the file and line it is
+/// attributed to, or else one for the test file with the end of its stderr. `command` is the workflow command,
+/// `error` or `warning`
+fn github_annotations(result: &TestResult, command: &str) -> Vec<String> {
+    let path = result.path.display().to_string();
+    if let TestOutcome::UnexpectedPass(reason) = &result.outcome {
+        return vec![github_annotation(
+            command,
+            &path,
+            None,
+            &path,
+            &format!("Passed despite its xfail directive: {}", reason),
+        )];
+    }
+    if !result.report.failures.is_empty() {
+        return result
+            .report
+            .failures
+            .iter()
+            .map(|failure| {
+                github_annotation(
+                    command,
+                    failure.file.as_deref().unwrap_or(&path),
+                    failure.line,
+                    failure.name.as_deref().unwrap_or(&path),
+                    &failure.message,
+                )
+            })
+            .collect();
+    }
+
+    let mut message = if result.timed_out {
+        format!("Timed out after {:.2?}", result.duration)
+    } else if let Some(signal) = result.signal {
+        format!("nvim crashed (signal {})", signal)
+    } else {
+        match result.exit_code {
+            Some(code) => format!("nvim exited with {}", code),
+            None => "Failed".to_string(),
+        }
+    };
+    let stderr: Vec<&str> = result.stderr.trim_end().lines().collect();
+    if !stderr.is_empty() {
+        message.push_str("\n\nstderr:\n");
+        message
+            .push_str(&stderr[stderr.len().saturating_sub(GITHUB_ANNOTATION_LINES)..].join("\n"));
+    }
+    vec![github_annotation(command, &path, None, &path, &message)]
+}
+
+/// The number of lines at the end of the stderr of a test that an annotation shows
+const GITHUB_ANNOTATION_LINES: usize = 20;
+
+fn github_annotation(
+    command: &str,
+    file: &str,
+    line: Option<u32>,
+    title: &str,
+    message: &str,
+) -> String {
+    let escape_data = |data: &str| {
+        data.replace('%', "%25")
+            .replace('\r', "%0D")
+            .replace('\n', "%0A")
+    };
+    let escape_property = |property: &str| {
+        escape_data(property)
+            .replace(':', "%3A")
+            .replace(',', "%2C")
+    };
+    let mut properties = format!("file={}", escape_property(file));
+    if let Some(line) = line {
+        properties.push_str(&format!(",line={}", line));
+    }
+    properties.push_str(&format!(",title={}", escape_property(title)));
+    format!("::{} {}::{}", command, properties, escape_data(message))
+}
@@ -0,0 +1,222 @@
+//! Reproduction bundles of failed tests: written with --record-failures, and re-run with --replay, so that a test
+//! failing in CI can be run again locally with the exact same nvim command
+
+use ansi_term::Colour;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::error::RunnerError;
+use crate::report::{sanitize_file_name, TestFailure, TestOutcome, TestResult};
+use crate::secrets::{redact_secrets, REDACTED};
+
+/// Whether an environment variable may hold a secret, judging by its name
+fn is_secret(name: &str) -> bool {
+    let name = name.to_ascii_uppercase();
+    [
+        "TOKEN",
+        "SECRET",
+        "PASSWORD",
+        "PASSWD",
+        "CREDENTIAL",
+        "AUTH",
+        "KEY",
+        "COOKIE",
+        "SESSION",
+        "PRIVATE",
+    ]
+    .iter()
+    .any(|word| name.contains(word))
+}
+
+/// The value recorded for an environment variable: [`REDACTED`] (which is not set again on replay) if it may hold
+/// a secret by its name, and otherwise the value with the secrets passed to the tests redacted
+fn redact(name: &str, value: String) -> String {
+    if is_secret(name) {
+        REDACTED.to_string()
+    } else {
+        redact_secrets(&value).into_owned()
+    }
+}
+
+/// An nvim command as it was run for a test
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Invocation {
+    pub program: String,
+    pub args: Vec<String>,
+    /// The absolute directory the command was run in, so that the relative paths of its arguments resolve
+    pub cwd: PathBuf,
+    /// The environment variables set by the runner, on top of the inherited environment, or unset if `None`
+    pub env: BTreeMap<String, Option<String>>,
+    /// The files the command reads that the runner removes once it has finished (e.g. the batch file), with
+    /// their contents, which are written again on replay
+    #[serde(default)]
+    pub files: BTreeMap<PathBuf, String>,
+    /// The entries of the runtimepath the command sets, in order, which are also in its arguments
+    #[serde(default)]
+    pub runtimepath: Vec<PathBuf>,
+}
+
+impl Invocation {
+    pub fn new(cmd: &Command, current_dir: &Path, runtimepath: &[PathBuf]) -> Invocation {
+        let lossy = |s: &std::ffi::OsStr| s.to_string_lossy().to_string();
+        Invocation {
+            program: lossy(cmd.get_program()),
+            args: cmd
+                .get_args()
+                .map(|arg| redact_secrets(&lossy(arg)).into_owned())
+                .collect(),
+            cwd: match cmd.get_current_dir() {
+                Some(cwd) => current_dir.join(cwd),
+                None => current_dir.to_path_buf(),
+            },
+            env: cmd
+                .get_envs()
+                .map(|(name, value)| {
+                    let name = lossy(name);
+                    let value = value.map(|value| redact(&name, lossy(value)));
+                    (name, value)
+                })
+                .collect(),
+            files: BTreeMap::new(),
+            runtimepath: runtimepath.to_vec(),
+        }
+    }
+
+    /// The command to run the invocation again with. Redacted variables are inherited instead
+    pub fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.program);
+        cmd.args(&self.args).current_dir(&self.cwd);
+        for (name, value) in &self.env {
+            match value {
+                Some(value) if value == REDACTED => {}
+                Some(value) => {
+                    cmd.env(name, value);
+                }
+                None => {
+                    cmd.env_remove(name);
+                }
+            }
+        }
+        cmd
+    }
+}
+
+/// A resolved external dependency, at the commit its clone was at
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordedDependency {
+    pub uri: String,
+    pub sha: String,
+}
+
+/// Everything needed to reproduce a failed test
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ReproBundle {
+    pub test: PathBuf,
+    pub outcome: TestOutcome,
+    pub invocation: Invocation,
+    pub runtimepath: Vec<PathBuf>,
+    pub dependencies: Vec<RecordedDependency>,
+    /// The environment of the runner, with secrets redacted. Only for reference, as replaying with the
+    /// environment of another machine (e.g. its PATH) would likely not work
+    pub environment: BTreeMap<String, String>,
+    pub stdout: String,
+    pub stderr: String,
+    pub failures: Vec<TestFailure>,
+}
+
+impl ReproBundle {
+    pub fn new(
+        result: &TestResult,
+        invocation: &Invocation,
+        runtimepath: &[PathBuf],
+        dependencies: &[RecordedDependency],
+    ) -> ReproBundle {
+        ReproBundle {
+            test: result.path.clone(),
+            outcome: result.outcome.clone(),
+            invocation: invocation.clone(),
+            runtimepath: runtimepath.to_vec(),
+            dependencies: dependencies.to_vec(),
+            environment: std::env::vars()
+                .map(|(name, value)| {
+                    let value = redact(&name, value);
+                    (name, value)
+                })
+                .collect(),
+            stdout: result.stdout.clone(),
+            stderr: result.stderr.clone(),
+            failures: result.report.failures.clone(),
+        }
+    }
+
+    /// Write the bundle into the given directory, named after the test. Returns the path of the bundle
+    pub fn write(&self, dir: &Path) -> Result<PathBuf, RunnerError> {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| RunnerError::io(format!("Failed to create {}", dir.display()), e))?;
+        let path = dir.join(format!("{}.json", sanitize_file_name(&self.test)));
+        let contents = serde_json::to_string_pretty(self).map_err(std::io::Error::from)?;
+        std::fs::write(&path, contents)
+            .map_err(|e| RunnerError::io(format!("Failed to write {}", path.display()), e))?;
+        Ok(path)
+    }
+}
+
+/// Run the nvim command of a reproduction bundle again, with its output going to the terminal. Returns the exit
+/// code of nvim
+pub fn replay(path: &Path) -> Result<i32, RunnerError> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| RunnerError::io(format!("Failed to read {}", path.display()), e))?;
+    let bundle: ReproBundle = serde_json::from_str(&contents).map_err(|e| {
+        RunnerError::Config(format!(
+            "{} is not a reproduction bundle: {}",
+            path.display(),
+            e
+        ))
+    })?;
+
+    for (file, contents) in &bundle.invocation.files {
+        std::fs::write(file, contents)
+            .map_err(|e| RunnerError::io(format!("Failed to write {}", file.display()), e))?;
+    }
+    // The isolated HOME (and XDG directories) of the test were removed once it had finished
+    for (name, value) in &bundle.invocation.env {
+        if let Some(value) = value {
+            if name == "HOME" || name.starts_with("XDG_") {
+                std::fs::create_dir_all(value)
+                    .map_err(|e| RunnerError::io(format!("Failed to create {}", value), e))?;
+            }
+        }
+    }
+
+    println!(
+        "{}",
+        Colour::Yellow.paint(format!(
+            "Replaying {} in {}: {} {}",
+            bundle.test.display(),
+            bundle.invocation.cwd.display(),
+            bundle.invocation.program,
+            bundle.invocation.args.join(" ")
+        ))
+    );
+    info!("Replaying {:?}", bundle.invocation);
+    for dependency in &bundle.dependencies {
+        println!("  {} at {}", dependency.uri, dependency.sha);
+    }
+
+    let status = bundle.invocation.command().status().map_err(|e| {
+        RunnerError::Nvim(format!(
+            "Failed to run {}: {}",
+            bundle.invocation.program, e
+        ))
+    })?;
+    for file in bundle.invocation.files.keys() {
+        let _ = std::fs::remove_file(file);
+    }
+    Ok(status.code().unwrap_or(1))
+}
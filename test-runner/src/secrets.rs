@@ -0,0 +1,162 @@
+//! Secrets passed to the tests as environment variables, e.g. the API token of an integration test, given by the
+//! `secrets` config field or --secret. The values are registered as soon as they are read, and replaced with
+//! `<redacted>` wherever the runner would show them: the log (see [`RedactingLogger`]), the output of the tests,
+//! and the reproduction bundles
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crate::config::{expand_path, TestConfig};
+use crate::error::RunnerError;
+
+/// What a secret is replaced with
+pub const REDACTED: &str = "<redacted>";
+
+/// The values of the secrets read so far, longest first so that a secret containing another is redacted whole
+static SECRETS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Register a value to be redacted from everything the runner shows. Empty values are ignored, as there is
+/// nothing to redact
+pub fn register(value: &str) {
+    if value.is_empty() {
+        return;
+    }
+    let mut secrets = SECRETS.lock().unwrap_or_else(|e| e.into_inner());
+    if !secrets.iter().any(|secret| secret == value) {
+        secrets.push(value.to_string());
+        secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+    }
+}
+
+/// The text with the values of the registered secrets replaced with [`REDACTED`]
+pub fn redact_secrets(text: &str) -> Cow<'_, str> {
+    let secrets = SECRETS.lock().unwrap_or_else(|e| e.into_inner());
+    let mut text = Cow::Borrowed(text);
+    for secret in secrets.iter() {
+        if text.contains(secret.as_str()) {
+            text = Cow::Owned(text.replace(secret.as_str(), REDACTED));
+        }
+    }
+    text
+}
+
+/// Parse a secret like `API_TOKEN=@~/.config/token` as given to --secret
+pub fn parse_secret(secret: &str) -> Result<(String, String), String> {
+    let (name, source) = secret.split_once('=').ok_or_else(|| {
+        "Invalid secret, expected NAME=VALUE, NAME=@FILE or NAME=$VAR".to_string()
+    })?;
+    if !is_valid_name(name) {
+        return Err(format!(
+            "Invalid secret name {:?}, expected the name of an environment variable",
+            name
+        ));
+    }
+    Ok((name.to_string(), source.to_string()))
+}
+
+/// Whether a name can be that of an environment variable. Names are kept to letters, digits and underscores, not
+/// starting with a digit, which is what shells accept
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Read the value of a secret from its source: the contents of a file for `@FILE` (without the trailing newline,
+/// `~` and environment variables being expanded in the path), the value of an environment variable of the runner
+/// for `$VAR` or `${VAR}`, and the source itself otherwise
+pub fn read_secret(name: &str, source: &str) -> Result<String, RunnerError> {
+    let value = if let Some(path) = source.strip_prefix('@') {
+        let path = expand_path(path);
+        let contents = std::fs::read_to_string(&path).map_err(|e| {
+            RunnerError::Config(format!(
+                "Failed to read the secret {} from {}: {}",
+                name,
+                path.display(),
+                e
+            ))
+        })?;
+        contents.trim_end_matches(['\n', '\r']).to_string()
+    } else if let Some(var) = source.strip_prefix('$') {
+        let var = var
+            .strip_prefix('{')
+            .and_then(|var| var.strip_suffix('}'))
+            .unwrap_or(var);
+        std::env::var(var).map_err(|_| {
+            RunnerError::Config(format!(
+                "The secret {} is read from ${}, which is not set",
+                name, var
+            ))
+        })?
+    } else {
+        source.to_string()
+    };
+    register(&value);
+    Ok(value)
+}
+
+/// Read the secrets of the config and of --secret (which override those of the config with the same name),
+/// registering their values. Returns them by name
+pub fn resolve_secrets(
+    config: &TestConfig,
+    cli: &[(String, String)],
+) -> Result<BTreeMap<String, String>, RunnerError> {
+    let sources: BTreeMap<&str, &str> = config
+        .settings
+        .secrets
+        .iter()
+        .flatten()
+        .chain(cli.iter().map(|(name, source)| (name, source)))
+        .map(|(name, source)| (name.as_str(), source.as_str()))
+        .collect();
+    sources
+        .into_iter()
+        .map(|(name, source)| Ok((name.to_string(), read_secret(name, source)?)))
+        .collect()
+}
+
+/// The sources of the secrets of the config as shown by --print-config: references to a file or an environment
+/// variable are kept, while values given in the config itself are redacted
+pub fn redact_sources(config: &mut TestConfig) {
+    for source in config
+        .settings
+        .secrets
+        .iter_mut()
+        .flat_map(|secrets| secrets.values_mut())
+    {
+        if !source.starts_with('@') && !source.starts_with('$') {
+            *source = REDACTED.to_string();
+        }
+    }
+}
+
+/// A logger passing every record to another with the registered secrets redacted from its message, so that
+/// nothing logged (e.g. the commands nvim is run with, and their environment) leaks a secret
+pub struct RedactingLogger<L>(pub L);
+
+impl<L: log::Log> log::Log for RedactingLogger<L> {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = record.args().to_string();
+        self.0.log(
+            &log::Record::builder()
+                .metadata(record.metadata().clone())
+                .args(format_args!("{}", redact_secrets(&message)))
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
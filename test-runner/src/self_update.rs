@@ -0,0 +1,254 @@
+//! The `self-update` subcommand, which replaces the runner with its latest release. The runner downloads the
+//! test-utils of its own version, so a stale runner keeps testing with a stale test-utils. The binary is
+//! downloaded from the release assets (`nvim-test-runner-<arch>-<os>`), checked against its SHA-256 checksum,
+//! and swapped in for the current one, which is kept next to it for `self-update --rollback`
+
+use ansi_term::Colour;
+use log::info;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+use crate::error::RunnerError;
+use crate::nvim::parse_version;
+use crate::test_utils::http_client;
+use crate::RunOptions;
+
+/// The latest release of the runner, as listed by the GitHub API
+const LATEST_RELEASE_URI: &str = "https://api.github.com/repos/samsze0/test.nvim/releases/latest";
+
+/// The version of this runner
+const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Deserialize, Debug)]
+struct Release {
+    tag_name: String,
+    assets: Vec<ReleaseAsset>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ReleaseAsset {
+    name: String,
+    browser_download_url: String,
+    /// The checksum GitHub computed of the asset, e.g. `sha256:…`. Absent for older releases, which have a
+    /// `<name>.sha256` asset instead
+    digest: Option<String>,
+}
+
+/// The name of the release asset holding the runner built for this platform, e.g.
+/// `nvim-test-runner-x86_64-linux`
+pub fn asset_name() -> String {
+    format!(
+        "nvim-test-runner-{}-{}{}",
+        std::env::consts::ARCH,
+        std::env::consts::OS,
+        std::env::consts::EXE_SUFFIX
+    )
+}
+
+/// Update the runner to its latest release, asking for a confirmation first unless `yes` is given. With
+/// `rollback`, restore the version the last update replaced instead
+pub fn run_self_update(options: &RunOptions, yes: bool, rollback: bool) -> Result<(), RunnerError> {
+    let exe = std::env::current_exe()
+        .and_then(std::fs::canonicalize)
+        .map_err(|e| RunnerError::io("Failed to find the path of the runner", e))?;
+    let previous = sibling(&exe, "old");
+
+    if rollback {
+        if !previous.is_file() {
+            return Err(RunnerError::Config(format!(
+                "There is no previous version to roll back to ({} does not exist)",
+                previous.display()
+            )));
+        }
+        std::fs::rename(&previous, &exe)
+            .map_err(|e| RunnerError::io(format!("Failed to restore {}", previous.display()), e))?;
+        let message = format!("Rolled back {} to the previous version", exe.display());
+        println!("{}", Colour::Green.paint(&message));
+        info!("{}", message);
+        return Ok(());
+    }
+
+    let client = http_client(options.proxy.as_deref())?;
+    let get = |uri: &str| {
+        let mut request = client.get(uri).header(
+            reqwest::header::USER_AGENT,
+            format!("nvim-test-runner/{}", CURRENT_VERSION),
+        );
+        // Unauthenticated requests to the API are rate limited per IP, which CI runners share
+        if let Ok(token) = std::env::var("GITHUB_TOKEN") {
+            if !token.is_empty() && uri.starts_with("https://api.github.com/") {
+                request = request.bearer_auth(token);
+            }
+        }
+        request
+            .send()
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| RunnerError::Network(format!("Failed to download {}: {}", uri, e)))
+    };
+
+    info!("Checking the latest release at {}", LATEST_RELEASE_URI);
+    let release: Release = get(LATEST_RELEASE_URI)?
+        .text()
+        .map_err(|e| RunnerError::Network(format!("Failed to read the latest release: {}", e)))
+        .and_then(|body| {
+            serde_json::from_str(&body).map_err(|e| {
+                RunnerError::Network(format!(
+                    "Unexpected response listing the latest release: {}",
+                    e
+                ))
+            })
+        })?;
+    let latest = release.tag_name.trim_start_matches('v');
+    let is_newer = match (parse_version(latest), parse_version(CURRENT_VERSION)) {
+        (Some(latest), Some(current)) => latest > current,
+        _ => {
+            return Err(RunnerError::Network(format!(
+                "The latest release {} is not a version",
+                release.tag_name
+            )))
+        }
+    };
+    if !is_newer {
+        let message = format!(
+            "nvim-test-runner {} is the latest version (the latest release is {})",
+            CURRENT_VERSION, latest
+        );
+        println!("{}", Colour::Green.paint(&message));
+        info!("{}", message);
+        return Ok(());
+    }
+
+    let name = asset_name();
+    let asset = release
+        .assets
+        .iter()
+        .find(|asset| asset.name == name)
+        .ok_or_else(|| {
+            RunnerError::Network(format!(
+                "The release {} has no binary for this platform ({}), update by building from source",
+                release.tag_name, name
+            ))
+        })?;
+
+    if !yes {
+        if !std::io::stdin().is_terminal() {
+            return Err(RunnerError::Config(format!(
+                "nvim-test-runner {} is available, pass --yes to update without a confirmation prompt",
+                latest
+            )));
+        }
+        print!(
+            "Update nvim-test-runner {} at {} to {}? [y/N] ",
+            CURRENT_VERSION,
+            exe.display(),
+            latest
+        );
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().lock().read_line(&mut answer)?;
+        if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+            println!("Not updating");
+            return Ok(());
+        }
+    }
+
+    let expected = match asset
+        .digest
+        .as_deref()
+        .and_then(|d| d.strip_prefix("sha256:"))
+    {
+        Some(digest) => digest.to_string(),
+        None => {
+            let checksum_name = format!("{}.sha256", name);
+            let checksum_asset = release
+                .assets
+                .iter()
+                .find(|asset| asset.name == checksum_name)
+                .ok_or_else(|| {
+                    RunnerError::Network(format!(
+                        "The release {} has no checksum of {}, not installing an unverified binary",
+                        release.tag_name, name
+                    ))
+                })?;
+            let checksums = get(&checksum_asset.browser_download_url)?
+                .text()
+                .map_err(|e| {
+                    RunnerError::Network(format!("Failed to download {}: {}", checksum_name, e))
+                })?;
+            // In the format of sha256sum: the checksum, then the file name
+            checksums
+                .split_whitespace()
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        }
+    };
+
+    info!("Downloading {}", asset.browser_download_url);
+    println!(
+        "{}",
+        Colour::Blue.paint(format!("Downloading nvim-test-runner {}", latest))
+    );
+    let binary = get(&asset.browser_download_url)?.bytes().map_err(|e| {
+        RunnerError::Network(format!(
+            "Failed to download {}: {}",
+            asset.browser_download_url, e
+        ))
+    })?;
+    let actual = format!("{:x}", Sha256::digest(&binary));
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        return Err(RunnerError::Network(format!(
+            "The checksum of the downloaded {} does not match that of the release (expected {}, got {}), \
+             not installing it",
+            name, expected, actual
+        )));
+    }
+
+    // Written next to the runner, so that it can be renamed into place
+    let downloaded = sibling(&exe, "new");
+    std::fs::write(&downloaded, &binary)
+        .map_err(|e| RunnerError::io(format!("Failed to write {}", downloaded.display()), e))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&downloaded, std::fs::Permissions::from_mode(0o755)).map_err(
+            |e| {
+                RunnerError::io(
+                    format!("Failed to make {} executable", downloaded.display()),
+                    e,
+                )
+            },
+        )?;
+    }
+    std::fs::rename(&exe, &previous)
+        .map_err(|e| RunnerError::io(format!("Failed to move {} aside", exe.display()), e))?;
+    if let Err(e) = std::fs::rename(&downloaded, &exe) {
+        // Put the current version back rather than leave no runner at all
+        let _ = std::fs::rename(&previous, &exe);
+        return Err(RunnerError::io(
+            format!("Failed to replace {}", exe.display()),
+            e,
+        ));
+    }
+
+    let message = format!(
+        "Updated nvim-test-runner from {} to {}. The previous version is kept at {}, restore it with \
+         `nvim-test-runner self-update --rollback`",
+        CURRENT_VERSION,
+        latest,
+        previous.display()
+    );
+    println!("{}", Colour::Green.paint(&message));
+    info!("{}", message);
+    Ok(())
+}
+
+/// A file next to the runner, e.g. `nvim-test-runner.old`
+fn sibling(exe: &Path, extension: &str) -> PathBuf {
+    let mut name = exe.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(extension);
+    exe.with_file_name(name)
+}
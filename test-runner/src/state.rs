@@ -0,0 +1,538 @@
+use ansi_term::Colour;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::deps::RemoteRefs;
+use crate::error::RunnerError;
+use crate::nvim::NvimCapabilities;
+use crate::report::{RunSummary, TestFailure, TestOutcome, TestResult, TestUtilsReport};
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TestDepedencyState {
+    pub uri: String,
+    pub hash: String,
+    pub branch: Option<String>,
+    pub sha: Option<String>,
+    /// Absent in state written by older runners
+    #[serde(rename = "ref", default)]
+    pub git_ref: Option<String>,
+    /// The `postInstall` commands that ran successfully in the clone, which are not run again until they change
+    /// or the dependency is cloned again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_install: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LuaTestUtilsState {
+    pub version: String,
+    /// The result-file protocol version the cached test-utils speaks; absent in state written by older runners
+    #[serde(default)]
+    pub protocol: Option<String>,
+}
+
+/// The output of `git ls-remote` for a remote, reused with --remote-ttl
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedRemoteRefs {
+    pub refs: RemoteRefs,
+    /// When the refs were listed, in seconds since the Unix epoch
+    pub fetched_at: u64,
+}
+
+impl CachedRemoteRefs {
+    pub fn new(refs: RemoteRefs) -> CachedRemoteRefs {
+        CachedRemoteRefs {
+            refs,
+            fetched_at: unix_now(),
+        }
+    }
+
+    /// Whether the refs were listed within the given duration
+    pub fn is_fresh(&self, ttl: Duration) -> bool {
+        unix_now().saturating_sub(self.fetched_at) < ttl.as_secs()
+    }
+}
+
+/// The capabilities `nvim --version` reported for an nvim binary, reused as long as the binary is unchanged
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedNvimProbe {
+    /// The modification time of the binary when it was probed, in nanoseconds since the Unix epoch
+    pub mtime_ns: u64,
+    /// The size of the binary in bytes when it was probed
+    pub size: u64,
+    pub capabilities: NvimCapabilities,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct State {
+    pub test_dependencies: Vec<TestDepedencyState>,
+    pub lua_test_utils: Option<LuaTestUtilsState>,
+    /// The refs of the remotes of the external dependencies by (normalized) uri; absent in state written by
+    /// older runners
+    #[serde(default)]
+    pub remote_refs: HashMap<String, CachedRemoteRefs>,
+    /// The capabilities of the nvim binaries the tests were run with, by the path of the binary
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub nvim_probes: HashMap<String, CachedNvimProbe>,
+}
+
+impl State {
+    pub fn new() -> State {
+        State {
+            test_dependencies: vec![],
+            lua_test_utils: None,
+            remote_refs: HashMap::new(),
+            nvim_probes: HashMap::new(),
+        }
+    }
+
+    /// Read the state from the given path. A missing or unparseable (e.g. truncated) state file results in
+    /// a new state, so that the dependencies are resolved from scratch. Fails if the file cannot be read
+    /// otherwise (e.g. for lack of permission), rather than replace a state that may be valid
+    pub fn load(path: &std::path::Path) -> Result<State, RunnerError> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                println!(
+                    "{}",
+                    Colour::Yellow.paint("State file not found, creating new state")
+                );
+                info!("State file not found, creating new state");
+                return Ok(State::new());
+            }
+            Err(e) => {
+                return Err(RunnerError::io(
+                    format!("Failed to read state file {}", path.display()),
+                    e,
+                ))
+            }
+        };
+        Ok(match serde_json::from_str(&contents) {
+            Ok(state) => state,
+            Err(e) => {
+                println!(
+                    "{}",
+                    Colour::Yellow.paint(format!(
+                        "State file {} is corrupted ({}), creating new state",
+                        path.display(),
+                        e
+                    ))
+                );
+                warn!(
+                    "State file {} is corrupted ({}), creating new state",
+                    path.display(),
+                    e
+                );
+                State::new()
+            }
+        })
+    }
+
+    /// Write the state to the given path, creating its directory if it does not exist yet
+    pub fn save(&self, path: &std::path::Path) -> Result<(), RunnerError> {
+        write_json_atomically(path, self)
+    }
+}
+
+/// Write a value as pretty JSON to a temporary file next to the given path, then rename it into place, so that
+/// the file is never left half-written (e.g. if the runner is killed)
+pub fn write_json_atomically<T: Serialize>(
+    path: &std::path::Path,
+    value: &T,
+) -> Result<(), RunnerError> {
+    let dir = path.parent().unwrap_or(std::path::Path::new("."));
+    std::fs::create_dir_all(dir)?;
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_path = dir.join(format!(".{}.{}.tmp", file_name, std::process::id()));
+
+    let write = || -> Result<(), RunnerError> {
+        let mut w = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer_pretty(&mut w, value).map_err(std::io::Error::from)?;
+        w.write_all(b"\n")?;
+        w.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    };
+    write().inspect_err(|_| {
+        let _ = std::fs::remove_file(&tmp_path);
+    })
+}
+
+/// Take an exclusive advisory lock on `state.lock` in the cache directory, waiting for other runners to
+/// release it. The lock is released when the returned file is dropped
+pub fn lock_state(cache_dir: &std::path::Path) -> Result<File, RunnerError> {
+    std::fs::create_dir_all(cache_dir)?;
+    let path = cache_dir.join("state.lock");
+    let file = File::create(&path).map_err(|e| {
+        RunnerError::io(format!("Failed to create lock file {}", path.display()), e)
+    })?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(std::fs::TryLockError::WouldBlock) => {
+            println!(
+                "{}",
+                Colour::Yellow.paint("Waiting for another runner to release the state lock...")
+            );
+            info!("Waiting for another runner to release the state lock");
+            file.lock()
+                .map_err(|e| RunnerError::io(format!("Failed to lock {}", path.display()), e))?;
+        }
+        Err(std::fs::TryLockError::Error(e)) => {
+            return Err(RunnerError::io(
+                format!("Failed to lock {}", path.display()),
+                e,
+            ));
+        }
+    }
+    Ok(file)
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The outcome of a test in a previous run, for --resume
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedTestResult {
+    pub passed: bool,
+    /// The modification time (in milliseconds since the epoch) of the test file when it was run
+    pub mtime: u64,
+}
+
+/// The outcomes of the tests in previous runs, keyed by the path of the test file. Stored in `results.json`
+/// in the cache directory
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ResultsCache {
+    pub tests: HashMap<String, CachedTestResult>,
+    /// The summary of the most recent run that finished, for --last
+    pub last_run: Option<RecordedRun>,
+    /// The number of runs in a row each quarantined test has passed in, keyed by its path
+    pub quarantine_passes: HashMap<String, usize>,
+    /// The revision (commit or archive checksum) each external dependency was at in the most recent run that
+    /// finished, by normalized uri, for --affected-by-deps
+    pub dependencies: std::collections::BTreeMap<String, String>,
+}
+
+/// The summary of a run, without the output of the tests that passed
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RecordedRun {
+    pub duration_ms: u64,
+    pub results: Vec<RecordedResult>,
+    pub unresolved_deps: Vec<String>,
+    pub unused_deps: Vec<String>,
+    pub warnings: Vec<String>,
+    pub user_config_changes: Vec<std::path::PathBuf>,
+    pub quarantined: Vec<std::path::PathBuf>,
+    pub filtered: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordedResult {
+    pub path: std::path::PathBuf,
+    pub outcome: TestOutcome,
+    pub duration_ms: u64,
+    #[serde(default)]
+    pub stderr: String,
+    #[serde(default)]
+    pub failures: Vec<TestFailure>,
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    #[serde(default)]
+    pub signal: Option<i32>,
+    #[serde(default)]
+    pub peak_rss: Option<u64>,
+    #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+    pub metadata: serde_json::Map<String, serde_json::Value>,
+    /// Of a failed test, the nvim command (and runtimepath) it was run with, to run it again locally
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invocation: Option<crate::repro::Invocation>,
+}
+
+/// How much slower or faster than in the previous run a test has to be for --compare to list it, as a factor
+/// of the previous duration
+const DURATION_CHANGE_FACTOR: f64 = 1.5;
+/// Below this difference in milliseconds a change in duration is noise, however large the factor
+const MIN_DURATION_CHANGE_MS: u64 = 100;
+
+/// The differences between a run and the previous one, for --compare
+#[derive(Debug, Clone, Default)]
+pub struct RunDelta {
+    /// The tests that passed in the previous run but fail now
+    pub newly_failed: Vec<std::path::PathBuf>,
+    pub newly_passed: Vec<std::path::PathBuf>,
+    /// The tests whose duration changed significantly, with the previous and the current duration
+    pub duration_changes: Vec<(std::path::PathBuf, Duration, Duration)>,
+    /// The tests run now but not in the previous run, e.g. as they were added or filtered out before
+    pub added: Vec<std::path::PathBuf>,
+    /// The tests run in the previous run but not now
+    pub removed: Vec<std::path::PathBuf>,
+}
+
+impl RunDelta {
+    pub fn is_empty(&self) -> bool {
+        self.newly_failed.is_empty()
+            && self.newly_passed.is_empty()
+            && self.duration_changes.is_empty()
+            && self.added.is_empty()
+            && self.removed.is_empty()
+    }
+}
+
+impl RecordedRun {
+    /// Compare the given run to this one. Tests skipped in either run only count as added or removed
+    pub fn delta(&self, summary: &RunSummary) -> RunDelta {
+        let run = |outcome: &TestOutcome| !matches!(outcome, TestOutcome::Skipped(_));
+        let previous: HashMap<&std::path::Path, &RecordedResult> = self
+            .results
+            .iter()
+            .filter(|recorded| run(&recorded.outcome))
+            .map(|recorded| (recorded.path.as_path(), recorded))
+            .collect();
+
+        let mut delta = RunDelta::default();
+        let mut current = std::collections::HashSet::new();
+        for result in summary.results.iter().filter(|r| run(&r.outcome)) {
+            current.insert(result.path.as_path());
+            let Some(recorded) = previous.get(result.path.as_path()) else {
+                delta.added.push(result.path.clone());
+                continue;
+            };
+            match (recorded.outcome.is_failure(), result.outcome.is_failure()) {
+                (false, true) => delta.newly_failed.push(result.path.clone()),
+                (true, false) => delta.newly_passed.push(result.path.clone()),
+                _ => {}
+            }
+            let before = recorded.duration_ms;
+            let after = result.duration.as_millis() as u64;
+            let (shorter, longer) = (before.min(after), before.max(after));
+            if longer - shorter >= MIN_DURATION_CHANGE_MS
+                && longer as f64 >= shorter as f64 * DURATION_CHANGE_FACTOR
+            {
+                delta.duration_changes.push((
+                    result.path.clone(),
+                    Duration::from_millis(before),
+                    result.duration,
+                ));
+            }
+        }
+        delta.removed = self
+            .results
+            .iter()
+            .filter(|recorded| run(&recorded.outcome) && !current.contains(recorded.path.as_path()))
+            .map(|recorded| recorded.path.clone())
+            .collect();
+        delta
+    }
+
+    pub fn new(summary: &RunSummary) -> RecordedRun {
+        RecordedRun {
+            duration_ms: summary.duration.as_millis() as u64,
+            results: summary
+                .results
+                .iter()
+                .map(|result| {
+                    let failed = matches!(
+                        result.outcome,
+                        TestOutcome::Failed | TestOutcome::ExpectedFailure(_)
+                    );
+                    RecordedResult {
+                        path: result.path.clone(),
+                        outcome: result.outcome.clone(),
+                        duration_ms: result.duration.as_millis() as u64,
+                        stderr: if failed {
+                            result.stderr.clone()
+                        } else {
+                            String::new()
+                        },
+                        failures: result.report.failures.clone(),
+                        exit_code: result.exit_code,
+                        signal: result.signal,
+                        peak_rss: result.peak_rss,
+                        metadata: result.report.metadata.clone(),
+                        invocation: result.invocation.clone().filter(|_| failed),
+                    }
+                })
+                .collect(),
+            unresolved_deps: summary.unresolved_deps.clone(),
+            unused_deps: summary.unused_deps.clone(),
+            warnings: summary.warnings.clone(),
+            user_config_changes: summary.user_config_changes.clone(),
+            quarantined: summary.quarantined.clone(),
+            filtered: summary.filtered,
+        }
+    }
+
+    /// The summary of the run as it was reported
+    pub fn summary(&self) -> RunSummary {
+        let results = self
+            .results
+            .iter()
+            .map(|recorded| TestResult {
+                duration: Duration::from_millis(recorded.duration_ms),
+                stderr: recorded.stderr.clone(),
+                report: TestUtilsReport {
+                    failures: recorded.failures.clone(),
+                    metadata: recorded.metadata.clone(),
+                    ..TestUtilsReport::default()
+                },
+                exit_code: recorded.exit_code,
+                signal: recorded.signal,
+                peak_rss: recorded.peak_rss,
+                invocation: recorded.invocation.clone(),
+                outcome: recorded.outcome.clone(),
+                ..TestResult::skipped(recorded.path.clone(), String::new())
+            })
+            .collect();
+        let mut summary = RunSummary::new(results, Duration::from_millis(self.duration_ms));
+        summary.unresolved_deps = self.unresolved_deps.clone();
+        summary.unused_deps = self.unused_deps.clone();
+        summary.warnings = self.warnings.clone();
+        summary.user_config_changes = self.user_config_changes.clone();
+        summary.quarantined = self.quarantined.clone();
+        summary.filtered = self.filtered;
+        summary
+    }
+}
+
+impl ResultsCache {
+    /// Read the results cache. Returns an empty cache if there is none or it cannot be parsed
+    pub fn load(path: &std::path::Path) -> ResultsCache {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, path: &std::path::Path) -> Result<(), RunnerError> {
+        write_json_atomically(path, self)
+    }
+
+    /// The modification time of a file in milliseconds since the epoch
+    pub fn mtime(path: &std::path::Path) -> Option<u64> {
+        let modified = std::fs::metadata(path).ok()?.modified().ok()?;
+        let since_epoch = modified.duration_since(std::time::UNIX_EPOCH).ok()?;
+        Some(since_epoch.as_millis() as u64)
+    }
+
+    /// Whether the test passed in a previous run and has not been modified since
+    pub fn passed_unchanged(&self, path: &std::path::Path) -> bool {
+        match self.tests.get(&path.to_string_lossy().to_string()) {
+            Some(cached) => cached.passed && ResultsCache::mtime(path) == Some(cached.mtime),
+            None => false,
+        }
+    }
+
+    /// Record the outcome of a test that was run
+    pub fn record(&mut self, path: &std::path::Path, passed: bool) {
+        let key = path.to_string_lossy().to_string();
+        match ResultsCache::mtime(path) {
+            Some(mtime) => {
+                self.tests.insert(key, CachedTestResult { passed, mtime });
+            }
+            None => {
+                self.tests.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "nvim-test-runner-test-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn loads_a_new_state_for_a_missing_or_truncated_file() {
+        let dir = temp_dir("load");
+        let path = dir.join("state.json");
+        assert!(State::load(&path).unwrap().test_dependencies.is_empty());
+
+        let mut state = State::new();
+        state.test_dependencies.push(TestDepedencyState {
+            uri: "https://github.com/foo/bar".to_string(),
+            hash: "1111111111111111111111111111111111111111".to_string(),
+            branch: None,
+            sha: None,
+            git_ref: None,
+            post_install: None,
+        });
+        state.save(&path).unwrap();
+        assert_eq!(State::load(&path).unwrap().test_dependencies.len(), 1);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, &contents[..contents.len() / 2]).unwrap();
+        let state = State::load(&path).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(state.test_dependencies.is_empty());
+    }
+
+    #[test]
+    fn fails_to_load_an_unreadable_state() {
+        let dir = temp_dir("unreadable");
+        // A directory cannot be read as a file
+        let result = State::load(&dir);
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(matches!(result, Err(RunnerError::Io(_))));
+    }
+
+    #[test]
+    fn lock_serializes_concurrent_writes() {
+        let dir = temp_dir("lock");
+        let path = dir.join("counter.json");
+        write_json_atomically(&path, &0).unwrap();
+
+        // Each write reads the count and writes it back incremented, losing increments unless the lock is held
+        // around both. The writes also share their temporary file, being in the same process
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let (dir, path) = (dir.clone(), path.clone());
+                std::thread::spawn(move || {
+                    for _ in 0..25 {
+                        let _lock = lock_state(&dir).unwrap();
+                        let count: u64 =
+                            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+                        std::thread::yield_now();
+                        write_json_atomically(&path, &(count + 1)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let count: u64 = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(count, 100);
+    }
+}
@@ -0,0 +1,264 @@
+use std::fs::File;
+use std::io::Write;
+
+use crate::error::RunnerError;
+use crate::{interrupt, nvim};
+
+/// The version of the protocol between the runner and test-utils (e.g. the result file).
+/// Bump the minor version for backwards compatible additions and the major version for breaking changes
+pub const TEST_UTILS_PROTOCOL_VERSION: &str = "1.10";
+
+/// Whether a cached test-utils speaking protocol `cached` can be used by a runner expecting protocol `expected`.
+/// The major versions must match and the cached minor version must be at least the expected one
+pub fn is_protocol_compatible(cached: &str, expected: &str) -> bool {
+    let parse = |version: &str| -> Option<(u64, u64)> {
+        let mut parts = version.trim().split('.');
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next().unwrap_or("0").parse().ok()?;
+        Some((major, minor))
+    };
+    match (parse(cached), parse(expected)) {
+        (Some((cached_major, cached_minor)), Some((expected_major, expected_minor))) => {
+            cached_major == expected_major && cached_minor >= expected_minor
+        }
+        _ => false,
+    }
+}
+
+/// The protocol version a test-utils declares with its `T.PROTOCOL_VERSION = "<version>"` line, if any
+pub fn parse_protocol_version(test_utils: &str) -> Option<String> {
+    test_utils.lines().find_map(|line| {
+        let value = line.trim().strip_prefix("T.PROTOCOL_VERSION")?;
+        let value = value.trim_start().strip_prefix('=')?.trim();
+        let value = value.strip_prefix('"')?;
+        let (version, _) = value.split_once('"')?;
+        Some(version.to_string())
+    })
+}
+
+/// The Lua module test-utils is loaded as. Namespaced, it is `test.utils`, which does not collide with a
+/// `test-utils` module a dependency may ship
+pub fn test_utils_module(namespaced: bool) -> &'static str {
+    if namespaced {
+        "test.utils"
+    } else {
+        "test-utils"
+    }
+}
+
+/// The path in the cache dir that test-utils is downloaded to, such that it is found as its module
+pub fn test_utils_path(cache_dir: &std::path::Path, namespaced: bool) -> std::path::PathBuf {
+    if namespaced {
+        cache_dir.join("lua/test/utils.lua")
+    } else {
+        cache_dir.join("lua/test-utils.lua")
+    }
+}
+
+/// The url to download the test-utils of the given version from
+pub fn test_utils_uri(version: &str) -> String {
+    // https://raw.githubusercontent.com/samsze0/test.nvim/{version}/lua/test/init.lua
+    format!(
+        "https://raw.githubusercontent.com/samsze0/test.nvim/{}/lua/test/init.lua",
+        version
+    )
+}
+
+/// The client used for all downloads. The proxies given by the `HTTP_PROXY`, `HTTPS_PROXY` and `NO_PROXY`
+/// environment variables are honored, unless a proxy is given explicitly (which still honors `NO_PROXY`)
+pub fn http_client(proxy: Option<&str>) -> Result<reqwest::blocking::Client, RunnerError> {
+    let mut builder = reqwest::blocking::Client::builder();
+    if let Some(proxy) = proxy {
+        let proxy = reqwest::Proxy::all(proxy)
+            .map_err(|e| RunnerError::Config(format!("Invalid proxy {}: {}", proxy, e)))?
+            .no_proxy(reqwest::NoProxy::from_env());
+        builder = builder.proxy(proxy);
+    }
+    builder
+        .build()
+        .map_err(|e| RunnerError::Network(format!("Failed to create the HTTP client: {}", e)))
+}
+
+/// Download the test-utils of the given version to the given path, creating its directory if needed. Returns the
+/// protocol version it declares. Fails without writing it if it does not declare one, or one this runner cannot
+/// use
+pub fn download_test_utils(
+    client: &reqwest::blocking::Client,
+    version: &str,
+    path: &std::path::Path,
+) -> Result<String, RunnerError> {
+    let uri = test_utils_uri(version);
+    let content = client
+        .get(&uri)
+        .header(reqwest::header::USER_AGENT, "private, no-store, max-age=0")
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.text())
+        .map_err(|e| {
+            RunnerError::Network(format!("Failed to download test-utils from {}: {}", uri, e))
+        })?;
+    let protocol = parse_protocol_version(&content).ok_or_else(|| {
+        RunnerError::Network(format!(
+            "The test-utils downloaded from {} does not declare its protocol version (T.PROTOCOL_VERSION), \
+             it is older than this runner",
+            uri
+        ))
+    })?;
+    if !is_protocol_compatible(&protocol, TEST_UTILS_PROTOCOL_VERSION) {
+        return Err(RunnerError::Network(format!(
+            "The test-utils downloaded from {} speaks protocol {} but protocol {} is expected",
+            uri, protocol, TEST_UTILS_PROTOCOL_VERSION
+        )));
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = File::create(path)?;
+    file.write_all(content.as_bytes())?;
+    Ok(protocol)
+}
+
+/// Check that the cached test-utils in the given cache dir can be loaded by nvim, e.g. that the download was not
+/// truncated
+pub fn validate_test_utils(
+    cache_dir: &std::path::Path,
+    namespaced: bool,
+    wrapper: Option<&[String]>,
+) -> Result<(), RunnerError> {
+    let mut cmd = nvim::new_command(wrapper);
+    cmd.arg("--noplugin")
+        .arg("--headless")
+        .arg("-i")
+        .arg("NONE")
+        .arg("--cmd")
+        .arg(nvim::set_rtp_command(cache_dir))
+        .arg("--cmd")
+        .arg(format!("lua require('{}')", test_utils_module(namespaced)))
+        .arg("+qa");
+    let output = interrupt::output(&mut cmd).map_err(|e| {
+        RunnerError::Nvim(format!(
+            "Failed to run {}: {}",
+            nvim::describe_command(wrapper),
+            e
+        ))
+    })?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() || stderr.contains("Error") {
+        return Err(RunnerError::Nvim(format!(
+            "Cached test-utils at {} is broken, re-run with --refresh-test-utils:\n{}",
+            test_utils_path(cache_dir, namespaced).display(),
+            stderr.trim()
+        )));
+    }
+    Ok(())
+}
+
+/// Check that the cached test-utils in the given cache dir can run batches of tests (`T.run_batch`), which an
+/// older one cached for --offline may not
+pub fn check_batch_support(
+    cache_dir: &std::path::Path,
+    namespaced: bool,
+) -> Result<(), RunnerError> {
+    let path = test_utils_path(cache_dir, namespaced);
+    let test_utils = std::fs::read_to_string(&path)
+        .map_err(|e| RunnerError::io(format!("Failed to read {}", path.display()), e))?;
+    if !test_utils.contains("function T.run_batch(") {
+        return Err(RunnerError::Nvim(format!(
+            "Cached test-utils at {} cannot run batches of tests (it has no T.run_batch), re-run with \
+             --refresh-test-utils or without --batch",
+            path.display()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_batch_support, is_protocol_compatible, parse_protocol_version, test_utils_path,
+        TEST_UTILS_PROTOCOL_VERSION,
+    };
+
+    #[test]
+    fn protocol_compatibility() {
+        // The cached test-utils is upgraded if it is older, and kept if it is newer within the same major version
+        let cases = [
+            ("1.10", "1.10", true),
+            ("1.11", "1.10", true),
+            ("1.9", "1.10", false),
+            ("1.2", "1.10", false),
+            ("2.0", "1.10", false),
+            ("0.9", "1.10", false),
+            ("1", "1.0", true),
+            ("1", "1.1", false),
+            (" 1.10\n", "1.10", true),
+            ("", "1.10", false),
+            ("1.x", "1.10", false),
+        ];
+        for (cached, expected, compatible) in cases {
+            assert_eq!(
+                is_protocol_compatible(cached, expected),
+                compatible,
+                "{:?} against {:?}",
+                cached,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn parses_protocol_versions() {
+        let cases = [
+            ("T.PROTOCOL_VERSION = \"1.10\"", Some("1.10")),
+            (
+                "local T = {}\n  T.PROTOCOL_VERSION=\"2.0\" -- comment\n",
+                Some("2.0"),
+            ),
+            ("-- T.PROTOCOL_VERSION = \"1.10\"", None),
+            ("T.PROTOCOL_VERSION = 1.10", None),
+            ("local T = {}", None),
+        ];
+        for (test_utils, expected) in cases {
+            assert_eq!(
+                parse_protocol_version(test_utils).as_deref(),
+                expected,
+                "{:?}",
+                test_utils
+            );
+        }
+    }
+
+    // The test-utils of the repo is the one the runner downloads for its version
+    #[test]
+    fn repo_test_utils_speaks_the_protocol() {
+        let test_utils = include_str!("../../lua/test/init.lua");
+        assert_eq!(
+            parse_protocol_version(test_utils).as_deref(),
+            Some(TEST_UTILS_PROTOCOL_VERSION)
+        );
+    }
+
+    #[test]
+    fn checks_batch_support() {
+        let cache_dir = std::env::temp_dir().join(format!(
+            "nvim-test-runner-test-{}-batch-support",
+            std::process::id()
+        ));
+        let path = test_utils_path(&cache_dir, false);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, include_str!("../../lua/test/init.lua")).unwrap();
+        let supported = check_batch_support(&cache_dir, false);
+        std::fs::write(
+            &path,
+            "local T = {}\nT.PROTOCOL_VERSION = \"1.9\"\nreturn T\n",
+        )
+        .unwrap();
+        let unsupported = check_batch_support(&cache_dir, false);
+        let _ = std::fs::remove_dir_all(&cache_dir);
+        assert!(supported.is_ok(), "{:?}", supported);
+        assert!(unsupported
+            .unwrap_err()
+            .to_string()
+            .contains("--refresh-test-utils"));
+    }
+}
@@ -0,0 +1,321 @@
+//! Browsing the results of a run interactively, with --tui: once the suite has run, its tests are listed next to
+//! the output of the selected one, and the selected test can be run again (e.g. while fixing it) without leaving
+//! the browser. The tests run again replace their results in the summary the runner exits with
+
+use std::borrow::Cow;
+
+use ansi_term::{Colour, Style};
+use console::{Alignment, Key, Term};
+
+use crate::config::TestConfig;
+use crate::error::RunnerError;
+use crate::report::{RunSummary, TestOutcome, TestResult};
+use crate::RunOptions;
+
+const ENTER_ALTERNATE_SCREEN: &str = "\x1b[?1049h";
+const LEAVE_ALTERNATE_SCREEN: &str = "\x1b[?1049l";
+
+const HELP: &str = "j/k select  n next failure  PgUp/PgDn scroll  r run again  q quit";
+
+/// The state of the browser
+struct Browser {
+    summary: RunSummary,
+    /// The index of the selected result
+    selected: usize,
+    /// The index of the first result shown in the list
+    list_offset: usize,
+    /// The first line of the output of the selected test that is shown
+    scroll: usize,
+    /// The message shown in place of the key help, e.g. the outcome of running a test again
+    status: Option<String>,
+}
+
+/// Browse the results of a run until the user quits, running the selected test again on request. Returns the
+/// summary with the results of the tests run again in place of those they had in the run. Fails if stdout is
+/// not a terminal
+pub fn browse(
+    config: &TestConfig,
+    options: &RunOptions,
+    summary: RunSummary,
+) -> Result<RunSummary, RunnerError> {
+    let term = Term::stdout();
+    if !term.is_term() {
+        return Err(RunnerError::Config(
+            "--tui needs stdout to be a terminal".to_string(),
+        ));
+    }
+    if summary.results.is_empty() {
+        return Ok(summary);
+    }
+
+    let mut browser = Browser {
+        // Starting at the first failure, which is what there is to look at
+        selected: summary
+            .results
+            .iter()
+            .position(|r| r.outcome.is_failure())
+            .unwrap_or(0),
+        summary,
+        list_offset: 0,
+        scroll: 0,
+        status: None,
+    };
+    term.write_str(ENTER_ALTERNATE_SCREEN)?;
+    term.hide_cursor()?;
+    let result = browser.run(&term, config, options);
+    // Restoring the terminal even if drawing failed
+    let _ = term.show_cursor();
+    let _ = term.write_str(LEAVE_ALTERNATE_SCREEN);
+    result.map(|()| browser.summary)
+}
+
+impl Browser {
+    fn run(
+        &mut self,
+        term: &Term,
+        config: &TestConfig,
+        options: &RunOptions,
+    ) -> Result<(), RunnerError> {
+        loop {
+            self.draw(term)?;
+            let page = (term.size().0 as usize).saturating_sub(2).max(1);
+            let key = term.read_key()?;
+            // The status is shown until the next key
+            self.status = None;
+            match key {
+                Key::ArrowDown | Key::Char('j') => self.select(self.selected + 1),
+                Key::ArrowUp | Key::Char('k') => self.select(self.selected.saturating_sub(1)),
+                Key::Home | Key::Char('g') => self.select(0),
+                Key::End | Key::Char('G') => self.select(self.summary.results.len() - 1),
+                Key::Char('n') => self.select_next_failure(),
+                Key::PageDown | Key::Char(' ') => self.scroll += page,
+                Key::PageUp => self.scroll = self.scroll.saturating_sub(page),
+                Key::Enter | Key::Char('r') => self.run_again(term, config, options)?,
+                // Ctrl-C does not interrupt while the terminal is in raw mode to read the key
+                Key::Escape | Key::Char('q') | Key::Char('\u{3}') => return Ok(()),
+                _ => {}
+            }
+        }
+    }
+
+    fn select(&mut self, index: usize) {
+        let index = index.min(self.summary.results.len() - 1);
+        if index != self.selected {
+            self.selected = index;
+            self.scroll = 0;
+        }
+    }
+
+    /// Select the next failure after the selected test, wrapping around to the first one
+    fn select_next_failure(&mut self) {
+        let results = &self.summary.results;
+        let next = (1..=results.len())
+            .map(|offset| (self.selected + offset) % results.len())
+            .find(|&index| results[index].outcome.is_failure());
+        match next {
+            Some(index) => self.select(index),
+            None => self.status = Some("No test failed".to_string()),
+        }
+    }
+
+    /// Run the selected test again on its own, replacing its result. The filters of the run (e.g. --failed or
+    /// --tags) are not applied, and no reports are written
+    fn run_again(
+        &mut self,
+        term: &Term,
+        config: &TestConfig,
+        options: &RunOptions,
+    ) -> Result<(), RunnerError> {
+        let path = self.summary.results[self.selected].path.clone();
+        self.status = Some(format!("Running {}…", path.display()));
+        self.draw(term)?;
+
+        let mut config = config.clone();
+        config.settings.test_paths = Some(vec![glob::Pattern::escape(&path.to_string_lossy())]);
+        config.settings.discovery_command = None;
+        let options = RunOptions {
+            quiet: true,
+            changed: false,
+            failed: false,
+            affected_by_deps: false,
+            resume: false,
+            run_skipped: true,
+            since: None,
+            shard: None,
+            tags: None,
+            exclude_tags: None,
+            formats: Vec::new(),
+            output: None,
+            tap: false,
+            markdown_report: None,
+            events: None,
+            repeat: 1,
+            warmup: 0,
+            baseline: None,
+            save_baseline: None,
+            expect_tests: None,
+            min_tests: None,
+            fail_on_empty: false,
+            compare: false,
+            slowest: None,
+            most_memory: None,
+            check_unused_deps: false,
+            fail_on_unused_deps: false,
+            debug_failures: false,
+            ..options.clone()
+        };
+        // Whatever tests and dependencies print would be drawn over by the next frame
+        let run = crate::run_with_reporters(config, options, &[]);
+        term.clear_screen()?;
+
+        self.status = Some(match run {
+            Ok(run) => match run.results.into_iter().find(|r| r.path == path) {
+                Some(result) => {
+                    let status = format!(
+                        "{} {} after {:.2?}",
+                        path.display(),
+                        outcome_description(&result),
+                        result.duration
+                    );
+                    self.summary.results[self.selected] = result;
+                    self.summary.recount();
+                    self.scroll = 0;
+                    status
+                }
+                None => format!("{} was not run", path.display()),
+            },
+            Err(e) => format!("Failed to run {}: {}", path.display(), e),
+        });
+        Ok(())
+    }
+
+    /// Draw the whole screen, over what was drawn before
+    fn draw(&mut self, term: &Term) -> Result<(), RunnerError> {
+        let (rows, columns) = term.size();
+        let (rows, columns) = (rows as usize, columns as usize);
+        // Leaving the last column out, as the terminal may wrap a line that fills it
+        let width = columns.saturating_sub(1);
+        let list_width = (width * 2 / 5).clamp(20, 60).min(width);
+        let output_width = width.saturating_sub(list_width + 3);
+        let body_rows = rows.saturating_sub(2);
+
+        // Keeping the selected test in view
+        if self.selected < self.list_offset {
+            self.list_offset = self.selected;
+        } else if self.selected >= self.list_offset + body_rows {
+            self.list_offset = self.selected + 1 - body_rows;
+        }
+        let output = output_lines(&self.summary.results[self.selected]);
+        self.scroll = self.scroll.min(output.len().saturating_sub(body_rows));
+
+        let summary = &self.summary;
+        let mut frame = String::from("\x1b[H");
+        let header = format!(
+            "{} passed, {} failed, {} skipped of {} tests",
+            summary.passed,
+            summary.failed + summary.unexpected_passes,
+            summary.skipped,
+            summary.results.len()
+        );
+        frame.push_str(
+            &Style::new()
+                .bold()
+                .paint(console::truncate_str(&header, width, "…"))
+                .to_string(),
+        );
+        frame.push_str("\x1b[K\n");
+
+        for row in 0..body_rows {
+            let index = self.list_offset + row;
+            match summary.results.get(index) {
+                Some(result) => {
+                    let (marker, colour) = marker(&result.outcome);
+                    let entry = format!("{} {}", marker, result.path.display());
+                    let entry = console::pad_str(&entry, list_width, Alignment::Left, Some("…"));
+                    if index == self.selected {
+                        frame.push_str(&Style::new().reverse().paint(entry).to_string());
+                    } else {
+                        frame.push_str(&colour.paint(entry).to_string());
+                    }
+                }
+                None => frame.push_str(&" ".repeat(list_width)),
+            }
+            frame.push_str(" │ ");
+            if let Some(line) = output.get(self.scroll + row) {
+                frame.push_str(&console::truncate_str(line, output_width, "…"));
+            }
+            frame.push_str("\x1b[K\n");
+        }
+
+        let footer = console::truncate_str(self.status.as_deref().unwrap_or(HELP), width, "…");
+        frame.push_str(&Colour::Blue.paint(footer).to_string());
+        frame.push_str("\x1b[K\x1b[J");
+        term.write_str(&frame)?;
+        Ok(())
+    }
+}
+
+/// The marker of an outcome in the list, with its colour
+fn marker(outcome: &TestOutcome) -> (&'static str, Colour) {
+    match outcome {
+        TestOutcome::Passed => ("✓", Colour::Green),
+        TestOutcome::Failed => ("✗", Colour::Red),
+        TestOutcome::Skipped(_) => ("-", Colour::Yellow),
+        TestOutcome::ExpectedFailure(_) => ("✗", Colour::Yellow),
+        TestOutcome::UnexpectedPass(_) => ("!", Colour::Red),
+    }
+}
+
+fn outcome_description(result: &TestResult) -> Cow<'static, str> {
+    match &result.outcome {
+        TestOutcome::Passed => "passed".into(),
+        TestOutcome::Failed if result.timed_out => "timed out".into(),
+        TestOutcome::Failed => "failed".into(),
+        TestOutcome::Skipped(reason) => format!("was skipped ({})", reason).into(),
+        TestOutcome::ExpectedFailure(reason) => format!("failed as expected ({})", reason).into(),
+        TestOutcome::UnexpectedPass(reason) => {
+            format!("passed despite being expected to fail ({})", reason).into()
+        }
+    }
+}
+
+/// The lines shown for the selected test: its outcome, its failures, and what nvim printed
+fn output_lines(result: &TestResult) -> Vec<String> {
+    let mut lines = vec![format!(
+        "{} {} after {:.2?}",
+        result.path.display(),
+        outcome_description(result),
+        result.duration
+    )];
+    if let Some(signal) = result.signal.filter(|_| !result.timed_out) {
+        lines.push(format!("nvim crashed (signal {})", signal));
+    }
+    if !result.report.failures.is_empty() {
+        lines.push(String::new());
+        lines.extend(result.report.failures.iter().flat_map(|failure| {
+            failure
+                .to_string()
+                .lines()
+                .map(sanitize)
+                .collect::<Vec<_>>()
+        }));
+    }
+    for (name, output) in [("stdout", &result.stdout), ("stderr", &result.stderr)] {
+        if output.trim().is_empty() {
+            continue;
+        }
+        lines.push(String::new());
+        lines.push(format!("{}:", name));
+        lines.extend(output.lines().map(sanitize));
+    }
+    lines
+}
+
+/// A line of output without the escape sequences and control characters that would mess up the screen
+fn sanitize(line: &str) -> String {
+    console::strip_ansi_codes(line)
+        .replace('\t', "    ")
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect()
+}
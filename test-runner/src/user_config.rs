@@ -0,0 +1,75 @@
+//! Guarding the nvim config and data of the user, with --guard-user-config: the files under the config and the
+//! data directory of nvim (e.g. `~/.config/nvim` and `~/.local/share/nvim`) are listed with their modification
+//! time and size before the tests run and after they have finished, and the files that were added, removed or
+//! modified fail the run. Catches the tests that write to the real nvim setup of the developer, which `--isolate`
+//! would have hidden rather than reported
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// The modification time and size of each file under the guarded directories
+pub type Snapshot = HashMap<PathBuf, (Option<SystemTime>, u64)>;
+
+/// The config and the data directory of nvim as it would find them for the environment of the runner, following
+/// `$XDG_CONFIG_HOME`, `$XDG_DATA_HOME` and `$NVIM_APPNAME`
+pub fn user_dirs() -> Vec<PathBuf> {
+    let app_name = std::env::var("NVIM_APPNAME")
+        .ok()
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "nvim".to_string());
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    [
+        ("XDG_CONFIG_HOME", ".config"),
+        ("XDG_DATA_HOME", ".local/share"),
+    ]
+    .into_iter()
+    .filter_map(|(var, default)| {
+        let base = std::env::var_os(var)
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| home.as_ref().map(|home| home.join(default)))?;
+        Some(base.join(&app_name))
+    })
+    .collect()
+}
+
+/// The files under the given directories, recursively. Symlinks are listed but not followed, and directories that
+/// do not exist or cannot be read are left out
+pub fn snapshot(dirs: &[PathBuf]) -> Snapshot {
+    let mut files = Snapshot::new();
+    let mut pending: Vec<PathBuf> = dirs.to_vec();
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if metadata.is_dir() {
+                pending.push(entry.path());
+            } else {
+                files.insert(entry.path(), (metadata.modified().ok(), metadata.len()));
+            }
+        }
+    }
+    files
+}
+
+/// The files that were added, removed or modified between two snapshots, sorted by path
+pub fn changed_files(before: &Snapshot, after: &Snapshot) -> Vec<PathBuf> {
+    let mut changed: Vec<PathBuf> = after
+        .iter()
+        .filter(|(path, file)| before.get(*path) != Some(file))
+        .map(|(path, _)| path.clone())
+        .chain(
+            before
+                .keys()
+                .filter(|path| !after.contains_key(*path))
+                .cloned(),
+        )
+        .collect();
+    changed.sort();
+    changed
+}
@@ -0,0 +1,38 @@
+//! The warnings about the setup of a run, e.g. a dependency that was skipped or a test path that did not match any
+//! file. They are printed (and logged) where they happen, and also recorded here, so that --fail-on-warning can
+//! fail a run that would otherwise pass despite its configuration having drifted
+
+use ansi_term::Colour;
+use std::sync::Mutex;
+
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Print a warning about the setup of the run, log it and record it
+pub fn warn(message: impl Into<String>) {
+    let message = message.into();
+    println!("{}", Colour::Yellow.paint(&message));
+    log::warn!("{}", message);
+    record(message);
+}
+
+/// Record a warning about the setup of the run
+pub fn record(message: impl Into<String>) {
+    WARNINGS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(message.into());
+}
+
+/// The warnings recorded since the last call, in the order they happened
+pub fn take() -> Vec<String> {
+    std::mem::take(&mut *WARNINGS.lock().unwrap_or_else(|e| e.into_inner()))
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn warn_records_the_warning() {
+        super::warn("Test path foo did not match any file");
+        assert!(super::take().contains(&"Test path foo did not match any file".to_string()));
+    }
+}